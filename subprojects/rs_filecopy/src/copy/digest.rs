@@ -0,0 +1,660 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::File,
+    io::{self, Read, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+/// Length of a SHA-256 digest in bytes
+pub(crate) const SHA256_LEN: usize = 32;
+
+const SHA256_INIT: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// Incremental SHA-256 hasher. Data can be fed with [`Sha256::update`] or
+/// through its [`Write`] implementation.
+#[derive(Clone)]
+pub(crate) struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+impl Sha256 {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: SHA256_INIT,
+            block: [0; 64],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+        if self.block_len > 0 {
+            let count = std::cmp::min(64 - self.block_len, data.len());
+            self.block[self.block_len..self.block_len + count].copy_from_slice(&data[..count]);
+            self.block_len += count;
+            data = &data[count..];
+            if self.block_len < 64 {
+                return;
+            }
+            let block = self.block;
+            self.compress(&block);
+            self.block_len = 0;
+        }
+        while data.len() >= 64 {
+            let (block, rest) = data.split_at(64);
+            self.compress(block.try_into().unwrap());
+            data = rest;
+        }
+        self.block[..data.len()].copy_from_slice(data);
+        self.block_len = data.len();
+    }
+
+    pub(crate) fn finalize(mut self) -> [u8; SHA256_LEN] {
+        let bit_len = self.total_len.wrapping_mul(8);
+        let mut padding = [0u8; 72];
+        padding[0] = 0x80;
+        let pad_len = if self.block_len < 56 {
+            56 - self.block_len
+        } else {
+            120 - self.block_len
+        };
+        padding[pad_len..pad_len + 8].copy_from_slice(&bit_len.to_be_bytes());
+        self.update(&padding[..pad_len + 8]);
+
+        let mut digest = [0u8; SHA256_LEN];
+        for (chunk, word) in digest.chunks_mut(4).zip(self.state.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8; 64]) {
+        let mut w = [0u32; 64];
+        for (i, chunk) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes(chunk.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (s, v) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+}
+
+impl Write for Sha256 {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Length of an MD5 digest in bytes
+pub(crate) const MD5_LEN: usize = 16;
+
+const MD5_INIT: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+
+const MD5_S: [u32; 64] = [
+    7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9,
+    14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10, 15,
+    21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+];
+
+const MD5_K: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+/// Incremental MD5 hasher, only used for the S3 ETags.
+#[derive(Clone)]
+pub(crate) struct Md5 {
+    state: [u32; 4],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+impl Md5 {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: MD5_INIT,
+            block: [0; 64],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+        if self.block_len > 0 {
+            let count = std::cmp::min(64 - self.block_len, data.len());
+            self.block[self.block_len..self.block_len + count].copy_from_slice(&data[..count]);
+            self.block_len += count;
+            data = &data[count..];
+            if self.block_len < 64 {
+                return;
+            }
+            let block = self.block;
+            self.compress(&block);
+            self.block_len = 0;
+        }
+        while data.len() >= 64 {
+            let (block, rest) = data.split_at(64);
+            self.compress(block.try_into().unwrap());
+            data = rest;
+        }
+        self.block[..data.len()].copy_from_slice(data);
+        self.block_len = data.len();
+    }
+
+    pub(crate) fn finalize(mut self) -> [u8; MD5_LEN] {
+        let bit_len = self.total_len.wrapping_mul(8);
+        let mut padding = [0u8; 72];
+        padding[0] = 0x80;
+        let pad_len = if self.block_len < 56 {
+            56 - self.block_len
+        } else {
+            120 - self.block_len
+        };
+        padding[pad_len..pad_len + 8].copy_from_slice(&bit_len.to_le_bytes());
+        self.update(&padding[..pad_len + 8]);
+
+        let mut digest = [0u8; MD5_LEN];
+        for (chunk, word) in digest.chunks_mut(4).zip(self.state.iter()) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8; 64]) {
+        let mut m = [0u32; 16];
+        for (i, chunk) in block.chunks(4).enumerate() {
+            m[i] = u32::from_le_bytes(chunk.try_into().unwrap());
+        }
+
+        let [mut a, mut b, mut c, mut d] = self.state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f.wrapping_add(a).wrapping_add(MD5_K[i]).wrapping_add(m[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(MD5_S[i]));
+        }
+
+        for (s, v) in self.state.iter_mut().zip([a, b, c, d]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+}
+
+/// Computes the ETag which S3 assigns to an object uploaded in parts of
+/// `part_size` bytes: the MD5 digest of the concatenated MD5 digests of the
+/// parts, followed by the number of parts. Objects which fit in a single part
+/// are uploaded in one go, and their ETag is the plain MD5 digest.
+#[derive(Clone)]
+pub(crate) struct S3ETag {
+    part_size: u64,
+    part: Md5,
+    part_len: u64,
+    part_digests: Md5,
+    parts: u64,
+}
+
+impl S3ETag {
+    pub(crate) fn new(part_size: u64) -> Self {
+        Self {
+            part_size,
+            part: Md5::new(),
+            part_len: 0,
+            part_digests: Md5::new(),
+            parts: 0,
+        }
+    }
+
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.part_len == self.part_size {
+                let part = std::mem::replace(&mut self.part, Md5::new());
+                self.part_digests.update(&part.finalize());
+                self.parts += 1;
+                self.part_len = 0;
+            }
+            let count = std::cmp::min(self.part_size - self.part_len, data.len() as u64) as usize;
+            self.part.update(&data[..count]);
+            self.part_len += count as u64;
+            data = &data[count..];
+        }
+    }
+
+    pub(crate) fn finalize(mut self) -> String {
+        if self.parts == 0 {
+            return to_hex(&self.part.finalize());
+        }
+        self.part_digests.update(&self.part.finalize());
+        format!(
+            "{}-{}",
+            to_hex(&self.part_digests.finalize()),
+            self.parts + 1
+        )
+    }
+}
+
+impl Write for S3ETag {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Computes the SHA-256 digest of `data`.
+pub(crate) fn sha256(data: &[u8]) -> [u8; SHA256_LEN] {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// Weak checksum of a block, as used by rsync, which is updated in constant
+/// time when the block slides forward by a byte.
+pub(crate) struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    pub(crate) fn new(block: &[u8]) -> Self {
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for (i, &byte) in block.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add(((block.len() - i) as u32).wrapping_mul(byte as u32));
+        }
+        Self {
+            a,
+            b,
+            len: block.len() as u32,
+        }
+    }
+
+    /// Slides the block forward, dropping `out` from its start and adding
+    /// `next` to its end.
+    pub(crate) fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(next as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    pub(crate) fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// Computes the SHA-256 digest of the contents of the file at `path`.
+pub(crate) fn sha256_file(path: &Path) -> io::Result<[u8; SHA256_LEN]> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 32 * super::util::KB as usize];
+    super::util::copy_stream(&mut file, &mut hasher, None, &mut buf)?;
+    Ok(hasher.finalize())
+}
+
+/// Formats `digest` as a lowercase hex string.
+pub(crate) fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Reader which optionally hashes everything read through it. The digest of
+/// the data read since the last call is returned by
+/// [`HashingReader::take_digest`], and the S3 ETag of all the data read is
+/// returned by [`HashingReader::etag`].
+pub(crate) struct HashingReader<R: Read> {
+    inner: R,
+    hasher: Option<Sha256>,
+    etag: Option<S3ETag>,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub(crate) fn new(inner: R, hash: bool) -> Self {
+        Self {
+            inner,
+            hasher: if hash { Some(Sha256::new()) } else { None },
+            etag: None,
+        }
+    }
+
+    /// Also computes the S3 ETag of the data read, starting from `etag`.
+    pub(crate) fn with_etag(mut self, etag: S3ETag) -> Self {
+        self.etag = Some(etag);
+        self
+    }
+
+    /// Returns the S3 ETag of all the data read, if it's computed.
+    pub(crate) fn etag(self) -> Option<String> {
+        self.etag.map(S3ETag::finalize)
+    }
+
+    /// Returns the digest of the data read so far and starts a new one.
+    pub(crate) fn take_digest(&mut self) -> [u8; SHA256_LEN] {
+        match &mut self.hasher {
+            Some(hasher) => std::mem::replace(hasher, Sha256::new()).finalize(),
+            None => [0; SHA256_LEN],
+        }
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..n]);
+        }
+        if let Some(etag) = &mut self.etag {
+            etag.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+/// Reads up to `buf.len()` bytes at `offset`, stopping early only at EOF.
+/// With `zero_fill`, the part beyond EOF is filled with zeros.
+fn read_full_at(file: &File, buf: &mut [u8], offset: u64, zero_fill: bool) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read_at(&mut buf[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if zero_fill {
+        buf[filled..].fill(0);
+        filled = buf.len();
+    }
+    Ok(filled)
+}
+
+/// Computes the SHA-256 digest of `len` bytes of `file` at `offset`. Bytes
+/// missing at the end of the file are hashed as zeros with `zero_fill`.
+pub(crate) fn sha256_range(
+    file: &File,
+    offset: u64,
+    len: u64,
+    zero_fill: bool,
+) -> io::Result<[u8; SHA256_LEN]> {
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 32 * super::util::KB as usize];
+    let mut done = 0;
+    while done < len {
+        let chunk = std::cmp::min(buf.len() as u64, len - done) as usize;
+        let n = read_full_at(file, &mut buf[..chunk], offset + done, zero_fill)?;
+        hasher.update(&buf[..n]);
+        if n < chunk {
+            break;
+        }
+        done += n as u64;
+    }
+    Ok(hasher.finalize())
+}
+
+/// Returns the offset of the first byte in the `len` bytes at `offset` that
+/// differs between `a` and `b`, or the end of the range if none does. Bytes
+/// missing at the end of `b` compare as zeros with `zero_fill`.
+pub(crate) fn first_difference(
+    a: &File,
+    b: &File,
+    offset: u64,
+    len: u64,
+    zero_fill: bool,
+) -> io::Result<u64> {
+    let mut buf_a = [0u8; 32 * super::util::KB as usize];
+    let mut buf_b = [0u8; 32 * super::util::KB as usize];
+    let mut done = 0;
+    while done < len {
+        let chunk = std::cmp::min(buf_a.len() as u64, len - done) as usize;
+        let n_a = read_full_at(a, &mut buf_a[..chunk], offset + done, false)?;
+        let n_b = read_full_at(b, &mut buf_b[..chunk], offset + done, zero_fill)?;
+        let n = std::cmp::min(n_a, n_b);
+        if let Some(i) = buf_a[..n].iter().zip(&buf_b[..n]).position(|(x, y)| x != y) {
+            return Ok(offset + done + i as u64);
+        }
+        if n < chunk {
+            return Ok(offset + done + n as u64);
+        }
+        done += n as u64;
+    }
+    Ok(offset + len)
+}
+
+/// Parses a hex encoded SHA-256 digest.
+pub(crate) fn from_hex(hex: &str) -> Option<[u8; SHA256_LEN]> {
+    if hex.len() != 2 * SHA256_LEN || !hex.is_ascii() {
+        return None;
+    }
+    let mut digest = [0u8; SHA256_LEN];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(digest)
+}
+
+/// Expected SHA-256 digests of files, as listed by `sha256sum`.
+pub(crate) struct DigestList {
+    by_name: HashMap<OsString, Vec<(PathBuf, [u8; SHA256_LEN])>>,
+}
+
+impl DigestList {
+    /// Reads the `<hex>  <path>` lines of the digest file at `path`. A `*`
+    /// in front of the path, which marks binary mode, is ignored.
+    pub(crate) fn read(path: &Path) -> io::Result<Self> {
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "failure in reading digest file '{}': {}",
+                    path.to_str().unwrap_or(""),
+                    e
+                ),
+            )
+        })?;
+        let mut by_name: HashMap<OsString, Vec<(PathBuf, [u8; SHA256_LEN])>> = HashMap::new();
+        for (lineno, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = line.split_once(' ').and_then(|(hex, file)| {
+                let file = file.strip_prefix([' ', '*'])?;
+                Some((
+                    from_hex(hex)?,
+                    PathBuf::from(file.strip_prefix("./").unwrap_or(file)),
+                ))
+            });
+            let (digest, file) = match entry {
+                Some(e) => e,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "invalid line {} in digest file '{}'",
+                            lineno + 1,
+                            path.to_str().unwrap_or("")
+                        ),
+                    ))
+                }
+            };
+            if let Some(name) = file.file_name() {
+                by_name
+                    .entry(name.to_owned())
+                    .or_default()
+                    .push((file, digest));
+            }
+        }
+        Ok(Self { by_name })
+    }
+
+    /// Returns the expected digest of the file at `path`. An entry matches
+    /// if its path is `path` or a trailing part of it, and the longest
+    /// matching entry wins.
+    pub(crate) fn lookup(&self, path: &Path) -> Option<[u8; SHA256_LEN]> {
+        let path = path.strip_prefix("./").unwrap_or(path);
+        self.by_name
+            .get(path.file_name()?)?
+            .iter()
+            .filter(|(file, _)| path.ends_with(file))
+            .max_by_key(|(file, _)| file.components().count())
+            .map(|(_, digest)| *digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the ETag of `data`, fed in pieces of `chunk` bytes.
+    fn etag(data: &[u8], part_size: u64, chunk: usize) -> String {
+        let mut etag = S3ETag::new(part_size);
+        for piece in data.chunks(chunk) {
+            etag.update(piece);
+        }
+        etag.finalize()
+    }
+
+    #[test]
+    fn s3_etag_matches_the_reference() {
+        // the reference ETags were computed with Python's hashlib
+        let data: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+        for chunk in [1000, 8192, 20000] {
+            assert_eq!(
+                etag(&data, 8192, chunk),
+                "0390222aa30f620e9b40bd87dd4611f6-3"
+            );
+        }
+
+        // a single part is the plain MD5 digest, a full last part adds no
+        // empty one
+        assert_eq!(etag(&data, 20000, 4096), "c968d3e881d1ec95343a421fe3e79a1a");
+        assert_eq!(
+            etag(&data[..16384], 8192, 4096),
+            "2c2fff294312859c0f799b67776299d7-2"
+        );
+    }
+
+    #[test]
+    fn sha256_matches_the_fips_180_vectors() {
+        assert_eq!(
+            to_hex(&sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            to_hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // the padding of a 56 byte message spills into a second block
+        assert_eq!(
+            to_hex(&sha256(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+
+        // a million 'a's, fed in pieces which straddle the blocks
+        let mut hasher = Sha256::new();
+        for piece in vec![b'a'; 1_000_000].chunks(999) {
+            hasher.update(piece);
+        }
+        assert_eq!(
+            to_hex(&hasher.finalize()),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    #[test]
+    fn md5_matches_the_rfc_1321_vectors() {
+        let digits = b"1234567890".repeat(8);
+        let vectors: [(&[u8], &str); 6] = [
+            (b"", "d41d8cd98f00b204e9800998ecf8427e"),
+            (b"a", "0cc175b9c0f1b6a831c399e269772661"),
+            (b"abc", "900150983cd24fb0d6963f7d28e17f72"),
+            (b"message digest", "f96b697d7cb7938d525a2f31aaf161d0"),
+            (
+                b"abcdefghijklmnopqrstuvwxyz",
+                "c3fcd3d76192e4007dfb496cca67e13b",
+            ),
+            (&digits, "57edf4a22be3c955ac49da2e2107b67a"),
+        ];
+        for (data, digest) in vectors {
+            let mut hasher = Md5::new();
+            hasher.update(data);
+            assert_eq!(to_hex(&hasher.finalize()), digest);
+        }
+    }
+}
@@ -1,8 +1,8 @@
 use super::util;
 use std::{
-    fs::File,
+    fs::{File, Metadata},
     io::{self, Seek, SeekFrom, Write},
-    os::unix::prelude::{MetadataExt, OpenOptionsExt},
+    os::unix::prelude::{FileTypeExt, MetadataExt, OpenOptionsExt, OsStrExt, PermissionsExt},
 };
 use std::{ops::Sub, path::Path};
 
@@ -11,10 +11,654 @@ struct StatsStore {
     pub transferred: u64,
     pub total: u64,
     pub time_taken: std::time::Duration,
+    pub start_time: Option<std::time::Instant>,
+    pub rate_window: std::collections::VecDeque<(std::time::Instant, u64)>,
+    pub vanished: u64,
+    pub progress_file_updated: Option<std::time::Instant>,
+    pub journal_logged: Option<std::time::Instant>,
+    pub rate_limiter: Option<util::RateLimiter>,
+    pub free_space: Option<(std::time::Instant, u64, u64)>,
+    pub free_inodes: Option<(std::time::Instant, Option<u64>, u64)>,
+    pub created_files: u64,
+    pub deduplicated: u64,
+    pub dedupe_saved: u64,
+    pub per_file_throughput: Vec<(std::path::PathBuf, u64)>,
+    pub last_etag: Option<String>,
+    pub dir_loops: std::cell::Cell<u64>,
+    pub up_to_date: u64,
+    pub touched: u64,
+    pub copied_files: u64,
+    pub non_regular: std::cell::Cell<u64>,
+    pub removable: Vec<std::path::PathBuf>,
+    pub removed: u64,
+    pub duplicate_sources: u64,
+    pub repaired_blocks: u64,
+    pub metadata_fixed: u64,
+    pub delta_written: u64,
+    pub delta_reused: u64,
+    pub scanned: u64,
+    pub skipped_bytes: u64,
+    pub failed_bytes: u64,
+    pub removal_time: std::time::Duration,
+    pub failed: Vec<String>,
+    pub last_percent: std::cell::Cell<Option<u64>>,
+    pub pv_updated: std::cell::Cell<Option<std::time::Instant>>,
+}
+
+impl StatsStore {
+    /// Duration over which the current transfer rate is calculated
+    const RATE_WINDOW: std::time::Duration = std::time::Duration::from_secs(2);
+
+    /// Records the bytes transferred so far in the rolling rate window,
+    /// dropping the samples which are older than [`Self::RATE_WINDOW`].
+    fn record_rate_sample(&mut self) {
+        let now = std::time::Instant::now();
+        self.rate_window.push_back((now, self.transferred));
+        while self.rate_window.len() > 2
+            && now.duration_since(self.rate_window[1].0) >= Self::RATE_WINDOW
+        {
+            self.rate_window.pop_front();
+        }
+    }
+
+    /// Returns the transfer rate in bytes per second over the rolling rate
+    /// window.
+    fn current_rate(&self) -> Option<u64> {
+        let (first_time, first_bytes) = self.rate_window.front()?;
+        let (last_time, last_bytes) = self.rate_window.back()?;
+        let elapsed = last_time.duration_since(*first_time).as_secs_f64();
+        if elapsed == 0.0 {
+            return None;
+        }
+        Some(((last_bytes - first_bytes) as f64 / elapsed) as u64)
+    }
+
+    /// Returns the average transfer rate in bytes per second since the
+    /// start of the transfer.
+    fn average_rate(&self) -> Option<u64> {
+        let elapsed = self.start_time?.elapsed().as_secs_f64();
+        if elapsed == 0.0 {
+            return None;
+        }
+        Some((self.transferred as f64 / elapsed) as u64)
+    }
+}
+
+/// Format of the estimated time of completion shown with the progress.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EtaFormat {
+    /// Time remaining for the transfer to complete, as `HH:MM:SS`
+    Relative,
+    /// Projected local wall clock time at which the transfer completes
+    Clock,
+}
+
+/// Format in which the progress of the transfer is shown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressFormat {
+    /// Progress line with the current file, sizes and ETA
+    Full,
+    /// Only the overall integer percentage, on its own line whenever it
+    /// changes
+    Percent,
+    /// Numeric lines like the ones of `pv`, once a second on stderr. Each
+    /// line holds the elapsed seconds, the bytes transferred, the average
+    /// rate in bytes per second, the integer percentage and the estimated
+    /// seconds remaining, separated by spaces. The percentage and the
+    /// remaining time are 0 while the total size is unknown.
+    Pv,
+    /// Bar of the overall progress with the percentage, sizes, rate and ETA
+    Bar,
+}
+
+/// Compression algorithm used to estimate the compressibility of the source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompressAlgo {
+    Gzip,
+    Zstd,
+}
+
+/// Order in which the entries of each directory are copied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortOrder {
+    /// Entries are copied in the order returned by the filesystem
+    None,
+    /// Entries are sorted by name
+    Name,
+    /// Entries are sorted by size, smallest first, and then by name
+    Size,
+}
+
+/// Order in which all the files of a recursive copy are copied, across
+/// directories.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CopyOrder {
+    /// Files are copied in the order they were listed in
+    None,
+    /// Largest files are copied first
+    Largest,
+    /// Smallest files are copied first
+    Smallest,
+    /// Files are sorted by their relative path
+    Name,
+}
+
+/// Handling of the files of a recursive copy which aren't listed in the
+/// order file set by [`CopyOptions::order_from`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unlisted {
+    /// The files are copied after all the listed ones, in their listed order
+    Last,
+    /// The files are skipped
+    Skip,
+}
+
+/// Handling of source symlinks whose target doesn't exist.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BrokenSymlinks {
+    /// The dangling symlink is recreated as is in the destination
+    Copy,
+    /// The dangling symlink is skipped with a warning
+    Skip,
+    /// The copy fails
+    Error,
+}
+
+/// Handling of failures in setting the permissions of a destination file
+/// whose data was copied successfully.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnPermError {
+    /// The destination file is kept and a warning is printed
+    Warn,
+    /// The copy fails
+    Error,
+}
+
+/// Format of the plan printed by a dry run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DryRunFormat {
+    /// One human readable line per planned operation
+    Text,
+    /// One quoted `mkdir`, `cp` or `mv` command per planned operation, which
+    /// can be run by a POSIX shell
+    Shell,
+}
+
+/// Handling of source files which shrink while they are being copied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnShrink {
+    /// The copy fails with the old and new size of the source
+    Error,
+    /// The destination keeps the data which was available, with a warning
+    TruncateDest,
+}
+
+/// Handling of the files which end up with the same name when a recursive
+/// copy is flattened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnCollision {
+    /// The copy fails before any file is copied
+    Error,
+    /// The file is copied under its name with a numeric suffix
+    Rename,
+    /// Only the first file with the name is copied
+    Skip,
+}
+
+/// Precision of the timestamps preserved on the destination files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimesPrecision {
+    /// The sub-second part of the timestamps is dropped
+    Second,
+    /// The timestamps are preserved with nanosecond precision
+    Nanosecond,
+}
+
+/// Verification of the destination permissions after a file is copied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PermVerify {
+    /// Permissions aren't verified
+    Off,
+    /// A warning is printed if the permissions don't match the source
+    Warn,
+    /// The copy fails if the permissions don't match the source
+    Strict,
+}
+
+/// Handling of destination directories owned by another user than the one
+/// running the copy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForeignOwner {
+    /// The ownership isn't checked
+    Allow,
+    /// A warning is printed and the copy goes ahead
+    Warn,
+    /// The copy fails
+    Refuse,
+}
+
+/// Group set on the copied files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DestGroup {
+    /// The group of the source file
+    Source,
+    /// The group of the destination directory the file is copied to
+    Dir,
+    /// The group with the given id
+    Id(u32),
 }
 
 pub type ProgressHandler = fn(&Path, &Path, u64, u64, &CopyOptions);
 
+/// Maps the path of a file relative to the source directory to its path
+/// relative to the destination directory, or to `None` to skip the file.
+pub type PathMapper = std::sync::Arc<dyn Fn(&Path) -> Option<std::path::PathBuf> + Send + Sync>;
+
+/// Outcome of a single file in the transfer report.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FileStatus {
+    Copied,
+    Linked,
+    Skipped,
+    Vanished,
+    Failed,
+}
+
+impl FileStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            FileStatus::Copied => "copied",
+            FileStatus::Linked => "linked",
+            FileStatus::Skipped => "skipped",
+            FileStatus::Vanished => "vanished",
+            FileStatus::Failed => "failed",
+        }
+    }
+}
+
+/// Per file record of the transfer report
+struct FileRecord {
+    src: std::path::PathBuf,
+    dst: std::path::PathBuf,
+    size: u64,
+    duration: std::time::Duration,
+    digest: Option<[u8; super::digest::SHA256_LEN]>,
+    etag: Option<String>,
+    status: FileStatus,
+    error: Option<String>,
+}
+
+impl FileRecord {
+    fn new(src: &Path, dst: &Path, size: u64, status: FileStatus) -> Self {
+        Self {
+            src: src.to_owned(),
+            dst: dst.to_owned(),
+            size,
+            duration: std::time::Duration::ZERO,
+            digest: None,
+            etag: None,
+            status,
+            error: None,
+        }
+    }
+}
+
+/// Records of the files handled so far, shared by all the clones of the
+/// options
+type ReportRecords = std::sync::Arc<std::sync::Mutex<Vec<FileRecord>>>;
+
+/// Device and inode numbers of the sources copied by the previous source
+/// arguments and by the current one, shared by all the clones of the options
+#[derive(Default)]
+struct SeenSources {
+    previous: std::collections::HashSet<(u64, u64)>,
+    current: std::collections::HashSet<(u64, u64)>,
+}
+
+/// Writer shared by all the clones of the options
+type SharedWriter = std::sync::Arc<std::sync::Mutex<Box<dyn Write + Send>>>;
+
+/// Writes rendered progress `text` to `output`.
+fn write_progress_to(output: &SharedWriter, text: &str) {
+    if let Ok(mut output) = output.lock() {
+        let _ = output.write_all(text.as_bytes());
+        let _ = output.flush();
+    }
+}
+
+/// Destinations of the messages printed by the copy. The progress, the per
+/// file messages and the statistics go to the regular output, while warnings
+/// and failures go to the error output. By default they are stdout and
+/// stderr.
+#[derive(Clone)]
+pub struct Output {
+    out: SharedWriter,
+    err: SharedWriter,
+    stdout: bool,
+}
+
+#[allow(dead_code)]
+impl Output {
+    /// Creates an output which writes the regular messages to `out` and the
+    /// warnings and failures to `err`.
+    pub fn new(out: Box<dyn Write + Send>, err: Box<dyn Write + Send>) -> Self {
+        Self {
+            out: std::sync::Arc::new(std::sync::Mutex::new(out)),
+            err: std::sync::Arc::new(std::sync::Mutex::new(err)),
+            stdout: false,
+        }
+    }
+
+    /// Returns whether the regular output is a terminal.
+    fn is_tty(&self) -> bool {
+        // SAFETY: `isatty` has no preconditions
+        self.stdout && unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1
+    }
+
+    /// Locks the regular output for writing multiple lines.
+    fn lock_out(&self) -> std::sync::MutexGuard<'_, Box<dyn Write + Send>> {
+        self.out
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    fn write_line(writer: &SharedWriter, args: std::fmt::Arguments) {
+        if let Ok(mut writer) = writer.lock() {
+            let _ = writer.write_fmt(args);
+            let _ = writer.write_all(b"\n");
+            let _ = writer.flush();
+        }
+    }
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Self {
+            out: std::sync::Arc::new(std::sync::Mutex::new(Box::new(io::stdout()))),
+            err: std::sync::Arc::new(std::sync::Mutex::new(Box::new(io::stderr()))),
+            stdout: true,
+        }
+    }
+}
+
+/// Prints a line to the regular output of the [`CopyOptions`].
+macro_rules! outln {
+    ($copy_opts:expr, $($arg:tt)*) => {
+        Output::write_line(&$copy_opts.output.out, format_args!($($arg)*))
+    };
+}
+
+/// Prints a warning or failure line to the error output of the
+/// [`CopyOptions`].
+macro_rules! errln {
+    ($copy_opts:expr, $($arg:tt)*) => {
+        Output::write_line(&$copy_opts.output.err, format_args!($($arg)*))
+    };
+}
+
+/// A progress line split around its ETA, so that the ETA can be rendered
+/// again as time passes without any data being transferred.
+struct ProgressLine {
+    head: String,
+    tail: String,
+    transferred: u64,
+    total: u64,
+    start_time: Option<std::time::Instant>,
+}
+
+impl ProgressLine {
+    fn render(&self, format: EtaFormat) -> String {
+        let remaining = self
+            .start_time
+            .and_then(|start| estimate_remaining(self.transferred, self.total, start.elapsed()));
+        let str_eta = format_eta(remaining, std::time::SystemTime::now(), format);
+        format!("{}{:>8}{}", self.head, str_eta, self.tail)
+    }
+}
+
+/// Watches the progress line from a background thread. When no data has
+/// been transferred for longer than the stall threshold, the line is redrawn
+/// with a "(stalled Ns)" indicator once a second. With a minimum refresh
+/// interval, the line is redrawn with an up to date ETA whenever it hasn't
+/// been drawn for that long. The thread exits once the monitor is dropped.
+struct StallMonitor {
+    threshold: Option<std::time::Duration>,
+    min_refresh: Option<std::time::Duration>,
+    eta_format: EtaFormat,
+    output: SharedWriter,
+    state: std::sync::Mutex<StallState>,
+    started: std::sync::Once,
+}
+
+struct StallState {
+    line: Option<ProgressLine>,
+    in_progress: bool,
+    updated: std::time::Instant,
+    drawn: std::time::Instant,
+    shown: bool,
+}
+
+impl StallMonitor {
+    /// Width of the longest stalled indicator to be cleared once data flows
+    const SUFFIX_WIDTH: usize = 20;
+    /// Interval in which the indicator is refreshed
+    const REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_millis(1000);
+
+    fn new(copy_opts: &CopyOptions) -> Self {
+        Self {
+            threshold: copy_opts.stall_threshold,
+            min_refresh: copy_opts.progress_min_refresh,
+            eta_format: copy_opts.eta_format,
+            output: copy_opts.progress_sink(),
+            state: std::sync::Mutex::new(StallState {
+                line: None,
+                in_progress: false,
+                updated: std::time::Instant::now(),
+                drawn: std::time::Instant::now(),
+                shown: false,
+            }),
+            started: std::sync::Once::new(),
+        }
+    }
+
+    /// Interval in which the background thread checks the progress line
+    fn interval(&self) -> std::time::Duration {
+        match (self.threshold, self.min_refresh) {
+            (Some(_), Some(refresh)) => refresh.min(Self::REFRESH_INTERVAL),
+            (None, Some(refresh)) => refresh,
+            _ => Self::REFRESH_INTERVAL,
+        }
+    }
+
+    /// Records the progress `line` which was just rendered, while a file is
+    /// `in_progress`. Returns the padding which clears a previously shown
+    /// indicator from the end of the line.
+    fn update(self: &std::sync::Arc<Self>, line: ProgressLine, in_progress: bool) -> String {
+        self.started.call_once(|| {
+            let monitor = std::sync::Arc::downgrade(self);
+            std::thread::spawn(move || Self::run(monitor));
+        });
+        let mut state = match self.state.lock() {
+            Ok(s) => s,
+            Err(_) => return String::new(),
+        };
+        state.line = Some(line);
+        state.in_progress = in_progress;
+        state.updated = std::time::Instant::now();
+        state.drawn = state.updated;
+        if std::mem::replace(&mut state.shown, false) {
+            " ".repeat(Self::SUFFIX_WIDTH)
+        } else {
+            String::new()
+        }
+    }
+
+    fn run(monitor: std::sync::Weak<Self>) {
+        let interval = match monitor.upgrade() {
+            Some(m) => m.interval(),
+            None => return,
+        };
+        loop {
+            std::thread::sleep(interval);
+            let monitor = match monitor.upgrade() {
+                Some(m) => m,
+                None => break,
+            };
+            let mut state = match monitor.state.lock() {
+                Ok(s) => s,
+                Err(_) => break,
+            };
+            let line = match &state.line {
+                Some(line) if state.in_progress => line.render(monitor.eta_format),
+                _ => continue,
+            };
+            let stalled = state.updated.elapsed();
+            if monitor.threshold.is_some_and(|t| stalled >= t) {
+                let text = format!("{} (stalled {}s)", line, stalled.as_secs());
+                write_progress_to(&monitor.output, &text);
+                state.shown = true;
+                state.drawn = std::time::Instant::now();
+            } else if monitor
+                .min_refresh
+                .is_some_and(|r| state.drawn.elapsed() >= r)
+            {
+                write_progress_to(&monitor.output, &line);
+                state.drawn = std::time::Instant::now();
+            }
+        }
+    }
+}
+
+/// Renders the progress of parallel copies as one line per job with its
+/// current file, followed by a line with the overall progress. On a terminal
+/// the lines are redrawn in place with ANSI cursor movement, otherwise they
+/// are printed in full at most once a second.
+struct MultiProgress {
+    tty: bool,
+    output: SharedWriter,
+    eta_format: EtaFormat,
+    state: std::sync::Mutex<MultiProgressState>,
+}
+
+struct MultiProgressState {
+    jobs: Vec<JobProgress>,
+    base: u64,
+    total: u64,
+    start: std::time::Instant,
+    rendered: Option<std::time::Instant>,
+    lines_drawn: usize,
+}
+
+#[derive(Clone, Default)]
+struct JobProgress {
+    file: Option<String>,
+    bytes: u64,
+    size: u64,
+    transferred: u64,
+}
+
+impl MultiProgress {
+    /// Interval in which the lines are redrawn on a terminal
+    const TTY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+    /// Interval in which the lines are printed otherwise
+    const PLAIN_INTERVAL: std::time::Duration = std::time::Duration::from_millis(1000);
+
+    /// Creates the renderer for `jobs` jobs, after `base` of the `total`
+    /// bytes were already transferred.
+    fn new(jobs: usize, base: u64, total: u64, copy_opts: &CopyOptions) -> Self {
+        let tty = copy_opts.progress_tty();
+        Self {
+            tty,
+            output: copy_opts.progress_sink(),
+            eta_format: copy_opts.eta_format,
+            state: std::sync::Mutex::new(MultiProgressState {
+                jobs: vec![JobProgress::default(); jobs],
+                base,
+                total,
+                start: std::time::Instant::now(),
+                rendered: None,
+                lines_drawn: 0,
+            }),
+        }
+    }
+
+    /// Records the progress of `job` and redraws the lines if they are due.
+    fn update(&self, job: usize, progress: JobProgress) {
+        let mut state = match self.state.lock() {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+        if let Some(slot) = state.jobs.get_mut(job) {
+            *slot = progress;
+        }
+        let interval = if self.tty {
+            Self::TTY_INTERVAL
+        } else {
+            Self::PLAIN_INTERVAL
+        };
+        if state.rendered.is_some_and(|t| t.elapsed() < interval) {
+            return;
+        }
+        self.render(&mut state);
+    }
+
+    /// Marks all jobs as done and draws the final lines.
+    fn finish(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            for job in state.jobs.iter_mut() {
+                job.file = None;
+            }
+            self.render(&mut state);
+        }
+    }
+
+    fn render(&self, state: &mut MultiProgressState) {
+        state.rendered = Some(std::time::Instant::now());
+        let mut lines: Vec<String> = state
+            .jobs
+            .iter()
+            .enumerate()
+            .map(|(i, job)| match &job.file {
+                Some(file) => format!(
+                    "Job {:>2}: {:50} ({:>8} /{:>8})",
+                    i + 1,
+                    format!("'{}'", file),
+                    get_str_size_precise(job.bytes),
+                    get_str_size_precise(job.size)
+                ),
+                None => format!("Job {:>2}: idle", i + 1),
+            })
+            .collect();
+        let transferred = state.base + state.jobs.iter().map(|j| j.transferred).sum::<u64>();
+        let remaining = estimate_remaining(transferred, state.total, state.start.elapsed());
+        lines.push(format!(
+            "Total: ({:>8} /{:>8})\tETA: {:>8}",
+            get_str_size_precise(transferred),
+            get_str_size_precise(state.total),
+            format_eta(remaining, std::time::SystemTime::now(), self.eta_format)
+        ));
+
+        let mut text = String::new();
+        if self.tty {
+            if state.lines_drawn > 0 {
+                text.push_str(&format!("\x1b[{}A", state.lines_drawn));
+            }
+            for line in &lines {
+                text.push_str("\x1b[2K");
+                text.push_str(line);
+                text.push('\n');
+            }
+        } else {
+            for line in &lines {
+                text.push_str(line);
+                text.push('\n');
+            }
+        }
+        state.lines_drawn = lines.len();
+        write_progress_to(&self.output, &text);
+    }
+}
+
 #[derive(Clone)]
 pub struct CopyOptions {
     block_size: u64,
@@ -22,11 +666,103 @@ pub struct CopyOptions {
     show_progress: bool,
     recursive: bool,
     show_stats: bool,
+    per_file_throughput: bool,
+    s3_etag: Option<u64>,
     remove: bool,
+    force_remove_root: bool,
+    truncate_source_after: bool,
+    wipe_passes: Option<u32>,
     no_dir_err: bool,
     verbose: bool,
     resume: bool,
+    update: bool,
+    repair: bool,
+    delta: bool,
+    touch_only: bool,
+    sync_perms: bool,
+    sparse: bool,
+    sparse_min_hole: u64,
+    eta_format: EtaFormat,
+    progress_format: ProgressFormat,
+    modified_since: Option<std::time::SystemTime>,
+    keep_newest: Option<usize>,
+    pattern: String,
+    verify_perms: PermVerify,
+    foreign_owner: ForeignOwner,
+    require_empty_dest: bool,
+    same_fs_only: bool,
+    group: Option<DestGroup>,
+    list_only: bool,
+    dry_run: bool,
+    dry_run_format: DryRunFormat,
+    null_separated: bool,
+    no_target_directory: bool,
+    name_prefix: String,
+    name_suffix: String,
+    show_current_speed: bool,
+    sort: SortOrder,
+    order: CopyOrder,
+    order_from: Option<std::path::PathBuf>,
+    unlisted: Unlisted,
+    listed_order: Option<std::sync::Arc<Vec<String>>>,
+    truncate_long_names: bool,
+    direct_io: bool,
+    fadvise: bool,
+    tolerate_vanished: bool,
+    compress_preview: Option<CompressAlgo>,
+    selinux: bool,
+    progress_file: Option<std::path::PathBuf>,
+    journal_progress: Option<std::time::Duration>,
+    retry_operation: u32,
+    verify_only: bool,
+    checksum: bool,
+    checksum_block: bool,
+    checksum_from: Option<std::path::PathBuf>,
+    expected_digests: Option<std::sync::Arc<super::digest::DigestList>>,
+    verify_size: bool,
+    strict_total: bool,
+    copy_contents: bool,
+    limit_rate: u64,
+    ramp_up: Option<std::time::Duration>,
+    acl: bool,
+    caps: bool,
+    min_free_space: u64,
+    min_free_inodes: u64,
+    max_files: Option<u64>,
+    regular_only: bool,
+    pad_to: u64,
+    device_size: Option<u64>,
+    head: Option<u64>,
+    tail: Option<u64>,
+    offset: u64,
+    length: Option<u64>,
+    dedupe: bool,
+    transactional: bool,
+    broken_symlinks: BrokenSymlinks,
+    on_shrink: OnShrink,
+    flatten: bool,
+    on_collision: OnCollision,
+    on_perm_error: OnPermError,
+    special_bits: bool,
+    preserve_times: bool,
+    times_precision: TimesPrecision,
+    stall_threshold: Option<std::time::Duration>,
+    progress_min_refresh: Option<std::time::Duration>,
+    progress_grace: Option<std::time::Duration>,
+    stall_monitor: std::sync::OnceLock<std::sync::Arc<StallMonitor>>,
     progress_handler: Option<ProgressHandler>,
+    path_mapper: Option<PathMapper>,
+    progress_output: Option<SharedWriter>,
+    progress_output_tty: bool,
+    output: Output,
+    run_total: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    inline_errors: bool,
+    jobs: usize,
+    max_memory: Option<u64>,
+    progress_multiline: bool,
+    job_progress: Option<(std::sync::Arc<MultiProgress>, usize)>,
+    report: Option<(std::path::PathBuf, ReportRecords)>,
+    seen_sources: Option<std::sync::Arc<std::sync::Mutex<SeenSources>>>,
     stats_store: StatsStore,
 }
 
@@ -39,21 +775,149 @@ impl CopyOptions {
             show_progress: false,
             recursive: false,
             show_stats: false,
+            per_file_throughput: false,
+            s3_etag: None,
             remove: false,
+            force_remove_root: false,
+            truncate_source_after: false,
+            wipe_passes: None,
             no_dir_err: false,
             verbose: false,
             resume: false,
+            update: false,
+            repair: false,
+            delta: false,
+            touch_only: false,
+            sync_perms: false,
+            sparse: false,
+            sparse_min_hole: 4 * util::KB,
+            eta_format: EtaFormat::Relative,
+            progress_format: ProgressFormat::Full,
+            modified_since: None,
+            keep_newest: None,
+            pattern: String::from("*"),
+            verify_perms: PermVerify::Off,
+            foreign_owner: ForeignOwner::Allow,
+            require_empty_dest: false,
+            same_fs_only: false,
+            group: None,
+            list_only: false,
+            dry_run: false,
+            dry_run_format: DryRunFormat::Text,
+            null_separated: false,
+            no_target_directory: false,
+            name_prefix: String::new(),
+            name_suffix: String::new(),
+            show_current_speed: false,
+            sort: SortOrder::None,
+            order: CopyOrder::None,
+            order_from: None,
+            unlisted: Unlisted::Last,
+            listed_order: None,
+            truncate_long_names: false,
+            direct_io: false,
+            fadvise: false,
+            tolerate_vanished: false,
+            compress_preview: None,
+            selinux: false,
+            progress_file: None,
+            journal_progress: None,
+            retry_operation: 0,
+            verify_only: false,
+            checksum: false,
+            checksum_block: false,
+            checksum_from: None,
+            expected_digests: None,
+            verify_size: true,
+            strict_total: false,
+            copy_contents: false,
+            limit_rate: 0,
+            ramp_up: None,
+            acl: false,
+            caps: false,
+            min_free_space: 0,
+            min_free_inodes: 0,
+            max_files: None,
+            regular_only: false,
+            pad_to: 0,
+            device_size: None,
+            head: None,
+            tail: None,
+            offset: 0,
+            length: None,
+            dedupe: false,
+            transactional: false,
+            broken_symlinks: BrokenSymlinks::Error,
+            on_shrink: OnShrink::Error,
+            flatten: false,
+            on_collision: OnCollision::Error,
+            on_perm_error: OnPermError::Warn,
+            special_bits: true,
+            preserve_times: false,
+            times_precision: TimesPrecision::Nanosecond,
+            stall_threshold: None,
+            progress_min_refresh: None,
+            progress_grace: None,
+            stall_monitor: std::sync::OnceLock::new(),
             progress_handler: Some(default_progress_handler),
+            path_mapper: None,
+            progress_output: None,
+            progress_output_tty: false,
+            output: Output::default(),
+            run_total: std::sync::Arc::default(),
+            inline_errors: false,
+            jobs: 1,
+            max_memory: None,
+            progress_multiline: false,
+            job_progress: None,
+            report: None,
+            seen_sources: None,
             stats_store: StatsStore {
                 time_taken: std::time::Duration::from_secs(0),
                 total: 0,
                 transferred: 0,
+                start_time: None,
+                rate_window: std::collections::VecDeque::new(),
+                vanished: 0,
+                progress_file_updated: None,
+                journal_logged: None,
+                rate_limiter: None,
+                free_space: None,
+                free_inodes: None,
+                created_files: 0,
+                deduplicated: 0,
+                dedupe_saved: 0,
+                per_file_throughput: Vec::new(),
+                last_etag: None,
+                dir_loops: std::cell::Cell::new(0),
+                up_to_date: 0,
+                touched: 0,
+                copied_files: 0,
+                non_regular: std::cell::Cell::new(0),
+                removable: Vec::new(),
+                removed: 0,
+                duplicate_sources: 0,
+                repaired_blocks: 0,
+                metadata_fixed: 0,
+                delta_written: 0,
+                delta_reused: 0,
+                scanned: 0,
+                skipped_bytes: 0,
+                failed_bytes: 0,
+                removal_time: std::time::Duration::ZERO,
+                failed: Vec::new(),
+                last_percent: std::cell::Cell::new(None),
+                pv_updated: std::cell::Cell::new(None),
             },
         }
     }
 
+    /// Size of the blocks in which the data is transferred, which is also the
+    /// size of the buffer a file is copied through. Sizes out of the range of
+    /// 4K to 1G are clamped to it, while a size of 0 is rejected by
+    /// [`CopyOptions::validate`].
     pub fn block_size(&mut self, blk_size: u64) -> &mut Self {
-        self.block_size = blk_size;
+        self.block_size = util::clamp_block_size(blk_size);
         self
     }
 
@@ -77,16 +941,135 @@ impl CopyOptions {
         self
     }
 
+    /// Allow a moved source directory to be removed even if it is the root
+    /// of a filesystem, the home directory or the current working directory.
+    pub fn force_remove_root(&mut self, force: bool) -> &mut Self {
+        self.force_remove_root = force;
+        self
+    }
+
+    /// Truncate every moved source file to zero right before it's removed once
+    /// the whole move is copied, so that its space is freed even while it's
+    /// still held open. Sources with other hard links are left as is. This is
+    /// not a secure wipe.
+    pub fn truncate_source_after(&mut self, truncate: bool) -> &mut Self {
+        self.truncate_source_after = truncate;
+        self
+    }
+
+    /// Overwrite every moved source file with `passes` passes of random data
+    /// and a final pass of zeros, each synced to the disk, right before it's
+    /// removed once the whole move is copied. Sources with other hard links
+    /// are left as is. This is best effort, as journaling and copy-on-write
+    /// filesystems may keep the old data elsewhere.
+    pub fn wipe_source(&mut self, passes: Option<u32>) -> &mut Self {
+        self.wipe_passes = passes;
+        self
+    }
+
     pub fn stats(&mut self, show_stats: bool) -> &mut Self {
         self.show_stats = show_stats;
         self
     }
 
+    /// Record the throughput of every copied file, which is printed in
+    /// verbose mode and along with the statistics.
+    pub fn per_file_throughput(&mut self, record: bool) -> &mut Self {
+        self.per_file_throughput = record;
+        self
+    }
+
+    /// Compute the ETag which S3 assigns to each copied file when it's
+    /// uploaded in parts of `part_size` bytes, while copying it. The ETags are
+    /// printed in verbose mode and written to the report.
+    pub fn s3_etag(&mut self, part_size: Option<u64>) -> &mut Self {
+        self.s3_etag = part_size.filter(|size| *size > 0);
+        self
+    }
+
     pub fn progress_handler(&mut self, handler: ProgressHandler) -> &mut Self {
         self.progress_handler = Some(handler);
         self
     }
 
+    /// Place each file of a recursive copy at the destination path returned by
+    /// `mapper` for its path relative to the source directory, skipping the
+    /// files it returns `None` for. The mapped paths have to stay within the
+    /// destination directory.
+    pub fn path_mapper(&mut self, mapper: Option<PathMapper>) -> &mut Self {
+        self.path_mapper = mapper;
+        self
+    }
+
+    /// Render the progress to `output` instead of the regular output.
+    pub fn progress_output(&mut self, output: Box<dyn Write + Send>) -> &mut Self {
+        self.progress_output = Some(std::sync::Arc::new(std::sync::Mutex::new(output)));
+        self.progress_output_tty = false;
+        self
+    }
+
+    /// Render the progress to the controlling terminal instead of the regular
+    /// output, so that it stays visible while stdout and stderr are piped.
+    /// Falls back to stderr without a controlling terminal, and hides the
+    /// progress if stderr isn't a terminal either.
+    pub fn progress_to_terminal(&mut self, enable: bool) -> &mut Self {
+        if !enable {
+            return self;
+        }
+        let (output, tty): (Box<dyn Write + Send>, bool) =
+            match std::fs::OpenOptions::new().write(true).open("/dev/tty") {
+                Ok(terminal) => (Box::new(terminal), true),
+                // SAFETY: `isatty` has no preconditions
+                Err(_) if unsafe { libc::isatty(libc::STDERR_FILENO) } == 1 => {
+                    (Box::new(io::stderr()), true)
+                }
+                Err(_) => (Box::new(io::sink()), false),
+            };
+        self.progress_output(output);
+        self.progress_output_tty = tty;
+        self
+    }
+
+    /// Print all the messages of the copy to `output` instead of stdout and
+    /// stderr, to capture them when embedding the copy.
+    pub fn output(&mut self, output: Output) -> &mut Self {
+        self.output = output;
+        self
+    }
+
+    /// Writes rendered progress `text` to the configured progress output,
+    /// which is the regular output by default. Progress handlers should use
+    /// this so that the output can be redirected.
+    pub fn write_progress(&self, text: &str) {
+        write_progress_to(
+            self.progress_output.as_ref().unwrap_or(&self.output.out),
+            text,
+        );
+    }
+
+    /// Returns the writer the progress is rendered to.
+    fn progress_sink(&self) -> SharedWriter {
+        self.progress_output
+            .clone()
+            .unwrap_or_else(|| self.output.out.clone())
+    }
+
+    /// Returns whether the progress is rendered to a terminal.
+    fn progress_tty(&self) -> bool {
+        if self.progress_output.is_some() {
+            self.progress_output_tty
+        } else {
+            self.output.is_tty()
+        }
+    }
+
+    /// Print the files which fail to copy when errors are ignored on their
+    /// own lines above the progress line, and list them again at the end.
+    pub fn progress_inline_errors(&mut self, inline: bool) -> &mut Self {
+        self.inline_errors = inline;
+        self
+    }
+
     pub fn dircopy_err(&mut self, ignore: bool) -> &mut Self {
         self.no_dir_err = ignore;
         self
@@ -101,410 +1084,10458 @@ impl CopyOptions {
         self.resume = is_resume;
         self
     }
-}
 
-fn copy_directory(src: &Path, dst: &Path, copy_opts: &mut CopyOptions) -> Result<(), io::Error> {
-    // get the list of all files under src recursively
-    let filelist = util::list_dir_recursive_rel(Path::new(src))?;
+    /// Replace existing destination files only when the source is newer, or
+    /// has the same modification time but a different size. Other existing
+    /// files are skipped as up to date.
+    pub fn update(&mut self, is_update: bool) -> &mut Self {
+        self.update = is_update;
+        self
+    }
 
-    // calculate total bytes to be copied
-    for fileinfo in &filelist {
-        copy_opts.stats_store.total += fileinfo.size();
+    /// Repair existing destination files in place. Both files are compared
+    /// block by block and only the blocks which differ, or are missing from
+    /// the destination, are copied.
+    pub fn repair(&mut self, is_repair: bool) -> &mut Self {
+        self.repair = is_repair;
+        self
     }
 
-    for fileinfo in &filelist {
-        let cpy_src = src.join(fileinfo.path());
-        let dst_src = dst.join(fileinfo.path());
-        if let Err(e) = copy_file(cpy_src.as_path(), dst_src.as_path(), copy_opts) {
-            if !copy_opts.no_dir_err {
-                return Err(e);
-            } else {
-                println!("Failed to copy file: {}", &e);
-            }
-        } else if copy_opts.remove {
-            if let Err(e) = std::fs::remove_file(&cpy_src) {
-                if !copy_opts.no_dir_err {
-                    return Err(io::Error::new(
-                        e.kind(),
-                        format!("failed to remove source file: {}", &e),
-                    ));
-                }
-            }
-        }
+    /// Update existing destination files in place with an rsync style delta
+    /// transfer. The parts of the source which are found anywhere in the old
+    /// destination are reused, and only the rest is written.
+    pub fn delta(&mut self, is_delta: bool) -> &mut Self {
+        self.delta = is_delta;
+        self
     }
 
-    if copy_opts.remove {
-        if let Err(e) = util::delete_dir_recursive(src) {
-            return Err(io::Error::new(
-                e.kind(),
-                format!("failed to remove source directory: {}", &e),
-            ));
-        } else {
-            return Ok(());
-        }
+    /// Only set the timestamps of the source on the existing destination
+    /// files of the same size, whose contents are assumed to match, instead
+    /// of copying them. With `checksum`, the contents are compared first and
+    /// the files which differ are copied.
+    pub fn touch_only(&mut self, is_touch_only: bool) -> &mut Self {
+        self.touch_only = is_touch_only;
+        self
     }
 
-    Ok(())
-}
+    /// Re-apply the permissions, the group and, if preserved, the timestamps
+    /// of the source to the destination files skipped as up to date, when
+    /// they differ, without copying their data. Requires `update`.
+    pub fn sync_perms(&mut self, is_sync_perms: bool) -> &mut Self {
+        self.sync_perms = is_sync_perms;
+        self
+    }
 
-/// copy copies `src` to `dst` based on the configuration options provded
-/// in `copy_opts`.
-pub fn copy(src: &str, dst: &str, copy_opts: CopyOptions) -> io::Result<()> {
-    // if source and destination paths are same, abort copy
-    if src == dst {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "destination is same as the source",
-        ));
+    pub fn sparse(&mut self, is_sparse: bool) -> &mut Self {
+        self.sparse = is_sparse;
+        self
     }
 
-    let mut copy_opts = copy_opts;
+    pub fn sparse_min_hole(&mut self, min_hole: u64) -> &mut Self {
+        self.sparse_min_hole = min_hole;
+        self
+    }
 
-    let source = Path::new(src);
-    let mut destination = Path::new(dst).to_owned();
+    pub fn eta_format(&mut self, format: EtaFormat) -> &mut Self {
+        self.eta_format = format;
+        self
+    }
 
-    // check if the source path exists
-    let src_stat = match std::fs::metadata(source) {
-        Err(e) => {
-            return Err(io::Error::new(
-                e.kind(),
-                format!("stat failed for source path: {}", &e),
-            ))
-        }
-        Ok(s) => s,
-    };
+    /// Show the progress in the given format. With [`ProgressFormat::Percent`]
+    /// and [`ProgressFormat::Pv`] the per file messages printed along with the
+    /// progress are suppressed. Nothing is shown with
+    /// [`ProgressFormat::Percent`] while the total size is unknown.
+    pub fn progress_format(&mut self, format: ProgressFormat) -> &mut Self {
+        self.progress_format = format;
+        self
+    }
 
-    // check for recursive copy
-    if src_stat.is_dir() && !copy_opts.recursive {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "source is a directory but --recursive option not specified",
-        ));
+    /// Copy only the files modified after `time`, like the start of the last
+    /// successful run. All files are copied if `time` is `None`.
+    pub fn modified_since(&mut self, time: Option<std::time::SystemTime>) -> &mut Self {
+        self.modified_since = time;
+        self
     }
 
-    // check if destination path exists
-    if let Ok(dst_stat) = std::fs::metadata(dst) {
-        if dst_stat.is_dir() {
-            // if destination exists and is directory
-            if let Some(basename) = source.file_name() {
-                // set destination path as the original destination + basename
-                // of the source path
-                destination = destination.join(basename);
-            }
-        } else if src_stat.is_dir() {
-            // if destination is a file but source is a directory, abort copy
-            // with an error
+    /// Copy only the `count` most recently modified files directly under the
+    /// source directory which match the [`CopyOptions::pattern`], instead of
+    /// the whole tree. Files with the same modification time are picked by
+    /// name.
+    pub fn keep_newest(&mut self, count: Option<usize>) -> &mut Self {
+        self.keep_newest = count;
+        self
+    }
+
+    /// Shell glob, like `*.log`, which the names of the files selected by
+    /// [`CopyOptions::keep_newest`] must match. All files match by default.
+    pub fn pattern(&mut self, pattern: &str) -> &mut Self {
+        self.pattern = pattern.to_owned();
+        self
+    }
+
+    pub fn verify_perms(&mut self, verify: PermVerify) -> &mut Self {
+        self.verify_perms = verify;
+        self
+    }
+
+    /// Check whether the destination directory is owned by the effective user
+    /// running the copy, to catch writes into another user's files.
+    pub fn foreign_owner(&mut self, handling: ForeignOwner) -> &mut Self {
+        self.foreign_owner = handling;
+        self
+    }
+
+    /// Refuse to copy a directory into a destination directory which already
+    /// has entries, so that the copy isn't mixed with stale files.
+    pub fn require_empty_dest(&mut self, require_empty: bool) -> &mut Self {
+        self.require_empty_dest = require_empty;
+        self
+    }
+
+    /// Refuse to copy when the source and the destination, or its closest
+    /// existing directory, are on different filesystems, so that a copy
+    /// expected to be a rename or a reflink isn't done the slow way.
+    pub fn same_fs_only(&mut self, same_fs: bool) -> &mut Self {
+        self.same_fs_only = same_fs;
+        self
+    }
+
+    /// Set the group of the copied files. By default the files get the group
+    /// the system gives to new files. Failures are handled as set with
+    /// [`CopyOptions::on_perm_error`].
+    pub fn group(&mut self, group: Option<DestGroup>) -> &mut Self {
+        self.group = group;
+        self
+    }
+
+    pub fn list_only(&mut self, is_list_only: bool) -> &mut Self {
+        self.list_only = is_list_only;
+        self
+    }
+
+    /// Print the directories which would be created and the files which
+    /// would be copied or moved, with their destination, without changing
+    /// anything.
+    pub fn dry_run(&mut self, is_dry_run: bool) -> &mut Self {
+        self.dry_run = is_dry_run;
+        self
+    }
+
+    /// Set the format of the plan printed by a dry run.
+    pub fn dry_run_format(&mut self, format: DryRunFormat) -> &mut Self {
+        self.dry_run_format = format;
+        self
+    }
+
+    pub fn null_separated(&mut self, is_null_separated: bool) -> &mut Self {
+        self.null_separated = is_null_separated;
+        self
+    }
+
+    /// Always treat the destination as the name of the copy, instead of
+    /// copying into it when it's an existing directory. A directory source
+    /// is then copied into the existing destination directory itself.
+    pub fn no_target_directory(&mut self, is_exact_name: bool) -> &mut Self {
+        self.no_target_directory = is_exact_name;
+        self
+    }
+
+    /// Prepend `prefix` to the name of every copied file. Only the file name
+    /// is changed, not the directory components.
+    pub fn name_prefix(&mut self, prefix: &str) -> &mut Self {
+        self.name_prefix = prefix.to_owned();
+        self
+    }
+
+    /// Append `suffix` to the name of every copied file. Only the file name
+    /// is changed, not the directory components.
+    pub fn name_suffix(&mut self, suffix: &str) -> &mut Self {
+        self.name_suffix = suffix.to_owned();
+        self
+    }
+
+    pub fn show_current_speed(&mut self, show_speed: bool) -> &mut Self {
+        self.show_current_speed = show_speed;
+        self
+    }
+
+    pub fn sort(&mut self, order: SortOrder) -> &mut Self {
+        self.sort = order;
+        self
+    }
+
+    /// Reorder the whole list of files of a recursive copy, after the
+    /// entries of each directory were sorted by [`CopyOptions::sort`]. Files
+    /// which compare equal keep their listed order.
+    pub fn order(&mut self, order: CopyOrder) -> &mut Self {
+        self.order = order;
+        self
+    }
+
+    /// Copy the files of a recursive copy in the order of the relative paths
+    /// listed in `order_file`, one per line, instead of the order they were
+    /// listed in. The files which aren't listed are handled as set by
+    /// [`CopyOptions::unlisted`].
+    pub fn order_from(&mut self, order_file: Option<&Path>) -> &mut Self {
+        self.order_from = order_file.map(|p| p.to_owned());
+        self
+    }
+
+    /// Set the handling of the files which aren't listed in the order file.
+    pub fn unlisted(&mut self, unlisted: Unlisted) -> &mut Self {
+        self.unlisted = unlisted;
+        self
+    }
+
+    /// Shorten destination file names which exceed the system limit by
+    /// replacing their end with a hash of the full name. This is lossy.
+    pub fn truncate_long_names(&mut self, truncate: bool) -> &mut Self {
+        self.truncate_long_names = truncate;
+        self
+    }
+
+    /// Write the destination with `O_DIRECT` to bypass the page cache.
+    pub fn direct_io(&mut self, is_direct: bool) -> &mut Self {
+        self.direct_io = is_direct;
+        self
+    }
+
+    /// Hint the kernel that the files are read sequentially and that their
+    /// data isn't needed in the page cache after the copy.
+    pub fn fadvise(&mut self, use_fadvise: bool) -> &mut Self {
+        self.fadvise = use_fadvise;
+        self
+    }
+
+    /// Skip, with a warning, the source files which disappear after the
+    /// source directory has been listed instead of failing the copy.
+    pub fn tolerate_vanished(&mut self, tolerate: bool) -> &mut Self {
+        self.tolerate_vanished = tolerate;
+        self
+    }
+
+    /// Instead of copying, estimate how well the source compresses with
+    /// `algo` and report the compression ratio.
+    pub fn compress_preview(&mut self, algo: Option<CompressAlgo>) -> &mut Self {
+        self.compress_preview = algo;
+        self
+    }
+
+    /// Preserve the SELinux security context of the source files. This is a
+    /// no-op on systems without SELinux.
+    pub fn selinux(&mut self, preserve_context: bool) -> &mut Self {
+        self.selinux = preserve_context;
+        self
+    }
+
+    /// Keep the current progress as a JSON object in `progress_file`, which
+    /// is replaced atomically on every update and removed once the operation
+    /// ends.
+    pub fn progress_file(&mut self, progress_file: Option<&Path>) -> &mut Self {
+        self.progress_file = progress_file.map(|p| p.to_owned());
+        self
+    }
+
+    /// Log the current progress to the systemd journal once every
+    /// `interval`, with the percentage, bytes and rate as structured fields.
+    /// The records are printed to stderr when the journal isn't available.
+    pub fn journal_progress(&mut self, interval: Option<std::time::Duration>) -> &mut Self {
+        self.journal_progress = interval;
+        self
+    }
+
+    /// Write a report with the outcome, size, duration and digest, when one
+    /// was computed, of every file to `report` once the operation ends, even
+    /// if it failed. The report is written as CSV if the file name ends with
+    /// `.csv` and as JSON otherwise.
+    pub fn report(&mut self, report: Option<&Path>) -> &mut Self {
+        self.report = report.map(|p| (p.to_owned(), ReportRecords::default()));
+        self
+    }
+
+    /// Retry the whole operation up to `retries` times on a transient I/O
+    /// failure, resuming the partially copied files instead of restarting.
+    /// Transient failures in creating the destination directories are also
+    /// retried up to `retries` times on their own.
+    pub fn retry_operation(&mut self, retries: u32) -> &mut Self {
+        self.retry_operation = retries;
+        self
+    }
+
+    /// Instead of copying, compare the destination against the source and
+    /// report the missing, mismatching and extraneous destination files.
+    pub fn verify_only(&mut self, verify: bool) -> &mut Self {
+        self.verify_only = verify;
+        self
+    }
+
+    /// Compare the file contents by their SHA-256 digest in addition to their
+    /// size when verifying.
+    pub fn checksum(&mut self, use_checksum: bool) -> &mut Self {
+        self.checksum = use_checksum;
+        self
+    }
+
+    /// Verify every block right after it is written by comparing its SHA-256
+    /// digest with the one of the data read back from the destination. A
+    /// mismatch aborts the copy and reports the offset of the first differing
+    /// byte. Direct I/O is not used while this is enabled.
+    pub fn checksum_block(&mut self, verify: bool) -> &mut Self {
+        self.checksum_block = verify;
+        self
+    }
+
+    /// Verify every copied file against the SHA-256 digest listed for it in
+    /// `digest_file`, in the `<hex>  <path>` format of `sha256sum`. The copy
+    /// fails if a digest differs or a file isn't listed. A listed path
+    /// matches a source file if it is the source path or a trailing part of
+    /// it.
+    pub fn checksum_from(&mut self, digest_file: Option<&Path>) -> &mut Self {
+        self.checksum_from = digest_file.map(|p| p.to_owned());
+        self
+    }
+
+    /// Check that the number of bytes copied matches the source size. When
+    /// disabled, files are copied until the source reports EOF, which is
+    /// needed for sources whose size isn't known up front, like pipes.
+    pub fn verify_size(&mut self, verify: bool) -> &mut Self {
+        self.verify_size = verify;
+        self
+    }
+
+    /// Check that every byte found by the scan of the sources is accounted
+    /// for at the end of the copy, either as transferred, skipped or lost to
+    /// a failed file. Unlike the default check, skipped and failed files don't
+    /// cause a mismatch, while files copied short or past their scanned size
+    /// do.
+    pub fn strict_total(&mut self, strict: bool) -> &mut Self {
+        self.strict_total = strict;
+        self
+    }
+
+    /// Descend into pseudo filesystem mounts, like `/proc` and `/sys`, which
+    /// are skipped by recursive copies otherwise.
+    pub fn copy_contents(&mut self, descend: bool) -> &mut Self {
+        self.copy_contents = descend;
+        self
+    }
+
+    /// Limit the transfer rate to `rate` bytes per second. A rate of 0 means
+    /// no limit.
+    pub fn limit_rate(&mut self, rate: u64) -> &mut Self {
+        self.limit_rate = rate;
+        self
+    }
+
+    /// Increase the allowed transfer rate linearly from zero to the rate
+    /// limit over `duration` at the start of the operation.
+    pub fn ramp_up(&mut self, duration: Option<std::time::Duration>) -> &mut Self {
+        self.ramp_up = duration;
+        self
+    }
+
+    /// Preserve the POSIX ACLs of the source files, along with the default
+    /// ACLs of the source directories which are inherited by the files later
+    /// created in them. This is a no-op on filesystems without ACL support.
+    pub fn acl(&mut self, preserve_acl: bool) -> &mut Self {
+        self.acl = preserve_acl;
+        self
+    }
+
+    /// Preserve the file capabilities of the source files. Setting them needs
+    /// `CAP_SETFCAP`, failures are handled as set with
+    /// [`CopyOptions::on_perm_error`].
+    pub fn preserve_caps(&mut self, preserve_caps: bool) -> &mut Self {
+        self.caps = preserve_caps;
+        self
+    }
+
+    /// Stop the copy before the free space on the destination filesystem
+    /// drops below `min_free` bytes. A value of 0 disables the check.
+    pub fn min_free_space(&mut self, min_free: u64) -> &mut Self {
+        self.min_free_space = min_free;
+        self
+    }
+
+    /// Keep at least `min_free` inodes free on the destination filesystem.
+    /// Directory copies check up front that the files and directories to be
+    /// created fit in the free inodes in any case, and with a margin set the
+    /// free inodes are checked again before every new file.
+    pub fn min_free_inodes(&mut self, min_free: u64) -> &mut Self {
+        self.min_free_inodes = min_free;
+        self
+    }
+
+    /// Copy `size` bytes from block device sources instead of the size
+    /// reported by the device.
+    pub fn device_size(&mut self, size: Option<u64>) -> &mut Self {
+        self.device_size = size;
+        self
+    }
+
+    /// Copy only the first `size` bytes of the source file, or all of it if
+    /// it's smaller.
+    pub fn head(&mut self, size: Option<u64>) -> &mut Self {
+        self.head = size;
+        self
+    }
+
+    /// Copy only the last `size` bytes of the source file, or all of it if
+    /// it's smaller.
+    pub fn tail(&mut self, size: Option<u64>) -> &mut Self {
+        self.tail = size;
+        self
+    }
+
+    /// Start copying the source file at `offset` bytes, which must not be
+    /// beyond its end.
+    pub fn offset(&mut self, offset: u64) -> &mut Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Copy only `length` bytes of the source file from the offset, or up to
+    /// its end if it's shorter.
+    pub fn length(&mut self, length: Option<u64>) -> &mut Self {
+        self.length = length;
+        self
+    }
+
+    /// Whether only a part of the source file is copied.
+    fn is_sliced(&self) -> bool {
+        self.head.is_some() || self.tail.is_some() || self.offset > 0 || self.length.is_some()
+    }
+
+    /// Returns whether the copy changes the data or the group of the files,
+    /// selects them by their state, or records them, none of which happens
+    /// when a move is done by renaming. Options of this kind have to be added
+    /// here.
+    fn transforms_files(&self) -> bool {
+        self.is_sliced()
+            || self.pad_to > 0
+            || self.device_size.is_some()
+            || self.group.is_some()
+            || self.update
+            || self.repair
+            || self.delta
+            || self.touch_only
+            || self.checksum_from.is_some()
+            || self.s3_etag.is_some()
+            || self.report.is_some()
+    }
+
+    /// Returns whether the copy of a directory names, places or selects its
+    /// files differently from the source tree, which renaming the directory
+    /// can't do. Options of this kind have to be added here.
+    fn reshapes_tree(&self) -> bool {
+        !self.name_prefix.is_empty()
+            || !self.name_suffix.is_empty()
+            || self.truncate_long_names
+            || self.modified_since.is_some()
+            || self.keep_newest.is_some()
+            || self.max_files.is_some()
+            || self.flatten
+            || self.regular_only
+            || self.dedupe
+            || self.seen_sources.is_some()
+            || self.path_mapper.is_some()
+            || (self.order_from.is_some() && self.unlisted == Unlisted::Skip)
+    }
+
+    /// Stop scanning the source directory with an error once more than `max`
+    /// files and directories are found, to bail out of runaway trees. A
+    /// value of 0 disables the limit.
+    pub fn max_files(&mut self, max: u64) -> &mut Self {
+        self.max_files = if max > 0 { Some(max) } else { None };
+        self
+    }
+
+    /// Copy only the regular files found in the source directory. Symlinks,
+    /// FIFOs, devices and sockets are skipped.
+    pub fn regular_only(&mut self, is_regular_only: bool) -> &mut Self {
+        self.regular_only = is_regular_only;
+        self
+    }
+
+    /// Skip the source files which were already copied as part of a previous
+    /// source argument, like a directory followed by one of its children.
+    /// Sources are identified by their device and inode numbers, so hard links
+    /// within a single source are still copied.
+    pub fn skip_duplicate_sources(&mut self, skip: bool) -> &mut Self {
+        self.seen_sources = if skip {
+            Some(std::sync::Arc::default())
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Extend every destination file to `size` bytes after its data is copied.
+    /// The padding is left as a hole, so it reads as zeros without taking up
+    /// space. Source files larger than `size` are an error. A value of 0
+    /// disables the padding.
+    pub fn pad_to(&mut self, size: u64) -> &mut Self {
+        self.pad_to = size;
+        self
+    }
+
+    /// Copy directories into a staging directory next to the destination and
+    /// only rename it into place once the whole tree is copied, replacing an
+    /// existing destination directory with `force`. A failed copy leaves the
+    /// destination untouched.
+    pub fn transactional(&mut self, is_transactional: bool) -> &mut Self {
+        self.transactional = is_transactional;
+        self
+    }
+
+    /// Hard link the files of a directory copy which are identical to an
+    /// already copied file, by size, mode and SHA-256 digest, instead of
+    /// copying them again.
+    pub fn dedupe(&mut self, is_dedupe: bool) -> &mut Self {
+        self.dedupe = is_dedupe;
+        self
+    }
+
+    /// Set how source symlinks with a missing target are handled. By default
+    /// the copy fails on them.
+    pub fn broken_symlinks(&mut self, handling: BrokenSymlinks) -> &mut Self {
+        self.broken_symlinks = handling;
+        self
+    }
+
+    /// Set how source files which shrink during the copy, like rotated logs,
+    /// are handled. By default the copy fails on them.
+    pub fn on_shrink(&mut self, handling: OnShrink) -> &mut Self {
+        self.on_shrink = handling;
+        self
+    }
+
+    /// Copy the files of a recursive copy directly into the destination
+    /// directory under their file names, leaving out the directories they are
+    /// in.
+    pub fn flatten(&mut self, is_flatten: bool) -> &mut Self {
+        self.flatten = is_flatten;
+        self
+    }
+
+    /// Set how the files of a flattened copy which have the same name are
+    /// handled. By default the copy fails on them.
+    pub fn on_collision(&mut self, handling: OnCollision) -> &mut Self {
+        self.on_collision = handling;
+        self
+    }
+
+    /// Set how failures in setting the permissions, the group or the
+    /// capabilities of the destination files, common on filesystems like FAT
+    /// or NFS, are handled. By default the copied file is kept with a warning.
+    pub fn on_perm_error(&mut self, handling: OnPermError) -> &mut Self {
+        self.on_perm_error = handling;
+        self
+    }
+
+    /// Carry over the setuid, setgid and sticky bits of the sources to the
+    /// destination files. They are preserved by default.
+    pub fn preserve_special_bits(&mut self, preserve: bool) -> &mut Self {
+        self.special_bits = preserve;
+        self
+    }
+
+    /// Returns the bits of a source mode which are carried over to the
+    /// destination.
+    fn mode_mask(&self) -> u32 {
+        if self.special_bits {
+            0o7777
+        } else {
+            0o777
+        }
+    }
+
+    /// Preserve the access and modification times of the source files and,
+    /// once they are filled, of the directories of recursive copies.
+    pub fn preserve_times(&mut self, preserve: bool) -> &mut Self {
+        self.preserve_times = preserve;
+        self
+    }
+
+    /// Set the precision of the preserved timestamps. Second precision avoids
+    /// comparison mismatches on filesystems which can't store sub-second
+    /// timestamps.
+    pub fn times_precision(&mut self, precision: TimesPrecision) -> &mut Self {
+        self.times_precision = precision;
+        self
+    }
+
+    /// Append a "(stalled Ns)" indicator, refreshed on a timer, to the
+    /// progress line when no data has been transferred for longer than
+    /// `threshold`.
+    pub fn progress_stall_indicator(
+        &mut self,
+        threshold: Option<std::time::Duration>,
+    ) -> &mut Self {
+        self.stall_threshold = threshold;
+        self
+    }
+
+    /// Redraw the progress line, with an up to date ETA, at least once every
+    /// `interval` even when no data is being transferred.
+    pub fn progress_min_refresh(&mut self, interval: Option<std::time::Duration>) -> &mut Self {
+        self.progress_min_refresh = interval;
+        self
+    }
+
+    /// Hold back the progress for the first `grace` of the copy, so that
+    /// copies finishing within it print no progress line, only their summary.
+    pub fn progress_suppress_when_fast(&mut self, grace: Option<std::time::Duration>) -> &mut Self {
+        self.progress_grace = grace;
+        self
+    }
+
+    /// Number of files copied in parallel by recursive copies. The largest
+    /// files are started first and each job picks the next file as soon as
+    /// it is done with the previous one.
+    pub fn jobs(&mut self, jobs: usize) -> &mut Self {
+        self.jobs = jobs;
+        self
+    }
+
+    /// Bound the combined size of the copy buffers of all the jobs, each of
+    /// which is a block, to `max` bytes. The block size is reduced first,
+    /// down to 1M, and then the number of jobs.
+    pub fn max_memory(&mut self, max: Option<u64>) -> &mut Self {
+        self.max_memory = max;
+        self
+    }
+
+    /// Show the progress of copies with more than one job as a line per job
+    /// with its current file and an overall line, redrawn in place on a
+    /// terminal.
+    pub fn progress_multiline(&mut self, multiline: bool) -> &mut Self {
+        self.progress_multiline = multiline;
+        self
+    }
+
+    /// Returns whether the progress is still held back by the grace period
+    /// of [`Self::progress_suppress_when_fast`].
+    fn progress_held(&self) -> bool {
+        match (self.progress_grace, self.stats_store.start_time) {
+            (Some(grace), Some(start)) => start.elapsed() < grace,
+            _ => false,
+        }
+    }
+
+    /// Returns whether the per file messages accompanying the progress should
+    /// be printed.
+    fn progress_messages(&self) -> bool {
+        self.show_progress
+            && self.progress_format == ProgressFormat::Full
+            && self.job_progress.is_none()
+    }
+
+    /// Checks the options for combinations which can't be honoured together
+    /// and returns an `InvalidInput` error describing the first one found.
+    /// The rejected combinations are:
+    ///
+    /// - a block size of 0
+    /// - 0 jobs
+    /// - `list_only` with `verify_only`, as each of them replaces the copy
+    /// - `dry_run` with `list_only` or `verify_only`, for the same reason
+    /// - `verify_only` with `remove`, as nothing is copied to be moved
+    /// - `sparse` with `direct_io`, as holes can't be created with direct I/O
+    /// - `ramp_up` without `limit_rate`, as there is no rate to ramp up to
+    /// - `keep_newest` with `remove`, as the whole source directory would be
+    ///   removed
+    /// - `require_empty_dest` with `resume`, as a resumed copy continues into
+    ///   a destination with content
+    /// - `strict_total` without `verify_size`, as the sizes of the sources
+    ///   aren't known up front
+    /// - `repair` or `delta` with `resume`, or with each other, as they all
+    ///   decide how to continue into an existing destination
+    ///
+    /// The copy functions validate their options before starting.
+    pub fn validate(&self) -> io::Result<()> {
+        let conflict = if self.block_size == 0 {
+            Some("block size must be greater than 0")
+        } else if self.jobs == 0 {
+            Some("number of jobs must be greater than 0")
+        } else if self.list_only && self.verify_only {
+            Some("list only and verify only options can't be used together")
+        } else if self.dry_run && (self.list_only || self.verify_only) {
+            Some("dry run option can't be used with list only or verify only options")
+        } else if self.verify_only && self.remove {
+            Some("verify only option can't be used when moving files")
+        } else if self.sparse && self.direct_io {
+            Some("sparse copies can't be written with direct I/O")
+        } else if self.ramp_up.is_some() && self.limit_rate == 0 {
+            Some("ramp up option requires a rate limit")
+        } else if self.head.is_some() && self.tail.is_some() {
+            Some("head and tail options can't be used together")
+        } else if (self.head.is_some() || self.tail.is_some())
+            && (self.offset > 0 || self.length.is_some())
+        {
+            Some("head and tail options can't be used with an offset or length")
+        } else if self.is_sliced()
+            && (self.recursive || self.repair || self.delta || !self.verify_size)
+        {
+            Some("head, tail, offset and length options can't be used with recursive, repair, delta or unverified size copies")
+        } else if (self.tail.is_some() || self.offset > 0) && self.checksum_block {
+            Some("tail and offset options can't be used with block checksums")
+        } else if self.truncate_source_after && !self.remove {
+            Some("truncate source after option can only be used when moving files")
+        } else if self.wipe_passes.is_some() && !self.remove {
+            Some("wipe source option can only be used when moving files")
+        } else if self.wipe_passes == Some(0) {
+            Some("number of wipe passes must be greater than 0")
+        } else if self.sync_perms && !self.update {
+            Some("sync perms option requires the update option")
+        } else if self.keep_newest.is_some() && self.remove {
+            Some("keep newest option can't be used when moving files")
+        } else if self.require_empty_dest && self.resume {
+            Some("require empty destination option can't be used when resuming a copy")
+        } else if self.transactional && (self.remove || self.resume) {
+            Some("transactional copies can't be used when moving files or resuming a copy")
+        } else if self.repair && self.resume {
+            Some("repair option can't be used when resuming a copy")
+        } else if self.delta && (self.resume || self.repair) {
+            Some("delta option can't be used when resuming or repairing a copy")
+        } else if self.strict_total && !self.verify_size {
+            Some("strict total option can't be used without verifying the copied size")
+        } else if self.order_from.is_some() && (self.order != CopyOrder::None || self.jobs > 1) {
+            Some("order from option can't be used with a copy order or parallel jobs")
+        } else {
+            None
+        };
+
+        match conflict {
+            Some(msg) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid copy options: {}", msg),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Validates the options like [`CopyOptions::validate`] for an operation
+    /// other than a plain copy, named `mode` in the error, which copies the
+    /// sources as a whole and so can't take a slice of them.
+    fn validate_unsliced(&self, mode: &str) -> io::Result<()> {
+        self.validate()?;
+        if self.is_sliced() {
             return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "source is a directory, destination is a file",
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "invalid copy options: head, tail, offset and length options can't be used {}",
+                    mode
+                ),
             ));
         }
+        Ok(())
     }
+}
 
-    // start timer
-    let start = std::time::Instant::now();
+/// Lists all the files under `src` recursively, skipping the ones which
+/// haven't been modified since the last run.
+fn collect_files(src: &Path, copy_opts: &CopyOptions) -> io::Result<Vec<util::DirFile>> {
+    let skip_dirs = pseudo_fs_dirs(src, copy_opts);
+    if skip_dirs.iter().any(|d| d.as_os_str().is_empty()) {
+        return Ok(Vec::new());
+    }
+    let mut filelist = match copy_opts.keep_newest {
+        Some(count) => util::list_dir_newest(src, count, &copy_opts.pattern)?,
+        None => {
+            let mut loops = Vec::new();
+            let filelist = util::list_dir_recursive_rel(
+                src,
+                copy_opts.sort,
+                &skip_dirs,
+                copy_opts.max_files,
+                &mut loops,
+            )?;
+            for dir in &loops {
+                errln!(
+                    copy_opts,
+                    "Warning: skipping directory '{}' which was already visited, it forms a loop",
+                    dir.to_str().unwrap_or("")
+                );
+            }
+            let dir_loops = &copy_opts.stats_store.dir_loops;
+            dir_loops.set(dir_loops.get() + loops.len() as u64);
+            filelist
+        }
+    };
+    if let Some(since) = copy_opts.modified_since {
+        filelist.retain(|fileinfo| fileinfo.modified().is_none_or(|m| m > since));
+    }
+    if copy_opts.regular_only {
+        filelist.retain(|fileinfo| {
+            let path = src.join(fileinfo.path());
+            let is_regular = std::fs::symlink_metadata(&path).is_ok_and(|m| m.is_file());
+            if !is_regular {
+                if copy_opts.verbose {
+                    outln!(
+                        copy_opts,
+                        "Skipping non-regular file '{}'",
+                        path.to_str().unwrap_or("")
+                    );
+                }
+                let non_regular = &copy_opts.stats_store.non_regular;
+                non_regular.set(non_regular.get() + 1);
+            }
+            is_regular
+        });
+    }
+    if let Some(mapper) = &copy_opts.path_mapper {
+        filelist.retain(|fileinfo| mapper(Path::new(fileinfo.path())).is_some());
+    }
+    match copy_opts.order {
+        CopyOrder::None => {}
+        CopyOrder::Largest => filelist.sort_by_key(|f| std::cmp::Reverse(f.size())),
+        CopyOrder::Smallest => filelist.sort_by_key(|f| f.size()),
+        CopyOrder::Name => filelist.sort_by(|a, b| a.path().cmp(b.path())),
+    }
+    if let Some(listed) = &copy_opts.listed_order {
+        // the first listing of a path decides its position
+        let mut positions = std::collections::HashMap::new();
+        for (i, path) in listed.iter().enumerate() {
+            positions.entry(path.as_str()).or_insert(i);
+        }
+        if copy_opts.unlisted == Unlisted::Skip {
+            filelist.retain(|fileinfo| positions.contains_key(fileinfo.path().as_str()));
+        }
+        filelist.sort_by_key(|fileinfo| {
+            positions
+                .get(fileinfo.path().as_str())
+                .copied()
+                .unwrap_or(usize::MAX)
+        });
+    }
+    Ok(filelist)
+}
 
-    if src_stat.is_dir() {
-        // if source is a directory, copy entire directory
-        if let Err(e) = copy_directory(source, destination.as_path(), &mut copy_opts) {
-            return Err(e);
+/// Finds the pseudo filesystem mounts, like `/proc`, under `src` which
+/// shouldn't be descended into unless the copy contents option is set. The
+/// returned paths are relative to `src`, with an empty path if `src` itself
+/// is on such a mount.
+fn pseudo_fs_dirs(src: &Path, copy_opts: &CopyOptions) -> Vec<std::path::PathBuf> {
+    pseudo_fs_dirs_in(src, Path::new(util::MOUNT_TABLE), copy_opts)
+}
+
+/// Finds the pseudo filesystem mounts under `src` like [`pseudo_fs_dirs`],
+/// reading the mounts from the mount table at `mount_table`.
+fn pseudo_fs_dirs_in(
+    src: &Path,
+    mount_table: &Path,
+    copy_opts: &CopyOptions,
+) -> Vec<std::path::PathBuf> {
+    if copy_opts.copy_contents {
+        return Vec::new();
+    }
+    let mounts = match util::read_pseudo_fs_mounts(mount_table) {
+        Ok(m) => m,
+        Err(_) => return Vec::new(),
+    };
+    let base = match std::fs::canonicalize(src) {
+        Ok(b) => b,
+        Err(_) => return Vec::new(),
+    };
+    let mut skip_dirs = Vec::new();
+    for (mount_point, fs_type) in mounts {
+        // a source inside of the mount is skipped as a whole
+        let rel = if base.starts_with(&mount_point) {
+            Ok(Path::new(""))
+        } else {
+            mount_point.strip_prefix(&base)
+        };
+        if let Ok(rel) = rel {
+            if copy_opts.verbose {
+                outln!(
+                    copy_opts,
+                    "Skipping {} filesystem mounted at '{}'",
+                    fs_type,
+                    mount_point.to_str().unwrap_or("")
+                );
+            }
+            skip_dirs.push(rel.to_owned());
+        }
+    }
+    skip_dirs
+}
+
+/// Checks if a file hasn't been modified since the last run.
+fn is_unmodified(metadata: &Metadata, copy_opts: &CopyOptions) -> bool {
+    copy_opts
+        .modified_since
+        .is_some_and(|since| metadata.modified().is_ok_and(|m| m <= since))
+}
+
+/// Lists all the files under `src` like [`collect_files`]. When the copy is
+/// flattened, every file is mapped to its name in the destination directory,
+/// with the files whose names collide handled as requested, and the mapping
+/// replaces the path mapper of `copy_opts`.
+fn collect_dst_files(src: &Path, copy_opts: &mut CopyOptions) -> io::Result<Vec<util::DirFile>> {
+    let mut filelist = collect_files(src, copy_opts)?;
+    if !copy_opts.flatten {
+        return Ok(filelist);
+    }
+
+    let mut names: Vec<Option<std::ffi::OsString>> = filelist
+        .iter()
+        .map(|f| dst_rel_path(f, copy_opts).file_name().map(|n| n.to_owned()))
+        .collect();
+
+    // the names are handed out in the order of the paths so that they don't
+    // depend on the copy order, and the first file with a name keeps it
+    let mut order: Vec<usize> = (0..filelist.len()).collect();
+    order.sort_by(|a, b| filelist[*a].path().cmp(filelist[*b].path()));
+    let mut owners = std::collections::HashMap::new();
+    let mut colliding = Vec::new();
+    for &i in &order {
+        if let Some(name) = &names[i] {
+            if owners.contains_key(name) {
+                colliding.push(i);
+            } else {
+                owners.insert(name.clone(), i);
+            }
+        }
+    }
+
+    for i in colliding {
+        let Some(name) = names[i].take() else {
+            continue;
+        };
+        let owner = filelist[owners[&name]].path();
+        match copy_opts.on_collision {
+            OnCollision::Error => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "files '{}' and '{}' have the same name in the flattened destination",
+                        owner,
+                        filelist[i].path()
+                    ),
+                ));
+            }
+            OnCollision::Skip => {
+                if copy_opts.verbose {
+                    outln!(
+                        copy_opts,
+                        "Skipping '{}' whose name is taken by '{}'",
+                        filelist[i].path(),
+                        owner
+                    );
+                }
+            }
+            OnCollision::Rename => {
+                let path = Path::new(&name);
+                let stem = path.file_stem().unwrap_or(&name).to_owned();
+                let mut suffix = 1;
+                let renamed = loop {
+                    let mut renamed = stem.clone();
+                    renamed.push(format!("-{}", suffix));
+                    if let Some(ext) = path.extension() {
+                        renamed.push(".");
+                        renamed.push(ext);
+                    }
+                    if !owners.contains_key(&renamed) {
+                        break renamed;
+                    }
+                    suffix += 1;
+                };
+                owners.insert(renamed.clone(), i);
+                names[i] = Some(renamed);
+            }
+        }
+    }
+
+    let mapping: std::collections::HashMap<String, std::path::PathBuf> = filelist
+        .iter()
+        .zip(names)
+        .filter_map(|(f, name)| Some((f.path().clone(), std::path::PathBuf::from(name?))))
+        .collect();
+    filelist.retain(|f| mapping.contains_key(f.path()));
+    copy_opts.path_mapper = Some(std::sync::Arc::new(move |path: &Path| {
+        mapping.get(path.to_str()?).cloned()
+    }));
+    Ok(filelist)
+}
+
+/// Returns the path of the file `fileinfo` relative to the destination
+/// directory, as mapped by the path mapper if one is configured.
+fn dst_rel_path(fileinfo: &util::DirFile, copy_opts: &CopyOptions) -> std::path::PathBuf {
+    match &copy_opts.path_mapper {
+        Some(mapper) => mapper(Path::new(fileinfo.path())).unwrap_or_default(),
+        None => std::path::PathBuf::from(fileinfo.path()),
+    }
+}
+
+/// Applies the configured prefix and suffix to the file name of `path`, and
+/// shortens the file names which are too long if requested.
+fn rename_dst(path: &Path, copy_opts: &CopyOptions) -> std::path::PathBuf {
+    if copy_opts.name_prefix.is_empty() && copy_opts.name_suffix.is_empty() {
+        return map_dst_dir(path, copy_opts);
+    }
+    match path.file_name() {
+        Some(name) => {
+            let mut new_name = std::ffi::OsString::from(&copy_opts.name_prefix);
+            new_name.push(name);
+            new_name.push(&copy_opts.name_suffix);
+            map_dst_dir(&path.with_file_name(new_name), copy_opts)
+        }
+        None => map_dst_dir(path, copy_opts),
+    }
+}
+
+/// Shortens the file names in a destination directory path which are too
+/// long if requested.
+fn map_dst_dir(path: &Path, copy_opts: &CopyOptions) -> std::path::PathBuf {
+    if copy_opts.truncate_long_names {
+        util::shorten_long_names(path)
+    } else {
+        path.to_owned()
+    }
+}
+
+/// Prints the paths of the files which would be copied from `src`, each
+/// terminated by a newline or, if requested, by a NUL byte.
+fn list_files(src: &Path, src_stat: &Metadata, copy_opts: &CopyOptions) -> io::Result<()> {
+    let mut paths = Vec::new();
+    if src_stat.is_dir() {
+        for fileinfo in collect_files(src, copy_opts)? {
+            paths.push(src.join(fileinfo.path()));
+        }
+    } else if !is_unmodified(src_stat, copy_opts) {
+        paths.push(src.to_owned());
+    }
+
+    let terminator = if copy_opts.null_separated {
+        b'\0'
+    } else {
+        b'\n'
+    };
+    let mut out = copy_opts.output.lock_out();
+    for path in &paths {
+        out.write_all(path.as_os_str().as_bytes())?;
+        out.write_all(&[terminator])?;
+    }
+    out.flush()
+}
+
+/// Prints the directories which would be created and the files which would
+/// be copied or moved from `src` to `dst` in the dry run format.
+fn print_plan(
+    src: &Path,
+    src_stat: &Metadata,
+    dst: &Path,
+    copy_opts: &CopyOptions,
+) -> io::Result<()> {
+    let mut dirs = std::collections::BTreeSet::new();
+    let mut files = Vec::new();
+    if src_stat.is_dir() {
+        dirs.insert(map_dst_dir(dst, copy_opts));
+        let mut plan_opts = copy_opts.clone();
+        for fileinfo in collect_dst_files(src, &mut plan_opts)? {
+            let rel_path = dst_rel_path(&fileinfo, &plan_opts);
+            if let Some(parent) = rel_path.parent() {
+                dirs.insert(map_dst_dir(&dst.join(parent), copy_opts));
+            }
+            files.push((
+                src.join(fileinfo.path()),
+                rename_dst(&dst.join(rel_path), copy_opts),
+            ));
+        }
+    } else if !is_unmodified(src_stat, copy_opts) {
+        if let Some(parent) = dst.parent().filter(|p| !p.as_os_str().is_empty()) {
+            dirs.insert(parent.to_owned());
+        }
+        files.push((src.to_owned(), dst.to_owned()));
+    }
+    // only the directories which are missing need to be created
+    dirs.retain(|dir| !dir.is_dir());
+
+    let mut out = copy_opts.output.lock_out();
+    for dir in &dirs {
+        match copy_opts.dry_run_format {
+            DryRunFormat::Text => writeln!(out, "Would create directory '{}'", dir.display())?,
+            DryRunFormat::Shell => writeln!(out, "mkdir -p -- {}", util::shell_quote(dir))?,
+        }
+    }
+    for (src_file, dst_file) in &files {
+        match copy_opts.dry_run_format {
+            DryRunFormat::Text => writeln!(
+                out,
+                "Would {} '{}' to '{}'",
+                if copy_opts.remove { "move" } else { "copy" },
+                src_file.display(),
+                dst_file.display()
+            )?,
+            DryRunFormat::Shell => writeln!(
+                out,
+                "{} -- {} {}",
+                if copy_opts.remove { "mv" } else { "cp" },
+                util::shell_quote(src_file),
+                util::shell_quote(dst_file)
+            )?,
+        }
+    }
+    out.flush()
+}
+
+/// Estimates the compression ratio of the files which would be copied from
+/// `src` by compressing them, or samples of the large ones, with `algo`.
+fn preview_compression(
+    src: &Path,
+    src_stat: &Metadata,
+    algo: CompressAlgo,
+    copy_opts: &CopyOptions,
+) -> io::Result<()> {
+    const SAMPLE_THRESHOLD: u64 = 64 * util::MB;
+    const SAMPLE_SIZE: u64 = 4 * util::MB;
+
+    let mut paths = Vec::new();
+    if src_stat.is_dir() {
+        for fileinfo in collect_files(src, copy_opts)? {
+            paths.push(src.join(fileinfo.path()));
+        }
+    } else {
+        paths.push(src.to_owned());
+    }
+
+    let name = match algo {
+        CompressAlgo::Gzip => "gzip",
+        CompressAlgo::Zstd => "zstd",
+    };
+    let (sampled, compressed) = util::measure_compression(algo, |encoder| {
+        let mut sampled = 0;
+        for path in &paths {
+            sampled += util::write_file_sample(path, encoder, SAMPLE_THRESHOLD, SAMPLE_SIZE)
+                .map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!(
+                            "failure in reading file '{}': {}",
+                            path.to_str().unwrap_or(""),
+                            e
+                        ),
+                    )
+                })?;
+        }
+        Ok(sampled)
+    })?;
+
+    let ratio = if compressed == 0 {
+        1.0
+    } else {
+        sampled as f64 / compressed as f64
+    };
+    outln!(
+        copy_opts,
+        "Estimated {} compression ratio: {:.2} (sampled {} into {})",
+        name,
+        ratio,
+        get_str_size_precise(sampled),
+        get_str_size_precise(compressed)
+    );
+    Ok(())
+}
+
+/// Copies the directory `src` into a staging directory next to `dst` and
+/// renames it to `dst` once all the files are copied. An existing `dst` is
+/// only replaced with the force option, and is kept until the staging
+/// directory is in its place. On a failure the staging directory is removed.
+fn copy_directory_transactional(
+    src: &Path,
+    dst: &Path,
+    copy_opts: &mut CopyOptions,
+) -> io::Result<()> {
+    let dst_exists = std::fs::symlink_metadata(dst).is_ok();
+    if dst_exists && !copy_opts.force {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "directory '{}' exists, can't replace it without --force option",
+                dst.to_str().unwrap_or("")
+            ),
+        ));
+    }
+
+    // the staging directory has to be on the destination filesystem for the
+    // final rename
+    let aside = |tag: &str| {
+        let mut name = std::ffi::OsString::from(".");
+        name.push(dst.file_name().unwrap_or_default());
+        name.push(format!(".{}-{}", tag, std::process::id()));
+        dst.with_file_name(name)
+    };
+    let staging = aside("staging");
+    let backup = aside("old");
+
+    if let Err(e) = copy_directory(src, &staging, copy_opts) {
+        let _ = std::fs::remove_dir_all(&staging);
+        return Err(e);
+    }
+
+    // keep the existing destination until the new one is in place
+    if dst_exists {
+        if let Err(e) = std::fs::rename(dst, &backup) {
+            let _ = std::fs::remove_dir_all(&staging);
+            return Err(io::Error::new(
+                e.kind(),
+                format!(
+                    "failure in moving aside the existing directory '{}': {}",
+                    dst.to_str().unwrap_or(""),
+                    e
+                ),
+            ));
+        }
+    }
+    if let Err(e) = std::fs::rename(&staging, dst) {
+        if dst_exists {
+            let _ = std::fs::rename(&backup, dst);
+        }
+        let _ = std::fs::remove_dir_all(&staging);
+        return Err(io::Error::new(
+            e.kind(),
+            format!(
+                "failure in moving the copied directory into place at '{}': {}",
+                dst.to_str().unwrap_or(""),
+                e
+            ),
+        ));
+    }
+    if dst_exists {
+        if let Err(e) = std::fs::remove_dir_all(&backup) {
+            errln!(
+                copy_opts,
+                "Warning: failure in removing the replaced directory '{}': {}",
+                backup.to_str().unwrap_or(""),
+                e
+            );
+        }
+    }
+    Ok(())
+}
+
+fn copy_directory(src: &Path, dst: &Path, copy_opts: &mut CopyOptions) -> Result<(), io::Error> {
+    // refuse to move a directory which mustn't be removed afterwards, before
+    // anything is copied
+    if copy_opts.remove && !copy_opts.force_remove_root {
+        if let Some(reason) = util::protected_dir(src)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "refusing to move '{}' as it is {}, use --force-remove-root to remove it anyway",
+                    src.to_str().unwrap_or(""),
+                    reason
+                ),
+            ));
+        }
+    }
+
+    // get the list of all files under src recursively
+    let filelist = collect_dst_files(src, copy_opts)?;
+
+    // calculate total bytes to be copied
+    for fileinfo in &filelist {
+        copy_opts.stats_store.total += fileinfo.size();
+        copy_opts.stats_store.scanned += fileinfo.size();
+    }
+
+    // verify that every destination directory can be created before starting
+    // the transfer
+    util::validate_dir_path(&map_dst_dir(dst, copy_opts))?;
+    let mut src_dirs = std::collections::BTreeSet::new();
+    let mut dst_dirs = std::collections::BTreeSet::new();
+    for fileinfo in &filelist {
+        if let Some(parent) = Path::new(fileinfo.path()).parent() {
+            src_dirs.extend(parent.ancestors().map(Path::to_owned));
+        }
+        let rel_path = dst_rel_path(fileinfo, copy_opts);
+        let is_contained = rel_path
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)));
+        if !is_contained {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "path of '{}' is mapped outside of the destination to '{}'",
+                    fileinfo.path(),
+                    rel_path.to_str().unwrap_or("")
+                ),
+            ));
+        }
+        if let Some(parent) = rel_path.parent() {
+            for ancestor in parent.ancestors() {
+                dst_dirs.insert(map_dst_dir(&dst.join(ancestor), copy_opts));
+            }
+        }
+    }
+    for dst_dir in &dst_dirs {
+        util::validate_dir_path(dst_dir)?;
+    }
+
+    // verify that renamed destination files don't collide with each other or
+    // with the destination directories
+    let mut dst_files = std::collections::HashSet::new();
+    for fileinfo in &filelist {
+        let dst_file = rename_dst(&dst.join(dst_rel_path(fileinfo, copy_opts)), copy_opts);
+        util::check_path_len(&dst_file)?;
+        if dst_dirs.contains(&dst_file) || !dst_files.insert(dst_file.clone()) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "multiple sources map to the destination '{}'",
+                    dst_file.to_str().unwrap_or("")
+                ),
+            ));
+        }
+    }
+
+    // verify that the new files and directories fit in the free inodes
+    let missing = dst_dirs
+        .iter()
+        .chain(dst_files.iter())
+        .filter(|p| std::fs::symlink_metadata(p).is_err())
+        .count();
+    check_free_inodes(dst, missing as u64, copy_opts)?;
+
+    // copied files by their size, mode and digest for deduplication
+    let copied_files = std::sync::Mutex::new(std::collections::HashMap::new());
+
+    if copy_opts.jobs > 1 {
+        copy_files_parallel(src, dst, &filelist, copy_opts, &copied_files)?;
+    } else {
+        for fileinfo in &filelist {
+            copy_dir_entry(src, dst, fileinfo, copy_opts, &copied_files)?;
+        }
+    }
+
+    // the default ACLs are set once the directories are filled so that they
+    // don't change the permissions of the copied files. The directories only
+    // match the source ones when the paths aren't mapped.
+    if copy_opts.acl && copy_opts.path_mapper.is_none() {
+        for src_dir in &src_dirs {
+            let dst_dir = map_dst_dir(&dst.join(src_dir), copy_opts);
+            if let Err(e) = copy_dir_acls(&src.join(src_dir), &dst_dir) {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!(
+                        "failure in setting ACLs of '{}': {}",
+                        dst_dir.to_str().unwrap_or(""),
+                        e
+                    ),
+                ));
+            }
+        }
+    }
+
+    // the directory timestamps are set last, as filling a directory changes
+    // them, and deepest first, so that a directory is only set once all of
+    // its subdirectories are done
+    if copy_opts.preserve_times && copy_opts.path_mapper.is_none() {
+        for src_dir in src_dirs.iter().rev() {
+            let dst_dir = map_dst_dir(&dst.join(src_dir), copy_opts);
+            let times_result = std::fs::metadata(src.join(src_dir)).and_then(|src_metadata| {
+                util::set_file_times(
+                    &File::open(&dst_dir)?,
+                    &src_metadata,
+                    copy_opts.times_precision,
+                )
+            });
+            if let Err(e) = times_result {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!(
+                        "failure in setting timestamps of '{}': {}",
+                        dst_dir.to_str().unwrap_or(""),
+                        e
+                    ),
+                ));
+            }
+        }
+    }
+
+    if copy_opts.remove {
+        remove_sources(Some(src), copy_opts)?;
+    }
+
+    Ok(())
+}
+
+/// Overwrites the copied source file `src` of a move, if requested, with the
+/// configured passes of random data followed by a pass of zeros, syncing
+/// every pass to the disk. A source with other hard links keeps its data.
+fn wipe_source(src: &Path, copy_opts: &CopyOptions) -> io::Result<()> {
+    let passes = match copy_opts.wipe_passes {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    let wipe_error = |e: io::Error| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "failure in wiping source file '{}': {}",
+                src.to_str().unwrap_or(""),
+                e
+            ),
+        )
+    };
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(src)
+        .map_err(wipe_error)?;
+    let metadata = file.metadata().map_err(wipe_error)?;
+    if metadata.nlink() > 1 {
+        errln!(
+            copy_opts,
+            "Warning: source file '{}' has other hard links, not wiping it",
+            src.to_str().unwrap_or("")
+        );
+        return Ok(());
+    }
+
+    let len = metadata.len();
+    let mut random = File::open("/dev/urandom").map_err(wipe_error)?;
+    let zeros: &[u8] = &[0];
+    for pass in 0..=passes {
+        file.seek(SeekFrom::Start(0)).map_err(wipe_error)?;
+        let mut written: u64 = 0;
+        while written < len {
+            let next_block = std::cmp::min(copy_opts.block_size, len - written);
+            let bytes_written = if pass < passes {
+                util::copy_n(&mut random, &mut file, next_block as usize)
+            } else {
+                util::copy_n(
+                    &mut util::PatternReader::new(zeros),
+                    &mut file,
+                    next_block as usize,
+                )
+            }
+            .map_err(wipe_error)?;
+            if bytes_written == 0 {
+                break;
+            }
+            written += bytes_written as u64;
+        }
+        file.sync_all().map_err(wipe_error)?;
+    }
+
+    if copy_opts.verbose {
+        outln!(
+            copy_opts,
+            "Wiped source file '{}' with {} random passes",
+            src.to_str().unwrap_or(""),
+            passes
+        );
+    }
+    Ok(())
+}
+
+/// Truncates the copied source file `src` of a move to zero, if requested,
+/// to free its space before it's removed. A source with other hard links
+/// keeps its data, and a failure only prints a warning, as the source is
+/// removed right after anyway.
+fn truncate_source(src: &Path, copy_opts: &CopyOptions) {
+    if !copy_opts.truncate_source_after {
+        return;
+    }
+    let result = std::fs::OpenOptions::new()
+        .write(true)
+        .open(src)
+        .and_then(|file| {
+            if file.metadata()?.nlink() > 1 {
+                return Ok(false);
+            }
+            file.set_len(0).map(|_| true)
+        });
+    match result {
+        Ok(true) if copy_opts.verbose => {
+            outln!(
+                copy_opts,
+                "Truncated source file '{}'",
+                src.to_str().unwrap_or("")
+            );
+        }
+        Ok(_) => {}
+        Err(e) => {
+            errln!(
+                copy_opts,
+                "Warning: failure in truncating source file '{}': {}",
+                src.to_str().unwrap_or(""),
+                e
+            );
+        }
+    }
+}
+
+/// Removes the copied sources of a move, followed by the source directory
+/// `dir` when a directory is moved. The sources are wiped and truncated first
+/// if requested, which is only done here so that a failed move never leaves
+/// wiped or emptied sources behind. Every path is attempted and the failures
+/// are reported together, as warnings when errors are ignored. The time taken
+/// is recorded apart from the time taken to copy.
+fn remove_sources(dir: Option<&Path>, copy_opts: &mut CopyOptions) -> io::Result<()> {
+    const PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+
+    let started = std::time::Instant::now();
+    let files = std::mem::take(&mut copy_opts.stats_store.removable);
+    let show_progress = dir.is_some() && copy_opts.progress_messages();
+    let mut failures = Vec::new();
+    let mut drawn: Option<std::time::Instant> = None;
+    for (count, file) in files.iter().enumerate() {
+        // a source which failed to be wiped is still removed, as it may be
+        // partly overwritten already
+        if let Err(e) = wipe_source(file, copy_opts) {
+            failures.push((file.clone(), e));
+        }
+        truncate_source(file, copy_opts);
+        match std::fs::remove_file(file) {
+            Ok(()) => copy_opts.stats_store.removed += 1,
+            Err(e) => failures.push((file.clone(), e)),
+        }
+        let done = count + 1 == files.len();
+        if show_progress && (done || drawn.is_none_or(|t| t.elapsed() >= PROGRESS_INTERVAL)) {
+            copy_opts.write_progress(&format!(
+                "\rRemoving sources: {} / {} files",
+                count + 1,
+                files.len()
+            ));
+            drawn = Some(std::time::Instant::now());
+        }
+    }
+    if drawn.is_some() {
+        copy_opts.write_progress("\n");
+    }
+    if let Some(dir) = dir {
+        if let Err(e) = util::delete_dir_recursive(dir) {
+            failures.push((dir.to_owned(), e));
+        }
+    }
+
+    let elapsed = started.elapsed();
+    copy_opts.stats_store.removal_time += elapsed;
+    if copy_opts.verbose {
+        outln!(
+            copy_opts,
+            "Removed {} source files in {:?}",
+            copy_opts.stats_store.removed,
+            elapsed
+        );
+    }
+
+    const SHOWN_FAILURES: usize = 3;
+    let mut failed = failures
+        .iter()
+        .take(SHOWN_FAILURES)
+        .map(|(path, e)| format!("'{}': {}", path.to_str().unwrap_or(""), e))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if failures.len() > SHOWN_FAILURES {
+        failed.push_str(&format!(" and {} more", failures.len() - SHOWN_FAILURES));
+    }
+    match failures.first() {
+        None => Ok(()),
+        Some(_) if copy_opts.no_dir_err => {
+            errln!(copy_opts, "Warning: failed to remove sources {}", failed);
+            Ok(())
+        }
+        Some((_, e)) => Err(io::Error::new(
+            e.kind(),
+            format!("failed to remove sources {}", failed),
+        )),
+    }
+}
+
+/// Digests of copied files mapped to their destination, shared between the
+/// jobs copying a directory for deduplication
+type CopiedFiles = std::sync::Mutex<
+    std::collections::HashMap<(u64, u32, [u8; super::digest::SHA256_LEN]), std::path::PathBuf>,
+>;
+
+/// Checks if the source with `metadata` was copied by a previous source
+/// argument.
+fn is_duplicate_source(metadata: &Metadata, copy_opts: &CopyOptions) -> bool {
+    copy_opts.seen_sources.as_ref().is_some_and(|seen| {
+        seen.lock()
+            .unwrap()
+            .previous
+            .contains(&(metadata.dev(), metadata.ino()))
+    })
+}
+
+/// Records the source with `metadata` as copied by the current source
+/// argument.
+fn record_copied_source(metadata: &Metadata, copy_opts: &CopyOptions) {
+    if let Some(seen) = &copy_opts.seen_sources {
+        seen.lock()
+            .unwrap()
+            .current
+            .insert((metadata.dev(), metadata.ino()));
+    }
+}
+
+/// Marks the sources copied by the current source argument as copied, so
+/// the following source arguments skip them.
+fn end_source(copy_opts: &CopyOptions) {
+    if let Some(seen) = &copy_opts.seen_sources {
+        let mut seen = seen.lock().unwrap();
+        let current = std::mem::take(&mut seen.current);
+        seen.previous.extend(current);
+    }
+}
+
+/// Copies the file `fileinfo` listed under `src` to the same relative path
+/// under `dst`.
+fn copy_dir_entry(
+    src: &Path,
+    dst: &Path,
+    fileinfo: &util::DirFile,
+    copy_opts: &mut CopyOptions,
+    copied_files: &CopiedFiles,
+) -> io::Result<()> {
+    let cpy_src = src.join(fileinfo.path());
+    let dst_src = rename_dst(&dst.join(dst_rel_path(fileinfo, copy_opts)), copy_opts);
+
+    let mut record = FileRecord::new(&cpy_src, &dst_src, fileinfo.size(), FileStatus::Copied);
+
+    if is_broken_symlink(&cpy_src) {
+        let copy_result = copy_broken_symlink(&cpy_src, &dst_src, copy_opts);
+        match &copy_result {
+            Ok(true) => {}
+            Ok(false) => record.status = FileStatus::Skipped,
+            Err(e) => {
+                record.status = FileStatus::Failed;
+                record.error = Some(e.to_string());
+            }
+        }
+        record_file(copy_opts, record);
+        match copy_result {
+            // the link itself stands in for its target in the totals
+            Ok(true) => copy_opts.stats_store.transferred += fileinfo.size(),
+            Ok(false) => {
+                copy_opts.stats_store.total -= fileinfo.size();
+                copy_opts.stats_store.skipped_bytes += fileinfo.size();
+            }
+            Err(e) if copy_opts.no_dir_err => {
+                print_failure(&e, copy_opts);
+                copy_opts.stats_store.total -= fileinfo.size();
+                copy_opts.stats_store.failed_bytes += fileinfo.size();
+            }
+            Err(e) => return Err(e),
+        }
+        return Ok(());
+    }
+
+    let src_metadata = copy_opts
+        .seen_sources
+        .as_ref()
+        .and_then(|_| std::fs::metadata(&cpy_src).ok());
+    if let Some(metadata) = src_metadata
+        .as_ref()
+        .filter(|m| is_duplicate_source(m, copy_opts))
+    {
+        if copy_opts.verbose {
+            outln!(
+                copy_opts,
+                "Skipping already copied file '{}'",
+                cpy_src.to_str().unwrap_or("")
+            );
+        }
+        record.status = FileStatus::Skipped;
+        record_file(copy_opts, record);
+        copy_opts.stats_store.duplicate_sources += 1;
+        copy_opts.stats_store.total -= metadata.len();
+        copy_opts.stats_store.skipped_bytes += metadata.len();
+        return Ok(());
+    }
+
+    let dedupe_key = if copy_opts.dedupe {
+        dedupe_key(&cpy_src)
+    } else {
+        None
+    };
+    record.digest = dedupe_key.map(|(_, _, digest)| digest);
+    if let Some(existing) = dedupe_key
+        .as_ref()
+        .and_then(|k| copied_files.lock().unwrap().get(k).cloned())
+    {
+        if link_duplicate(&existing, &dst_src, copy_opts) {
+            record.status = FileStatus::Linked;
+            record_file(copy_opts, record);
+            copy_opts.stats_store.deduplicated += 1;
+            copy_opts.stats_store.dedupe_saved += fileinfo.size();
+            copy_opts.stats_store.transferred += fileinfo.size();
+            return Ok(());
+        }
+    }
+
+    let started = std::time::Instant::now();
+    let skipped = copy_opts.stats_store.up_to_date + copy_opts.stats_store.touched;
+    let transferred = copy_opts.stats_store.transferred;
+    let copy_result = copy_file(cpy_src.as_path(), dst_src.as_path(), copy_opts);
+    record.duration = started.elapsed();
+    if copy_opts.stats_store.up_to_date + copy_opts.stats_store.touched > skipped {
+        record.status = FileStatus::Skipped;
+    }
+    record.etag = copy_opts.stats_store.last_etag.take();
+    if let (Ok(_), Some(key)) = (&copy_result, dedupe_key) {
+        copied_files
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert_with(|| dst_src.clone());
+    }
+    if let Err(e) = copy_result {
+        // the part of the file which wasn't transferred before the failure
+        let missing = fileinfo
+            .size()
+            .saturating_sub(copy_opts.stats_store.transferred - transferred);
+        record.error = Some(e.to_string());
+        let vanished = copy_opts.tolerate_vanished
+            && e.kind() == io::ErrorKind::NotFound
+            && std::fs::symlink_metadata(&cpy_src).is_err();
+        record.status = if vanished {
+            FileStatus::Vanished
+        } else {
+            FileStatus::Failed
+        };
+        record_file(copy_opts, record);
+
+        if vanished {
+            // source file was removed after listing the source directory
+            errln!(
+                copy_opts,
+                "Warning: file '{}' has vanished, skipping",
+                cpy_src.to_str().unwrap_or("")
+            );
+            copy_opts.stats_store.vanished += 1;
+            copy_opts.stats_store.total -= fileinfo.size();
+            copy_opts.stats_store.skipped_bytes += missing;
+        } else if !copy_opts.no_dir_err {
+            return Err(e);
+        } else {
+            print_failure(&e, copy_opts);
+            copy_opts.stats_store.total -= missing;
+            copy_opts.stats_store.failed_bytes += missing;
+        }
+    } else {
+        record_file(copy_opts, record);
+        if let Some(metadata) = &src_metadata {
+            record_copied_source(metadata, copy_opts);
+        }
+        if copy_opts.remove {
+            // the sources are removed once all the files are copied
+            copy_opts.stats_store.removable.push(cpy_src);
+        }
+    }
+    Ok(())
+}
+
+/// Copies the files in `filelist` with [`CopyOptions::jobs`] threads. The
+/// files are queued largest first and each job takes the next one as soon as
+/// it is free, so that a huge file doesn't hold back the rest of the queue.
+/// The rate limit is split evenly between the jobs, and the progress is only
+/// shown with the multiline progress.
+fn copy_files_parallel(
+    src: &Path,
+    dst: &Path,
+    filelist: &[util::DirFile],
+    copy_opts: &mut CopyOptions,
+    copied_files: &CopiedFiles,
+) -> io::Result<()> {
+    let mut queue: Vec<&util::DirFile> = filelist.iter().collect();
+    queue.sort_by_key(|fileinfo| std::cmp::Reverse(fileinfo.size()));
+
+    let next = std::sync::atomic::AtomicUsize::new(0);
+    let failed = std::sync::atomic::AtomicBool::new(false);
+    let jobs = std::cmp::min(copy_opts.jobs, std::cmp::max(queue.len(), 1));
+
+    let mut job_opts = copy_opts.clone();
+    let multi_progress = if copy_opts.show_progress && copy_opts.progress_multiline {
+        Some(std::sync::Arc::new(MultiProgress::new(
+            jobs,
+            copy_opts.stats_store.transferred,
+            copy_opts.stats_store.total,
+            copy_opts,
+        )))
+    } else {
+        job_opts.show_progress = false;
+        None
+    };
+    if job_opts.limit_rate > 0 {
+        job_opts.limit_rate = std::cmp::max(job_opts.limit_rate / jobs as u64, 1);
+    }
+    job_opts.stats_store.transferred = 0;
+
+    let results: Vec<(io::Result<()>, CopyOptions, u64)> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..jobs)
+            .map(|job| {
+                let mut opts = job_opts.clone();
+                opts.job_progress = multi_progress.clone().map(|p| (p, job));
+                let (queue, next, failed) = (&queue, &next, &failed);
+                scope.spawn(move || {
+                    let mut files = 0;
+                    while !failed.load(std::sync::atomic::Ordering::Relaxed) {
+                        let index = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        let Some(fileinfo) = queue.get(index) else {
+                            break;
+                        };
+                        if let Err(e) = copy_dir_entry(src, dst, fileinfo, &mut opts, copied_files)
+                        {
+                            failed.store(true, std::sync::atomic::Ordering::Relaxed);
+                            return (Err(e), opts, files);
+                        }
+                        files += 1;
+                    }
+                    (Ok(()), opts, files)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("copy job panicked"))
+            .collect()
+    });
+    if let Some(multi_progress) = &multi_progress {
+        multi_progress.finish();
+    }
+
+    // merge the statistics of the jobs
+    let mut result = Ok(());
+    for (job, (job_result, opts, files)) in results.into_iter().enumerate() {
+        let stats = &opts.stats_store;
+        copy_opts.stats_store.transferred += stats.transferred;
+        copy_opts.stats_store.total -= job_opts.stats_store.total - stats.total;
+        copy_opts.stats_store.vanished += stats.vanished - job_opts.stats_store.vanished;
+        copy_opts.stats_store.deduplicated +=
+            stats.deduplicated - job_opts.stats_store.deduplicated;
+        copy_opts.stats_store.dedupe_saved +=
+            stats.dedupe_saved - job_opts.stats_store.dedupe_saved;
+        copy_opts.stats_store.up_to_date += stats.up_to_date - job_opts.stats_store.up_to_date;
+        copy_opts.stats_store.touched += stats.touched - job_opts.stats_store.touched;
+        copy_opts.stats_store.copied_files +=
+            stats.copied_files - job_opts.stats_store.copied_files;
+        copy_opts.stats_store.duplicate_sources +=
+            stats.duplicate_sources - job_opts.stats_store.duplicate_sources;
+        copy_opts.stats_store.repaired_blocks +=
+            stats.repaired_blocks - job_opts.stats_store.repaired_blocks;
+        copy_opts.stats_store.metadata_fixed +=
+            stats.metadata_fixed - job_opts.stats_store.metadata_fixed;
+        copy_opts.stats_store.delta_written +=
+            stats.delta_written - job_opts.stats_store.delta_written;
+        copy_opts.stats_store.delta_reused +=
+            stats.delta_reused - job_opts.stats_store.delta_reused;
+        copy_opts.stats_store.skipped_bytes +=
+            stats.skipped_bytes - job_opts.stats_store.skipped_bytes;
+        copy_opts.stats_store.failed_bytes +=
+            stats.failed_bytes - job_opts.stats_store.failed_bytes;
+        copy_opts.stats_store.per_file_throughput.extend_from_slice(
+            &stats.per_file_throughput[job_opts.stats_store.per_file_throughput.len()..],
+        );
+        copy_opts
+            .stats_store
+            .failed
+            .extend_from_slice(&stats.failed[job_opts.stats_store.failed.len()..]);
+        copy_opts
+            .stats_store
+            .removable
+            .extend_from_slice(&stats.removable[job_opts.stats_store.removable.len()..]);
+        if copy_opts.verbose {
+            outln!(
+                copy_opts,
+                "Job {} copied {} in {} files",
+                job + 1,
+                get_str_size_precise(stats.transferred),
+                files
+            );
+        }
+        if result.is_ok() {
+            result = job_result;
+        }
+    }
+    result
+}
+
+/// Checks if `path` is a symlink whose target doesn't exist.
+fn is_broken_symlink(path: &Path) -> bool {
+    std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink())
+        && std::fs::metadata(path).is_err()
+}
+
+/// Handles the broken symlink `src` according to the configured option.
+/// Returns whether the symlink was recreated at `dst`, or an error if broken
+/// symlinks aren't allowed.
+fn copy_broken_symlink(src: &Path, dst: &Path, copy_opts: &CopyOptions) -> io::Result<bool> {
+    let src_name = src.to_str().unwrap_or("");
+    match copy_opts.broken_symlinks {
+        BrokenSymlinks::Error => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("source '{}' is a broken symlink", src_name),
+        )),
+        BrokenSymlinks::Skip => {
+            errln!(copy_opts, "Warning: skipping broken symlink '{}'", src_name);
+            Ok(false)
+        }
+        BrokenSymlinks::Copy => {
+            let target = std::fs::read_link(src)?;
+            if std::fs::symlink_metadata(dst).is_ok() {
+                if !copy_opts.force {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!(
+                            "file '{}' exists, can't copy file without --force option",
+                            dst.to_str().unwrap_or("")
+                        ),
+                    ));
+                }
+                std::fs::remove_file(dst)?;
+            } else if let Some(dst_dir) = dst.parent() {
+                create_dst_dir(dst_dir, copy_opts)?;
+            }
+            if let Err(e) = std::os::unix::fs::symlink(&target, dst) {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!(
+                        "failure in creating symlink '{}': {}",
+                        dst.to_str().unwrap_or(""),
+                        e
+                    ),
+                ));
+            }
+            if copy_opts.verbose {
+                outln!(
+                    copy_opts,
+                    "Copied broken symlink '{}' -> '{}'",
+                    src_name,
+                    target.to_str().unwrap_or("")
+                );
+            }
+            Ok(true)
+        }
+    }
+}
+
+/// Identifies the contents of the file at `path` by its size, mode and
+/// SHA-256 digest. Returns `None` if the file can't be read, in which case
+/// it is left for the copy to report.
+fn dedupe_key(path: &Path) -> Option<(u64, u32, [u8; super::digest::SHA256_LEN])> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let digest = super::digest::sha256_file(path).ok()?;
+    Some((metadata.len(), metadata.mode(), digest))
+}
+
+/// Replaces `dst` with a hard link to the identical, already copied file
+/// `existing`. Returns `false` if the link can't be made, like when both are
+/// on different filesystems, so that the file gets copied instead.
+fn link_duplicate(existing: &Path, dst: &Path, copy_opts: &CopyOptions) -> bool {
+    if let Ok(m) = std::fs::symlink_metadata(dst) {
+        if !copy_opts.force || m.is_dir() || std::fs::remove_file(dst).is_err() {
+            return false;
+        }
+    }
+    if let Some(dst_dir) = dst.parent() {
+        if create_dst_dir(dst_dir, copy_opts).is_err() {
+            return false;
+        }
+    }
+    // hard links can't cross filesystems
+    let same_fs = match (
+        std::fs::metadata(existing),
+        dst.parent().map(std::fs::metadata),
+    ) {
+        (Ok(e), Some(Ok(d))) => e.dev() == d.dev(),
+        _ => false,
+    };
+    if !same_fs || std::fs::hard_link(existing, dst).is_err() {
+        return false;
+    }
+    if copy_opts.verbose || copy_opts.progress_messages() {
+        outln!(
+            copy_opts,
+            "Linked '{}' to identical file '{}'",
+            dst.to_str().unwrap_or(""),
+            existing.to_str().unwrap_or("")
+        );
+    }
+    true
+}
+
+/// Copies the access and default ACLs of the `src` directory to the `dst`
+/// directory, if it has been created.
+fn copy_dir_acls(src: &Path, dst: &Path) -> io::Result<()> {
+    if !dst.is_dir() {
+        return Ok(());
+    }
+    let src_dir = File::open(src)?;
+    let dst_dir = File::open(dst)?;
+    util::copy_xattr(&src_dir, &dst_dir, c"system.posix_acl_access")?;
+    util::copy_xattr(&src_dir, &dst_dir, c"system.posix_acl_default")?;
+    Ok(())
+}
+
+/// Compares the files under `src` with their copies under `dst` without
+/// writing anything. Missing destination files, files which differ in size
+/// or, with the checksum option, in content, and destination files without a
+/// source are reported. Fails if any discrepancy is found.
+fn verify_tree(
+    src: &Path,
+    src_stat: &Metadata,
+    dst: &Path,
+    copy_opts: &CopyOptions,
+) -> io::Result<()> {
+    let mut discrepancies: u64 = 0;
+    let mut verified: u64 = 0;
+
+    if src_stat.is_dir() {
+        // every source file is expected in the destination, regardless of
+        // when it was modified
+        let mut verify_opts = copy_opts.clone();
+        verify_opts.modified_since = None;
+        let mut expected = std::collections::HashSet::new();
+        for fileinfo in collect_dst_files(src, &mut verify_opts)? {
+            let dst_path = rename_dst(&dst.join(dst_rel_path(&fileinfo, &verify_opts)), copy_opts);
+            if verify_file(&src.join(fileinfo.path()), &dst_path, copy_opts)? {
+                verified += 1;
+            } else {
+                discrepancies += 1;
+            }
+            expected.insert(dst_path);
+        }
+
+        // report the destination files which have no source
+        if std::fs::metadata(dst).is_ok_and(|m| m.is_dir()) {
+            for fileinfo in
+                util::list_dir_recursive_rel(dst, copy_opts.sort, &[], None, &mut Vec::new())?
+            {
+                let dst_path = dst.join(fileinfo.path());
+                if !expected.contains(&dst_path) {
+                    outln!(
+                        copy_opts,
+                        "Extraneous: '{}'",
+                        dst_path.to_str().unwrap_or("")
+                    );
+                    discrepancies += 1;
+                }
+            }
+        }
+    } else {
+        let mut dst_path = dst.to_owned();
+        if std::fs::metadata(dst).is_ok_and(|m| m.is_dir()) && !copy_opts.no_target_directory {
+            if let Some(basename) = src.file_name() {
+                dst_path = dst.join(basename);
+            }
+        }
+        let dst_path = rename_dst(&dst_path, copy_opts);
+        if verify_file(src, &dst_path, copy_opts)? {
+            verified += 1;
+        } else {
+            discrepancies += 1;
+        }
+    }
+
+    if discrepancies > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "verification failed: {} of {} files differ",
+                discrepancies,
+                discrepancies + verified
+            ),
+        ));
+    }
+    outln!(
+        copy_opts,
+        "Verified {} files, no differences found",
+        verified
+    );
+    Ok(())
+}
+
+/// Compares the source file `src` with its copy `dst` and reports a
+/// difference. Returns whether both match.
+fn verify_file(src: &Path, dst: &Path, copy_opts: &CopyOptions) -> io::Result<bool> {
+    let dst_name = dst.to_str().unwrap_or("");
+    let src_len = std::fs::metadata(src)?.len();
+    let dst_len = match std::fs::metadata(dst) {
+        Ok(m) => m.len(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            outln!(copy_opts, "Missing: '{}'", dst_name);
+            return Ok(false);
+        }
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("stat failed for destination path '{}': {}", dst_name, &e),
+            ))
+        }
+    };
+    if src_len != dst_len {
+        outln!(
+            copy_opts,
+            "Size mismatch: '{}' (source: {} bytes, destination: {} bytes)",
+            dst_name,
+            src_len,
+            dst_len
+        );
+        return Ok(false);
+    }
+    let mut digest = String::new();
+    if copy_opts.checksum {
+        let src_digest = super::digest::sha256_file(src)?;
+        let dst_digest = super::digest::sha256_file(dst)?;
+        if src_digest != dst_digest {
+            outln!(copy_opts, "Checksum mismatch: '{}'", dst_name);
+            return Ok(false);
+        }
+        digest = format!(" (sha256: {})", super::digest::to_hex(&dst_digest));
+    }
+    if copy_opts.verbose {
+        outln!(copy_opts, "Verified file '{}'{}", dst_name, digest);
+    }
+    Ok(true)
+}
+
+/// copy copies `src` to `dst` based on the configuration options provded
+/// in `copy_opts`.
+pub fn copy(src: &str, dst: &str, copy_opts: CopyOptions) -> io::Result<()> {
+    const MAX_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(30);
+
+    copy_opts.validate()?;
+
+    let progress_file = copy_opts.progress_file.clone();
+    let first_record = report_len(&copy_opts);
+    let mut result = copy_path(src, dst, copy_opts.clone());
+    let mut delay = std::time::Duration::from_secs(1);
+    let mut attempt = 0;
+    while let Err(e) = &result {
+        if attempt >= copy_opts.retry_operation || !is_retryable(e) {
+            break;
+        }
+        attempt += 1;
+        outln!(
+            copy_opts,
+            "\nAttempt {} of {} failed: {}, retrying in {:?}",
+            attempt,
+            copy_opts.retry_operation + 1,
+            e,
+            delay
+        );
+        std::thread::sleep(delay);
+        delay = std::cmp::min(delay * 2, MAX_RETRY_DELAY);
+
+        // resume from where the failed attempt stopped, reporting only the
+        // outcome of the last attempt
+        truncate_report(&copy_opts, first_record);
+        let mut retry_opts = copy_opts.clone();
+        retry_opts.resume = true;
+        result = copy_path(src, dst, retry_opts);
+    }
+    remove_progress_file(progress_file.as_deref());
+    end_source(&copy_opts);
+    write_report(&copy_opts, result)
+}
+
+/// Creates the destination directory `dir` along with its missing parents.
+/// Transient failures, like the ones of network filesystems under concurrent
+/// operations, are retried up to [`CopyOptions::retry_operation`] times with
+/// an exponential backoff. An existing path counts as created.
+fn create_dst_dir(dir: &Path, copy_opts: &CopyOptions) -> io::Result<()> {
+    create_dst_dir_with(dir, copy_opts, |d| std::fs::create_dir_all(d))
+}
+
+/// Creates the directory `dir` with `create_dir`, retrying transient failures
+/// as described for [`create_dst_dir`].
+fn create_dst_dir_with(
+    dir: &Path,
+    copy_opts: &CopyOptions,
+    mut create_dir: impl FnMut(&Path) -> io::Result<()>,
+) -> io::Result<()> {
+    const MAX_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(30);
+
+    let mut delay = std::time::Duration::from_millis(100);
+    let mut attempt = 0;
+    loop {
+        let e = match create_dir(dir) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(()),
+            Err(e) => e,
+        };
+        let is_transient = matches!(
+            e.raw_os_error(),
+            Some(libc::EAGAIN) | Some(libc::ETIMEDOUT) | Some(libc::EBUSY)
+        );
+        if !is_transient || attempt >= copy_opts.retry_operation {
+            return Err(e);
+        }
+        attempt += 1;
+        if copy_opts.verbose {
+            outln!(
+                copy_opts,
+                "Creating directory '{}' failed: {}, retrying in {:?}",
+                dir.to_str().unwrap_or(""),
+                e,
+                delay
+            );
+        }
+        std::thread::sleep(delay);
+        delay = std::cmp::min(delay * 2, MAX_RETRY_DELAY);
+    }
+}
+
+/// Checks if a failed copy may succeed when retried. Only transient I/O
+/// errors, like the ones of an unreliable network mount, are retried. Errors
+/// caused by the arguments or the state of the filesystem, like a missing
+/// source or a read only destination, and the failed checks of the copy will
+/// fail the same way again.
+fn is_retryable(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::ResourceBusy
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::NetworkDown
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::StaleNetworkFileHandle
+    ) || e.raw_os_error() == Some(libc::EIO)
+}
+
+fn copy_path(src: &str, dst: &str, copy_opts: CopyOptions) -> io::Result<()> {
+    // if source and destination paths are same, abort copy
+    if src == dst {
+        return Err(io::Error::other("destination is same as the source"));
+    }
+
+    // download URL sources
+    if util::is_url(src) {
+        return copy_url(src, dst, copy_opts);
+    }
+
+    let mut copy_opts = copy_opts;
+
+    let source = Path::new(src);
+    let mut destination = Path::new(dst).to_owned();
+
+    // check if the source path exists
+    let src_stat = match std::fs::metadata(source) {
+        Err(_) if is_broken_symlink(source) => {
+            let mut destination = destination;
+            if destination.is_dir() && !copy_opts.no_target_directory {
+                if let Some(basename) = source.file_name() {
+                    destination = destination.join(basename);
+                }
+            }
+            let destination = rename_dst(&destination, &copy_opts);
+            copy_broken_symlink(source, &destination, &copy_opts)?;
+            return Ok(());
+        }
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("stat failed for source path: {}", &e),
+            ))
+        }
+        Ok(s) => s,
+    };
+
+    // keep the copy buffers of the jobs within the memory limit, only
+    // directories are copied by more than one job
+    if let Some(max_memory) = copy_opts.max_memory {
+        let jobs = if src_stat.is_dir() { copy_opts.jobs } else { 1 };
+        let (fit_jobs, fit_block_size) = util::fit_memory(jobs, copy_opts.block_size, max_memory);
+        if copy_opts.verbose && (fit_jobs, fit_block_size) != (jobs, copy_opts.block_size) {
+            outln!(
+                copy_opts,
+                "Using {} job(s) with {} buffers to stay within {} of memory",
+                fit_jobs,
+                get_str_size_precise(fit_block_size),
+                get_str_size_precise(max_memory)
+            );
+        }
+        if src_stat.is_dir() {
+            copy_opts.jobs = fit_jobs;
+        }
+        copy_opts.block_size = fit_block_size;
+    }
+
+    // load the expected digests of the copied files
+    if let Some(digest_file) = &copy_opts.checksum_from {
+        let digests = super::digest::DigestList::read(digest_file)?;
+        copy_opts.expected_digests = Some(std::sync::Arc::new(digests));
+    }
+
+    // load the order in which the files are copied
+    if let Some(order_file) = &copy_opts.order_from {
+        let listed = util::read_path_list(order_file)?;
+        copy_opts.listed_order = Some(std::sync::Arc::new(listed));
+    }
+
+    // check for recursive copy, the newest files are selected from the
+    // source directory itself
+    if src_stat.is_dir() && !copy_opts.recursive && copy_opts.keep_newest.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "source is a directory but --recursive option not specified",
+        ));
+    }
+
+    // if only a listing is requested, print the files to be copied and exit
+    if copy_opts.list_only {
+        return list_files(source, &src_stat, &copy_opts);
+    }
+
+    // if only a compression estimate is requested, print it and exit
+    if let Some(algo) = copy_opts.compress_preview {
+        return preview_compression(source, &src_stat, algo, &copy_opts);
+    }
+
+    // if only a verification is requested, compare the trees and exit
+    if copy_opts.verify_only {
+        return verify_tree(source, &src_stat, &destination, &copy_opts);
+    }
+
+    // check if destination path exists
+    if let Ok(dst_stat) = std::fs::metadata(dst) {
+        if dst_stat.is_dir() && copy_opts.no_target_directory {
+            // the destination is the exact name of the copy, which a file
+            // can't replace
+            if !src_stat.is_dir() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("can't overwrite directory '{}' with a file", dst),
+                ));
+            }
+        } else if dst_stat.is_dir() {
+            // if destination exists and is directory
+            if let Some(basename) = source.file_name() {
+                // set destination path as the original destination + basename
+                // of the source path
+                destination = destination.join(basename);
+            }
+        } else if src_stat.is_dir() {
+            // if destination is a file but source is a directory, abort copy
+            // with an error
+            return Err(io::Error::other(
+                "source is a directory, destination is a file",
+            ));
+        }
+    }
+
+    // rename the destination file for a single file copy
+    if !src_stat.is_dir() {
+        destination = rename_dst(&destination, &copy_opts);
+        util::check_path_len(&destination)?;
+    }
+
+    // check who owns the directory the copy is written to
+    if copy_opts.foreign_owner != ForeignOwner::Allow {
+        check_foreign_owner(&destination, &copy_opts)?;
+    }
+
+    // check that the copy stays on the filesystem of the source
+    if copy_opts.same_fs_only {
+        check_same_fs(source, &src_stat, &destination)?;
+    }
+
+    // check that a directory is copied into an empty destination
+    if src_stat.is_dir() && copy_opts.require_empty_dest {
+        check_empty_dest(&destination)?;
+    }
+
+    // if only a dry run is requested, print the plan and exit
+    if copy_opts.dry_run {
+        return print_plan(source, &src_stat, &destination, &copy_opts);
+    }
+
+    // block devices report a size of 0, so it's queried from the device
+    let src_len = if src_stat.is_dir() {
+        0
+    } else {
+        source_len(source, &src_stat, &copy_opts)?
+    };
+
+    // start timer
+    let start = std::time::Instant::now();
+    copy_opts.stats_store.start_time = Some(start);
+
+    if copy_opts.remove
+        && can_rename(source, &src_stat, &destination, &copy_opts)
+        && move_by_rename(source, &destination, &copy_opts)
+    {
+        // a move within the same filesystem is a rename
+        if !src_stat.is_dir() {
+            copy_opts.stats_store.total = src_len;
+            copy_opts.stats_store.scanned = src_len;
+            copy_opts.stats_store.transferred = src_len;
+        }
+    } else if src_stat.is_dir() && copy_opts.transactional {
+        // copy the directory aside and move it into place once complete
+        copy_directory_transactional(source, destination.as_path(), &mut copy_opts)?;
+    } else if src_stat.is_dir() {
+        // if source is a directory, copy entire directory
+        copy_directory(source, destination.as_path(), &mut copy_opts)?;
+    } else if is_unmodified(&src_stat, &copy_opts) {
+        // if source file hasn't been modified since the last run, skip it
+        if copy_opts.verbose {
+            outln!(
+                copy_opts,
+                "Skipping unmodified file '{}'",
+                source.to_str().unwrap_or("")
+            );
+        }
+        let record = FileRecord::new(source, &destination, src_len, FileStatus::Skipped);
+        record_file(&copy_opts, record);
+    } else if is_duplicate_source(&src_stat, &copy_opts) {
+        // if source was copied by a previous source argument, skip it
+        if copy_opts.verbose {
+            outln!(
+                copy_opts,
+                "Skipping already copied file '{}'",
+                source.to_str().unwrap_or("")
+            );
+        }
+        let record = FileRecord::new(source, &destination, src_len, FileStatus::Skipped);
+        record_file(&copy_opts, record);
+        copy_opts.stats_store.duplicate_sources += 1;
+    } else {
+        // if source is a file, copy the individual file
+        copy_opts.stats_store.total = src_len;
+        copy_opts.stats_store.scanned = src_len;
+        let started = std::time::Instant::now();
+        let copy_result = copy_file(source, destination.as_path(), &mut copy_opts);
+        let status = if copy_opts.stats_store.up_to_date + copy_opts.stats_store.touched > 0 {
+            FileStatus::Skipped
+        } else {
+            FileStatus::Copied
+        };
+        let mut record = FileRecord::new(source, &destination, src_len, status);
+        record.duration = started.elapsed();
+        record.etag = copy_opts.stats_store.last_etag.take();
+        if let Err(e) = &copy_result {
+            record.status = FileStatus::Failed;
+            record.error = Some(e.to_string());
+        }
+        record_file(&copy_opts, record);
+
+        copy_result?;
+        record_copied_source(&src_stat, &copy_opts);
+        if copy_opts.remove {
+            // if move option was specified, remove source file after
+            // successful copy
+            copy_opts.stats_store.removable.push(source.to_owned());
+            remove_sources(None, &mut copy_opts)?;
+        }
+    }
+
+    // stop timer, the removal of the sources is timed on its own
+    let end = std::time::Instant::now() - copy_opts.stats_store.removal_time;
+
+    // verify copy stats
+    if !copy_opts.verify_size {
+        // the sizes of streaming sources are only known after the copy
+        copy_opts.stats_store.total = copy_opts.stats_store.transferred;
+    } else if copy_opts.strict_total {
+        check_strict_total(&copy_opts)?;
+    } else if copy_opts.stats_store.transferred != copy_opts.stats_store.total {
+        return Err(io::Error::other(format!(
+            "error in copy: transferred={}, total={}",
+            &copy_opts.stats_store.transferred, &copy_opts.stats_store.total
+        )));
+    }
+
+    add_run_total(&copy_opts);
+
+    // if statistics are requested, calculate and show the file transfer
+    // statisctics
+    if copy_opts.show_stats {
+        copy_opts.stats_store.time_taken = end.sub(start);
+        print_stats(&copy_opts);
+    }
+    print_failures(&copy_opts);
+
+    Ok(())
+}
+
+/// Checks that the bytes transferred, skipped and lost to failed files add up
+/// to the total found by the scan of the source, and returns an error with
+/// the breakdown of the total otherwise.
+fn check_strict_total(copy_opts: &CopyOptions) -> io::Result<()> {
+    let stats = &copy_opts.stats_store;
+    let accounted = stats.transferred + stats.skipped_bytes + stats.failed_bytes;
+    if accounted == stats.scanned {
+        return Ok(());
+    }
+    let mismatch = if accounted < stats.scanned {
+        format!("{} bytes missing", stats.scanned - accounted)
+    } else {
+        format!("{} bytes in excess", accounted - stats.scanned)
+    };
+    Err(io::Error::other(format!(
+        "error in copy: {}, scanned={}, copied={}, skipped={}, failed={}",
+        mismatch, stats.scanned, stats.transferred, stats.skipped_bytes, stats.failed_bytes
+    )))
+}
+
+/// Downloads the HTTP or HTTPS `url` to `dst`. The size reported by the server
+/// is the total for the progress, and a resumed download only fetches the
+/// missing part with a range request. The body is written like the contents
+/// of a source file, and gets the modification time reported by the server
+/// if timestamps are preserved.
+fn copy_url(url: &str, dst: &str, copy_opts: CopyOptions) -> io::Result<()> {
+    let mut copy_opts = copy_opts;
+
+    // download into the destination directory under the name in the URL
+    let mut destination = Path::new(dst).to_owned();
+    if destination.is_dir() && !copy_opts.no_target_directory {
+        match util::url_file_name(url) {
+            Some(name) => destination = destination.join(name),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "can't find a file name in the URL '{}', specify the destination file",
+                        url
+                    ),
+                ))
+            }
+        }
+    }
+    let destination = rename_dst(&destination, &copy_opts);
+    util::check_path_len(&destination)?;
+
+    // check if destination file exists
+    let mut offset = 0;
+    if let Ok(m) = std::fs::metadata(&destination) {
+        if !copy_opts.force && !copy_opts.resume {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "file '{}' exists, can't copy file without --force or --continue option",
+                    destination.to_str().unwrap_or("")
+                ),
+            ));
+        }
+        if copy_opts.resume {
+            offset = m.len();
+        }
+    } else if let Some(dst_dir) = destination.parent() {
+        // create all the directories in the destination path
+        if let Err(e) = create_dst_dir(dst_dir, &copy_opts) {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("failure in creating destination directory: {}", &e),
+            ));
+        }
+    }
+
+    let response = util::open_url(url, offset)?;
+    let total = response.total;
+
+    // open the destination file, where direct I/O and holes need a fresh
+    // file just like for a source file
+    let appending = offset > 0;
+    let mut dst_file_open_options = std::fs::OpenOptions::new();
+    dst_file_open_options
+        .create(true)
+        .write(true)
+        .append(appending)
+        .truncate(!appending)
+        .mode(0o666 & copy_opts.mode_mask());
+    let (mut dst_file_handle, direct_io) = open_dst_file(
+        &mut dst_file_open_options,
+        &destination,
+        0,
+        copy_opts.direct_io && !appending,
+        &copy_opts,
+    )?;
+    let sparse_min_hole = if copy_opts.sparse && !appending {
+        Some(copy_opts.sparse_min_hole)
+    } else {
+        None
+    };
+
+    // start timer
+    let start = std::time::Instant::now();
+    copy_opts.stats_store.start_time = Some(start);
+    copy_opts.stats_store.total = total.unwrap_or(0);
+    copy_opts.stats_store.transferred = offset;
+
+    let mut body = response.body;
+    let mut strategy = copy_strategy(
+        &mut dst_file_handle,
+        direct_io,
+        sparse_min_hole,
+        total.map_or(copy_opts.block_size, |t| t.saturating_sub(offset)),
+        &copy_opts,
+    );
+    let mut progress = CopyProgress {
+        src: Path::new(url),
+        dst: &destination,
+        len: total.unwrap_or(0),
+        transferred: offset,
+    };
+    copy_loop(
+        &mut body,
+        &mut strategy,
+        &mut progress,
+        None,
+        "downloading",
+        None,
+        &mut copy_opts,
+    )?;
+    drop(strategy);
+    let bytes_transferred = progress.transferred;
+
+    // stop timer
+    let end = std::time::Instant::now();
+
+    // verify file transfer against the size reported by the server
+    match total {
+        Some(t) if copy_opts.verify_size && bytes_transferred != t => {
+            return Err(io::Error::other(format!(
+                "error while downloading '{}': transferred {} bytes, expected {}",
+                url, bytes_transferred, t
+            )));
+        }
+        Some(_) => {}
+        None => copy_opts.stats_store.total = copy_opts.stats_store.transferred,
+    }
+
+    // the server's modification time stands in for the source's
+    if let Some(modified) = response.modified.filter(|_| copy_opts.preserve_times) {
+        if let Err(e) = dst_file_handle.set_modified(modified) {
+            return Err(io::Error::new(
+                e.kind(),
+                format!(
+                    "failure in setting timestamps of '{}': {}",
+                    destination.to_str().unwrap_or(""),
+                    e
+                ),
+            ));
+        }
+    }
+
+    if copy_opts.progress_messages() {
+        outln!(
+            copy_opts,
+            "\rDownloaded file '{}' ",
+            destination.to_str().unwrap_or("")
+        );
+    }
+
+    add_run_total(&copy_opts);
+
+    if copy_opts.show_stats {
+        copy_opts.stats_store.time_taken = end.sub(start);
+        print_stats(&copy_opts);
+    }
+
+    Ok(())
+}
+
+/// Checks if moving `src` to `dst` by renaming it gives the same result as
+/// copying it. Renaming moves symlinks themselves instead of their targets,
+/// keeps the data of the files as is, can't rename or filter the files of a
+/// directory, and replaces existing files without asking.
+fn can_rename(src: &Path, src_stat: &Metadata, dst: &Path, copy_opts: &CopyOptions) -> bool {
+    if std::fs::symlink_metadata(src).is_ok_and(|m| m.file_type().is_symlink())
+        || is_unmodified(src_stat, copy_opts)
+        || copy_opts.transforms_files()
+    {
+        return false;
+    }
+    match std::fs::symlink_metadata(dst) {
+        Ok(dst_stat) => !src_stat.is_dir() && !dst_stat.is_dir() && copy_opts.force,
+        Err(_) => !src_stat.is_dir() || !copy_opts.reshapes_tree(),
+    }
+}
+
+/// Tries to move `src` to `dst` with a rename. Returns `false` if the rename
+/// failed, like for a move across filesystems, in which case the move falls
+/// back to copying and removing the source.
+fn move_by_rename(src: &Path, dst: &Path, copy_opts: &CopyOptions) -> bool {
+    match std::fs::rename(src, dst) {
+        Ok(()) => {
+            let file_name = src
+                .file_name()
+                .unwrap_or_else(|| std::ffi::OsStr::new(""))
+                .to_str()
+                .unwrap_or("");
+            if copy_opts.progress_messages() {
+                copy_opts.write_progress(&format!(
+                    "{}Moved file '{}'\n",
+                    progress_line_clear(copy_opts),
+                    file_name
+                ));
+            } else if copy_opts.verbose && !copy_opts.show_progress {
+                outln!(copy_opts, "Moved '{}'", file_name);
+            }
+            true
+        }
+        Err(e) => {
+            if copy_opts.verbose && e.raw_os_error() == Some(libc::EXDEV) {
+                outln!(copy_opts, "cross-device move, copying instead of renaming");
+            }
+            false
+        }
+    }
+}
+
+/// Adds the bytes transferred by a completed operation to the total of all
+/// the operations run with clones of the options.
+fn add_run_total(copy_opts: &CopyOptions) {
+    copy_opts.run_total.fetch_add(
+        copy_opts.stats_store.transferred,
+        std::sync::atomic::Ordering::Relaxed,
+    );
+}
+
+/// run_completion_hook runs the shell command `cmd` once all the operations
+/// run with `copy_opts` have succeeded. The command gets the destination `dst`
+/// as its first argument and in `FILECOPY_DEST`, the bytes transferred by all
+/// the operations in `FILECOPY_BYTES`, and the seconds they took in
+/// `FILECOPY_ELAPSED`. A dry run only prints the command. The copied files
+/// are kept when the command fails.
+pub fn run_completion_hook(
+    cmd: &str,
+    dst: &str,
+    elapsed: std::time::Duration,
+    copy_opts: &CopyOptions,
+) -> io::Result<()> {
+    if copy_opts.dry_run {
+        outln!(copy_opts, "Would run completion hook '{}'", cmd);
+        return Ok(());
+    }
+
+    let transferred = copy_opts
+        .run_total
+        .load(std::sync::atomic::Ordering::Relaxed);
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .arg("sh")
+        .arg(dst)
+        .env("FILECOPY_DEST", dst)
+        .env("FILECOPY_BYTES", transferred.to_string())
+        .env("FILECOPY_ELAPSED", format!("{:.3}", elapsed.as_secs_f64()))
+        .status()
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failure in running completion hook '{}': {}", cmd, e),
+            )
+        })?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "completion hook '{}' failed: {}",
+            cmd, status
+        )));
+    }
+    Ok(())
+}
+
+/// swap atomically exchanges the paths `a` and `b`, so that neither of them is
+/// ever missing. Both paths have to exist on the same filesystem. When the
+/// kernel or the filesystem doesn't support the atomic exchange, the paths are
+/// swapped with three renames through a temporary name instead, with a
+/// warning as the swap isn't atomic then.
+pub fn swap(a: &str, b: &str, copy_opts: CopyOptions) -> io::Result<()> {
+    let (a, b) = (Path::new(a), Path::new(b));
+    if a == b {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "can't swap a path with itself",
+        ));
+    }
+    let mut devices = Vec::new();
+    for path in [a, b] {
+        match std::fs::symlink_metadata(path) {
+            Ok(m) => devices.push(m.dev()),
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!(
+                        "stat failed for swapped path '{}': {}",
+                        path.to_str().unwrap_or(""),
+                        e
+                    ),
+                ));
+            }
+        }
+    }
+    if devices[0] != devices[1] {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "swapped paths must be on the same filesystem",
+        ));
+    }
+
+    match util::exchange_paths(a, b) {
+        Err(e) if matches!(e.raw_os_error(), Some(libc::ENOSYS) | Some(libc::EINVAL)) => {
+            errln!(copy_opts, "Warning: atomic exchange is not supported, swapping with renames which isn't atomic");
+            swap_by_rename(a, b)?;
+        }
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("failure in swapping paths: {}", e),
+            ));
+        }
+        Ok(()) => {}
+    }
+
+    if copy_opts.verbose {
+        outln!(
+            copy_opts,
+            "Swapped '{}' and '{}'",
+            a.to_str().unwrap_or(""),
+            b.to_str().unwrap_or("")
+        );
+    }
+    Ok(())
+}
+
+/// Swaps the paths `a` and `b` by renaming `a` to a temporary name next to it,
+/// `b` to `a` and finally the temporary name to `b`. The completed renames are
+/// undone if a later one fails.
+fn swap_by_rename(a: &Path, b: &Path) -> io::Result<()> {
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(a.file_name().unwrap_or_else(|| std::ffi::OsStr::new("")));
+    tmp_name.push(format!(".swap-{}", std::process::id()));
+    let tmp = a.with_file_name(tmp_name);
+
+    let failure =
+        |e: io::Error| io::Error::new(e.kind(), format!("failure in swapping paths: {}", e));
+    std::fs::rename(a, &tmp).map_err(failure)?;
+    if let Err(e) = std::fs::rename(b, a) {
+        let _ = std::fs::rename(&tmp, a);
+        return Err(failure(e));
+    }
+    if let Err(e) = std::fs::rename(&tmp, b) {
+        let _ = std::fs::rename(a, b);
+        let _ = std::fs::rename(&tmp, a);
+        return Err(failure(e));
+    }
+    Ok(())
+}
+
+/// concat concatenates the `srcs` files, in order, into the `dst` file based
+/// on the configuration options provided in `copy_opts`. The destination gets
+/// the permissions of the first source.
+pub fn concat(srcs: &[&str], dst: &str, copy_opts: CopyOptions) -> io::Result<()> {
+    copy_opts.validate_unsliced("when appending")?;
+
+    let progress_file = copy_opts.progress_file.clone();
+    let result = concat_paths(srcs, dst, copy_opts.clone());
+    remove_progress_file(progress_file.as_deref());
+    write_report(&copy_opts, result)
+}
+
+fn concat_paths(srcs: &[&str], dst: &str, copy_opts: CopyOptions) -> io::Result<()> {
+    let mut copy_opts = copy_opts;
+
+    // check that all the sources are files
+    let mut src_stats = Vec::new();
+    for src in srcs {
+        if *src == dst {
+            return Err(io::Error::other(format!(
+                "destination is same as the source '{}'",
+                src
+            )));
+        }
+        let src_stat = match std::fs::metadata(src) {
+            Ok(s) => s,
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("stat failed for source path '{}': {}", src, &e),
+                ))
+            }
+        };
+        if src_stat.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("source '{}' is a directory, can't concatenate it", src),
+            ));
+        }
+        copy_opts.stats_store.total += src_stat.len();
+        src_stats.push(src_stat);
+    }
+    let first_src_stat = match src_stats.first() {
+        Some(s) => s,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no source files to concatenate",
+            ))
+        }
+    };
+
+    // check if destination path exists
+    if let Ok(dst_stat) = std::fs::metadata(dst) {
+        if dst_stat.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "destination is a directory, can't concatenate into it",
+            ));
+        } else if !copy_opts.force {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "file '{}' exists, can't concatenate into it without --force option",
+                    dst
+                ),
+            ));
+        }
+    }
+
+    // open the destination file
+    let mut dst_file_handle = match std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(first_src_stat.mode() & copy_opts.mode_mask())
+        .open(dst)
+    {
+        Ok(f) => f,
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("failure in opening destination file: {}", &e),
+            ));
+        }
+    };
+
+    // start timer
+    let start = std::time::Instant::now();
+    copy_opts.stats_store.start_time = Some(start);
+
+    let total = copy_opts.stats_store.total;
+    let mut strategy = copy_strategy(&mut dst_file_handle, false, None, total, &copy_opts);
+    for (src, src_stat) in srcs.iter().zip(&src_stats) {
+        let src = Path::new(src);
+        let mut src_file_handle = match File::open(src) {
+            Ok(f) => f,
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("failure in opening source file: {}", e),
+                ));
+            }
+        };
+
+        let mut progress = CopyProgress {
+            src,
+            dst: Path::new(dst),
+            len: src_stat.len(),
+            transferred: 0,
+        };
+        copy_loop(
+            &mut src_file_handle,
+            &mut strategy,
+            &mut progress,
+            None,
+            "copying file",
+            None,
+            &mut copy_opts,
+        )?;
+        let bytes_transferred = progress.transferred;
+
+        // verify file transfer
+        if copy_opts.verify_size && bytes_transferred != src_stat.len() {
+            return Err(io::Error::other(format!(
+                "error while copying file '{}': transferred {} bytes, expected {}",
+                &src.to_str().unwrap_or(""),
+                bytes_transferred,
+                src_stat.len()
+            )));
+        }
+    }
+
+    drop(strategy);
+
+    // stop timer
+    let end = std::time::Instant::now();
+
+    // verify that the destination holds all the sources
+    let dst_len = dst_file_handle.metadata()?.len();
+    if !copy_opts.verify_size {
+        copy_opts.stats_store.total = copy_opts.stats_store.transferred;
+    } else if dst_len != copy_opts.stats_store.total {
+        return Err(io::Error::other(format!(
+            "error in concatenation: destination size={}, total={}",
+            dst_len, &copy_opts.stats_store.total
+        )));
+    }
+
+    // sync permissions between the first source and destination files
+    set_permissions(
+        &dst_file_handle,
+        Path::new(dst),
+        first_src_stat.permissions(),
+        &copy_opts,
+    )?;
+
+    if copy_opts.progress_messages() {
+        outln!(
+            copy_opts,
+            "\rConcatenated {} files into '{}' ",
+            srcs.len(),
+            dst
+        );
+    }
+
+    // if move option was specified, remove the sources after successful copy
+    if copy_opts.remove {
+        for src in srcs {
+            if let Err(e) = std::fs::remove_file(src) {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("failed to remove source file: {}", &e),
+                ));
+            }
+        }
+    }
+
+    add_run_total(&copy_opts);
+
+    if copy_opts.show_stats {
+        copy_opts.stats_store.time_taken = end.sub(start);
+        print_stats(&copy_opts);
+    }
+
+    Ok(())
+}
+
+/// copy_to_writer copies the `srcs` files, in order, into the `dst` stream,
+/// like the standard output, based on the configuration options provided in
+/// `copy_opts`. Only the contents of the sources are written to `dst`.
+pub fn copy_to_writer(
+    srcs: &[&str],
+    dst: &mut dyn Write,
+    copy_opts: CopyOptions,
+) -> io::Result<()> {
+    copy_opts.validate_unsliced("when writing to a stream")?;
+
+    let progress_file = copy_opts.progress_file.clone();
+    let result = copy_to_writer_paths(srcs, dst, copy_opts.clone());
+    remove_progress_file(progress_file.as_deref());
+    write_report(&copy_opts, result)
+}
+
+fn copy_to_writer_paths(
+    srcs: &[&str],
+    dst: &mut dyn Write,
+    copy_opts: CopyOptions,
+) -> io::Result<()> {
+    let mut copy_opts = copy_opts;
+
+    // check that all the sources are files
+    let mut src_stats = Vec::new();
+    for src in srcs {
+        let src_stat = match std::fs::metadata(src) {
+            Ok(s) => s,
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("stat failed for source path '{}': {}", src, &e),
+                ))
+            }
+        };
+        if src_stat.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "source '{}' is a directory, can't write it to a stream",
+                    src
+                ),
+            ));
+        }
+        copy_opts.stats_store.total += src_stat.len();
+        src_stats.push(src_stat);
+    }
+
+    // a stream has no free space to check
+    copy_opts.min_free_space = 0;
+
+    // start timer
+    let start = std::time::Instant::now();
+    copy_opts.stats_store.start_time = Some(start);
+
+    let mut strategy = util::CopyStrategy::stream(
+        &mut *dst,
+        util::copy_buffer_size(copy_opts.block_size, copy_opts.stats_store.total),
+    );
+    for (src, src_stat) in srcs.iter().zip(&src_stats) {
+        let src = Path::new(src);
+        let mut src_file_handle = match File::open(src) {
+            Ok(f) => f,
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("failure in opening source file: {}", e),
+                ));
+            }
+        };
+
+        let mut progress = CopyProgress {
+            src,
+            dst: Path::new("-"),
+            len: src_stat.len(),
+            transferred: 0,
+        };
+        copy_loop(
+            &mut src_file_handle,
+            &mut strategy,
+            &mut progress,
+            None,
+            "copying file",
+            None,
+            &mut copy_opts,
+        )?;
+        let bytes_transferred = progress.transferred;
+
+        // verify file transfer
+        if copy_opts.verify_size && bytes_transferred != src_stat.len() {
+            return Err(io::Error::other(format!(
+                "error while copying file '{}': transferred {} bytes, expected {}",
+                &src.to_str().unwrap_or(""),
+                bytes_transferred,
+                src_stat.len()
+            )));
+        }
+    }
+    drop(strategy);
+    if let Err(e) = dst.flush() {
+        return Err(io::Error::new(
+            e.kind(),
+            format!("failure in flushing the destination: {}", e),
+        ));
+    }
+
+    // stop timer
+    let end = std::time::Instant::now();
+    if !copy_opts.verify_size {
+        copy_opts.stats_store.total = copy_opts.stats_store.transferred;
+    }
+
+    if copy_opts.progress_messages() {
+        outln!(copy_opts, "\rCopied {} files to the stream ", srcs.len());
+    }
+
+    // if move option was specified, remove the sources after successful copy
+    if copy_opts.remove {
+        for src in srcs {
+            if let Err(e) = std::fs::remove_file(src) {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("failed to remove source file: {}", &e),
+                ));
+            }
+        }
+    }
+
+    add_run_total(&copy_opts);
+
+    if copy_opts.show_stats {
+        copy_opts.stats_store.time_taken = end.sub(start);
+        print_stats(&copy_opts);
+    }
+
+    Ok(())
+}
+
+/// fill writes the repeating `pattern` into the `dst` file up to `size` bytes
+/// based on the configuration options provided in `copy_opts`, as a copy
+/// from a source which only holds the pattern.
+pub fn fill(pattern: &[u8], size: u64, dst: &str, copy_opts: CopyOptions) -> io::Result<()> {
+    copy_opts.validate_unsliced("when filling a file")?;
+
+    let progress_file = copy_opts.progress_file.clone();
+    let result = fill_path(pattern, size, dst, copy_opts.clone());
+    remove_progress_file(progress_file.as_deref());
+    write_report(&copy_opts, result)
+}
+
+fn fill_path(pattern: &[u8], size: u64, dst: &str, copy_opts: CopyOptions) -> io::Result<()> {
+    let mut copy_opts = copy_opts;
+
+    if pattern.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "fill pattern must not be empty",
+        ));
+    }
+    copy_opts.stats_store.total = size;
+    copy_opts.stats_store.scanned = size;
+
+    // check if destination path exists
+    let destination = Path::new(dst);
+    if let Ok(dst_stat) = std::fs::metadata(destination) {
+        if dst_stat.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "destination is a directory, can't fill it",
+            ));
+        } else if !copy_opts.force {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "file '{}' exists, can't fill it without --force option",
+                    dst
+                ),
+            ));
+        }
+    }
+
+    // open the destination file
+    let mut dst_file_handle = match std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(destination)
+    {
+        Ok(f) => f,
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("failure in opening destination file: {}", &e),
+            ));
+        }
+    };
+
+    // start timer
+    let start = std::time::Instant::now();
+    copy_opts.stats_store.start_time = Some(start);
+
+    let mut src_reader = util::PatternReader::new(pattern);
+    let mut strategy = copy_strategy(&mut dst_file_handle, false, None, size, &copy_opts);
+    let mut progress = CopyProgress {
+        src: destination,
+        dst: destination,
+        len: size,
+        transferred: 0,
+    };
+    copy_loop(
+        &mut src_reader,
+        &mut strategy,
+        &mut progress,
+        Some(size),
+        "filling file",
+        None,
+        &mut copy_opts,
+    )?;
+    drop(strategy);
+
+    // stop timer
+    let end = std::time::Instant::now();
+
+    // verify that the destination got the whole size
+    let dst_len = dst_file_handle.metadata()?.len();
+    if dst_len != size {
+        return Err(io::Error::other(format!(
+            "error in fill: destination size={}, total={}",
+            dst_len, size
+        )));
+    }
+    copy_opts.stats_store.created_files += 1;
+
+    if copy_opts.progress_messages() {
+        outln!(copy_opts, "\rFilled '{}' ", dst);
+    }
+
+    add_run_total(&copy_opts);
+
+    if copy_opts.show_stats {
+        copy_opts.stats_store.time_taken = end.sub(start);
+        print_stats(&copy_opts);
+    }
+
+    Ok(())
+}
+
+/// join joins the chunks written by [`split`], the files named `prefix`
+/// followed by a sequential number, in numeric order into the `dst` file
+/// based on the configuration options provided in `copy_opts`. The sequence
+/// has to start at 0 and be contiguous.
+pub fn join(prefix: &str, dst: &str, copy_opts: CopyOptions) -> io::Result<()> {
+    copy_opts.validate_unsliced("when joining chunks")?;
+
+    let progress_file = copy_opts.progress_file.clone();
+    let result = find_chunks(prefix).and_then(|chunks| {
+        let srcs = chunks.iter().map(String::as_str).collect::<Vec<_>>();
+        concat_paths(&srcs, dst, copy_opts.clone())
+    });
+    remove_progress_file(progress_file.as_deref());
+    write_report(&copy_opts, result)
+}
+
+/// Returns the paths of the chunk files named `prefix` followed by a number,
+/// in numeric order. Fails if there are none or the numbers have a gap.
+fn find_chunks(prefix: &str) -> io::Result<Vec<String>> {
+    let prefix_path = Path::new(prefix);
+    let dir = prefix_path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let name_prefix = prefix_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("");
+
+    let entries = std::fs::read_dir(dir).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "failure in listing the chunks in '{}': {}",
+                dir.to_str().unwrap_or(""),
+                e
+            ),
+        )
+    })?;
+    let mut chunks = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+        let number = match name.strip_prefix(name_prefix) {
+            Some(n) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => n,
+            _ => continue,
+        };
+        if let Ok(index) = number.parse::<u64>() {
+            chunks.push((index, entry.path()));
+        }
+    }
+    chunks.sort();
+
+    if chunks.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no chunks found for prefix '{}'", prefix),
+        ));
+    }
+    for (expected, (index, path)) in chunks.iter().enumerate() {
+        let expected = expected as u64;
+        if *index < expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "chunk {} of '{}' is found more than once, last as '{}'",
+                    index,
+                    prefix,
+                    path.to_str().unwrap_or("")
+                ),
+            ));
+        } else if *index > expected {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "gap in the chunks of '{}', chunk {} is missing before '{}'",
+                    prefix,
+                    expected,
+                    path.to_str().unwrap_or("")
+                ),
+            ));
+        }
+    }
+    Ok(chunks
+        .into_iter()
+        .map(|(_, path)| path.to_str().unwrap_or("").to_owned())
+        .collect())
+}
+
+/// split splits the `src` file into chunks of up to `chunk_size` bytes, named
+/// after the source with a sequential `.000`, `.001`, ... suffix, in the `dst`
+/// directory based on the configuration options provided in `copy_opts`. The
+/// chunks get the permissions of the source.
+pub fn split(src: &str, dst: &str, chunk_size: u64, copy_opts: CopyOptions) -> io::Result<()> {
+    copy_opts.validate_unsliced("when splitting a file")?;
+
+    let progress_file = copy_opts.progress_file.clone();
+    let result = split_path(src, dst, chunk_size, copy_opts.clone());
+    remove_progress_file(progress_file.as_deref());
+    write_report(&copy_opts, result)
+}
+
+fn split_path(src: &str, dst: &str, chunk_size: u64, copy_opts: CopyOptions) -> io::Result<()> {
+    let mut copy_opts = copy_opts;
+
+    if chunk_size == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "chunk size must be greater than 0",
+        ));
+    }
+
+    // check that the source is a file
+    let source = Path::new(src);
+    let src_stat = match std::fs::metadata(source) {
+        Ok(s) => s,
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("stat failed for source path '{}': {}", src, &e),
+            ))
+        }
+    };
+    if src_stat.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("source '{}' is a directory, can't split it", src),
+        ));
+    }
+    let src_len = source_len(source, &src_stat, &copy_opts)?;
+    let file_name = match source.file_name() {
+        Some(name) => name.to_owned(),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("source '{}' has no file name to name the chunks after", src),
+            ))
+        }
+    };
+    copy_opts.stats_store.total = src_len;
+    copy_opts.stats_store.scanned = src_len;
+
+    // the chunks are written into the destination directory, an empty source
+    // still gets a single empty chunk
+    let destination = Path::new(dst);
+    match std::fs::metadata(destination) {
+        Ok(dst_stat) if !dst_stat.is_dir() => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "destination is a file, can't split into it",
+            ));
+        }
+        Ok(_) => {}
+        Err(_) => {
+            if let Err(e) = std::fs::create_dir_all(destination) {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("failure in creating destination directory: {}", &e),
+                ));
+            }
+        }
+    }
+    let chunk_count = std::cmp::max(src_len.div_ceil(chunk_size), 1);
+    let chunk_paths = (0..chunk_count)
+        .map(|i| {
+            let mut name = file_name.clone();
+            name.push(format!(".{:03}", i));
+            destination.join(name)
+        })
+        .collect::<Vec<_>>();
+    if !copy_opts.force {
+        if let Some(existing) = chunk_paths.iter().find(|p| p.exists()) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "file '{}' exists, can't split into it without --force option",
+                    existing.to_str().unwrap_or("")
+                ),
+            ));
+        }
+    }
+
+    let mut src_file_handle = match File::open(source) {
+        Ok(f) => f,
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("failure in opening source file: {}", e),
+            ));
+        }
+    };
+
+    // start timer
+    let start = std::time::Instant::now();
+    copy_opts.stats_store.start_time = Some(start);
+
+    let mut bytes_transferred: u64 = 0;
+    let mut chunks_len: u64 = 0;
+    for chunk_path in &chunk_paths {
+        let mut dst_file_handle = match std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .mode(src_stat.mode() & copy_opts.mode_mask())
+            .open(chunk_path)
+        {
+            Ok(f) => f,
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!(
+                        "failure in opening destination file '{}': {}",
+                        chunk_path.to_str().unwrap_or(""),
+                        &e
+                    ),
+                ));
+            }
+        };
+
+        let mut strategy = copy_strategy(&mut dst_file_handle, false, None, src_len, &copy_opts);
+        let mut progress = CopyProgress {
+            src: source,
+            dst: chunk_path,
+            len: src_len,
+            transferred: bytes_transferred,
+        };
+        copy_loop(
+            &mut src_file_handle,
+            &mut strategy,
+            &mut progress,
+            Some(bytes_transferred + chunk_size),
+            "copying file",
+            None,
+            &mut copy_opts,
+        )?;
+        drop(strategy);
+        bytes_transferred = progress.transferred;
+
+        // sync permissions between the source and the chunk
+        set_permissions(
+            &dst_file_handle,
+            chunk_path,
+            src_stat.permissions(),
+            &copy_opts,
+        )?;
+        chunks_len += dst_file_handle.metadata()?.len();
+        copy_opts.stats_store.created_files += 1;
+    }
+
+    // stop timer
+    let end = std::time::Instant::now();
+
+    // verify that the chunks hold all of the source
+    if !copy_opts.verify_size {
+        copy_opts.stats_store.total = copy_opts.stats_store.transferred;
+    } else if chunks_len != src_len {
+        return Err(io::Error::other(format!(
+            "error in split: size of the chunks={}, total={}",
+            chunks_len, src_len
+        )));
+    }
+
+    if copy_opts.progress_messages() {
+        outln!(
+            copy_opts,
+            "\rSplit '{}' into {} chunks in '{}' ",
+            src,
+            chunk_paths.len(),
+            dst
+        );
+    }
+
+    // if move option was specified, remove the source after successful copy
+    if copy_opts.remove {
+        if let Err(e) = std::fs::remove_file(source) {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("failed to remove source file: {}", &e),
+            ));
+        }
+    }
+
+    add_run_total(&copy_opts);
+
+    if copy_opts.show_stats {
+        copy_opts.stats_store.time_taken = end.sub(start);
+        print_stats(&copy_opts);
+    }
+
+    Ok(())
+}
+
+/// Waits until `bytes` more bytes may be transferred under the configured
+/// rate limit. The limiter lives for the whole operation.
+fn throttle(copy_opts: &mut CopyOptions, bytes: u64) {
+    if copy_opts.limit_rate == 0 {
+        return;
+    }
+    let (rate, ramp_up) = (copy_opts.limit_rate, copy_opts.ramp_up);
+    copy_opts
+        .stats_store
+        .rate_limiter
+        .get_or_insert_with(|| util::RateLimiter::new(rate, ramp_up))
+        .throttle(bytes);
+}
+
+/// Returns the number of bytes the next block of a file of size `len` may
+/// copy after `bytes_transferred` bytes. The size of streaming sources isn't
+/// known, so they may always copy a full block.
+fn next_block_size(bytes_transferred: u64, len: u64, copy_opts: &CopyOptions) -> u64 {
+    if copy_opts.verify_size {
+        std::cmp::min(copy_opts.block_size, len.saturating_sub(bytes_transferred))
+    } else {
+        copy_opts.block_size
+    }
+}
+
+/// Compares the owner of the directory `dst` is written to, or of its closest
+/// existing ancestor, with the effective user of the process. A foreign owner
+/// is reported as a warning or an error based on [`CopyOptions::foreign_owner`].
+fn check_foreign_owner(dst: &Path, copy_opts: &CopyOptions) -> io::Result<()> {
+    let (dir, dir_stat) = match closest_existing_dir(dst) {
+        Some(d) => d,
+        None => return Ok(()),
+    };
+
+    // SAFETY: `geteuid` has no preconditions and can't fail
+    let euid = unsafe { libc::geteuid() };
+    if dir_stat.uid() == euid {
+        return Ok(());
+    }
+    let msg = format!(
+        "destination directory '{}' is owned by uid {}, not by the current user (uid {})",
+        dir.to_str().unwrap_or(""),
+        dir_stat.uid(),
+        euid
+    );
+    match copy_opts.foreign_owner {
+        ForeignOwner::Refuse => Err(io::Error::new(io::ErrorKind::PermissionDenied, msg)),
+        _ => {
+            errln!(copy_opts, "Warning: {}", msg);
+            Ok(())
+        }
+    }
+}
+
+/// Checks that the destination `dst`, or its closest existing directory, is
+/// on the same filesystem as the source `src`.
+fn check_same_fs(src: &Path, src_stat: &Metadata, dst: &Path) -> io::Result<()> {
+    let (dir, dir_stat) = match closest_existing_dir(dst) {
+        Some(d) => d,
+        None => return Ok(()),
+    };
+    if dir_stat.dev() == src_stat.dev() {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "source '{}' and destination directory '{}' are on different filesystems, refusing to copy across them",
+            src.to_str().unwrap_or(""),
+            dir.to_str().unwrap_or("")
+        ),
+    ))
+}
+
+/// Returns the closest existing directory of `path`, which may be `path`
+/// itself, along with its metadata.
+fn closest_existing_dir(path: &Path) -> Option<(&Path, Metadata)> {
+    path.ancestors()
+        .map(|dir| {
+            if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            }
+        })
+        .find_map(|dir| {
+            std::fs::metadata(dir)
+                .ok()
+                .filter(|m| m.is_dir())
+                .map(|m| (dir, m))
+        })
+}
+
+/// Checks that the destination directory `dst` is empty or doesn't exist. The
+/// error lists the first few entries found in it.
+fn check_empty_dest(dst: &Path) -> io::Result<()> {
+    let dir_reader = match std::fs::read_dir(dst) {
+        Ok(r) => r,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!(
+                    "failure in reading destination directory '{}': {}",
+                    dst.to_str().unwrap_or(""),
+                    e
+                ),
+            ));
+        }
+    };
+    let mut entries: Vec<_> = dir_reader
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name())
+        .collect();
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    const SHOWN_ENTRIES: usize = 3;
+    entries.sort();
+    let mut listed = entries
+        .iter()
+        .take(SHOWN_ENTRIES)
+        .map(|name| format!("'{}'", name.to_str().unwrap_or("")))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if entries.len() > SHOWN_ENTRIES {
+        listed.push_str(&format!(" and {} more", entries.len() - SHOWN_ENTRIES));
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!(
+            "destination directory '{}' is not empty, it contains {}",
+            dst.to_str().unwrap_or(""),
+            listed
+        ),
+    ))
+}
+
+/// Returns the id of the group which the destination file `dst` gets as set
+/// by `group`.
+fn dest_gid(dst: &Path, src_metadata: &Metadata, group: DestGroup) -> io::Result<u32> {
+    match group {
+        DestGroup::Source => Ok(src_metadata.gid()),
+        DestGroup::Dir => std::fs::metadata(
+            dst.parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .unwrap_or(Path::new(".")),
+        )
+        .map(|m| m.gid()),
+        DestGroup::Id(gid) => Ok(gid),
+    }
+}
+
+/// Changes the group of the destination file `dst` as set by `group`. A
+/// failure only prints a warning unless the copy should fail on it.
+fn set_group(
+    file: &File,
+    dst: &Path,
+    src_metadata: &Metadata,
+    group: DestGroup,
+    copy_opts: &CopyOptions,
+) -> io::Result<()> {
+    let gid = dest_gid(dst, src_metadata, group);
+    match gid.and_then(|gid| util::set_file_group(file, gid)) {
+        Err(e) if copy_opts.on_perm_error == OnPermError::Warn => {
+            errln!(
+                copy_opts,
+                "Warning: failure in setting group of '{}': {}",
+                dst.to_str().unwrap_or(""),
+                e
+            );
+            Ok(())
+        }
+        Err(e) => Err(io::Error::new(
+            e.kind(),
+            format!(
+                "failure in setting group of '{}': {}",
+                dst.to_str().unwrap_or(""),
+                e
+            ),
+        )),
+        Ok(()) => Ok(()),
+    }
+}
+
+/// Sets the permissions of the destination file `dst`, including the setuid,
+/// setgid and sticky bits unless they aren't preserved. Writes and ownership
+/// changes can clear those bits, so this is done after them. A failure only
+/// prints a warning unless the copy should fail on it.
+fn set_permissions(
+    file: &File,
+    dst: &Path,
+    permissions: std::fs::Permissions,
+    copy_opts: &CopyOptions,
+) -> io::Result<()> {
+    let mode = permissions.mode() & copy_opts.mode_mask();
+    match file.set_permissions(std::fs::Permissions::from_mode(mode)) {
+        Err(e) if copy_opts.on_perm_error == OnPermError::Warn => {
+            errln!(
+                copy_opts,
+                "Warning: failure in setting permissions of '{}': {}",
+                dst.to_str().unwrap_or(""),
+                e
+            );
+            Ok(())
+        }
+        Err(e) => Err(io::Error::new(
+            e.kind(),
+            format!(
+                "failure in setting permissions of '{}': {}",
+                dst.to_str().unwrap_or(""),
+                e
+            ),
+        )),
+        Ok(()) => Ok(()),
+    }
+}
+
+/// Re-applies the group, permissions and timestamps of the source to the
+/// existing destination file `dst`, as far as they are preserved and differ
+/// from it. The group is set first, as changing it may clear the setuid and
+/// setgid bits. Returns whether any of them was changed.
+fn sync_metadata(src_metadata: &Metadata, dst: &Path, copy_opts: &CopyOptions) -> io::Result<bool> {
+    let dst_file_handle = File::open(dst).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "failure in opening '{}' to sync its metadata: {}",
+                dst.to_str().unwrap_or(""),
+                e
+            ),
+        )
+    })?;
+    let dst_metadata = dst_file_handle.metadata()?;
+    let mut fixed = false;
+
+    if let Some(group) = copy_opts.group {
+        if dest_gid(dst, src_metadata, group).is_ok_and(|gid| gid != dst_metadata.gid()) {
+            set_group(&dst_file_handle, dst, src_metadata, group, copy_opts)?;
+            fixed = true;
+        }
+    }
+
+    let mode_mask = copy_opts.mode_mask();
+    let mode_differs = src_metadata.mode() & mode_mask != dst_metadata.mode() & mode_mask;
+    if mode_differs || fixed {
+        set_permissions(&dst_file_handle, dst, src_metadata.permissions(), copy_opts)?;
+    }
+    fixed |= mode_differs;
+
+    if copy_opts.preserve_times {
+        let times_differ = src_metadata.mtime() != dst_metadata.mtime()
+            || (copy_opts.times_precision == TimesPrecision::Nanosecond
+                && src_metadata.mtime_nsec() != dst_metadata.mtime_nsec());
+        if times_differ {
+            if let Err(e) =
+                util::set_file_times(&dst_file_handle, src_metadata, copy_opts.times_precision)
+            {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!(
+                        "failure in setting timestamps of '{}': {}",
+                        dst.to_str().unwrap_or(""),
+                        e
+                    ),
+                ));
+            }
+            fixed = true;
+        }
+    }
+    Ok(fixed)
+}
+
+/// Returns the number of bytes to copy from the source file `path`.
+fn source_len(path: &Path, metadata: &Metadata, copy_opts: &CopyOptions) -> io::Result<u64> {
+    source_range(path, metadata, copy_opts).map(|(_, len)| len)
+}
+
+/// Returns the offset and the number of bytes of the part of the source file
+/// `path` which is copied, as set by [`CopyOptions::head`],
+/// [`CopyOptions::tail`] or [`CopyOptions::offset`] and
+/// [`CopyOptions::length`]. Fails if the offset is beyond the end of the file.
+fn source_range(
+    path: &Path,
+    metadata: &Metadata,
+    copy_opts: &CopyOptions,
+) -> io::Result<(u64, u64)> {
+    let len = full_source_len(path, metadata, copy_opts)?;
+    if let Some(head) = copy_opts.head {
+        return Ok((0, std::cmp::min(len, head)));
+    }
+    if let Some(tail) = copy_opts.tail {
+        let slice = std::cmp::min(len, tail);
+        return Ok((len - slice, slice));
+    }
+    if copy_opts.offset > len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "offset {} is beyond the end of '{}' at {}",
+                copy_opts.offset,
+                path.to_str().unwrap_or(""),
+                len
+            ),
+        ));
+    }
+    let remaining = len - copy_opts.offset;
+    let slice = copy_opts
+        .length
+        .map_or(remaining, |length| std::cmp::min(length, remaining));
+    Ok((copy_opts.offset, slice))
+}
+
+/// Returns the size of the source file `path`. Block devices are queried for
+/// their size, unless it's overridden with [`CopyOptions::device_size`].
+fn full_source_len(path: &Path, metadata: &Metadata, copy_opts: &CopyOptions) -> io::Result<u64> {
+    if !metadata.file_type().is_block_device() {
+        return Ok(metadata.len());
+    }
+    if let Some(size) = copy_opts.device_size {
+        return Ok(size);
+    }
+    util::block_device_size(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "failure in querying the size of block device '{}': {}",
+                path.to_str().unwrap_or(""),
+                e
+            ),
+        )
+    })
+}
+
+/// Reads back the block of `len` bytes at `offset` from the destination and
+/// compares its digest with `src_digest`. On a mismatch the destination is
+/// truncated to the start of the block and an error with the offset of the
+/// first differing byte is returned. Unwritten trailing holes of sparse
+/// copies read as zeros.
+fn verify_block(
+    src: &File,
+    dst: &File,
+    src_digest: [u8; super::digest::SHA256_LEN],
+    offset: u64,
+    len: u64,
+    sparse: bool,
+) -> io::Result<()> {
+    if super::digest::sha256_range(dst, offset, len, sparse)? == src_digest {
+        return Ok(());
+    }
+
+    let mismatch = super::digest::first_difference(src, dst, offset, len, sparse)?;
+    dst.set_len(offset)?;
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("checksum mismatch at offset {}", mismatch),
+    ))
+}
+
+/// Fails if writing `bytes` more bytes to `dst` would drop the free space of
+/// its filesystem below the configured minimum. The free space is queried at
+/// most once every [`FREE_SPACE_INTERVAL`] and estimated from the bytes
+/// written since in between, so that concurrent writers are noticed without
+/// a system call for every block.
+fn check_free_space(dst: &Path, bytes: u64, copy_opts: &mut CopyOptions) -> io::Result<()> {
+    const FREE_SPACE_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+
+    if copy_opts.min_free_space == 0 {
+        return Ok(());
+    }
+    let now = std::time::Instant::now();
+    let stats = &mut copy_opts.stats_store;
+    let (checked_at, mut free, transferred) = match stats.free_space {
+        Some(f) if now.duration_since(f.0) < FREE_SPACE_INTERVAL => f,
+        _ => {
+            let free = match util::free_space(dst) {
+                Ok(f) => f,
+                Err(e) => {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!(
+                            "failure in checking free space of '{}': {}",
+                            dst.to_str().unwrap_or(""),
+                            e
+                        ),
+                    ))
+                }
+            };
+            (now, free, stats.transferred)
+        }
+    };
+    stats.free_space = Some((checked_at, free, transferred));
+    free = free.saturating_sub(stats.transferred - transferred);
+
+    if free.saturating_sub(bytes) < copy_opts.min_free_space {
+        return Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "stopped before the free space on the destination drops below {}, {} left, copied {} of {}",
+                get_str_size_precise(copy_opts.min_free_space),
+                get_str_size_precise(free),
+                get_str_size_precise(stats.transferred),
+                get_str_size_precise(stats.total)
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Fails if creating `inodes` more files or directories at `dst`, which may
+/// not exist yet, would drop the free inodes of its filesystem below the
+/// configured minimum. Like the free space, the free inodes are queried at
+/// most once every [`FREE_INODES_INTERVAL`] and estimated from the files
+/// created since in between.
+fn check_free_inodes(dst: &Path, inodes: u64, copy_opts: &mut CopyOptions) -> io::Result<()> {
+    const FREE_INODES_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+
+    let now = std::time::Instant::now();
+    let stats = &mut copy_opts.stats_store;
+    let (checked_at, free, created) = match stats.free_inodes {
+        Some(f) if now.duration_since(f.0) < FREE_INODES_INTERVAL => f,
+        _ => {
+            // query the closest existing ancestor of the destination
+            let existing = dst
+                .ancestors()
+                .find(|p| p.exists())
+                .filter(|p| !p.as_os_str().is_empty())
+                .unwrap_or_else(|| Path::new("."));
+            let free = match util::free_inodes(existing) {
+                Ok(f) => f,
+                Err(e) => {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!(
+                            "failure in checking free inodes of '{}': {}",
+                            existing.to_str().unwrap_or(""),
+                            e
+                        ),
+                    ))
+                }
+            };
+            (now, free, stats.created_files)
+        }
+    };
+    stats.free_inodes = Some((checked_at, free, created));
+    let free = match free {
+        Some(f) => f.saturating_sub(stats.created_files - created),
+        None => return Ok(()),
+    };
+
+    let needed = inodes + copy_opts.min_free_inodes;
+    if free < needed {
+        return Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "not enough free inodes on the destination for {} new files with a margin of {}: {} free, {} short",
+                inodes,
+                copy_opts.min_free_inodes,
+                free,
+                needed - free
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Reports the current progress of the file `src` to the progress file and
+/// the journal, as far as they are configured.
+fn report_progress(src: &Path, bytes_transferred: u64, total: u64, copy_opts: &mut CopyOptions) {
+    update_progress_file(src, bytes_transferred, total, copy_opts);
+    log_journal_progress(src, bytes_transferred, total, copy_opts);
+}
+
+/// Logs the current progress to the journal, if requested, once every
+/// [`CopyOptions::journal_progress`] interval. Falls back to printing the
+/// record to stderr when the journal can't be reached.
+fn log_journal_progress(
+    src: &Path,
+    bytes_transferred: u64,
+    total: u64,
+    copy_opts: &mut CopyOptions,
+) {
+    let interval = match copy_opts.journal_progress {
+        Some(i) => i,
+        None => return,
+    };
+    let now = std::time::Instant::now();
+    if copy_opts
+        .stats_store
+        .journal_logged
+        .is_some_and(|t| now.duration_since(t) < interval)
+    {
+        return;
+    }
+    copy_opts.stats_store.journal_logged = Some(now);
+
+    let stats = &copy_opts.stats_store;
+    let overall_percent = if stats.total == 0 {
+        100.0
+    } else {
+        stats.transferred as f64 * 100.0 / stats.total as f64
+    };
+    let rate = stats.average_rate();
+    let message = format!(
+        "Copying '{}': {} / {}, overall {:.2}% ({} / {}) at {}",
+        src.to_str().unwrap_or(""),
+        get_str_size_precise(bytes_transferred),
+        get_str_size_precise(total),
+        overall_percent,
+        get_str_size_precise(stats.transferred),
+        get_str_size_precise(stats.total),
+        get_str_rate(rate)
+    );
+    let fields = [
+        ("MESSAGE", message.clone()),
+        ("PRIORITY", "6".to_string()),
+        ("SYSLOG_IDENTIFIER", "rs_filecopy".to_string()),
+        ("FILECOPY_FILE", src.to_str().unwrap_or("").to_string()),
+        ("FILECOPY_PERCENT", format!("{:.2}", overall_percent)),
+        ("FILECOPY_BYTES", stats.transferred.to_string()),
+        ("FILECOPY_TOTAL", stats.total.to_string()),
+        ("FILECOPY_RATE", rate.unwrap_or(0).to_string()),
+    ];
+    // progress reporting must not fail the copy
+    if util::journal_send(&fields).is_err() {
+        errln!(copy_opts, "rs_filecopy: {}", message);
+    }
+}
+
+/// Writes the current progress to the progress file, if one is configured,
+/// at most once every [`PROGRESS_FILE_INTERVAL`] and when a file completes.
+fn update_progress_file(
+    src: &Path,
+    bytes_transferred: u64,
+    total: u64,
+    copy_opts: &mut CopyOptions,
+) {
+    const PROGRESS_FILE_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+
+    let progress_file = match &copy_opts.progress_file {
+        Some(p) => p,
+        None => return,
+    };
+    let now = std::time::Instant::now();
+    if bytes_transferred != total
+        && copy_opts
+            .stats_store
+            .progress_file_updated
+            .is_some_and(|t| now.duration_since(t) < PROGRESS_FILE_INTERVAL)
+    {
+        return;
+    }
+    copy_opts.stats_store.progress_file_updated = Some(now);
+
+    let stats = &copy_opts.stats_store;
+    let overall_percent = if stats.total == 0 {
+        100.0
+    } else {
+        stats.transferred as f64 * 100.0 / stats.total as f64
+    };
+    let content = format!(
+        "{{ \"file\": \"{}\", \"transferred\": {}, \"total\": {}, \"overall_percent\": {:.2}, \"rate\": {} }}\n",
+        util::json_escape(src.to_str().unwrap_or("")),
+        bytes_transferred,
+        total,
+        overall_percent,
+        stats.average_rate().unwrap_or(0)
+    );
+    // progress reporting must not fail the copy
+    if let Err(e) = util::write_atomic(progress_file, content.as_bytes()) {
+        if copy_opts.verbose {
+            errln!(
+                copy_opts,
+                "\nWarning: failure in writing progress file: {}",
+                e
+            );
+        }
+    }
+}
+
+/// Removes the progress file once the operation has ended.
+fn remove_progress_file(progress_file: Option<&Path>) {
+    if let Some(progress_file) = progress_file {
+        let _ = std::fs::remove_file(progress_file);
+    }
+}
+
+/// Adds `record` to the transfer report, if one is requested.
+fn record_file(copy_opts: &CopyOptions, record: FileRecord) {
+    if let Some((_, records)) = &copy_opts.report {
+        if let Ok(mut records) = records.lock() {
+            records.push(record);
+        }
+    }
+}
+
+fn report_len(copy_opts: &CopyOptions) -> usize {
+    match &copy_opts.report {
+        Some((_, records)) => records.lock().map_or(0, |r| r.len()),
+        None => 0,
+    }
+}
+
+fn truncate_report(copy_opts: &CopyOptions, len: usize) {
+    if let Some((_, records)) = &copy_opts.report {
+        if let Ok(mut records) = records.lock() {
+            records.truncate(len);
+        }
+    }
+}
+
+/// Writes the transfer report, if one is requested, with the records of all
+/// the files handled so far and the outcome of the operation in `result`,
+/// which is returned unless writing the report fails. A failure in writing
+/// the report of a failed operation is only printed.
+fn write_report(copy_opts: &CopyOptions, result: io::Result<()>) -> io::Result<()> {
+    let (report, records) = match &copy_opts.report {
+        Some(r) => r,
+        None => return result,
+    };
+    let records = match records.lock() {
+        Ok(records) => records,
+        Err(_) => return result,
+    };
+
+    let digest = |r: &FileRecord| r.digest.map(|d| super::digest::to_hex(&d));
+    let csv = report.extension().is_some_and(|ext| ext == "csv");
+    let mut content = String::new();
+    if csv {
+        content.push_str("source,destination,size,duration_ms,sha256,s3_etag,status,error\n");
+        for r in records.iter() {
+            content.push_str(&format!(
+                "{},{},{},{},{},{},{},{}\n",
+                util::csv_escape(r.src.to_str().unwrap_or("")),
+                util::csv_escape(r.dst.to_str().unwrap_or("")),
+                r.size,
+                r.duration.as_millis(),
+                digest(r).unwrap_or_default(),
+                r.etag.as_deref().unwrap_or(""),
+                r.status.as_str(),
+                util::csv_escape(r.error.as_deref().unwrap_or(""))
+            ));
+        }
+    } else {
+        let json_str = |s: Option<&str>| match s {
+            Some(s) => format!("\"{}\"", util::json_escape(s)),
+            None => "null".to_owned(),
+        };
+        let files: Vec<String> = records
+            .iter()
+            .map(|r| {
+                format!(
+                    "    {{ \"source\": {}, \"destination\": {}, \"size\": {}, \"duration_ms\": {}, \"sha256\": {}, \"s3_etag\": {}, \"status\": \"{}\", \"error\": {} }}",
+                    json_str(r.src.to_str()),
+                    json_str(r.dst.to_str()),
+                    r.size,
+                    r.duration.as_millis(),
+                    json_str(digest(r).as_deref()),
+                    json_str(r.etag.as_deref()),
+                    r.status.as_str(),
+                    json_str(r.error.as_deref())
+                )
+            })
+            .collect();
+        content.push_str(&format!(
+            "{{\n  \"status\": \"{}\",\n  \"error\": {},\n  \"files\": [\n{}\n  ]\n}}\n",
+            if result.is_ok() { "success" } else { "failed" },
+            json_str(result.as_ref().err().map(|e| e.to_string()).as_deref()),
+            files.join(",\n")
+        ));
+    }
+
+    match (std::fs::write(report, content), result) {
+        (Err(e), Ok(())) => Err(io::Error::new(
+            e.kind(),
+            format!("failure in writing report: {}", e),
+        )),
+        (Err(e), Err(err)) => {
+            errln!(copy_opts, "Warning: failure in writing report: {}", e);
+            Err(err)
+        }
+        (Ok(()), result) => result,
+    }
+}
+
+/// copy_to_tar writes the `srcs` files and directories into a new tar
+/// archive at `archive` instead of copying them to the filesystem, based on
+/// the configuration options provided in `copy_opts`. Each entry keeps the
+/// path relative to the parent of its source along with its mode, ownership
+/// and modification time. Directories, including empty ones, get entries of
+/// their own, and symlinks inside them are archived as symlinks.
+pub fn copy_to_tar(srcs: &[&str], archive: &str, copy_opts: CopyOptions) -> io::Result<()> {
+    copy_opts.validate_unsliced("when writing an archive")?;
+
+    let progress_file = copy_opts.progress_file.clone();
+    let result = copy_paths_to_tar(srcs, archive, copy_opts.clone());
+    remove_progress_file(progress_file.as_deref());
+    write_report(&copy_opts, result)
+}
+
+fn copy_paths_to_tar(srcs: &[&str], archive: &str, copy_opts: CopyOptions) -> io::Result<()> {
+    let mut copy_opts = copy_opts;
+
+    // collect the entries to be archived along with their names
+    let entries = collect_tar_entries(srcs, &mut copy_opts)?;
+
+    // check if the archive exists
+    if std::fs::metadata(archive).is_ok() && !copy_opts.force {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "file '{}' exists, can't write archive without --force option",
+                archive
+            ),
+        ));
+    }
+    let archive_file = match File::create(archive) {
+        Ok(f) => f,
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("failure in creating archive '{}': {}", archive, &e),
+            ));
+        }
+    };
+    let mut tar_builder = tar::Builder::new(io::BufWriter::new(archive_file));
+
+    // start timer
+    let start = std::time::Instant::now();
+    copy_opts.stats_store.start_time = Some(start);
+
+    for entry in &entries {
+        let (src, entry_name) = match entry {
+            TarEntry::Directory(src, name)
+            | TarEntry::Symlink(src, name)
+            | TarEntry::File(src, name) => (src, name),
+        };
+        let archived = match entry {
+            TarEntry::Directory(..) => std::fs::metadata(src).and_then(|metadata| {
+                let mut header = tar_header(&metadata);
+                tar_builder.append_data(&mut header, entry_name, io::empty())
+            }),
+            TarEntry::Symlink(..) => std::fs::symlink_metadata(src).and_then(|metadata| {
+                let target = std::fs::read_link(src)?;
+                let mut header = tar_header(&metadata);
+                tar_builder.append_link(&mut header, entry_name, target)
+            }),
+            TarEntry::File(..) => archive_file_entry(
+                &mut tar_builder,
+                src,
+                entry_name,
+                Path::new(archive),
+                &mut copy_opts,
+            ),
+        };
+        if let Err(e) = archived {
+            return Err(io::Error::new(
+                e.kind(),
+                format!(
+                    "error while archiving '{}': {}",
+                    &src.to_str().unwrap_or(""),
+                    e
+                ),
+            ));
+        }
+
+        if copy_opts.progress_messages() {
+            outln!(copy_opts, "\rArchived '{}' ", entry_name);
+        }
+    }
+
+    let finished = tar_builder
+        .into_inner()
+        .and_then(|mut writer| writer.flush());
+    if let Err(e) = finished {
+        return Err(io::Error::new(
+            e.kind(),
+            format!("failure in writing archive '{}': {}", archive, &e),
+        ));
+    }
+
+    // stop timer
+    let end = std::time::Instant::now();
+
+    // verify copy stats
+    if copy_opts.stats_store.transferred != copy_opts.stats_store.total {
+        return Err(io::Error::other(format!(
+            "error in copy: transferred={}, total={}",
+            &copy_opts.stats_store.transferred, &copy_opts.stats_store.total
+        )));
+    }
+
+    add_run_total(&copy_opts);
+
+    if copy_opts.show_stats {
+        copy_opts.stats_store.time_taken = end.sub(start);
+        print_stats(&copy_opts);
+    }
+
+    Ok(())
+}
+
+/// Entry of an archive written by [`copy_to_tar`], with the source path and
+/// the name of the entry.
+enum TarEntry {
+    Directory(std::path::PathBuf, String),
+    /// Symlink stored as a link to its target, not as the target's data
+    Symlink(std::path::PathBuf, String),
+    File(std::path::PathBuf, String),
+}
+
+/// Builds the header of an archive entry with the type, mode, ownership,
+/// size and modification time from `metadata`.
+fn tar_header(metadata: &Metadata) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata_in_mode(metadata, tar::HeaderMode::Complete);
+    header
+}
+
+/// Appends the file `src` as the entry `entry_name`, reading it in blocks
+/// which are accounted and reported like the blocks of a file copy. The data
+/// written must match the size recorded in the entry header.
+fn archive_file_entry<W: Write>(
+    tar_builder: &mut tar::Builder<W>,
+    src: &Path,
+    entry_name: &str,
+    archive: &Path,
+    copy_opts: &mut CopyOptions,
+) -> io::Result<()> {
+    let file = match File::open(src) {
+        Ok(f) => f,
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("failure in opening source file: {}", e),
+            ));
+        }
+    };
+    let metadata = file.metadata()?;
+    let mut header = tar_header(&metadata);
+    let block_size = copy_opts.block_size as usize;
+    let mut reader = io::BufReader::with_capacity(
+        block_size,
+        ArchivedFile {
+            file,
+            progress: CopyProgress {
+                src,
+                dst: archive,
+                len: metadata.len(),
+                transferred: 0,
+            },
+            copy_opts,
+        },
+    );
+    tar_builder.append_data(&mut header, entry_name, &mut reader)?;
+
+    // the entry is padded to the size in its header, so a file which shrank
+    // leaves a corrupt archive
+    let transferred = reader.get_ref().progress.transferred;
+    if transferred != metadata.len() {
+        return Err(io::Error::other(format!(
+            "tar entry size mismatch: written={}, expected={}",
+            transferred,
+            metadata.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Reader of a file archived by [`copy_to_tar`], which reads up to the size
+/// of the entry and accounts the data read like a block of a file copy.
+struct ArchivedFile<'a> {
+    file: File,
+    progress: CopyProgress<'a>,
+    copy_opts: &'a mut CopyOptions,
+}
+
+impl io::Read for ArchivedFile<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let progress = &mut self.progress;
+        let bytes_to_copy = std::cmp::min(buf.len() as u64, progress.len - progress.transferred);
+        if bytes_to_copy == 0 {
+            return Ok(0);
+        }
+        check_free_space(progress.dst, bytes_to_copy, self.copy_opts)?;
+        let bytes_copied = self.file.read(&mut buf[..bytes_to_copy as usize])?;
+        if bytes_copied == 0 {
+            return Ok(0);
+        }
+
+        progress.advance(bytes_copied as u64, self.copy_opts);
+        Ok(bytes_copied)
+    }
+}
+
+/// Lists the entries of the archive of the `srcs` files and directories with
+/// names relative to the parent of their source, adding the sizes of the
+/// files to the total. Directories are listed first, including the empty
+/// ones, and symlinks inside directories are kept as symlinks. Other special
+/// files are left out.
+fn collect_tar_entries(srcs: &[&str], copy_opts: &mut CopyOptions) -> io::Result<Vec<TarEntry>> {
+    let mut entries = Vec::new();
+    for src in srcs {
+        let source = Path::new(src);
+        let src_stat = match std::fs::metadata(source) {
+            Ok(s) => s,
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("stat failed for source path '{}': {}", src, &e),
+                ))
+            }
+        };
+        let basename = source
+            .file_name()
+            .map(|n| n.to_str().unwrap_or("").to_owned())
+            .unwrap_or_default();
+        if !src_stat.is_dir() {
+            if !is_unmodified(&src_stat, copy_opts) {
+                copy_opts.stats_store.total += src_stat.len();
+                entries.push(TarEntry::File(source.to_owned(), basename));
+            }
+            continue;
+        }
+        if !copy_opts.recursive {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "source is a directory but --recursive option not specified",
+            ));
+        }
+
+        let mut dirs = Vec::new();
+        collect_dirs(
+            source,
+            Path::new(""),
+            &pseudo_fs_dirs(source, copy_opts),
+            &mut dirs,
+        )?;
+        entries.push(TarEntry::Directory(source.to_owned(), basename.clone()));
+        for dir in dirs {
+            let entry_name = Path::new(&basename).join(&dir);
+            entries.push(TarEntry::Directory(
+                source.join(&dir),
+                entry_name.to_str().unwrap_or("").to_owned(),
+            ));
+        }
+        for fileinfo in collect_files(source, copy_opts)? {
+            let path = source.join(fileinfo.path());
+            let entry_name = Path::new(&basename)
+                .join(fileinfo.path())
+                .to_str()
+                .unwrap_or("")
+                .to_owned();
+            match std::fs::symlink_metadata(&path) {
+                Ok(m) if m.file_type().is_symlink() => {
+                    entries.push(TarEntry::Symlink(path, entry_name))
+                }
+                Ok(m) if m.is_file() => {
+                    copy_opts.stats_store.total += fileinfo.size();
+                    entries.push(TarEntry::File(path, entry_name));
+                }
+                _ => {
+                    if copy_opts.verbose {
+                        outln!(
+                            copy_opts,
+                            "Skipping non-regular file '{}'",
+                            path.to_str().unwrap_or("")
+                        );
+                    }
+                    let non_regular = &copy_opts.stats_store.non_regular;
+                    non_regular.set(non_regular.get() + 1);
+                }
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Appends the directories under `base.join(rel)`, relative to `base`, to
+/// `dirs`, each before the ones inside it. Symlinks to directories and the
+/// directories in `skip_dirs` aren't descended into.
+fn collect_dirs(
+    base: &Path,
+    rel: &Path,
+    skip_dirs: &[std::path::PathBuf],
+    dirs: &mut Vec<std::path::PathBuf>,
+) -> io::Result<()> {
+    let dir = base.join(rel);
+    let mut children = Vec::new();
+    for entry in std::fs::read_dir(&dir)? {
+        let entry = entry?;
+        let path = rel.join(entry.file_name());
+        if entry.file_type()?.is_dir() && !skip_dirs.contains(&path) {
+            children.push(path);
+        }
+    }
+    children.sort();
+    for child in children {
+        dirs.push(child.clone());
+        collect_dirs(base, &child, skip_dirs, dirs)?;
+    }
+    Ok(())
+}
+
+/// Lists the files under the `srcs` files and directories along with their
+/// paths relative to the parent of their source, adding their sizes to the
+/// total.
+fn collect_entries(
+    srcs: &[&str],
+    copy_opts: &mut CopyOptions,
+) -> io::Result<Vec<(std::path::PathBuf, String)>> {
+    let mut entries = Vec::new();
+    for src in srcs {
+        let source = Path::new(src);
+        let src_stat = match std::fs::metadata(source) {
+            Ok(s) => s,
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("stat failed for source path '{}': {}", src, &e),
+                ))
+            }
+        };
+        let basename = source
+            .file_name()
+            .map(|n| n.to_str().unwrap_or("").to_owned())
+            .unwrap_or_default();
+        if src_stat.is_dir() {
+            if !copy_opts.recursive {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "source is a directory but --recursive option not specified",
+                ));
+            }
+            for fileinfo in collect_files(source, copy_opts)? {
+                copy_opts.stats_store.total += fileinfo.size();
+                let entry_name = Path::new(&basename).join(fileinfo.path());
+                entries.push((
+                    source.join(fileinfo.path()),
+                    entry_name.to_str().unwrap_or("").to_owned(),
+                ));
+            }
+        } else if !is_unmodified(&src_stat, copy_opts) {
+            copy_opts.stats_store.total += src_stat.len();
+            entries.push((source.to_owned(), basename));
+        }
+    }
+    Ok(entries)
+}
+
+/// copy_to_store copies the `srcs` files and directories into the content
+/// addressed store at `store`, based on the configuration options provided in
+/// `copy_opts`. Each file is stored once under the hex SHA-256 digest of its
+/// contents, split as `ab/cdef...`, so identical files share the same object.
+/// The digest of every file is appended to the `index` file of the store
+/// along with the path of the file relative to the parent of its source, in
+/// the format of `sha256sum`.
+pub fn copy_to_store(srcs: &[&str], store: &str, copy_opts: CopyOptions) -> io::Result<()> {
+    copy_opts.validate_unsliced("when copying into a store")?;
+
+    let progress_file = copy_opts.progress_file.clone();
+    let result = copy_paths_to_store(srcs, store, copy_opts.clone());
+    remove_progress_file(progress_file.as_deref());
+    write_report(&copy_opts, result)
+}
+
+fn copy_paths_to_store(srcs: &[&str], store: &str, copy_opts: CopyOptions) -> io::Result<()> {
+    let mut copy_opts = copy_opts;
+
+    // collect the files to be stored along with the paths they're indexed by
+    let entries = collect_entries(srcs, &mut copy_opts)?;
+
+    let store_dir = Path::new(store);
+    if let Err(e) = std::fs::create_dir_all(store_dir) {
+        return Err(io::Error::new(
+            e.kind(),
+            format!("failure in creating store '{}': {}", store, &e),
+        ));
+    }
+    let mut index = match std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(store_dir.join("index"))
+    {
+        Ok(f) => f,
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("failure in opening index of store '{}': {}", store, &e),
+            ));
+        }
+    };
+
+    // every file is written aside under a temporary name, as its object name
+    // is only known once all of it is read
+    let tmp_path = store_dir.join(format!(".tmp-{}", std::process::id()));
+
+    // start timer
+    let start = std::time::Instant::now();
+    copy_opts.stats_store.start_time = Some(start);
+
+    for (src, entry_name) in &entries {
+        let src_file_handle = match File::open(src) {
+            Ok(f) => f,
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("failure in opening source file: {}", e),
+                ));
+            }
+        };
+        let src_len = src_file_handle.metadata()?.len();
+        let mut src_reader = super::digest::HashingReader::new(src_file_handle, true);
+        let mut tmp_file_handle = match File::create(&tmp_path) {
+            Ok(f) => f,
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("failure in creating file in store '{}': {}", store, e),
+                ));
+            }
+        };
+
+        let mut strategy = copy_strategy(&mut tmp_file_handle, false, None, src_len, &copy_opts);
+        let mut progress = CopyProgress {
+            src,
+            dst: store_dir,
+            len: src_len,
+            transferred: 0,
+        };
+        let stored = copy_loop(
+            &mut src_reader,
+            &mut strategy,
+            &mut progress,
+            Some(src_len),
+            "storing file",
+            None,
+            &mut copy_opts,
+        );
+        drop(strategy);
+        if let Err(e) = stored {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        let bytes_transferred = progress.transferred;
+
+        // verify file transfer
+        if bytes_transferred != src_len {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(io::Error::other(format!(
+                "error while storing file '{}': missing {} bytes in store",
+                &src.to_str().unwrap_or(""),
+                src_len - bytes_transferred
+            )));
+        }
+
+        // move the file to its object path, unless an identical file is
+        // already stored
+        let digest = super::digest::to_hex(&src_reader.take_digest());
+        let object_path = store_dir.join(&digest[..2]).join(&digest[2..]);
+        let mut record = FileRecord::new(src, &object_path, src_len, FileStatus::Copied);
+        record.digest = super::digest::from_hex(&digest);
+        if std::fs::symlink_metadata(&object_path).is_ok() {
+            std::fs::remove_file(&tmp_path)?;
+            record.status = FileStatus::Linked;
+            copy_opts.stats_store.deduplicated += 1;
+            copy_opts.stats_store.dedupe_saved += src_len;
+        } else {
+            let stored = std::fs::create_dir_all(store_dir.join(&digest[..2]))
+                .and_then(|_| std::fs::rename(&tmp_path, &object_path));
+            if let Err(e) = stored {
+                let _ = std::fs::remove_file(&tmp_path);
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!(
+                        "failure in storing file '{}': {}",
+                        &src.to_str().unwrap_or(""),
+                        e
+                    ),
+                ));
+            }
+        }
+        record_file(&copy_opts, record);
+
+        if let Err(e) = writeln!(index, "{}  {}", digest, entry_name) {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("failure in writing index of store '{}': {}", store, &e),
+            ));
+        }
+
+        if copy_opts.progress_messages() {
+            outln!(copy_opts, "\rStored file '{}' as {} ", entry_name, digest);
+        }
+    }
+
+    // stop timer
+    let end = std::time::Instant::now();
+
+    // verify copy stats
+    if copy_opts.stats_store.transferred != copy_opts.stats_store.total {
+        return Err(io::Error::other(format!(
+            "error in copy: transferred={}, total={}",
+            &copy_opts.stats_store.transferred, &copy_opts.stats_store.total
+        )));
+    }
+
+    add_run_total(&copy_opts);
+
+    if copy_opts.show_stats {
+        copy_opts.stats_store.time_taken = end.sub(start);
+        print_stats(&copy_opts);
+    }
+
+    Ok(())
+}
+
+/// Extracts the entries of the tar archive at `archive` into the destination
+/// directory `dst`. Regular files go through the same destination checks,
+/// renaming and permission handling as a normal copy, and get the mode and
+/// modification time stored in the archive, as do the directories, while
+/// symlinks are recreated. Entries which would land outside of `dst`, like
+/// absolute paths, paths with `..` components or paths leading through a
+/// symlink, are skipped.
+pub fn copy_from_tar(archive: &str, dst: &str, copy_opts: CopyOptions) -> io::Result<()> {
+    copy_opts.validate_unsliced("when extracting an archive")?;
+
+    let progress_file = copy_opts.progress_file.clone();
+    let result = copy_paths_from_tar(archive, dst, copy_opts.clone());
+    remove_progress_file(progress_file.as_deref());
+    write_report(&copy_opts, result)
+}
+
+fn copy_paths_from_tar(archive: &str, dst: &str, copy_opts: CopyOptions) -> io::Result<()> {
+    let mut copy_opts = copy_opts;
+
+    let archive_file = match File::open(archive) {
+        Ok(f) => f,
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("failure in opening archive '{}': {}", archive, &e),
+            ));
+        }
+    };
+    let mut tar_archive = tar::Archive::new(io::BufReader::new(archive_file));
+    let read_failure = |e: io::Error| {
+        io::Error::new(
+            e.kind(),
+            format!("failure in reading archive '{}': {}", archive, e),
+        )
+    };
+
+    let destination = Path::new(dst);
+    if let Ok(m) = std::fs::metadata(destination) {
+        if !m.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("destination '{}' is not a directory", dst),
+            ));
+        }
+    }
+
+    // start timer
+    let start = std::time::Instant::now();
+    copy_opts.stats_store.start_time = Some(start);
+
+    // the directories get their archived mode and time once their contents
+    // are extracted
+    let mut dirs = Vec::new();
+    for entry in tar_archive.entries().map_err(read_failure)? {
+        let mut entry = entry.map_err(read_failure)?;
+        let entry_path = entry.path().map_err(read_failure)?.into_owned();
+        let entry_name = entry_path.to_str().unwrap_or("").to_owned();
+
+        // only accept plain relative paths, which don't lead through a
+        // symlink, so that no entry escapes the destination directory
+        let is_contained = entry_path.components().all(|c| {
+            matches!(
+                c,
+                std::path::Component::Normal(_) | std::path::Component::CurDir
+            )
+        });
+        if !is_contained || has_symlink_parent(destination, &entry_path) {
+            errln!(
+                copy_opts,
+                "Warning: skipping archive entry '{}' which points outside of the destination",
+                &entry_name
+            );
+            continue;
+        }
+
+        let header = entry.header();
+        let mode = header.mode().map_err(read_failure)? & 0o7777;
+        let mtime = header.mtime().map_err(read_failure)?;
+        let entry_type = header.entry_type();
+        let extracted = match entry_type {
+            tar::EntryType::Directory => {
+                let dir_path = map_dst_dir(&destination.join(&entry_path), &copy_opts);
+                if let Err(e) = std::fs::create_dir_all(&dir_path) {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!("failure in creating destination directory: {}", &e),
+                    ));
+                }
+                dirs.push((dir_path, mode, mtime));
+                continue;
+            }
+            tar::EntryType::Symlink => {
+                let target = match entry.link_name().map_err(read_failure)? {
+                    Some(target) => target.into_owned(),
+                    None => {
+                        return Err(read_failure(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("symlink entry '{}' has no target", &entry_name),
+                        )))
+                    }
+                };
+                // a symlink out of the destination would let later entries
+                // escape through it
+                let is_contained = target.components().all(|c| {
+                    matches!(
+                        c,
+                        std::path::Component::Normal(_) | std::path::Component::CurDir
+                    )
+                });
+                if !is_contained {
+                    errln!(
+                        copy_opts,
+                        "Warning: skipping archive symlink '{}' to '{}' which points outside of the destination",
+                        &entry_name,
+                        target.to_str().unwrap_or("")
+                    );
+                    continue;
+                }
+                let dst_path = rename_dst(&destination.join(&entry_path), &copy_opts);
+                extract_symlink(&target, &dst_path, &copy_opts)
+            }
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                // apply the modification time filter to the archived time
+                let entry_mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime);
+                if copy_opts
+                    .modified_since
+                    .is_some_and(|since| entry_mtime <= since)
+                {
+                    continue;
+                }
+
+                let dst_path = rename_dst(&destination.join(&entry_path), &copy_opts);
+                util::check_path_len(&dst_path)?;
+
+                let size = entry.size();
+                copy_opts.stats_store.total += size;
+                let extracted = extract_file(
+                    &mut entry,
+                    &entry_name,
+                    size,
+                    mode,
+                    mtime,
+                    &dst_path,
+                    &mut copy_opts,
+                );
+                if extracted.is_err() {
+                    copy_opts.stats_store.total -= size;
+                }
+                extracted
+            }
+            _ => {
+                errln!(
+                    copy_opts,
+                    "Warning: skipping unsupported archive entry '{}' of type '{}'",
+                    &entry_name,
+                    entry_type.as_byte() as char
+                );
+                continue;
+            }
+        };
+        if let Err(e) = extracted {
+            if !copy_opts.no_dir_err {
+                return Err(e);
+            }
+            print_failure(&e, &mut copy_opts);
+        }
+    }
+
+    // restore the directories from the innermost, as a read-only directory
+    // can't be changed inside
+    for (dir, mode, mtime) in dirs.iter().rev() {
+        let dir_handle = File::open(dir)?;
+        set_permissions(
+            &dir_handle,
+            dir,
+            std::fs::Permissions::from_mode(*mode),
+            &copy_opts,
+        )?;
+        dir_handle.set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(*mtime))?;
+    }
+
+    // stop timer
+    let end = std::time::Instant::now();
+
+    // verify copy stats
+    if copy_opts.stats_store.transferred != copy_opts.stats_store.total {
+        return Err(io::Error::other(format!(
+            "error in copy: transferred={}, total={}",
+            &copy_opts.stats_store.transferred, &copy_opts.stats_store.total
+        )));
+    }
+
+    if copy_opts.remove {
+        if let Err(e) = std::fs::remove_file(archive) {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("failed to remove archive: {}", &e),
+            ));
+        }
+    }
+
+    add_run_total(&copy_opts);
+
+    if copy_opts.show_stats {
+        copy_opts.stats_store.time_taken = end.sub(start);
+        print_stats(&copy_opts);
+    }
+    print_failures(&copy_opts);
+
+    Ok(())
+}
+
+/// Checks if any of the parent directories of `path` inside `base` is a
+/// symlink, possibly extracted from an earlier archive entry, through which
+/// `path` could lead outside of `base`.
+fn has_symlink_parent(base: &Path, path: &Path) -> bool {
+    let mut parent = base.to_owned();
+    let mut components = path.components().peekable();
+    while let Some(component) = components.next() {
+        if components.peek().is_none() {
+            break;
+        }
+        parent.push(component);
+        if std::fs::symlink_metadata(&parent).is_ok_and(|m| m.file_type().is_symlink()) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Creates the symlink `dst` to `target` for an archived symlink.
+fn extract_symlink(target: &Path, dst: &Path, copy_opts: &CopyOptions) -> io::Result<()> {
+    if std::fs::symlink_metadata(dst).is_ok() {
+        if !copy_opts.force {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "file '{}' exists, can't copy file without --force option",
+                    dst.to_str().unwrap_or("")
+                ),
+            ));
+        }
+        std::fs::remove_file(dst)?;
+    } else if let Some(dst_dir) = dst.parent() {
+        if let Err(e) = create_dst_dir(dst_dir, copy_opts) {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("failure in creating destination directory: {}", &e),
+            ));
+        }
+    }
+
+    if let Err(e) = std::os::unix::fs::symlink(target, dst) {
+        return Err(io::Error::new(
+            e.kind(),
+            format!(
+                "failure in creating symlink '{}': {}",
+                dst.to_str().unwrap_or(""),
+                e
+            ),
+        ));
+    }
+    if copy_opts.progress_messages() {
+        outln!(
+            copy_opts,
+            "\rExtracted symlink '{}' -> '{}' ",
+            dst.to_str().unwrap_or(""),
+            target.to_str().unwrap_or("")
+        );
+    }
+    Ok(())
+}
+
+/// Writes the `size` bytes of data of the archive entry `entry_name` from
+/// `reader` to `dst` and restores the archived `mode` and modification time.
+fn extract_file<R: io::Read>(
+    reader: &mut R,
+    entry_name: &str,
+    size: u64,
+    mode: u32,
+    mtime: u64,
+    dst: &Path,
+    copy_opts: &mut CopyOptions,
+) -> io::Result<()> {
+    let src = Path::new(entry_name);
+
+    // check if destination file exists, without following a symlink, which
+    // an earlier entry may have pointed anywhere
+    if let Ok(dst_stat) = std::fs::symlink_metadata(dst) {
+        if !copy_opts.force {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "file '{}' exists, can't copy file without --force option",
+                    dst.to_str().unwrap_or("")
+                ),
+            ));
+        }
+        if dst_stat.file_type().is_symlink() {
+            std::fs::remove_file(dst)?;
+        }
+    } else if let Some(dst_dir) = dst.parent() {
+        // create all the directories in the destination path
+        if let Err(e) = create_dst_dir(dst_dir, copy_opts) {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("failure in creating destination directory: {}", &e),
+            ));
+        }
+    }
+
+    let mode = mode & copy_opts.mode_mask();
+    let mut dst_file_open_options = std::fs::OpenOptions::new();
+    dst_file_open_options
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(mode);
+    let (mut dst_file_handle, direct_io) = open_dst_file(
+        &mut dst_file_open_options,
+        dst,
+        libc::O_NOFOLLOW,
+        copy_opts.direct_io,
+        copy_opts,
+    )?;
+
+    let sparse_min_hole = if copy_opts.sparse {
+        Some(copy_opts.sparse_min_hole)
+    } else {
+        None
+    };
+    let mut strategy = copy_strategy(
+        &mut dst_file_handle,
+        direct_io,
+        sparse_min_hole,
+        size,
+        copy_opts,
+    );
+    let mut progress = CopyProgress {
+        src,
+        dst,
+        len: size,
+        transferred: 0,
+    };
+    copy_loop(
+        reader,
+        &mut strategy,
+        &mut progress,
+        Some(size),
+        "extracting file",
+        None,
+        copy_opts,
+    )?;
+    drop(strategy);
+    let bytes_transferred = progress.transferred;
+
+    // verify file transfer against the archived size
+    if bytes_transferred != size {
+        return Err(io::Error::other(format!(
+            "error while extracting file '{}': missing {} bytes in destination",
+            entry_name,
+            size - bytes_transferred
+        )));
+    }
+
+    // restore the archived permissions and modification time
+    set_permissions(
+        &dst_file_handle,
+        dst,
+        std::fs::Permissions::from_mode(mode),
+        copy_opts,
+    )?;
+    dst_file_handle.set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime))?;
+
+    if copy_opts.verify_perms != PermVerify::Off {
+        let dst_mode = dst_file_handle.metadata()?.mode();
+        verify_mode(mode, dst_mode, dst, copy_opts)?;
+    }
+
+    if copy_opts.progress_messages() {
+        outln!(copy_opts, "\rExtracted file '{}' ", entry_name);
+    }
+    Ok(())
+}
+
+/// Prints the transfer statistics of a completed copy.
+fn print_stats(copy_opts: &CopyOptions) {
+    outln!(
+        copy_opts,
+        "\nTime taken to copy: {:?}",
+        copy_opts.stats_store.time_taken
+    );
+    let transfer_speed = (copy_opts.stats_store.total as f64
+        / copy_opts.stats_store.time_taken.as_micros() as f64) as u64
+        * 1_000_000;
+
+    outln!(
+        copy_opts,
+        "Transfer speed: {}/s",
+        get_str_size_precise(transfer_speed)
+    );
+
+    if copy_opts.remove && !copy_opts.stats_store.removal_time.is_zero() {
+        outln!(
+            copy_opts,
+            "Time taken to remove sources: {:?} ({} files)",
+            copy_opts.stats_store.removal_time,
+            copy_opts.stats_store.removed
+        );
+    }
+
+    if copy_opts.stats_store.vanished > 0 {
+        outln!(
+            copy_opts,
+            "Vanished files: {}",
+            copy_opts.stats_store.vanished
+        );
+    }
+
+    if copy_opts.stats_store.non_regular.get() > 0 {
+        outln!(
+            copy_opts,
+            "Non-regular files skipped: {}",
+            copy_opts.stats_store.non_regular.get()
+        );
+    }
+
+    if copy_opts.stats_store.up_to_date > 0 {
+        outln!(
+            copy_opts,
+            "Up to date files: {}",
+            copy_opts.stats_store.up_to_date
+        );
+    }
+
+    if copy_opts.touch_only {
+        outln!(
+            copy_opts,
+            "Touched files: {} ({} copied)",
+            copy_opts.stats_store.touched,
+            copy_opts.stats_store.copied_files
+        );
+    }
+
+    if copy_opts.stats_store.dir_loops.get() > 0 {
+        outln!(
+            copy_opts,
+            "Directory loops skipped: {}",
+            copy_opts.stats_store.dir_loops.get()
+        );
+    }
+
+    if copy_opts.stats_store.metadata_fixed > 0 {
+        outln!(
+            copy_opts,
+            "Metadata fixed: {} files",
+            copy_opts.stats_store.metadata_fixed
+        );
+    }
+
+    if copy_opts.stats_store.repaired_blocks > 0 {
+        outln!(
+            copy_opts,
+            "Repaired blocks: {}",
+            copy_opts.stats_store.repaired_blocks
+        );
+    }
+
+    if copy_opts.stats_store.delta_written + copy_opts.stats_store.delta_reused > 0 {
+        outln!(
+            copy_opts,
+            "Delta transfer: {} written, {} reused",
+            get_str_size_precise(copy_opts.stats_store.delta_written),
+            get_str_size_precise(copy_opts.stats_store.delta_reused)
+        );
+    }
+
+    if copy_opts.stats_store.duplicate_sources > 0 {
+        outln!(
+            copy_opts,
+            "Duplicate sources skipped: {}",
+            copy_opts.stats_store.duplicate_sources
+        );
+    }
+
+    if copy_opts.stats_store.deduplicated > 0 {
+        outln!(
+            copy_opts,
+            "Deduplicated files: {} ({} saved)",
+            copy_opts.stats_store.deduplicated,
+            get_str_size_precise(copy_opts.stats_store.dedupe_saved)
+        );
+    }
+
+    if !copy_opts.stats_store.per_file_throughput.is_empty() {
+        outln!(copy_opts, "Per file transfer speed:");
+        for (path, rate) in &copy_opts.stats_store.per_file_throughput {
+            outln!(
+                copy_opts,
+                "  {}: {}/s",
+                path.to_str().unwrap_or(""),
+                get_str_size_precise(*rate)
+            );
+        }
+    }
+}
+
+/// What to do with a source file, given the state of its destination.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CopyDecision {
+    /// Copy the whole file, replacing the destination if it exists
+    Copy,
+    /// Leave the up to date destination untouched
+    Skip,
+    /// Append the remainder of the source to the partial destination
+    Resume,
+    /// Rewrite the blocks of the destination which differ from the source
+    Repair,
+    /// Write the parts of the source which aren't found in the destination
+    Delta,
+    /// Only set the timestamps of the source on the matching destination
+    Touch,
+}
+
+/// Decides how to copy a source file with metadata `src_meta` onto a
+/// destination with metadata `dst_meta`, if it exists. With --update, an
+/// existing destination is up to date if it's newer than the source, or has
+/// the same modification time and size.
+fn should_copy(
+    src_meta: &Metadata,
+    dst_meta: Option<&Metadata>,
+    copy_opts: &CopyOptions,
+) -> io::Result<CopyDecision> {
+    let dst_meta = match dst_meta {
+        Some(m) => m,
+        None => return Ok(CopyDecision::Copy),
+    };
+
+    if copy_opts.update {
+        let src_mtime = (src_meta.mtime(), src_meta.mtime_nsec());
+        let dst_mtime = (dst_meta.mtime(), dst_meta.mtime_nsec());
+        if dst_mtime > src_mtime || (dst_mtime == src_mtime && dst_meta.len() == src_meta.len()) {
+            return Ok(CopyDecision::Skip);
+        }
+    }
+
+    if copy_opts.touch_only && dst_meta.len() == src_meta.len() {
+        return Ok(CopyDecision::Touch);
+    }
+
+    if copy_opts.repair {
+        Ok(CopyDecision::Repair)
+    } else if copy_opts.delta {
+        Ok(CopyDecision::Delta)
+    } else if copy_opts.resume {
+        Ok(CopyDecision::Resume)
+    } else if copy_opts.force || copy_opts.update {
+        Ok(CopyDecision::Copy)
+    } else {
+        // if neither of force or resume option specified, abort copy
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "exists, can't copy file without --force or --continue option",
+        ))
+    }
+}
+
+/// Compares the existing destination `dst` with the source `src` block by
+/// block, and rewrites the blocks whose digests differ or which are missing
+/// from the destination, which is then cut to `src_size`. Returns the number
+/// of bytes of the source which were repaired or found intact.
+fn repair_blocks(
+    src_file: &File,
+    src: &Path,
+    dst_file: &File,
+    dst: &Path,
+    src_size: u64,
+    copy_opts: &mut CopyOptions,
+) -> io::Result<u64> {
+    use std::os::unix::fs::FileExt;
+
+    let prgrs_hndlr = copy_opts
+        .progress_handler
+        .unwrap_or(default_progress_handler);
+    let repair_error = |e: io::Error| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "error while repairing file '{}': {}",
+                &dst.to_str().unwrap_or(""),
+                e
+            ),
+        )
+    };
+
+    let dst_size = dst_file.metadata().map_err(repair_error)?.len();
+    let mut buf = vec![0u8; copy_opts.block_size as usize];
+    let mut repaired: u64 = 0;
+    let mut offset: u64 = 0;
+    while offset < src_size {
+        let len = std::cmp::min(copy_opts.block_size, src_size - offset);
+        let intact = offset + len <= dst_size
+            && super::digest::sha256_range(src_file, offset, len, false).map_err(repair_error)?
+                == super::digest::sha256_range(dst_file, offset, len, false)
+                    .map_err(repair_error)?;
+        if !intact {
+            let block = &mut buf[..len as usize];
+            src_file
+                .read_exact_at(block, offset)
+                .map_err(repair_error)?;
+            dst_file.write_all_at(block, offset).map_err(repair_error)?;
+            throttle(copy_opts, len);
+            repaired += 1;
+        }
+
+        offset += len;
+        copy_opts.stats_store.transferred += len;
+        report_progress(src, offset, src_size, copy_opts);
+        if copy_opts.show_progress {
+            copy_opts.stats_store.record_rate_sample();
+            prgrs_hndlr(src, dst, offset, src_size, copy_opts);
+        }
+    }
+    dst_file.set_len(src_size).map_err(repair_error)?;
+
+    if copy_opts.verbose && repaired > 0 {
+        outln!(
+            copy_opts,
+            "Repaired {} blocks of '{}'",
+            repaired,
+            dst.to_str().unwrap_or("")
+        );
+    }
+    copy_opts.stats_store.repaired_blocks += repaired;
+    Ok(offset)
+}
+
+/// Size of the blocks of the destination reused by a delta transfer
+const DELTA_BLOCK_SIZE: u64 = 16 * util::KB;
+
+/// Updates the existing destination `dst` in place to match the source `src`
+/// with an rsync style delta transfer. The whole blocks of the destination
+/// are indexed by their rolling and strong checksums, and the source is
+/// scanned for them at every offset. A block found at its own offset is left
+/// alone, a block found elsewhere is copied within the destination, and the
+/// rest of the source is written as is. As the destination is overwritten
+/// from its start, only the blocks ahead of the data written so far are
+/// reused. Returns the number of bytes of the source covered.
+fn delta_blocks(
+    src_file: &File,
+    src: &Path,
+    dst_file: &File,
+    dst: &Path,
+    src_size: u64,
+    copy_opts: &mut CopyOptions,
+) -> io::Result<u64> {
+    use super::digest::RollingChecksum;
+    use std::os::unix::fs::FileExt;
+
+    let prgrs_hndlr = copy_opts
+        .progress_handler
+        .unwrap_or(default_progress_handler);
+    let delta_error = |e: io::Error| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "error while updating file '{}': {}",
+                &dst.to_str().unwrap_or(""),
+                e
+            ),
+        )
+    };
+
+    // index the whole blocks of the destination
+    let dst_size = dst_file.metadata().map_err(delta_error)?.len();
+    let mut block = vec![0u8; DELTA_BLOCK_SIZE as usize];
+    let mut blocks: std::collections::HashMap<u32, Vec<(u64, [u8; super::digest::SHA256_LEN])>> =
+        std::collections::HashMap::new();
+    let mut offset = 0;
+    while offset + DELTA_BLOCK_SIZE <= dst_size {
+        dst_file
+            .read_exact_at(&mut block, offset)
+            .map_err(delta_error)?;
+        blocks
+            .entry(RollingChecksum::new(&block).digest())
+            .or_default()
+            .push((offset, super::digest::sha256(&block)));
+        offset += DELTA_BLOCK_SIZE;
+    }
+
+    // writes the source data from `from` up to `to` to the destination
+    let write_literal = |from: u64, to: u64, block: &mut [u8]| -> io::Result<u64> {
+        let mut offset = from;
+        while offset < to {
+            let len = std::cmp::min(block.len() as u64, to - offset) as usize;
+            src_file.read_exact_at(&mut block[..len], offset)?;
+            dst_file.write_all_at(&block[..len], offset)?;
+            offset += len as u64;
+        }
+        Ok(to - from)
+    };
+
+    // the source is read ahead into `data`, which starts at `data_offset`
+    let mut data: Vec<u8> = Vec::new();
+    let mut data_offset: u64 = 0;
+    let mut checksum: Option<RollingChecksum> = None;
+    // the window of the source compared with the blocks starts at `pos`, and
+    // the source is written up to `literal`
+    let mut pos: u64 = 0;
+    let mut literal: u64 = 0;
+    let mut written: u64 = 0;
+    let mut reused: u64 = 0;
+    while !blocks.is_empty() && pos + DELTA_BLOCK_SIZE <= src_size {
+        let window_end = std::cmp::min(pos + DELTA_BLOCK_SIZE + 1, src_size);
+        if window_end > data_offset + data.len() as u64 {
+            data.drain(..(pos - data_offset) as usize);
+            data_offset = pos;
+            let data_end = std::cmp::min(pos + 4 * DELTA_BLOCK_SIZE, src_size);
+            let start = data.len();
+            data.resize((data_end - data_offset) as usize, 0);
+            src_file
+                .read_exact_at(&mut data[start..], data_offset + start as u64)
+                .map_err(delta_error)?;
+        }
+
+        let i = (pos - data_offset) as usize;
+        let window = &data[i..i + DELTA_BLOCK_SIZE as usize];
+        let sum = checksum.get_or_insert_with(|| RollingChecksum::new(window));
+        // the block at the same offset needs no write, so it's preferred
+        let found = blocks.get(&sum.digest()).and_then(|candidates| {
+            let strong = super::digest::sha256(window);
+            candidates
+                .iter()
+                .filter(|(offset, digest)| *offset >= pos && *digest == strong)
+                .map(|(offset, _)| *offset)
+                .min()
+        });
+
+        match found {
+            Some(offset) => {
+                written += write_literal(literal, pos, &mut block).map_err(delta_error)?;
+                if offset != pos {
+                    dst_file
+                        .read_exact_at(&mut block, offset)
+                        .and_then(|_| dst_file.write_all_at(&block, pos))
+                        .map_err(delta_error)?;
+                    written += DELTA_BLOCK_SIZE;
+                }
+                reused += DELTA_BLOCK_SIZE;
+                pos += DELTA_BLOCK_SIZE;
+                checksum = None;
+            }
+            None => {
+                if pos + DELTA_BLOCK_SIZE < src_size {
+                    sum.roll(data[i], data[i + DELTA_BLOCK_SIZE as usize]);
+                }
+                pos += 1;
+                // the data which matches no block is written as it's passed
+                if pos - literal < DELTA_BLOCK_SIZE {
+                    continue;
+                }
+                written += write_literal(literal, pos, &mut block).map_err(delta_error)?;
+            }
+        }
+
+        throttle(copy_opts, pos - literal);
+        copy_opts.stats_store.transferred += pos - literal;
+        literal = pos;
+        report_progress(src, literal, src_size, copy_opts);
+        if copy_opts.show_progress {
+            copy_opts.stats_store.record_rate_sample();
+            prgrs_hndlr(src, dst, literal, src_size, copy_opts);
+        }
+    }
+    written += write_literal(literal, src_size, &mut block).map_err(delta_error)?;
+    copy_opts.stats_store.transferred += src_size - literal;
+    dst_file.set_len(src_size).map_err(delta_error)?;
+
+    if copy_opts.verbose {
+        outln!(
+            copy_opts,
+            "Wrote {} of {} bytes of '{}'",
+            written,
+            src_size,
+            dst.to_str().unwrap_or("")
+        );
+    }
+    copy_opts.stats_store.delta_written += written;
+    copy_opts.stats_store.delta_reused += reused;
+    Ok(src_size)
+}
+
+/// Opens the destination file `dst` with `options` and the additional open
+/// `flags`, using direct I/O if `direct_io` is set and the filesystem
+/// supports it. Returns the file and whether it was opened for direct I/O.
+fn open_dst_file(
+    options: &mut std::fs::OpenOptions,
+    dst: &Path,
+    flags: libc::c_int,
+    direct_io: bool,
+    copy_opts: &CopyOptions,
+) -> io::Result<(File, bool)> {
+    let mut direct_io = direct_io;
+    options.custom_flags(flags);
+    if direct_io {
+        options.custom_flags(flags | libc::O_DIRECT);
+    }
+
+    let mut open_result = options.open(dst);
+    if direct_io {
+        if let Err(e) = &open_result {
+            if e.raw_os_error() == Some(libc::EINVAL) {
+                // fall back to buffered I/O if the filesystem doesn't
+                // support O_DIRECT
+                errln!(copy_opts, "Warning: direct I/O is not supported on the destination, falling back to buffered I/O");
+                direct_io = false;
+                options.custom_flags(flags);
+                open_result = options.open(dst);
+            }
+        }
+    }
+
+    match open_result {
+        Ok(f) => Ok((f, direct_io)),
+        Err(e) => Err(io::Error::new(
+            e.kind(),
+            format!("failure in opening destination file: {}", &e),
+        )),
+    }
+}
+
+/// Creates the strategy which writes `len` bytes to the destination `file`,
+/// with direct I/O if the file was opened for it, or else leaving holes for
+/// the runs of zeros of at least `sparse_min_hole` bytes if it's set.
+fn copy_strategy<'a>(
+    file: &'a mut File,
+    direct_io: bool,
+    sparse_min_hole: Option<u64>,
+    len: u64,
+    copy_opts: &CopyOptions,
+) -> util::CopyStrategy<'a> {
+    let write_mode = match (direct_io, sparse_min_hole) {
+        (true, _) => util::WriteMode::Direct,
+        (false, Some(min_hole)) => util::WriteMode::Sparse(min_hole),
+        (false, None) => util::WriteMode::Buffered,
+    };
+    let output = copy_opts.output.clone();
+    let on_fallback = move || {
+        Output::write_line(
+            &output.err,
+            format_args!(
+                "Warning: direct I/O is not supported on the destination, falling back to buffered I/O"
+            ),
+        )
+    };
+    util::CopyStrategy::new(
+        file,
+        write_mode,
+        util::copy_buffer_size(copy_opts.block_size, len),
+        Box::new(on_fallback),
+    )
+}
+
+/// The progress of the copy of `len` bytes of `src`, as far as they're known,
+/// to `dst`, of which `transferred` bytes are copied.
+struct CopyProgress<'a> {
+    src: &'a Path,
+    dst: &'a Path,
+    len: u64,
+    transferred: u64,
+}
+
+impl CopyProgress<'_> {
+    /// Accounts a copied block of `bytes_copied` bytes, throttles the copy to
+    /// the rate limit and reports the progress.
+    fn advance(&mut self, bytes_copied: u64, copy_opts: &mut CopyOptions) {
+        self.transferred += bytes_copied;
+        copy_opts.stats_store.transferred += bytes_copied;
+        throttle(copy_opts, bytes_copied);
+        report_progress(self.src, self.transferred, self.len, copy_opts);
+
+        // skip progress logging if not requested
+        if !copy_opts.show_progress {
+            return;
+        }
+
+        copy_opts.stats_store.record_rate_sample();
+        self.show(copy_opts);
+    }
+
+    /// Shows the progress with the configured progress handler.
+    fn show(&self, copy_opts: &CopyOptions) {
+        let prgrs_hndlr = copy_opts
+            .progress_handler
+            .unwrap_or(default_progress_handler);
+        prgrs_hndlr(self.src, self.dst, self.transferred, self.len, copy_opts);
+    }
+}
+
+/// A check of every block copied by [`copy_loop`], called with the reader,
+/// the offset and the size of the block before it's accounted. A block which
+/// isn't accepted ends the copy without being accounted.
+type BlockCheck<'a, R> = &'a mut dyn FnMut(&mut R, u64, u64) -> io::Result<bool>;
+
+/// Copies blocks of up to [`CopyOptions::block_size`] bytes from `reader`
+/// through `strategy`, until `reader` ends or, with a `limit`, until the
+/// transferred bytes of `progress` reach it. The free space of the
+/// destination is checked ahead of every block, and every block is passed
+/// to `check_block`, if any, before it's accounted to `progress`. A failure
+/// is reported as an error while `action` is done to the source.
+fn copy_loop<R: io::Read>(
+    reader: &mut R,
+    strategy: &mut util::CopyStrategy,
+    progress: &mut CopyProgress,
+    limit: Option<u64>,
+    action: &str,
+    mut check_block: Option<BlockCheck<R>>,
+    copy_opts: &mut CopyOptions,
+) -> io::Result<()> {
+    let src = progress.src;
+    let failure = |e: io::Error| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "error while {} '{}': {}",
+                action,
+                src.to_str().unwrap_or(""),
+                e
+            ),
+        )
+    };
+    loop {
+        let block_size = match limit {
+            Some(limit) => std::cmp::min(
+                copy_opts.block_size,
+                limit.saturating_sub(progress.transferred),
+            ),
+            None => copy_opts.block_size,
+        };
+        if block_size == 0 {
+            break;
+        }
+        let next_block = std::cmp::min(
+            block_size,
+            next_block_size(progress.transferred, progress.len, copy_opts),
+        );
+        check_free_space(progress.dst, next_block, copy_opts)?;
+
+        // if 0 bytes were read, the source has ended. An empty source ends on
+        // the first read, while pseudo files, like the ones in /proc, report a
+        // size of 0 but still have contents.
+        let bytes_copied = strategy.copy_block(reader, block_size).map_err(failure)?;
+        if bytes_copied == 0 {
+            break;
+        }
+        if let Some(check_block) = check_block.as_mut() {
+            if !check_block(reader, progress.transferred, bytes_copied).map_err(failure)? {
+                break;
+            }
+        }
+        progress.advance(bytes_copied, copy_opts);
+    }
+    strategy.finish().map_err(failure)
+}
+
+fn copy_file(src: &Path, dst: &Path, copy_opts: &mut CopyOptions) -> io::Result<usize> {
+    // open the source file
+    let mut src_file_handle = match File::open(src) {
+        Ok(f) => f,
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("failure in opening source file: {}", e),
+            ));
+        }
+    };
+
+    // retrive source file metadata
+    let src_file_metadata = match src_file_handle.metadata() {
+        Ok(m) => m,
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("failure in fetching metadata for source file: {}", &e),
+            ));
+        }
+    };
+    let (src_start, src_size) = source_range(src, &src_file_metadata, copy_opts)?;
+
+    // a slice of the source is read from its start
+    if src_start > 0 {
+        if let Err(e) = src_file_handle.seek(SeekFrom::Start(src_start)) {
+            return Err(io::Error::new(
+                e.kind(),
+                format!(
+                    "failure in seeking to offset {} of source file: {}",
+                    src_start, e
+                ),
+            ));
+        }
+    }
+
+    // the source has to fit in the padded size
+    if copy_opts.pad_to > 0 && src_size > copy_opts.pad_to {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "source file '{}' of {} bytes is larger than the padded size of {} bytes",
+                src.to_str().unwrap_or(""),
+                src_size,
+                copy_opts.pad_to
+            ),
+        ));
+    }
+
+    // check if destination file exists
+    let dst_file_metadata = std::fs::metadata(dst).ok();
+    let mut decision = should_copy(&src_file_metadata, dst_file_metadata.as_ref(), copy_opts)
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("file '{}' {}", dst.to_str().unwrap_or(""), e),
+            )
+        })?;
+    if decision == CopyDecision::Touch
+        && copy_opts.checksum
+        && super::digest::sha256_file(src)? != super::digest::sha256_file(dst)?
+    {
+        // the contents differ, so the file is copied after all
+        decision = CopyDecision::Copy;
+    }
+    match decision {
+        CopyDecision::Touch => {
+            // destination already has the contents of the source
+            let times_result = File::open(dst).and_then(|dst_file_handle| {
+                util::set_file_times(
+                    &dst_file_handle,
+                    &src_file_metadata,
+                    copy_opts.times_precision,
+                )
+            });
+            if let Err(e) = times_result {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!(
+                        "failure in setting timestamps of '{}': {}",
+                        dst.to_str().unwrap_or(""),
+                        e
+                    ),
+                ));
+            }
+            if copy_opts.verbose {
+                outln!(copy_opts, "Touched '{}'", dst.to_str().unwrap_or(""));
+            }
+            copy_opts.stats_store.total -= src_size;
+            copy_opts.stats_store.skipped_bytes += src_size;
+            copy_opts.stats_store.touched += 1;
+            return Ok(0);
+        }
+        CopyDecision::Skip => {
+            // destination is already up to date
+            if copy_opts.verbose {
+                outln!(
+                    copy_opts,
+                    "Skipping up to date file '{}'",
+                    dst.to_str().unwrap_or("")
+                );
+            }
+            if copy_opts.sync_perms && sync_metadata(&src_file_metadata, dst, copy_opts)? {
+                if copy_opts.verbose {
+                    outln!(
+                        copy_opts,
+                        "Fixed metadata of '{}'",
+                        dst.to_str().unwrap_or("")
+                    );
+                }
+                copy_opts.stats_store.metadata_fixed += 1;
+            }
+            copy_opts.stats_store.total -= src_size;
+            copy_opts.stats_store.skipped_bytes += src_size;
+            copy_opts.stats_store.up_to_date += 1;
+            return Ok(0);
+        }
+        CopyDecision::Copy | CopyDecision::Resume | CopyDecision::Repair | CopyDecision::Delta
+            if dst_file_metadata.is_some() => {}
+        CopyDecision::Copy | CopyDecision::Resume | CopyDecision::Repair | CopyDecision::Delta => {
+            // if destination file doesn't exist
+            if copy_opts.min_free_inodes > 0 {
+                check_free_inodes(dst, 1, copy_opts)?;
+            }
+            copy_opts.stats_store.created_files += 1;
+            if let Some(dst_dir) = dst.parent() {
+                // create all the directories in the destination path
+                if let Err(e) = create_dst_dir(dst_dir, copy_opts) {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!("failure in creating destination directory: {}", &e),
+                    ));
+                }
+            }
+        }
+    }
+
+    // direct I/O needs aligned file offsets, which can't be guaranteed when
+    // appending to an existing file
+    let appending = decision == CopyDecision::Resume;
+    let in_place = matches!(decision, CopyDecision::Repair | CopyDecision::Delta);
+    let mut direct_io = copy_opts.direct_io && !appending && !in_place && !copy_opts.checksum_block;
+
+    // open the destination file
+    let mut dst_file_handle: File = {
+        let mut dst_file_open_options = std::fs::OpenOptions::new();
+
+        dst_file_open_options.create(true).write(true);
+        dst_file_open_options.mode(src_file_metadata.mode() & copy_opts.mode_mask());
+
+        if let Some(dst_file_meta) = &dst_file_metadata {
+            if appending {
+                // open in append mode if resume option is specified
+                dst_file_open_options.append(true);
+                dst_file_open_options.mode(dst_file_meta.mode());
+            } else if in_place {
+                // the blocks are compared and rewritten in place
+                dst_file_open_options.read(true);
+            } else {
+                // drop the old contents when replacing the file
+                dst_file_open_options.truncate(true);
+            }
+        }
+
+        let (f, direct) = open_dst_file(&mut dst_file_open_options, dst, 0, direct_io, copy_opts)?;
+        direct_io = direct;
+        f
+    };
+
+    let mut bytes_transferred: u64 = 0;
+
+    if let Some(dst_file_meta) = &dst_file_metadata {
+        // if destination file exists
+        let dst_file_size = dst_file_meta.len();
+        if appending {
+            // if resume option is specified, skip the already copied bytes
+            if let Err(e) = src_file_handle.seek(SeekFrom::Start(src_start + dst_file_size)) {
+                return Err(io::Error::other(format!(
+                    "failed to resume copy due to seek fail on source file: {}",
+                    e
+                )));
+            }
+
+            // update transfer statistics
+            bytes_transferred = dst_file_size;
+            copy_opts.stats_store.transferred += dst_file_size;
+        } else if in_place {
+            // the updated destination matches the source, so the copy below
+            // has nothing left to write
+            let update_in_place = if decision == CopyDecision::Repair {
+                repair_blocks
+            } else {
+                delta_blocks
+            };
+            bytes_transferred = update_in_place(
+                &src_file_handle,
+                src,
+                &dst_file_handle,
+                dst,
+                src_size,
+                copy_opts,
+            )?;
+            src_file_handle.seek(SeekFrom::Start(bytes_transferred))?;
+            dst_file_handle.seek(SeekFrom::Start(bytes_transferred))?;
+        }
+    }
+
+    if copy_opts.fadvise {
+        util::fadvise(&src_file_handle, util::FileAdvice::Sequential);
+    }
+
+    // holes can only be created when not appending to an existing file
+    let sparse_min_hole = if copy_opts.sparse && !appending && !in_place {
+        Some(copy_opts.sparse_min_hole)
+    } else {
+        None
+    };
+    let mut strategy = copy_strategy(
+        &mut dst_file_handle,
+        direct_io,
+        sparse_min_hole,
+        src_size,
+        copy_opts,
+    );
+
+    // separate handle to read back the written blocks and to cut off a
+    // corrupted block, so that a retry or resume starts from it again
+    let readback_handle = if copy_opts.checksum_block {
+        match std::fs::OpenOptions::new().read(true).write(true).open(dst) {
+            Ok(f) => Some(f),
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!(
+                        "failure in opening destination file for verification: {}",
+                        &e
+                    ),
+                ));
+            }
+        }
+    } else {
+        None
+    };
+    // block devices and slices are only read up to the copied size, which may
+    // be less than the source size
+    let src_limit = if src_file_metadata.file_type().is_block_device() || copy_opts.is_sliced() {
+        src_size.saturating_sub(bytes_transferred)
+    } else {
+        u64::MAX
+    };
+    let mut src_reader = super::digest::HashingReader::new(
+        io::Read::take(&src_file_handle, src_limit),
+        copy_opts.checksum_block,
+    );
+    if let Some(part_size) = copy_opts.s3_etag {
+        // the ETag covers the whole file, including an already copied part
+        let mut etag = super::digest::S3ETag::new(part_size);
+        if bytes_transferred > 0 {
+            let mut copied = io::Read::take(File::open(dst)?, bytes_transferred);
+            io::copy(&mut copied, &mut etag)?;
+        }
+        src_reader = src_reader.with_etag(etag);
+    }
+
+    let started = std::time::Instant::now();
+    let resumed_bytes = bytes_transferred;
+    let mut progress = CopyProgress {
+        src,
+        dst,
+        len: src_size,
+        transferred: bytes_transferred,
+    };
+    let verify_size = copy_opts.verify_size;
+    let mut check_block = |reader: &mut super::digest::HashingReader<_>, offset, bytes_copied| {
+        // if requested number of bytes were copied successfully, exit loop.
+        // Without size verification only EOF ends the copy.
+        if verify_size && offset == src_size {
+            return Ok(false);
+        }
+
+        if let Some(readback) = &readback_handle {
+            verify_block(
+                &src_file_handle,
+                readback,
+                reader.take_digest(),
+                offset,
+                bytes_copied,
+                sparse_min_hole.is_some(),
+            )?;
+        }
+        Ok(true)
+    };
+    copy_loop(
+        &mut src_reader,
+        &mut strategy,
+        &mut progress,
+        None,
+        "copying file",
+        Some(&mut check_block),
+        copy_opts,
+    )?;
+    let bytes_transferred = progress.transferred;
+
+    // an empty source never reaches the progress in the loop, it's complete
+    // as soon as its destination is created
+    if src_size == 0 && bytes_transferred == 0 {
+        report_progress(src, 0, 0, copy_opts);
+        if copy_opts.show_progress {
+            progress.show(copy_opts);
+        }
+    }
+
+    drop(strategy);
+    let etag = src_reader.etag();
+
+    // drop the copied data from the page cache
+    if copy_opts.fadvise {
+        util::fadvise(&src_file_handle, util::FileAdvice::DontNeed);
+        util::fadvise(&dst_file_handle, util::FileAdvice::DontNeed);
+    }
+
+    // check whether the source was truncated while it was being copied
+    let src_len = src_file_handle
+        .metadata()
+        .and_then(|m| source_len(src, &m, copy_opts))
+        .unwrap_or(src_size);
+    let shrunk = copy_opts.verify_size && bytes_transferred < src_size && src_len < src_size;
+    if shrunk {
+        match copy_opts.on_shrink {
+            OnShrink::Error => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "source file '{}' shrank from {} to {} bytes during the copy",
+                        &src.to_str().unwrap_or(""),
+                        src_size,
+                        src_len
+                    ),
+                ));
+            }
+            OnShrink::TruncateDest => {
+                errln!(copy_opts,
+                    "Warning: source file '{}' shrank from {} to {} bytes during the copy, destination truncated to {} bytes",
+                    &src.to_str().unwrap_or(""),
+                    src_size,
+                    src_len,
+                    bytes_transferred
+                );
+                dst_file_handle.set_len(bytes_transferred)?;
+                copy_opts.stats_store.total -= src_size - bytes_transferred;
+                copy_opts.stats_store.skipped_bytes += src_size - bytes_transferred;
+            }
+        }
+    }
+
+    // verify file transfer
+    if copy_opts.verify_size && !shrunk && bytes_transferred != src_size {
+        return Err(io::Error::other(format!(
+            "error while copying file '{}': missing {} bytes in destination",
+            &src.to_str().unwrap_or(""),
+            src_size - bytes_transferred
+        )));
+    }
+
+    if let Some(etag) = etag {
+        if copy_opts.verbose {
+            outln!(
+                copy_opts,
+                "S3 ETag of '{}': {}",
+                src.to_str().unwrap_or(""),
+                etag
+            );
+        }
+        copy_opts.stats_store.last_etag = Some(etag);
+    }
+
+    // record the throughput of this file, not counting the resumed part
+    if copy_opts.per_file_throughput {
+        let elapsed = started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            ((bytes_transferred - resumed_bytes) as f64 / elapsed) as u64
+        } else {
+            0
+        };
+        if copy_opts.verbose {
+            outln!(
+                copy_opts,
+                "Transfer speed of '{}': {}/s",
+                src.to_str().unwrap_or(""),
+                get_str_size_precise(rate)
+            );
+        }
+        copy_opts
+            .stats_store
+            .per_file_throughput
+            .push((src.to_owned(), rate));
+    }
+
+    // pad the destination with a trailing hole
+    if copy_opts.pad_to > 0 {
+        if let Err(e) = dst_file_handle.set_len(copy_opts.pad_to) {
+            return Err(io::Error::new(
+                e.kind(),
+                format!(
+                    "failure in padding '{}' to {} bytes: {}",
+                    dst.to_str().unwrap_or(""),
+                    copy_opts.pad_to,
+                    e
+                ),
+            ));
+        }
+    }
+
+    // set the group before the permissions, as changing it may clear the
+    // setuid and setgid bits
+    if let Some(group) = copy_opts.group {
+        set_group(&dst_file_handle, dst, &src_file_metadata, group, copy_opts)?;
+    }
+
+    // sync permissions between source and destination files, once all the
+    // data is written and the group is set
+    set_permissions(
+        &dst_file_handle,
+        dst,
+        src_file_metadata.permissions(),
+        copy_opts,
+    )?;
+
+    // sync access and modification times between source and destination
+    if copy_opts.preserve_times {
+        if let Err(e) = util::set_file_times(
+            &dst_file_handle,
+            &src_file_metadata,
+            copy_opts.times_precision,
+        ) {
+            return Err(io::Error::new(
+                e.kind(),
+                format!(
+                    "failure in setting timestamps of '{}': {}",
+                    dst.to_str().unwrap_or(""),
+                    e
+                ),
+            ));
+        }
+    }
+
+    // carry over the SELinux security context of the source
+    if copy_opts.selinux {
+        if let Err(e) = util::copy_xattr(&src_file_handle, &dst_file_handle, c"security.selinux") {
+            return Err(io::Error::new(
+                e.kind(),
+                format!(
+                    "failure in setting security context of '{}': {}",
+                    dst.to_str().unwrap_or(""),
+                    e
+                ),
+            ));
+        }
+    }
+
+    // carry over the access ACL of the source
+    if copy_opts.acl {
+        if let Err(e) = util::copy_xattr(
+            &src_file_handle,
+            &dst_file_handle,
+            c"system.posix_acl_access",
+        ) {
+            return Err(io::Error::new(
+                e.kind(),
+                format!(
+                    "failure in setting ACL of '{}': {}",
+                    dst.to_str().unwrap_or(""),
+                    e
+                ),
+            ));
+        }
+    }
+
+    // carry over the file capabilities of the source, this has to come after
+    // the last write as writing to the file clears them
+    if copy_opts.caps {
+        match util::copy_xattr(&src_file_handle, &dst_file_handle, c"security.capability") {
+            Err(e) if copy_opts.on_perm_error == OnPermError::Warn => {
+                errln!(
+                    copy_opts,
+                    "Warning: failure in setting capabilities of '{}': {}",
+                    dst.to_str().unwrap_or(""),
+                    e
+                );
+            }
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!(
+                        "failure in setting capabilities of '{}': {}",
+                        dst.to_str().unwrap_or(""),
+                        e
+                    ),
+                ));
+            }
+            Ok(_) => {}
+        }
+    }
+
+    // verify that the destination actually holds the source permissions
+    if copy_opts.verify_perms != PermVerify::Off {
+        let dst_mode = dst_file_handle.metadata()?.mode();
+        verify_mode(
+            src_file_metadata.mode() & copy_opts.mode_mask(),
+            dst_mode,
+            dst,
+            copy_opts,
+        )?;
+    }
+
+    // verify the copy against the externally provided digest
+    if let Some(digests) = &copy_opts.expected_digests {
+        verify_expected_digest(src, dst, digests)?;
+    }
+
+    // print the final message about the file copy, replacing the progress
+    // line if it's shown
+    let action = if copy_opts.remove { "Moved" } else { "Copied" };
+    let file_name = src
+        .file_name()
+        .unwrap_or_else(|| std::ffi::OsStr::new(""))
+        .to_str()
+        .unwrap_or("");
+    if copy_opts.progress_messages() {
+        copy_opts.write_progress(&format!(
+            "{}{} file '{}'\n",
+            progress_line_clear(copy_opts),
+            action,
+            file_name
+        ));
+    } else if copy_opts.verbose && !copy_opts.show_progress {
+        outln!(copy_opts, "{} '{}'", action, file_name);
+    }
+    copy_opts.stats_store.copied_files += 1;
+    Ok(bytes_transferred as usize)
+}
+
+/// Compares the SHA-256 digest of the copied file `dst` with the one listed
+/// for its source `src` in `digests`.
+fn verify_expected_digest(
+    src: &Path,
+    dst: &Path,
+    digests: &super::digest::DigestList,
+) -> io::Result<()> {
+    let expected = match digests.lookup(src) {
+        Some(d) => d,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "no expected digest for '{}' in the digest file",
+                    src.to_str().unwrap_or("")
+                ),
+            ))
+        }
+    };
+    let actual = super::digest::sha256_file(dst).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "failure in computing digest of '{}': {}",
+                dst.to_str().unwrap_or(""),
+                e
+            ),
+        )
+    })?;
+    if actual != expected {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "checksum mismatch for '{}': expected {}, got {}",
+                dst.to_str().unwrap_or(""),
+                super::digest::to_hex(&expected),
+                super::digest::to_hex(&actual)
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Compares the permission bits of the source and destination modes and
+/// reports a mismatch according to [`CopyOptions::verify_perms`].
+fn verify_mode(
+    src_mode: u32,
+    dst_mode: u32,
+    dst: &Path,
+    copy_opts: &CopyOptions,
+) -> io::Result<()> {
+    const PERM_BITS: u32 = 0o7777;
+    if src_mode & PERM_BITS == dst_mode & PERM_BITS {
+        return Ok(());
+    }
+    let msg = format!(
+        "permissions of '{}' are {:o} instead of {:o}, destination can't hold the source permissions",
+        dst.to_str().unwrap_or(""),
+        dst_mode & PERM_BITS,
+        src_mode & PERM_BITS
+    );
+    match copy_opts.verify_perms {
+        PermVerify::Strict => Err(io::Error::new(io::ErrorKind::PermissionDenied, msg)),
+        _ => {
+            errln!(copy_opts, "\nWarning: {}", msg);
+            Ok(())
+        }
+    }
+}
+
+#[inline]
+fn default_progress_handler(
+    src: &Path,
+    _dst: &Path,
+    bytes_transferred: u64,
+    total: u64,
+    copy_opts: &CopyOptions,
+) {
+    // copies finishing within the grace period show no progress at all
+    if copy_opts.progress_held() {
+        return;
+    }
+
+    if let Some((multi_progress, job)) = &copy_opts.job_progress {
+        let file = src.file_name().unwrap_or_else(|| std::ffi::OsStr::new("/"));
+        multi_progress.update(
+            *job,
+            JobProgress {
+                file: Some(file.to_str().unwrap_or("").to_owned()),
+                bytes: bytes_transferred,
+                size: total,
+                transferred: copy_opts.stats_store.transferred,
+            },
+        );
+        return;
+    }
+
+    if copy_opts.progress_format == ProgressFormat::Percent {
+        print_percent(copy_opts);
+        return;
+    }
+
+    if copy_opts.progress_format == ProgressFormat::Pv {
+        print_pv(copy_opts);
+        return;
+    }
+
+    let line = if copy_opts.progress_format == ProgressFormat::Bar {
+        bar_progress_line(copy_opts)
+    } else {
+        full_progress_line(src, bytes_transferred, total, copy_opts)
+    };
+    let text = line.render(copy_opts.eta_format);
+
+    // keep the line for the timed redraws and clear a previous indicator
+    let text = if copy_opts.stall_threshold.is_some() || copy_opts.progress_min_refresh.is_some() {
+        let monitor = copy_opts
+            .stall_monitor
+            .get_or_init(|| std::sync::Arc::new(StallMonitor::new(copy_opts)));
+        // the size of streaming sources is unknown until they end
+        let in_progress = total == 0 || bytes_transferred < total;
+        let padding = monitor.update(line, in_progress);
+        text + &padding
+    } else {
+        text
+    };
+
+    copy_opts.write_progress(&text);
+
+    // the stats end the line on the regular output, but not on a separate one
+    let stats = &copy_opts.stats_store;
+    if copy_opts.progress_format == ProgressFormat::Bar
+        && copy_opts.progress_output.is_some()
+        && stats.total > 0
+        && stats.transferred >= stats.total
+        && stats.last_percent.replace(Some(100)) != Some(100)
+    {
+        copy_opts.write_progress("\n");
+    }
+}
+
+/// Builds the progress line of [`ProgressFormat::Full`] for the file `src`.
+fn full_progress_line(
+    src: &Path,
+    bytes_transferred: u64,
+    total: u64,
+    copy_opts: &CopyOptions,
+) -> ProgressLine {
+    let human_readable = true;
+    let str_stats_transferred = get_str_size_precise(copy_opts.stats_store.transferred);
+    let str_bytes_transferred = get_str_size_precise(bytes_transferred);
+    let str_stats_total = get_str_size_precise(copy_opts.stats_store.total);
+    let str_bytes_total = get_str_size_precise(total);
+
+    let str_speed = if copy_opts.show_current_speed {
+        format!(
+            "\tSpeed: {:>9} (avg {:>9})",
+            get_str_rate(copy_opts.stats_store.current_rate()),
+            get_str_rate(copy_opts.stats_store.average_rate())
+        )
+    } else {
+        String::new()
+    };
+
+    let head = if human_readable {
+        format!(
+            "\rCopying file {:50} ({:>8} /{:>8})\tTotal: ({:>8} /{:>8})\tETA: ",
+            format!(
+                "'{}'",
+                src.file_name()
+                    .unwrap_or_else(|| std::ffi::OsStr::new("/"))
+                    .to_str()
+                    .unwrap_or("")
+            ),
+            &str_bytes_transferred,
+            &str_bytes_total,
+            &str_stats_transferred,
+            &str_stats_total,
+        )
+    } else {
+        format!(
+            "\rCopying file {:50} ({:8}/{:8})\tTotal: ({:10}/{:10})\tETA: ",
+            format!("'{}'", src.to_str().unwrap_or("")),
+            &bytes_transferred,
+            &total,
+            &copy_opts.stats_store.transferred,
+            &copy_opts.stats_store.total,
+        )
+    };
+    ProgressLine {
+        head,
+        tail: str_speed,
+        transferred: copy_opts.stats_store.transferred,
+        total: copy_opts.stats_store.total,
+        start_time: copy_opts.stats_store.start_time,
+    }
+}
+
+/// Builds the progress line of [`ProgressFormat::Bar`], a bar of the overall
+/// progress followed by the percentage, the sizes and the average rate.
+fn bar_progress_line(copy_opts: &CopyOptions) -> ProgressLine {
+    const WIDTH: u64 = 30;
+
+    let stats = &copy_opts.stats_store;
+    let filled = stats
+        .transferred
+        .saturating_mul(WIDTH)
+        .checked_div(stats.total)
+        .map_or(0, |n| std::cmp::min(n, WIDTH)) as usize;
+    let percent = stats
+        .transferred
+        .saturating_mul(100)
+        .checked_div(stats.total)
+        .map_or(0, |p| std::cmp::min(p, 100));
+    let head = format!(
+        "\r[{}{}] {:>3}% ({:>8} /{:>8}) {:>11}  ETA: ",
+        "#".repeat(filled),
+        "-".repeat(WIDTH as usize - filled),
+        percent,
+        get_str_size_precise(stats.transferred),
+        get_str_size_precise(stats.total),
+        get_str_rate(stats.average_rate()),
+    );
+    ProgressLine {
+        head,
+        tail: String::new(),
+        transferred: stats.transferred,
+        total: stats.total,
+        start_time: stats.start_time,
+    }
+}
+
+/// Prints a file which failed to copy while errors are ignored. With inline
+/// errors and the progress shown, the progress line is cleared on a terminal
+/// or ended otherwise, so that the error gets a line of its own and the next
+/// update draws the progress below it.
+fn print_failure(e: &io::Error, copy_opts: &mut CopyOptions) {
+    if !copy_opts.inline_errors {
+        errln!(copy_opts, "Failed to copy file: {}", e);
+        return;
+    }
+    copy_opts.stats_store.failed.push(e.to_string());
+    if !copy_opts.show_progress {
+        errln!(copy_opts, "Failed to copy file: {}", e);
+        return;
+    }
+    copy_opts.write_progress(&format!(
+        "{}Failed to copy file: {}\n",
+        progress_line_clear(copy_opts),
+        e
+    ));
+}
+
+/// Returns the text which makes way for a line of its own after the progress
+/// line. The progress line is cleared on a terminal, and ended otherwise.
+/// Nothing is needed while the progress is held back.
+fn progress_line_clear(copy_opts: &CopyOptions) -> &'static str {
+    if copy_opts.progress_held() {
+        ""
+    } else if copy_opts.progress_tty() {
+        "\r\x1b[2K"
+    } else {
+        "\n"
+    }
+}
+
+/// Lists the files which failed to copy, collected with inline errors.
+fn print_failures(copy_opts: &CopyOptions) {
+    let failed = &copy_opts.stats_store.failed;
+    if failed.is_empty() {
+        return;
+    }
+    let mut text = format!("\n{} file(s) failed to copy:\n", failed.len());
+    for error in failed {
+        text.push_str(&format!("  {}\n", error));
+    }
+    copy_opts.write_progress(&text);
+}
+
+/// Prints the overall percentage of the transfer on its own line if it has
+/// changed since it was last printed. Nothing is printed while the total is
+/// unknown, while a transfer of only empty files is complete right away.
+fn print_percent(copy_opts: &CopyOptions) {
+    let stats = &copy_opts.stats_store;
+    let percent = match (stats.transferred, stats.total) {
+        (0, 0) => 100,
+        (_, 0) => return,
+        (transferred, total) => std::cmp::min(transferred * 100 / total, 100),
+    };
+    if stats.last_percent.get() != Some(percent) {
+        stats.last_percent.set(Some(percent));
+        copy_opts.write_progress(&format!("{}\n", percent));
+    }
+}
+
+/// Prints the numeric progress line of [`ProgressFormat::Pv`] to stderr, or
+/// to the progress output if one is set, once a second and when the transfer
+/// completes.
+fn print_pv(copy_opts: &CopyOptions) {
+    const INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+    let stats = &copy_opts.stats_store;
+    let done = stats.total > 0 && stats.transferred >= stats.total;
+    if !done
+        && stats
+            .pv_updated
+            .get()
+            .is_some_and(|t| t.elapsed() < INTERVAL)
+    {
+        return;
+    }
+    stats.pv_updated.set(Some(std::time::Instant::now()));
+
+    let elapsed = stats.start_time.map(|t| t.elapsed()).unwrap_or_default();
+    let percent = (stats.transferred * 100)
+        .checked_div(stats.total)
+        .map_or(0, |p| std::cmp::min(p, 100));
+    let remaining = estimate_remaining(stats.transferred, stats.total, elapsed).unwrap_or_default();
+    let text = format!(
+        "{:.4} {} {} {} {}\n",
+        elapsed.as_secs_f64(),
+        stats.transferred,
+        stats.average_rate().unwrap_or(0),
+        percent,
+        remaining.as_secs_f64().ceil() as u64
+    );
+    match &copy_opts.progress_output {
+        Some(_) => copy_opts.write_progress(&text),
+        None => write_progress_to(&copy_opts.output.err, &text),
+    }
+}
+
+/// Estimates the time remaining for the transfer based on the average
+/// transfer rate so far. Returns `None` if the total is unknown or nothing has
+/// been transferred yet.
+fn estimate_remaining(
+    transferred: u64,
+    total: u64,
+    elapsed: std::time::Duration,
+) -> Option<std::time::Duration> {
+    if total == 0 || transferred == 0 || elapsed.is_zero() {
+        return None;
+    }
+    let rate = transferred as f64 / elapsed.as_secs_f64();
+    let remaining_bytes = total.saturating_sub(transferred);
+    // a rate close to zero after a long stall yields estimates beyond what a
+    // duration can hold, which are as good as unknown
+    std::time::Duration::try_from_secs_f64(remaining_bytes as f64 / rate).ok()
+}
+
+/// Formats the estimated time of completion. `remaining` is rendered as
+/// `HH:MM:SS` for [`EtaFormat::Relative`], or added to `now` and rendered as a
+/// local wall clock time for [`EtaFormat::Clock`]. An unknown `remaining`
+/// is rendered as `--:--`.
+fn format_eta(
+    remaining: Option<std::time::Duration>,
+    now: std::time::SystemTime,
+    format: EtaFormat,
+) -> String {
+    let remaining = match remaining {
+        Some(r) => r,
+        None => return "--:--".to_string(),
+    };
+    match format {
+        EtaFormat::Relative => {
+            let secs = remaining.as_secs();
+            format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+        }
+        EtaFormat::Clock => match now.checked_add(remaining) {
+            Some(finish) => {
+                let (hours, minutes, seconds) = util::local_time_of_day(finish);
+                format!("{:02}:{:02}:{:02}", hours, minutes, seconds)
+            }
+            None => "--:--".to_string(),
+        },
+    }
+}
+
+/// Formats a transfer rate in bytes per second. An unknown rate is rendered
+/// as `--`.
+#[inline]
+fn get_str_rate(rate: Option<u64>) -> String {
+    match rate {
+        Some(r) => format!("{}/s", get_str_size_precise(r)),
+        None => "--".to_string(),
+    }
+}
+
+#[inline]
+fn get_str_size_precise(bytes: u64) -> String {
+    let result: String;
+    if bytes > util::GB {
+        result = format!("{:.2}G", (bytes as f64) / (util::GB as f64));
+    } else if bytes > util::MB {
+        result = format!("{:.2}M", (bytes as f64) / (util::MB as f64));
+    } else if bytes > util::KB {
+        result = format!("{:.2}K", (bytes as f64) / (util::KB as f64));
+    } else {
+        result = format!("{}B", bytes);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Options which discard all the messages of the copy.
+    fn quiet_opts() -> CopyOptions {
+        let mut copy_opts = CopyOptions::new();
+        copy_opts.output(Output::new(Box::new(io::sink()), Box::new(io::sink())));
+        copy_opts
+    }
+
+    fn path_str(path: &Path) -> &str {
+        path.to_str().unwrap()
+    }
+
+    /// Writer into a buffer which stays readable after the writer is boxed.
+    #[derive(Clone, Default)]
+    struct SharedBuf(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuf {
+        fn text(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    /// Options which capture the regular and the error output of the copy.
+    fn captured_opts() -> (CopyOptions, SharedBuf, SharedBuf) {
+        let (out, err) = (SharedBuf::default(), SharedBuf::default());
+        let mut copy_opts = CopyOptions::new();
+        copy_opts.output(Output::new(Box::new(out.clone()), Box::new(err.clone())));
+        (copy_opts, out, err)
+    }
+
+    #[test]
+    fn estimate_remaining_is_unknown_without_progress() {
+        let second = std::time::Duration::from_secs(1);
+        assert_eq!(estimate_remaining(0, 100, second), None);
+        assert_eq!(estimate_remaining(10, 0, second), None);
+        assert_eq!(estimate_remaining(10, 100, std::time::Duration::ZERO), None);
+    }
+
+    #[test]
+    fn estimate_remaining_uses_average_rate() {
+        let remaining = estimate_remaining(25, 100, std::time::Duration::from_secs(5));
+        assert_eq!(remaining, Some(std::time::Duration::from_secs(15)));
+    }
+
+    #[test]
+    fn estimate_remaining_survives_long_stall() {
+        let elapsed = std::time::Duration::from_secs(u64::MAX / 2);
+        assert_eq!(estimate_remaining(1, u64::MAX, elapsed), None);
+    }
+
+    #[test]
+    fn format_eta_renders_relative_and_clock() {
+        let now = std::time::SystemTime::now();
+        let remaining = Some(std::time::Duration::from_secs(3725));
+        assert_eq!(format_eta(remaining, now, EtaFormat::Relative), "01:02:05");
+        assert_eq!(format_eta(None, now, EtaFormat::Relative), "--:--");
+        let huge = Some(std::time::Duration::MAX);
+        assert_eq!(format_eta(huge, now, EtaFormat::Clock), "--:--");
+    }
+
+    #[test]
+    fn move_with_head_copies_the_slice() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("f"), dir.path().join("out"));
+        std::fs::write(&src, vec![7u8; 10000]).unwrap();
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.remove(true).head(Some(4096));
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(std::fs::metadata(&dst).unwrap().len(), 4096);
+        assert!(!src.exists());
+    }
+
+    #[test]
+    fn move_with_flatten_reshapes_the_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("sub/x"), "x").unwrap();
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.remove(true).recursive(true).flatten(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert!(dst.join("x").is_file());
+        assert!(!dst.join("sub").exists());
+        assert!(!src.exists());
+    }
+
+    #[test]
+    fn move_with_regular_only_leaves_out_special_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir(&src).unwrap();
+        std::fs::write(src.join("x"), "x").unwrap();
+        let fifo = std::ffi::CString::new(path_str(&src.join("fifo"))).unwrap();
+        // SAFETY: `fifo` is a valid NUL terminated path
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+
+        // the skipped FIFO keeps the source directory from being removed
+        let mut copy_opts = quiet_opts();
+        copy_opts.remove(true).recursive(true).regular_only(true);
+        assert!(copy(path_str(&src), path_str(&dst), copy_opts).is_err());
+        assert!(dst.join("x").is_file());
+        assert!(!dst.join("fifo").exists());
+        assert!(src.join("fifo").exists());
+    }
+
+    #[test]
+    fn plain_move_is_a_rename() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("f"), dir.path().join("g"));
+        std::fs::write(&src, "data").unwrap();
+        let ino = std::fs::metadata(&src).unwrap().ino();
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.remove(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(std::fs::metadata(&dst).unwrap().ino(), ino);
+    }
+
+    #[test]
+    fn only_transient_errors_are_retryable() {
+        assert!(is_retryable(&io::Error::from(io::ErrorKind::TimedOut)));
+        assert!(is_retryable(&io::Error::from_raw_os_error(libc::EIO)));
+        assert!(is_retryable(&io::Error::from_raw_os_error(libc::ESTALE)));
+        assert!(!is_retryable(&io::Error::from_raw_os_error(libc::ENOENT)));
+        assert!(!is_retryable(&io::Error::from_raw_os_error(libc::EROFS)));
+        assert!(!is_retryable(&io::Error::other(
+            "destination is same as the source"
+        )));
+    }
+
+    #[test]
+    fn validation_failures_are_not_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("f"));
+        std::fs::create_dir(&src).unwrap();
+        std::fs::write(&dst, "f").unwrap();
+
+        let started = std::time::Instant::now();
+        let mut copy_opts = quiet_opts();
+        copy_opts.retry_operation(2);
+        assert!(copy(path_str(&dst), path_str(&dst), copy_opts.clone()).is_err());
+        copy_opts.recursive(true);
+        assert!(copy(path_str(&src), path_str(&dst), copy_opts).is_err());
+        // a retry waits for at least a second
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn block_size_is_clamped_and_zero_rejected() {
+        let mut copy_opts = CopyOptions::new();
+        copy_opts.block_size(1);
+        assert_eq!(copy_opts.block_size, util::MIN_BLOCK_SIZE);
+        copy_opts.block_size(500 * util::GB);
+        assert_eq!(copy_opts.block_size, util::MAX_BLOCK_SIZE);
+        copy_opts.block_size(0);
+        assert!(copy_opts.validate().is_err());
+    }
+
+    #[test]
+    fn output_is_split_between_captured_streams() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir(&src).unwrap();
+        std::fs::write(src.join("x"), "x").unwrap();
+        std::os::unix::fs::symlink("missing", src.join("dangling")).unwrap();
+
+        let (mut copy_opts, out, err) = captured_opts();
+        copy_opts
+            .recursive(true)
+            .stats(true)
+            .broken_symlinks(BrokenSymlinks::Skip);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert!(out.text().contains("Time taken to copy"));
+        assert!(!out.text().contains("Warning"));
+        assert!(err.text().contains("Warning: skipping broken symlink"));
+        assert!(!err.text().contains("Time taken"));
+    }
+
+    #[test]
+    fn max_memory_reduces_parallel_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir(&src).unwrap();
+        for i in 0..8 {
+            std::fs::write(src.join(format!("f{}", i)), vec![i as u8; 1000]).unwrap();
+        }
+
+        let (mut copy_opts, out, _) = captured_opts();
+        copy_opts
+            .recursive(true)
+            .verbose(true)
+            .jobs(8)
+            .max_memory(Some(4 * util::MB));
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert!(out.text().contains("Using 4 job(s)"));
+        for i in 0..8 {
+            assert_eq!(
+                std::fs::read(dst.join(format!("f{}", i))).unwrap(),
+                vec![i as u8; 1000]
+            );
+        }
+    }
+
+    #[test]
+    fn copy_to_writer_writes_only_the_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        std::fs::write(&a, vec![1u8; 300 * 1024]).unwrap();
+        std::fs::write(&b, "tail").unwrap();
+
+        let (mut copy_opts, out, _) = captured_opts();
+        let progress = SharedBuf::default();
+        copy_opts
+            .progress(true)
+            .stats(true)
+            .progress_format(ProgressFormat::Bar)
+            .progress_output(Box::new(progress.clone()));
+        let mut dst = Vec::new();
+        copy_to_writer(&[path_str(&a), path_str(&b)], &mut dst, copy_opts).unwrap();
+
+        let mut expected = std::fs::read(&a).unwrap();
+        expected.extend_from_slice(b"tail");
+        assert_eq!(dst, expected);
+        assert!(out.text().contains("Time taken to copy"));
+        let progress = progress.text();
+        assert!(progress.contains(&format!("\r[{}] 100%", "#".repeat(30))));
+        assert!(progress.ends_with('\n'));
+    }
+
+    #[test]
+    fn copy_to_writer_rejects_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut dst = Vec::new();
+        let err = copy_to_writer(&[path_str(dir.path())], &mut dst, quiet_opts()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(dst.is_empty());
+    }
+
+    /// Serves `data` as `/file.bin` on a local port for `requests` requests,
+    /// honouring range requests, and answers anything else with a 404.
+    /// Returns the URL of the file and a handle which joins into the heads of
+    /// the requests, in lowercase.
+    #[cfg(feature = "http")]
+    fn serve(data: Vec<u8>, requests: usize) -> (String, std::thread::JoinHandle<Vec<String>>) {
+        use std::io::BufRead;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file.bin", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut heads = Vec::new();
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+                let mut head = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    head.push_str(&line.to_lowercase());
+                }
+                let range = head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("range: bytes="))
+                    .map(|r| r.trim().trim_end_matches('-').parse::<usize>().unwrap());
+                let (status, start) = match range {
+                    _ if !head.starts_with("get /file.bin ") => ("404 Not Found", data.len()),
+                    Some(start) => ("206 Partial Content", start),
+                    None => ("200 OK", 0),
+                };
+                let mut response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nLast-Modified: Wed, 21 Oct 2015 07:28:00 GMT\r\nConnection: close\r\n",
+                    status,
+                    data.len() - start
+                );
+                if range.is_some() {
+                    response += &format!(
+                        "Content-Range: bytes {}-{}/{}\r\n",
+                        start,
+                        data.len() - 1,
+                        data.len()
+                    );
+                }
+                response += "\r\n";
+                stream.write_all(response.as_bytes()).unwrap();
+                stream.write_all(&data[start..]).unwrap();
+                heads.push(head);
+            }
+            heads
+        });
+        (url, server)
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn url_download_completes() {
+        let data: Vec<u8> = (0..200 * 1024).map(|i| (i % 253) as u8).collect();
+        let (url, server) = serve(data.clone(), 1);
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.preserve_times(true);
+        copy(&url, path_str(dir.path()), copy_opts).unwrap();
+
+        let dst = dir.path().join("file.bin");
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        let modified = std::fs::metadata(&dst).unwrap().modified().unwrap();
+        assert_eq!(
+            modified,
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1445412480)
+        );
+        let heads = server.join().unwrap();
+        assert!(!heads[0].contains("range:"));
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn url_download_resumes_with_range_request() {
+        let data: Vec<u8> = (0..200 * 1024).map(|i| (i % 253) as u8).collect();
+        let (url, server) = serve(data.clone(), 1);
+        let dir = tempfile::tempdir().unwrap();
+        let dst = dir.path().join("out.bin");
+        std::fs::write(&dst, &data[..1000]).unwrap();
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.resume(true);
+        copy(&url, path_str(&dst), copy_opts).unwrap();
+
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        let heads = server.join().unwrap();
+        assert!(heads[0].contains("range: bytes=1000-"), "{}", heads[0]);
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn url_download_fails_on_error_status() {
+        let (url, server) = serve(Vec::new(), 1);
+        let url = url.replace("file.bin", "missing.bin");
+        let dir = tempfile::tempdir().unwrap();
+
+        let err = copy(&url, path_str(&dir.path().join("out")), quiet_opts()).unwrap_err();
+        assert!(err.to_string().contains("HTTP 404"), "{}", err);
+        assert!(!dir.path().join("out").exists());
+        server.join().unwrap();
+    }
+
+    #[cfg(not(feature = "http"))]
+    #[test]
+    fn url_sources_need_the_http_feature() {
+        let dir = tempfile::tempdir().unwrap();
+        let dst = dir.path().join("out");
+        let err = copy("http://127.0.0.1:1/file.bin", path_str(&dst), quiet_opts()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(!dst.exists());
+    }
+
+    /// Runs the compression preview of `data` with `algo` and returns the
+    /// estimated ratio printed for it.
+    fn preview_ratio(data: &[u8], algo: CompressAlgo) -> f64 {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        std::fs::write(&src, data).unwrap();
+
+        let (mut copy_opts, out, _) = captured_opts();
+        copy_opts.compress_preview(Some(algo));
+        let dst = dir.path().join("dst");
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert!(!dst.exists());
+
+        let text = out.text();
+        let ratio = text
+            .split("ratio: ")
+            .nth(1)
+            .unwrap()
+            .split(' ')
+            .next()
+            .unwrap();
+        ratio.parse().unwrap()
+    }
+
+    /// Returns `len` pseudo random bytes, which don't compress.
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn compress_preview_of_zeros_predicts_high_ratio() {
+        let zeros = vec![0u8; 4 * 1024 * 1024];
+        for algo in [CompressAlgo::Gzip, CompressAlgo::Zstd] {
+            let ratio = preview_ratio(&zeros, algo);
+            assert!(ratio > 100.0, "{:?}: {}", algo, ratio);
+        }
+    }
+
+    #[test]
+    fn compress_preview_of_random_data_predicts_no_gain() {
+        let random = random_bytes(1024 * 1024);
+        for algo in [CompressAlgo::Gzip, CompressAlgo::Zstd] {
+            let ratio = preview_ratio(&random, algo);
+            assert!((0.95..=1.01).contains(&ratio), "{:?}: {}", algo, ratio);
+        }
+    }
+
+    #[test]
+    fn compress_preview_samples_large_files() {
+        // only the zero filled start, middle and end chunks are sampled
+        let mut data = random_bytes(80 * 1024 * 1024);
+        for range in [
+            0..4 * 1024 * 1024,
+            38 * 1024 * 1024..42 * 1024 * 1024,
+            76 * 1024 * 1024..80 * 1024 * 1024,
+        ] {
+            data[range].fill(0);
+        }
+        assert!(preview_ratio(&data, CompressAlgo::Zstd) > 100.0);
+    }
+
+    #[test]
+    fn tar_round_trip_keeps_the_tree() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let tree = dir.path().join("tree");
+        std::fs::create_dir_all(tree.join("sub")).unwrap();
+        std::fs::create_dir(tree.join("empty")).unwrap();
+        std::fs::write(tree.join("a.txt"), "alpha").unwrap();
+        std::fs::write(tree.join("sub/b.bin"), vec![7u8; 100_000]).unwrap();
+        std::fs::set_permissions(tree.join("a.txt"), std::fs::Permissions::from_mode(0o640))
+            .unwrap();
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        File::options()
+            .write(true)
+            .open(tree.join("a.txt"))
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        std::os::unix::fs::symlink("a.txt", tree.join("link")).unwrap();
+
+        let archive = dir.path().join("out.tar");
+        let mut copy_opts = quiet_opts();
+        copy_opts.recursive(true);
+        copy_to_tar(&[path_str(&tree)], path_str(&archive), copy_opts).unwrap();
+
+        // the archive reads back with the same entries
+        let mut entries = std::collections::BTreeMap::new();
+        let mut tar_archive = tar::Archive::new(File::open(&archive).unwrap());
+        for entry in tar_archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_str().unwrap().to_owned();
+            let kind = entry.header().entry_type();
+            let link = entry
+                .link_name()
+                .unwrap()
+                .map(|l| l.to_str().unwrap().to_owned());
+            let mut data = Vec::new();
+            io::Read::read_to_end(&mut entry, &mut data).unwrap();
+            entries.insert(path, (kind, link, data));
+        }
+        let names: Vec<&str> = entries.keys().map(|k| k.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "tree",
+                "tree/a.txt",
+                "tree/empty",
+                "tree/link",
+                "tree/sub",
+                "tree/sub/b.bin"
+            ]
+        );
+        assert_eq!(entries["tree/a.txt"].2, b"alpha");
+        assert_eq!(entries["tree/empty"].0, tar::EntryType::Directory);
+        assert_eq!(entries["tree/link"].0, tar::EntryType::Symlink);
+        assert_eq!(entries["tree/link"].1.as_deref(), Some("a.txt"));
+
+        // and extracts into the same tree
+        let out = dir.path().join("out");
+        copy_from_tar(path_str(&archive), path_str(&out), quiet_opts()).unwrap();
+        let a = out.join("tree/a.txt");
+        assert_eq!(std::fs::read(&a).unwrap(), b"alpha");
+        let a_stat = std::fs::metadata(&a).unwrap();
+        assert_eq!(a_stat.mode() & 0o777, 0o640);
+        assert_eq!(a_stat.modified().unwrap(), mtime);
+        assert_eq!(
+            std::fs::read(out.join("tree/sub/b.bin")).unwrap(),
+            vec![7u8; 100_000]
+        );
+        assert!(out.join("tree/empty").is_dir());
+        assert_eq!(
+            std::fs::read_link(out.join("tree/link")).unwrap(),
+            Path::new("a.txt")
+        );
+    }
+
+    #[test]
+    fn tar_extraction_keeps_entries_inside_the_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("evil.tar");
+        let mut builder = tar::Builder::new(File::create(&archive).unwrap());
+        let mut append = |name: &[u8], kind: tar::EntryType, link: &[u8], data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            let gnu = header.as_gnu_mut().unwrap();
+            gnu.name[..name.len()].copy_from_slice(name);
+            gnu.linkname[..link.len()].copy_from_slice(link);
+            header.set_entry_type(kind);
+            header.set_mode(0o644);
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        };
+        append(b"../escaped", tar::EntryType::Regular, b"", b"evil");
+        append(b"up", tar::EntryType::Symlink, b"..", b"");
+        append(b"up/through-link", tar::EntryType::Regular, b"", b"evil");
+        let outside = dir.path().join("outside");
+        let outside_name = path_str(&outside).as_bytes().to_vec();
+        append(b"evil", tar::EntryType::Symlink, &outside_name, b"");
+        append(b"evil", tar::EntryType::Regular, b"", b"evil");
+        append(b"ok.txt", tar::EntryType::Regular, b"", b"fine");
+        builder.finish().unwrap();
+        drop(builder);
+
+        let dst = dir.path().join("dst");
+        let (copy_opts, _, err) = captured_opts();
+        copy_from_tar(path_str(&archive), path_str(&dst), copy_opts).unwrap();
+
+        assert_eq!(std::fs::read(dst.join("ok.txt")).unwrap(), b"fine");
+        assert!(!dir.path().join("escaped").exists());
+        assert!(!dir.path().join("through-link").exists());
+        assert_eq!(std::fs::read(dst.join("up/through-link")).unwrap(), b"evil");
+        assert!(!outside.exists());
+        assert_eq!(std::fs::read(dst.join("evil")).unwrap(), b"evil");
+        let err = err.text();
+        assert!(err.contains("'../escaped' which points outside"), "{}", err);
+        assert!(err.contains("symlink 'up' to '..' which points"), "{}", err);
+        assert!(err.contains("symlink 'evil' to '"), "{}", err);
+    }
+
+    #[test]
+    fn tar_extraction_replaces_an_existing_symlink_instead_of_following_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("a.tar");
+        let mut builder = tar::Builder::new(File::create(&archive).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_path("f").unwrap();
+        header.set_mode(0o644);
+        header.set_size(4);
+        header.set_cksum();
+        builder.append(&header, &b"data"[..]).unwrap();
+        builder.finish().unwrap();
+        drop(builder);
+
+        let dst = dir.path().join("dst");
+        let target = dir.path().join("target");
+        std::fs::create_dir(&dst).unwrap();
+        std::fs::write(&target, "kept").unwrap();
+        std::os::unix::fs::symlink(&target, dst.join("f")).unwrap();
+
+        let err = copy_from_tar(path_str(&archive), path_str(&dst), quiet_opts()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.force(true);
+        copy_from_tar(path_str(&archive), path_str(&dst), copy_opts).unwrap();
+        assert!(!dst.join("f").is_symlink());
+        assert_eq!(std::fs::read(dst.join("f")).unwrap(), b"data");
+        assert_eq!(std::fs::read(&target).unwrap(), b"kept");
+    }
+
+    #[test]
+    fn file_in_destination_path_is_reported_before_copying() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("s");
+        std::fs::create_dir(&src).unwrap();
+        std::fs::write(src.join("x"), "x").unwrap();
+        std::fs::write(dir.path().join("blocker"), "").unwrap();
+        let dst = dir.path().join("blocker/inner/d");
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.recursive(true);
+        let err = copy(path_str(&src), path_str(&dst), copy_opts).unwrap_err();
+        let expected = format!(
+            "path component '{}' is a file, not a directory",
+            path_str(&dir.path().join("blocker"))
+        );
+        assert_eq!(err.to_string(), expected);
+        assert_eq!(std::fs::read(dir.path().join("blocker")).unwrap(), b"");
+    }
+
+    /// Returns the holes of the file at `path` as `(start, end)` offsets.
+    fn holes(path: &Path) -> Vec<(u64, u64)> {
+        use std::os::unix::io::AsRawFd;
+
+        let file = File::open(path).unwrap();
+        let len = file.metadata().unwrap().len() as i64;
+        let mut holes = Vec::new();
+        let mut offset = 0;
+        while offset < len {
+            // SAFETY: the descriptor is valid for the lifetime of `file`
+            let hole = unsafe { libc::lseek(file.as_raw_fd(), offset, libc::SEEK_HOLE) };
+            if hole < 0 || hole >= len {
+                break;
+            }
+            // SAFETY: the descriptor is valid for the lifetime of `file`
+            let data = unsafe { libc::lseek(file.as_raw_fd(), hole, libc::SEEK_DATA) };
+            let end = if data < 0 { len } else { data };
+            holes.push((hole as u64, end as u64));
+            offset = end;
+        }
+        holes
+    }
+
+    #[test]
+    fn sparse_copy_punches_only_large_holes() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("f");
+        let kb = util::KB as usize;
+        let mut data = Vec::new();
+        for zeros in [16 * kb, 16 * kb, util::MB as usize] {
+            data.extend(vec![1u8; 64 * kb]);
+            data.extend(vec![0u8; zeros]);
+        }
+        data.extend(vec![1u8; 64 * kb]);
+        std::fs::write(&src, &data).unwrap();
+
+        let dst = dir.path().join("large");
+        let mut copy_opts = quiet_opts();
+        copy_opts.sparse(true).sparse_min_hole(256 * util::KB);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        let hole_start = 3 * 64 * util::KB + 2 * 16 * util::KB;
+        assert_eq!(holes(&dst), [(hole_start, hole_start + util::MB)]);
+        let allocated = std::fs::metadata(&dst).unwrap().blocks() * 512;
+        assert!(allocated <= data.len() as u64 - util::MB);
+        assert!(allocated >= data.len() as u64 - util::MB - 4 * util::KB);
+
+        // below the threshold, the tiny zero runs become holes as well
+        let dst = dir.path().join("all");
+        let mut copy_opts = quiet_opts();
+        copy_opts.sparse(true).sparse_min_hole(4 * util::KB);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        assert_eq!(holes(&dst).len(), 3);
+    }
+
+    #[test]
+    fn permission_mismatch_warns_or_fails() {
+        let dst = Path::new("/mnt/fat/f");
+        let (mut copy_opts, _, err) = captured_opts();
+        copy_opts.verify_perms(PermVerify::Warn);
+        verify_mode(0o100640, 0o100640, dst, &copy_opts).unwrap();
+        assert_eq!(err.text(), "");
+        verify_mode(0o100640, 0o100755, dst, &copy_opts).unwrap();
+        assert!(err.text().contains(
+            "Warning: permissions of '/mnt/fat/f' are 755 instead of 640, destination can't hold the source permissions"
+        ));
+
+        copy_opts.verify_perms(PermVerify::Strict);
+        let e = verify_mode(0o100640, 0o100755, dst, &copy_opts).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn verified_permissions_of_a_copy_match() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("f"), dir.path().join("g"));
+        std::fs::write(&src, "data").unwrap();
+        std::fs::set_permissions(&src, std::fs::Permissions::from_mode(0o604)).unwrap();
+
+        let (mut copy_opts, _, err) = captured_opts();
+        copy_opts.verify_perms(PermVerify::Strict);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(std::fs::metadata(&dst).unwrap().mode() & 0o7777, 0o604);
+        assert!(!err.text().contains("Warning"));
+    }
+
+    #[test]
+    fn null_separated_listing_keeps_odd_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("s");
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        for name in ["with space", "new\nline", "sub/plain"] {
+            std::fs::write(src.join(name), name).unwrap();
+        }
+
+        let (mut copy_opts, out, _) = captured_opts();
+        copy_opts
+            .recursive(true)
+            .list_only(true)
+            .null_separated(true);
+        let dst = dir.path().join("d");
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        let text = out.text();
+        assert!(text.ends_with('\0'));
+        let listed: std::collections::BTreeSet<_> = text
+            .split_terminator('\0')
+            .map(std::path::PathBuf::from)
+            .collect();
+        let expected: std::collections::BTreeSet<_> = ["with space", "new\nline", "sub/plain"]
+            .iter()
+            .map(|name| src.join(name))
+            .collect();
+        assert_eq!(listed, expected);
+        assert!(!dst.exists());
+    }
+
+    #[test]
+    fn suffix_and_prefix_rename_only_the_file_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("x.txt"), "x").unwrap();
+        std::fs::write(src.join("sub/y"), "y").unwrap();
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.recursive(true).name_suffix(".bak");
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(std::fs::read(dst.join("x.txt.bak")).unwrap(), b"x");
+        assert_eq!(std::fs::read(dst.join("sub/y.bak")).unwrap(), b"y");
+        assert!(!dst.join("x.txt").exists());
+
+        let single = dir.path().join("single");
+        std::fs::create_dir(&single).unwrap();
+        let mut copy_opts = quiet_opts();
+        copy_opts.name_prefix("old_");
+        copy(path_str(&src.join("x.txt")), path_str(&single), copy_opts).unwrap();
+        assert_eq!(std::fs::read(single.join("old_x.txt")).unwrap(), b"x");
+    }
+
+    #[test]
+    fn suffixed_name_colliding_with_a_directory_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir_all(src.join("a.bak")).unwrap();
+        std::fs::write(src.join("a"), "a").unwrap();
+        std::fs::write(src.join("a.bak/f"), "f").unwrap();
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.recursive(true).name_suffix(".bak");
+        let err = copy(path_str(&src), path_str(&dst), copy_opts).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "multiple sources map to the destination '{}'",
+                path_str(&dst.join("a.bak"))
+            )
+        );
+        assert!(!dst.join("a.bak/f.bak").exists());
+    }
+
+    #[test]
+    fn current_speed_follows_rate_change_while_average_lags() {
+        let mut copy_opts = quiet_opts();
+        copy_opts.show_current_speed(true);
+        let second = std::time::Duration::from_secs(1);
+        let now = std::time::Instant::now();
+        let stats = &mut copy_opts.stats_store;
+        stats.total = 100 * util::MB;
+        stats.start_time = Some(now - 10 * second);
+
+        // 1 MiB/s for the first eight seconds
+        stats.transferred = 8 * util::MB;
+        stats.rate_window = [
+            (now - 4 * second, 4 * util::MB),
+            (now - 2 * second, 6 * util::MB),
+            (now, 8 * util::MB),
+        ]
+        .into();
+        assert_eq!(stats.current_rate(), Some(util::MB));
+        let line = full_progress_line(Path::new("f"), 0, 0, &copy_opts);
+        assert!(line
+            .tail
+            .contains(&format!("Speed: {:>9}", get_str_rate(Some(util::MB)))));
+
+        // then 10 MiB/s, which the average only slowly catches up with
+        let stats = &mut copy_opts.stats_store;
+        stats.transferred = 28 * util::MB;
+        stats.rate_window = [(now - 2 * second, 8 * util::MB), (now, 28 * util::MB)].into();
+        assert_eq!(stats.current_rate(), Some(10 * util::MB));
+        let average = stats.average_rate().unwrap();
+        assert!(average > util::MB && average < 3 * util::MB);
+        let line = full_progress_line(Path::new("f"), 0, 0, &copy_opts);
+        assert!(line
+            .tail
+            .contains(&format!("Speed: {:>9}", get_str_rate(Some(10 * util::MB)))));
+        assert!(!line
+            .tail
+            .contains(&format!("(avg {:>9})", get_str_rate(Some(10 * util::MB)))));
+
+        copy_opts.show_current_speed(false);
+        let line = full_progress_line(Path::new("f"), 0, 0, &copy_opts);
+        assert!(line.tail.is_empty());
+    }
+
+    #[test]
+    fn too_long_destination_paths_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("f");
+        std::fs::write(&src, "data").unwrap();
+
+        let long_name = "n".repeat(300);
+        let err = copy(
+            path_str(&src),
+            path_str(&dir.path().join(&long_name)),
+            quiet_opts(),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "file name '{}' in destination path is too long: 300 bytes, limit is 255 bytes",
+                long_name
+            )
+        );
+
+        let deep = dir.path().join("d/".repeat(2100));
+        let err = copy(path_str(&src), path_str(&deep), quiet_opts()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("is too long"));
+        assert!(err.to_string().ends_with("limit is 4095 bytes"));
+        assert!(!dir.path().join("d").exists());
+    }
+
+    #[test]
+    fn long_names_are_shortened_on_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("f");
+        std::fs::write(&src, "data").unwrap();
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.truncate_long_names(true);
+        let dst = dir.path().join("d");
+        std::fs::create_dir(&dst).unwrap();
+        copy(
+            path_str(&src),
+            path_str(&dst.join("n".repeat(300))),
+            copy_opts,
+        )
+        .unwrap();
+        let names: Vec<_> = std::fs::read_dir(&dst)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(names.len(), 1);
+        assert_eq!(names[0].len(), 255);
+        assert!(names[0].starts_with(&"n".repeat(238)));
+        assert_eq!(std::fs::read(dst.join(&names[0])).unwrap(), b"data");
+    }
+
+    #[test]
+    fn direct_io_copy_handles_unaligned_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("f"), dir.path().join("g"));
+        let data = random_bytes(3 * util::MB as usize + 1234);
+        std::fs::write(&src, &data).unwrap();
+        let supported = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(dir.path().join("probe"))
+            .is_ok();
+
+        let (mut copy_opts, _, err) = captured_opts();
+        copy_opts.direct_io(true).block_size(util::MB);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        // without O_DIRECT support the copy falls back to buffered I/O
+        assert_eq!(
+            err.text().contains("direct I/O is not supported"),
+            !supported
+        );
+    }
+
+    #[test]
+    fn fadvise_copy_keeps_the_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir(&src).unwrap();
+        let data = random_bytes(5 * util::MB as usize + 17);
+        std::fs::write(src.join("big"), &data).unwrap();
+        std::fs::write(src.join("empty"), "").unwrap();
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.recursive(true).fadvise(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(std::fs::read(dst.join("big")).unwrap(), data);
+        assert_eq!(std::fs::read(dst.join("empty")).unwrap(), b"");
+    }
+
+    /// Progress handler which removes the source file `b` while `a` is copied.
+    fn remove_sibling(src: &Path, _dst: &Path, _bytes: u64, _total: u64, _: &CopyOptions) {
+        if src.file_name() == Some(std::ffi::OsStr::new("a")) {
+            let _ = std::fs::remove_file(src.with_file_name("b"));
+        }
+    }
+
+    #[test]
+    fn vanished_sources_are_skipped_when_tolerated() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir(&src).unwrap();
+        for name in ["a", "b", "c"] {
+            std::fs::write(src.join(name), name).unwrap();
+        }
+
+        let (mut copy_opts, out, err) = captured_opts();
+        copy_opts
+            .recursive(true)
+            .sort(SortOrder::Name)
+            .progress(true)
+            .progress_handler(remove_sibling)
+            .stats(true)
+            .tolerate_vanished(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert!(err.text().contains(&format!(
+            "Warning: file '{}' has vanished, skipping",
+            path_str(&src.join("b"))
+        )));
+        assert!(out.text().contains("Vanished files: 1"));
+        assert_eq!(std::fs::read(dst.join("a")).unwrap(), b"a");
+        assert_eq!(std::fs::read(dst.join("c")).unwrap(), b"c");
+        assert!(!dst.join("b").exists());
+    }
+
+    #[test]
+    fn vanished_sources_fail_the_copy_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir(&src).unwrap();
+        for name in ["a", "b", "c"] {
+            std::fs::write(src.join(name), name).unwrap();
+        }
+
+        let mut copy_opts = quiet_opts();
+        copy_opts
+            .recursive(true)
+            .sort(SortOrder::Name)
+            .progress(true)
+            .progress_handler(remove_sibling);
+        let err = copy(path_str(&src), path_str(&dst), copy_opts).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn concat_joins_the_sources_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let parts = [
+            random_bytes(300 * 1024),
+            b"middle".to_vec(),
+            random_bytes(70 * 1024 + 3),
+        ];
+        let mut srcs = Vec::new();
+        for (i, data) in parts.iter().enumerate() {
+            let path = dir.path().join(format!("p{}", i));
+            std::fs::write(&path, data).unwrap();
+            srcs.push(path);
+        }
+        std::fs::set_permissions(&srcs[0], std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        let dst = dir.path().join("joined");
+        let src_strs: Vec<&str> = srcs.iter().map(|p| path_str(p)).collect();
+        concat(&src_strs, path_str(&dst), quiet_opts()).unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), parts.concat());
+        assert_eq!(std::fs::metadata(&dst).unwrap().mode() & 0o777, 0o640);
+    }
+
+    /// Sets the extended attribute `name` of `path`, returning `false` if the
+    /// filesystem or the system doesn't support it.
+    fn set_xattr(path: &Path, name: &std::ffi::CStr, value: &[u8]) -> bool {
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+        // SAFETY: `path` and `name` are NUL terminated and `value` holds
+        // `value.len()` bytes
+        let ret = unsafe {
+            libc::lsetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        ret == 0
+    }
+
+    /// Returns the extended attribute `name` of `path`, if it is set.
+    fn get_xattr(path: &Path, name: &std::ffi::CStr) -> Option<Vec<u8>> {
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+        let mut value = vec![0u8; 4096];
+        // SAFETY: `path` and `name` are NUL terminated and `value` has room
+        // for `value.len()` bytes
+        let len = unsafe {
+            libc::lgetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr() as *mut libc::c_void,
+                value.len(),
+            )
+        };
+        if len < 0 {
+            return None;
+        }
+        value.truncate(len as usize);
+        Some(value)
+    }
+
+    #[test]
+    fn selinux_context_is_carried_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("f"), dir.path().join("g"));
+        std::fs::write(&src, "data").unwrap();
+        let context = b"system_u:object_r:user_home_t:s0\0";
+        if !set_xattr(&src, c"security.selinux", context) {
+            // without SELinux support the option is a no-op
+            let mut copy_opts = quiet_opts();
+            copy_opts.selinux(true);
+            copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+            return;
+        }
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.selinux(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(
+            get_xattr(&dst, c"security.selinux").as_deref(),
+            Some(&context[..])
+        );
+
+        let plain = dir.path().join("plain");
+        copy(path_str(&src), path_str(&plain), quiet_opts()).unwrap();
+        assert_ne!(
+            get_xattr(&plain, c"security.selinux").as_deref(),
+            Some(&context[..])
+        );
+    }
+
+    /// Returns the number following `"key": ` in the JSON object `json`.
+    fn json_number(json: &str, key: &str) -> f64 {
+        let start = json.find(&format!("\"{}\": ", key)).unwrap() + key.len() + 4;
+        let len = json[start..].find([',', ' ', '}']).unwrap();
+        json[start..start + len].parse().unwrap()
+    }
+
+    #[test]
+    fn progress_file_shows_increasing_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("f"), dir.path().join("g"));
+        let progress = dir.path().join("progress.json");
+        let size = 2 * util::MB;
+        std::fs::write(&src, vec![5u8; size as usize]).unwrap();
+
+        let mut copy_opts = quiet_opts();
+        copy_opts
+            .block_size(256 * util::KB)
+            .limit_rate(util::MB)
+            .progress_file(Some(&progress));
+        let (src_arg, dst_arg) = (path_str(&src).to_owned(), path_str(&dst).to_owned());
+        let copier = std::thread::spawn(move || copy(&src_arg, &dst_arg, copy_opts));
+
+        let mut snapshots = Vec::new();
+        while !copier.is_finished() {
+            if let Ok(json) = std::fs::read_to_string(&progress) {
+                snapshots.push(json);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        copier.join().unwrap().unwrap();
+        assert!(!progress.exists());
+
+        assert!(snapshots.len() >= 2);
+        let mut last = 0.0;
+        for json in &snapshots {
+            assert!(
+                json.starts_with("{ \"file\": ") && json.ends_with(" }\n"),
+                "{}",
+                json
+            );
+            assert!(json.contains(&format!("\"file\": \"{}\"", path_str(&src))));
+            assert_eq!(json_number(json, "total"), size as f64);
+            let transferred = json_number(json, "transferred");
+            assert!(transferred >= last && transferred <= size as f64);
+            let percent = json_number(json, "overall_percent");
+            assert!((percent - transferred * 100.0 / size as f64).abs() < 0.01);
+            json_number(json, "rate");
+            last = transferred;
+        }
+        assert!(last > 0.0);
+    }
+
+    #[test]
+    fn verify_only_reports_a_single_differing_byte() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        let data = random_bytes(100 * 1024);
+        std::fs::write(src.join("sub/f"), &data).unwrap();
+        std::fs::write(src.join("g"), "same").unwrap();
+        let mut copy_opts = quiet_opts();
+        copy_opts.recursive(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+
+        let (mut copy_opts, out, _) = captured_opts();
+        copy_opts.recursive(true).verify_only(true).checksum(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert!(out
+            .text()
+            .contains("Verified 2 files, no differences found"));
+
+        let mut changed = data.clone();
+        changed[54321] ^= 1;
+        std::fs::write(dst.join("sub/f"), &changed).unwrap();
+        let (mut copy_opts, out, _) = captured_opts();
+        copy_opts.recursive(true).verify_only(true).checksum(true);
+        let err = copy(path_str(&src), path_str(&dst), copy_opts).unwrap_err();
+        assert_eq!(err.to_string(), "verification failed: 1 of 2 files differ");
+        let expected = format!("Checksum mismatch: '{}'\n", path_str(&dst.join("sub/f")));
+        assert_eq!(out.text(), expected);
+        // nothing is written by the verification
+        assert_eq!(std::fs::read(dst.join("sub/f")).unwrap(), changed);
+    }
+
+    #[test]
+    fn verify_only_reports_missing_and_extraneous_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir(&src).unwrap();
+        std::fs::create_dir(&dst).unwrap();
+        std::fs::write(src.join("a"), "a").unwrap();
+        std::fs::write(dst.join("b"), "b").unwrap();
+
+        let (mut copy_opts, out, _) = captured_opts();
+        copy_opts
+            .recursive(true)
+            .no_target_directory(true)
+            .verify_only(true);
+        assert!(copy(path_str(&src), path_str(&dst), copy_opts).is_err());
+        assert!(out
+            .text()
+            .contains(&format!("Missing: '{}'", path_str(&dst.join("a")))));
+        assert!(out
+            .text()
+            .contains(&format!("Extraneous: '{}'", path_str(&dst.join("b")))));
+        assert!(!dst.join("a").exists());
+    }
+
+    #[test]
+    fn progress_is_rendered_into_the_progress_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("report.txt"), dir.path().join("g"));
+        std::fs::write(&src, vec![b'r'; 3000]).unwrap();
+
+        let progress = SharedBuf::default();
+        let (mut copy_opts, out, _) = captured_opts();
+        copy_opts
+            .progress(true)
+            .progress_output(Box::new(progress.clone()));
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        let text = progress.text();
+        let size = get_str_size_precise(3000);
+        assert!(text.contains("Copying file 'report.txt'"), "{}", text);
+        assert!(text.contains(&format!("({:>8} /{:>8})", size, size)));
+        assert!(text.contains(&format!("Total: ({:>8} /{:>8})", size, size)));
+        assert!(!out.text().contains("report.txt"));
+    }
+
+    #[test]
+    fn pipe_is_copied_without_size_verification() {
+        let dir = tempfile::tempdir().unwrap();
+        let (fifo, dst) = (dir.path().join("fifo"), dir.path().join("g"));
+        let fifo_path = std::ffi::CString::new(path_str(&fifo)).unwrap();
+        // SAFETY: `fifo_path` is a valid NUL terminated path
+        assert_eq!(unsafe { libc::mkfifo(fifo_path.as_ptr(), 0o644) }, 0);
+        let data = random_bytes(700 * 1024 + 5);
+
+        let writer = {
+            let (fifo, data) = (fifo.clone(), data.clone());
+            std::thread::spawn(move || std::fs::write(fifo, data).unwrap())
+        };
+        let mut copy_opts = quiet_opts();
+        copy_opts.verify_size(false);
+        copy(path_str(&fifo), path_str(&dst), copy_opts).unwrap();
+        writer.join().unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+    }
+
+    #[test]
+    fn pseudo_fs_mounts_are_skipped_unless_requested() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("s");
+        std::fs::create_dir_all(src.join("proc")).unwrap();
+        std::fs::create_dir_all(src.join("data")).unwrap();
+        std::fs::write(src.join("proc/stat"), "cpu").unwrap();
+        std::fs::write(src.join("data/f"), "f").unwrap();
+        let base = std::fs::canonicalize(&src).unwrap();
+        let mount_table = dir.path().join("mounts");
+        std::fs::write(
+            &mount_table,
+            format!(
+                "/dev/vda / ext4 rw 0 0\nproc {} proc rw 0 0\ntmpfs {} tmpfs rw 0 0\n",
+                path_str(&base.join("proc")),
+                path_str(&base.join("data"))
+            ),
+        )
+        .unwrap();
+
+        let (mut copy_opts, out, _) = captured_opts();
+        copy_opts.verbose(true);
+        let skip_dirs = pseudo_fs_dirs_in(&src, &mount_table, &copy_opts);
+        assert_eq!(skip_dirs, [Path::new("proc")]);
+        assert!(out.text().contains(&format!(
+            "Skipping proc filesystem mounted at '{}'",
+            path_str(&base.join("proc"))
+        )));
+        let files =
+            util::list_dir_recursive_rel(&src, SortOrder::Name, &skip_dirs, None, &mut Vec::new())
+                .unwrap();
+        let paths: Vec<_> = files.iter().map(|f| f.path()).collect();
+        assert_eq!(paths, ["data/f"]);
+
+        // a source inside of the mount is skipped as a whole
+        let inside = pseudo_fs_dirs_in(&src.join("proc"), &mount_table, &copy_opts);
+        assert_eq!(inside, [Path::new("")]);
+
+        copy_opts.copy_contents(true);
+        assert!(pseudo_fs_dirs_in(&src, &mount_table, &copy_opts).is_empty());
+    }
+
+    /// Encodes a POSIX ACL in the format of the `system.posix_acl_*`
+    /// extended attributes from `(tag, permissions)` pairs without qualifier.
+    fn acl_xattr(entries: &[(u16, u16)]) -> Vec<u8> {
+        let mut value = 2u32.to_le_bytes().to_vec();
+        for (tag, perm) in entries {
+            value.extend(tag.to_le_bytes());
+            value.extend(perm.to_le_bytes());
+            value.extend(u32::MAX.to_le_bytes());
+        }
+        value
+    }
+
+    #[test]
+    fn default_acl_of_directories_is_preserved() {
+        const USER_OBJ: u16 = 0x01;
+        const GROUP_OBJ: u16 = 0x04;
+        const OTHER: u16 = 0x20;
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("sub/f"), "f").unwrap();
+        let acl = acl_xattr(&[(USER_OBJ, 7), (GROUP_OBJ, 5), (OTHER, 0)]);
+        if !set_xattr(&src.join("sub"), c"system.posix_acl_default", &acl) {
+            // the filesystem doesn't support ACLs
+            return;
+        }
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.recursive(true).acl(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(
+            get_xattr(&dst.join("sub"), c"system.posix_acl_default"),
+            Some(acl)
+        );
+        assert_eq!(get_xattr(&dst, c"system.posix_acl_default"), None);
+
+        let plain = dir.path().join("plain");
+        let mut copy_opts = quiet_opts();
+        copy_opts.recursive(true);
+        copy(path_str(&src), path_str(&plain), copy_opts).unwrap();
+        assert_eq!(
+            get_xattr(&plain.join("sub"), c"system.posix_acl_default"),
+            None
+        );
+    }
+
+    #[test]
+    fn free_space_check_stops_before_the_threshold() {
+        let dst = Path::new("/nonexistent");
+        let mut copy_opts = quiet_opts();
+        copy_opts.min_free_space(8 * util::MB);
+        copy_opts.stats_store.total = 4 * util::MB;
+        // free space as last read from the destination
+        copy_opts.stats_store.free_space = Some((std::time::Instant::now(), 10 * util::MB, 0));
+
+        check_free_space(dst, util::MB, &mut copy_opts).unwrap();
+        copy_opts.stats_store.transferred = util::MB;
+        check_free_space(dst, util::MB, &mut copy_opts).unwrap();
+        copy_opts.stats_store.transferred = 3 * util::MB / 2;
+        let err = check_free_space(dst, util::MB, &mut copy_opts).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "stopped before the free space on the destination drops below {}, {} left, copied {} of {}",
+                get_str_size_precise(8 * util::MB),
+                get_str_size_precise(17 * util::MB / 2),
+                get_str_size_precise(3 * util::MB / 2),
+                get_str_size_precise(4 * util::MB)
+            )
+        );
+    }
+
+    #[test]
+    fn dedupe_hard_links_identical_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir(&src).unwrap();
+        let data = random_bytes(64 * 1024);
+        std::fs::write(src.join("a"), &data).unwrap();
+        std::fs::write(src.join("b"), &data).unwrap();
+        std::fs::write(src.join("c"), "different").unwrap();
+
+        let (mut copy_opts, out, _) = captured_opts();
+        copy_opts
+            .recursive(true)
+            .sort(SortOrder::Name)
+            .dedupe(true)
+            .stats(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        let ino = |name| std::fs::metadata(dst.join(name)).unwrap().ino();
+        assert_eq!(ino("a"), ino("b"));
+        assert_ne!(ino("a"), ino("c"));
+        assert_eq!(std::fs::metadata(dst.join("a")).unwrap().nlink(), 2);
+        assert_eq!(std::fs::read(dst.join("b")).unwrap(), data);
+        assert!(out.text().contains(&format!(
+            "Deduplicated files: 1 ({} saved)",
+            get_str_size_precise(64 * 1024)
+        )));
+    }
+
+    #[test]
+    fn broken_symlinks_are_handled_per_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("s");
+        std::fs::create_dir(&src).unwrap();
+        std::fs::write(src.join("x"), "x").unwrap();
+        std::os::unix::fs::symlink("missing", src.join("dangling")).unwrap();
+
+        let dst = dir.path().join("copy");
+        let mut copy_opts = quiet_opts();
+        copy_opts
+            .recursive(true)
+            .broken_symlinks(BrokenSymlinks::Copy);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(
+            std::fs::read_link(dst.join("dangling")).unwrap(),
+            Path::new("missing")
+        );
+        assert!(dst.join("x").is_file());
+
+        let dst = dir.path().join("skip");
+        let (mut copy_opts, _, err) = captured_opts();
+        copy_opts
+            .recursive(true)
+            .broken_symlinks(BrokenSymlinks::Skip);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert!(std::fs::symlink_metadata(dst.join("dangling")).is_err());
+        assert!(dst.join("x").is_file());
+        assert!(err.text().contains(&format!(
+            "Warning: skipping broken symlink '{}'",
+            path_str(&src.join("dangling"))
+        )));
+
+        let dst = dir.path().join("error");
+        let mut copy_opts = quiet_opts();
+        copy_opts
+            .recursive(true)
+            .broken_symlinks(BrokenSymlinks::Error);
+        let e = copy(path_str(&src), path_str(&dst), copy_opts).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(e.to_string().contains(&format!(
+            "source '{}' is a broken symlink",
+            path_str(&src.join("dangling"))
+        )));
+    }
+
+    #[test]
+    fn broken_symlink_source_follows_the_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("dangling");
+        std::os::unix::fs::symlink("missing", &src).unwrap();
+
+        let dst = dir.path().join("copy");
+        let mut copy_opts = quiet_opts();
+        copy_opts.broken_symlinks(BrokenSymlinks::Copy);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(std::fs::read_link(&dst).unwrap(), Path::new("missing"));
+
+        let dst = dir.path().join("skip");
+        let mut copy_opts = quiet_opts();
+        copy_opts.broken_symlinks(BrokenSymlinks::Skip);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert!(std::fs::symlink_metadata(&dst).is_err());
+
+        let dst = dir.path().join("error");
+        let mut copy_opts = quiet_opts();
+        copy_opts.broken_symlinks(BrokenSymlinks::Error);
+        assert!(copy(path_str(&src), path_str(&dst), copy_opts).is_err());
+        assert!(std::fs::symlink_metadata(&dst).is_err());
+    }
+
+    #[test]
+    fn stall_indicator_appears_and_clears() {
+        let dir = tempfile::tempdir().unwrap();
+        let (fifo, dst) = (dir.path().join("fifo"), dir.path().join("g"));
+        let fifo_path = std::ffi::CString::new(path_str(&fifo)).unwrap();
+        // SAFETY: `fifo_path` is a valid NUL terminated path
+        assert_eq!(unsafe { libc::mkfifo(fifo_path.as_ptr(), 0o644) }, 0);
+
+        // the writer pauses between two chunks of data
+        let writer = {
+            let fifo = fifo.clone();
+            std::thread::spawn(move || {
+                let mut pipe = File::create(fifo).unwrap();
+                pipe.write_all(&[1u8; 4096]).unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(2500));
+                pipe.write_all(&[2u8; 4096]).unwrap();
+            })
+        };
+        let progress = SharedBuf::default();
+        let mut copy_opts = quiet_opts();
+        copy_opts
+            .verify_size(false)
+            .block_size(4096)
+            .progress(true)
+            .progress_output(Box::new(progress.clone()))
+            .progress_stall_indicator(Some(std::time::Duration::from_secs(1)));
+        copy(path_str(&fifo), path_str(&dst), copy_opts).unwrap();
+        writer.join().unwrap();
+        assert_eq!(std::fs::metadata(&dst).unwrap().len(), 8192);
+
+        let text = progress.text();
+        let stalled = text.rfind(" (stalled ").expect("no stalled indicator");
+        let rest = &text[stalled..];
+        assert!(rest.starts_with(" (stalled 1s)") || rest.starts_with(" (stalled 2s)"));
+        // once data flows again, the next line is padded over the indicator
+        let next = &rest[rest.find('\r').unwrap()..];
+        assert!(next.contains(&format!("{}\n", " ".repeat(StallMonitor::SUFFIX_WIDTH))));
+        assert!(!next.contains("stalled"));
+    }
+
+    #[test]
+    fn preserved_times_keep_the_requested_precision() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("f");
+        std::fs::write(&src, "data").unwrap();
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::new(1_600_000_000, 123_456_789);
+        let atime = std::time::UNIX_EPOCH + std::time::Duration::new(1_600_000_100, 987_654_321);
+        File::options()
+            .write(true)
+            .open(&src)
+            .unwrap()
+            .set_times(
+                std::fs::FileTimes::new()
+                    .set_modified(mtime)
+                    .set_accessed(atime),
+            )
+            .unwrap();
+        if std::fs::metadata(&src).unwrap().mtime_nsec() != 123_456_789 {
+            // the filesystem can't hold nanosecond timestamps
+            return;
+        }
+
+        let dst = dir.path().join("nsec");
+        let mut copy_opts = quiet_opts();
+        copy_opts.preserve_times(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        let metadata = std::fs::metadata(&dst).unwrap();
+        assert_eq!(
+            (metadata.mtime(), metadata.mtime_nsec()),
+            (1_600_000_000, 123_456_789)
+        );
+        assert_eq!(
+            (metadata.atime(), metadata.atime_nsec()),
+            (1_600_000_100, 987_654_321)
+        );
+
+        let dst = dir.path().join("sec");
+        let mut copy_opts = quiet_opts();
+        copy_opts
+            .preserve_times(true)
+            .times_precision(TimesPrecision::Second);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        let metadata = std::fs::metadata(&dst).unwrap();
+        assert_eq!(
+            (metadata.mtime(), metadata.mtime_nsec()),
+            (1_600_000_000, 0)
+        );
+        // reading the source in the first copy may have updated its access time
+        assert_eq!(metadata.atime_nsec(), 0);
+    }
+
+    #[test]
+    fn block_verification_reports_the_corrupted_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src_path, dst_path) = (dir.path().join("f"), dir.path().join("g"));
+        let data = random_bytes(3 * 64 * 1024);
+        let mut corrupted = data.clone();
+        corrupted[64 * 1024 + 777] ^= 0xff;
+        std::fs::write(&src_path, &data).unwrap();
+        std::fs::write(&dst_path, &corrupted).unwrap();
+        let src = File::open(&src_path).unwrap();
+        let dst = File::options()
+            .read(true)
+            .write(true)
+            .open(&dst_path)
+            .unwrap();
+
+        let block = |i: usize| super::super::digest::sha256(&data[i * 65536..(i + 1) * 65536]);
+        verify_block(&src, &dst, block(0), 0, 65536, false).unwrap();
+        let err = verify_block(&src, &dst, block(1), 65536, 65536, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "checksum mismatch at offset 66313");
+        // the corrupted block is cut off for a resume to start from
+        assert_eq!(std::fs::metadata(&dst_path).unwrap().len(), 65536);
+    }
+
+    #[test]
+    fn block_verified_copy_matches_the_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("f"), dir.path().join("g"));
+        let data = random_bytes(util::MB as usize + 99);
+        std::fs::write(&src, &data).unwrap();
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.block_size(64 * util::KB).checksum_block(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+    }
+
+    #[test]
+    fn validate_rejects_incompatible_options() {
+        type Setup = fn(&mut CopyOptions) -> &mut CopyOptions;
+        let cases: [(Setup, &str); 26] = [
+            (|o| o.jobs(0), "number of jobs must be greater than 0"),
+            (
+                |o| o.list_only(true).verify_only(true),
+                "list only and verify only options can't be used together",
+            ),
+            (
+                |o| o.dry_run(true).list_only(true),
+                "dry run option can't be used with list only or verify only options",
+            ),
+            (
+                |o| o.dry_run(true).verify_only(true),
+                "dry run option can't be used with list only or verify only options",
+            ),
+            (
+                |o| o.verify_only(true).remove(true),
+                "verify only option can't be used when moving files",
+            ),
+            (
+                |o| o.sparse(true).direct_io(true),
+                "sparse copies can't be written with direct I/O",
+            ),
+            (
+                |o| o.ramp_up(Some(std::time::Duration::from_secs(1))),
+                "ramp up option requires a rate limit",
+            ),
+            (
+                |o| o.head(Some(1)).tail(Some(1)),
+                "head and tail options can't be used together",
+            ),
+            (
+                |o| o.head(Some(1)).offset(1),
+                "head and tail options can't be used with an offset or length",
+            ),
+            (
+                |o| o.tail(Some(1)).length(Some(1)),
+                "head and tail options can't be used with an offset or length",
+            ),
+            (
+                |o| o.offset(1).recursive(true),
+                "head, tail, offset and length options can't be used with recursive, repair, delta or unverified size copies",
+            ),
+            (
+                |o| o.head(Some(1)).verify_size(false),
+                "head, tail, offset and length options can't be used with recursive, repair, delta or unverified size copies",
+            ),
+            (
+                |o| o.offset(1).checksum_block(true),
+                "tail and offset options can't be used with block checksums",
+            ),
+            (
+                |o| o.truncate_source_after(true),
+                "truncate source after option can only be used when moving files",
+            ),
+            (
+                |o| o.wipe_source(Some(1)),
+                "wipe source option can only be used when moving files",
+            ),
+            (
+                |o| o.remove(true).wipe_source(Some(0)),
+                "number of wipe passes must be greater than 0",
+            ),
+            (
+                |o| o.sync_perms(true),
+                "sync perms option requires the update option",
+            ),
+            (
+                |o| o.keep_newest(Some(1)).remove(true),
+                "keep newest option can't be used when moving files",
+            ),
+            (
+                |o| o.require_empty_dest(true).resume(true),
+                "require empty destination option can't be used when resuming a copy",
+            ),
+            (
+                |o| o.transactional(true).remove(true),
+                "transactional copies can't be used when moving files or resuming a copy",
+            ),
+            (
+                |o| o.transactional(true).resume(true),
+                "transactional copies can't be used when moving files or resuming a copy",
+            ),
+            (
+                |o| o.repair(true).resume(true),
+                "repair option can't be used when resuming a copy",
+            ),
+            (
+                |o| o.delta(true).repair(true),
+                "delta option can't be used when resuming or repairing a copy",
+            ),
+            (
+                |o| o.strict_total(true).verify_size(false),
+                "strict total option can't be used without verifying the copied size",
+            ),
+            (
+                |o| o.order_from(Some(Path::new("order"))).order(CopyOrder::Name),
+                "order from option can't be used with a copy order or parallel jobs",
+            ),
+            (
+                |o| o.order_from(Some(Path::new("order"))).jobs(2),
+                "order from option can't be used with a copy order or parallel jobs",
+            ),
+        ];
+        for (setup, msg) in cases {
+            let mut copy_opts = CopyOptions::new();
+            setup(&mut copy_opts);
+            let err = copy_opts.validate().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            assert_eq!(err.to_string(), format!("invalid copy options: {}", msg));
+        }
+    }
+
+    #[test]
+    fn validate_accepts_compatible_options() {
+        let cases: [fn(&mut CopyOptions) -> &mut CopyOptions; 8] = [
+            |o| o,
+            |o| o.recursive(true).jobs(4).sparse(true),
+            |o| {
+                o.limit_rate(util::MB)
+                    .ramp_up(Some(std::time::Duration::from_secs(1)))
+            },
+            |o| o.head(Some(1)).checksum_block(true),
+            |o| o.offset(1).length(Some(10)),
+            |o| {
+                o.remove(true)
+                    .truncate_source_after(true)
+                    .wipe_source(Some(3))
+            },
+            |o| o.update(true).sync_perms(true),
+            |o| o.order_from(Some(Path::new("order"))),
+        ];
+        for setup in cases {
+            let mut copy_opts = CopyOptions::new();
+            setup(&mut copy_opts);
+            copy_opts.validate().unwrap();
+        }
+    }
+
+    #[test]
+    fn percent_progress_is_a_clean_increasing_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir(&src).unwrap();
+        for (i, size) in [300 * 1024, 5, 1024 * 1024, 0].into_iter().enumerate() {
+            std::fs::write(src.join(format!("f{}", i)), vec![1u8; size]).unwrap();
+        }
+
+        let (mut copy_opts, out, err) = captured_opts();
+        copy_opts
+            .recursive(true)
+            .verbose(true)
+            .block_size(64 * util::KB)
+            .progress(true)
+            .progress_format(ProgressFormat::Percent);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(err.text(), "");
+        let text = out.text();
+        let percents: Vec<u64> = text.lines().map(|l| l.parse().unwrap()).collect();
+        assert!(percents.len() > 2, "{}", text);
+        assert!(percents.windows(2).all(|w| w[0] < w[1]), "{}", text);
+        assert_eq!(percents.last(), Some(&100));
+    }
+
+    #[test]
+    fn idle_jobs_take_the_small_files_while_a_large_one_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir(&src).unwrap();
+        let giant = random_bytes(4 * util::MB as usize);
+        std::fs::write(src.join("giant"), &giant).unwrap();
+        for i in 0..20 {
+            std::fs::write(src.join(format!("tiny{}", i)), format!("{}", i)).unwrap();
+        }
+
+        // the rate limit keeps the job with the large file busy for a second
+        let (mut copy_opts, out, _) = captured_opts();
+        copy_opts
+            .recursive(true)
+            .verbose(true)
+            .jobs(2)
+            .limit_rate(4 * util::MB);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        let text = out.text();
+        let giant_size = get_str_size_precise(4 * util::MB);
+        assert!(
+            text.contains(&format!("copied {} in 1 files", giant_size)),
+            "{}",
+            text
+        );
+        assert!(text.contains(" in 20 files"), "{}", text);
+
+        assert_eq!(std::fs::read(dst.join("giant")).unwrap(), giant);
+        for i in 0..20 {
+            let tiny = std::fs::read_to_string(dst.join(format!("tiny{}", i))).unwrap();
+            assert_eq!(tiny, i.to_string());
+        }
+    }
+
+    /// Progress handler which truncates the source to 128 KiB once its first
+    /// 64 KiB are copied.
+    fn shrink_source(src: &Path, _dst: &Path, bytes: u64, _total: u64, _: &CopyOptions) {
+        if bytes == 64 * util::KB {
+            File::options()
+                .write(true)
+                .open(src)
+                .unwrap()
+                .set_len(128 * util::KB)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn shrinking_source_is_handled_per_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("f");
+        let data = random_bytes(util::MB as usize);
+
+        std::fs::write(&src, &data).unwrap();
+        let dst = dir.path().join("error");
+        let mut copy_opts = quiet_opts();
+        copy_opts
+            .block_size(64 * util::KB)
+            .progress(true)
+            .progress_handler(shrink_source);
+        let err = copy(path_str(&src), path_str(&dst), copy_opts).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "source file '{}' shrank from 1048576 to 131072 bytes during the copy",
+                path_str(&src)
+            )
+        );
+
+        std::fs::write(&src, &data).unwrap();
+        let dst = dir.path().join("truncate");
+        let (mut copy_opts, _, err) = captured_opts();
+        copy_opts
+            .block_size(64 * util::KB)
+            .progress(true)
+            .progress_handler(shrink_source)
+            .on_shrink(OnShrink::TruncateDest);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), &data[..128 * 1024]);
+        assert!(err.text().contains(
+            "shrank from 1048576 to 131072 bytes during the copy, destination truncated to 131072 bytes"
+        ));
+    }
+
+    #[test]
+    fn report_records_every_file_even_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir(&src).unwrap();
+        for name in ["a", "b", "c"] {
+            std::fs::write(src.join(name), name).unwrap();
+        }
+        // a directory in the way of `b` fails its copy
+        std::fs::create_dir_all(dst.join("b/x")).unwrap();
+
+        let report = dir.path().join("report.csv");
+        let mut copy_opts = quiet_opts();
+        copy_opts
+            .recursive(true)
+            .no_target_directory(true)
+            .dircopy_err(true)
+            .report(Some(&report));
+        let _ = copy(path_str(&src), path_str(&dst), copy_opts);
+        let content = std::fs::read_to_string(&report).unwrap();
+        let mut lines = content.lines();
+        assert_eq!(
+            lines.next(),
+            Some("source,destination,size,duration_ms,sha256,s3_etag,status,error")
+        );
+        let mut statuses: Vec<(String, String)> = lines
+            .map(|line| {
+                let fields: Vec<&str> = line.split(',').collect();
+                let name = Path::new(fields[0]).file_name().unwrap();
+                (name.to_str().unwrap().to_owned(), fields[6].to_owned())
+            })
+            .collect();
+        statuses.sort();
+        let expected = [("a", "copied"), ("b", "failed"), ("c", "copied")]
+            .map(|(name, status)| (name.to_owned(), status.to_owned()));
+        assert_eq!(statuses, expected);
+
+        // without ignoring errors, the report covers the files up to the failure
+        let json = dir.path().join("report.json");
+        let mut copy_opts = quiet_opts();
+        copy_opts
+            .recursive(true)
+            .no_target_directory(true)
+            .sort(SortOrder::Name)
+            .force(true)
+            .report(Some(&json));
+        assert!(copy(path_str(&src), path_str(&dst), copy_opts).is_err());
+        let content = std::fs::read_to_string(&json).unwrap();
+        assert!(content.starts_with("{\n  \"status\": \"failed\",\n  \"error\": \""));
+        assert_eq!(content.matches("\"status\": \"copied\"").count(), 1);
+        // the overall status and the record of `b`
+        assert_eq!(content.matches("\"status\": \"failed\"").count(), 2);
+    }
+
+    #[test]
+    fn keep_newest_copies_only_the_newest_matching_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        let base = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let hour = std::time::Duration::from_secs(3600);
+        // `e.log` and `d.log` tie, `d.log` wins by name
+        let files = [
+            ("a.log", 1),
+            ("b.log", 5),
+            ("c.log", 2),
+            ("d.log", 4),
+            ("e.log", 4),
+            ("newer.txt", 9),
+            ("sub/newest.log", 9),
+        ];
+        for (name, hours) in files {
+            let file = File::create(src.join(name)).unwrap();
+            file.set_modified(base + hour * hours).unwrap();
+        }
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.keep_newest(Some(2)).pattern("*.log");
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        let mut copied: Vec<_> = std::fs::read_dir(&dst)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        copied.sort();
+        assert_eq!(copied, ["b.log", "d.log"]);
+    }
+
+    #[test]
+    fn shell_dry_run_commands_parse_back_to_the_plan() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir_all(src.join("sub dir")).unwrap();
+        std::fs::write(src.join("with space"), "1").unwrap();
+        std::fs::write(src.join("it's \"quoted\""), "2").unwrap();
+        std::fs::write(src.join("sub dir/$HOME"), "3").unwrap();
+
+        let (mut copy_opts, out, _) = captured_opts();
+        copy_opts
+            .recursive(true)
+            .dry_run(true)
+            .dry_run_format(DryRunFormat::Shell);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert!(!dst.exists());
+
+        // let a shell split the commands into their arguments
+        let script = format!(
+            "cp() {{ printf 'cp'; printf '\\t%s' \"$@\"; echo; }}\nmkdir() {{ printf 'mkdir'; printf '\\t%s' \"$@\"; echo; }}\n{}",
+            out.text()
+        );
+        let parsed = std::process::Command::new("sh")
+            .args(["-c", &script])
+            .output()
+            .unwrap();
+        assert!(parsed.status.success());
+        let mut commands: Vec<Vec<String>> = String::from_utf8(parsed.stdout)
+            .unwrap()
+            .lines()
+            .map(|line| line.split('\t').map(str::to_owned).collect())
+            .collect();
+        commands.sort();
+
+        let command = |args: &[&Path]| -> Vec<String> {
+            args.iter()
+                .map(|p| p.to_str().unwrap().to_owned())
+                .collect()
+        };
+        let mut expected = vec![
+            command(&[Path::new("mkdir"), Path::new("-p"), Path::new("--"), &dst]),
+            command(&[
+                Path::new("mkdir"),
+                Path::new("-p"),
+                Path::new("--"),
+                &dst.join("sub dir"),
+            ]),
+        ];
+        for name in ["with space", "it's \"quoted\"", "sub dir/$HOME"] {
+            expected.push(command(&[
+                Path::new("cp"),
+                Path::new("--"),
+                &src.join(name),
+                &dst.join(name),
+            ]));
+        }
+        expected.sort();
+        assert_eq!(commands, expected);
+    }
+
+    #[test]
+    fn permission_failure_warns_or_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let dst = dir.path().join("f");
+        std::fs::write(&dst, "data").unwrap();
+        // changing the mode through an O_PATH descriptor fails with EBADF
+        let file = File::options()
+            .read(true)
+            .custom_flags(libc::O_PATH)
+            .open(&dst)
+            .unwrap();
+        let permissions = std::fs::Permissions::from_mode(0o600);
+
+        let (mut copy_opts, _, err) = captured_opts();
+        set_permissions(&file, &dst, permissions.clone(), &copy_opts).unwrap();
+        assert!(err.text().contains(&format!(
+            "Warning: failure in setting permissions of '{}'",
+            path_str(&dst)
+        )));
+        assert_eq!(std::fs::read(&dst).unwrap(), b"data");
+
+        copy_opts.on_perm_error(OnPermError::Error);
+        let e = set_permissions(&file, &dst, permissions, &copy_opts).unwrap_err();
+        assert!(e.to_string().starts_with(&format!(
+            "failure in setting permissions of '{}'",
+            path_str(&dst)
+        )));
+    }
+
+    thread_local! {
+        /// Names of the files in the order their progress was first reported,
+        /// kept per thread as the tests run in parallel
+        static PROGRESS_ORDER: std::cell::RefCell<Vec<String>> =
+            const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    fn record_order(src: &Path, _dst: &Path, _bytes: u64, _total: u64, _: &CopyOptions) {
+        let name = src.file_name().unwrap().to_str().unwrap().to_owned();
+        PROGRESS_ORDER.with_borrow_mut(|order| {
+            if order.last() != Some(&name) {
+                order.push(name);
+            }
+        });
+    }
+
+    #[test]
+    fn copy_order_follows_the_file_sizes() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("s");
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        for (name, size) in [("mid", 2000), ("sub/big", 5000), ("small", 10), ("tiny", 1)] {
+            std::fs::write(src.join(name), vec![0u8; size]).unwrap();
+        }
+
+        for (order, expected) in [
+            (CopyOrder::Largest, ["big", "mid", "small", "tiny"]),
+            (CopyOrder::Smallest, ["tiny", "small", "mid", "big"]),
+            (CopyOrder::Name, ["mid", "small", "big", "tiny"]),
+        ] {
+            PROGRESS_ORDER.take();
+            let mut copy_opts = quiet_opts();
+            copy_opts
+                .recursive(true)
+                .order(order)
+                .progress(true)
+                .progress_handler(record_order);
+            let dst = dir.path().join(format!("{:?}", order));
+            copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+            assert_eq!(PROGRESS_ORDER.take(), expected, "{:?}", order);
+        }
+    }
+
+    #[test]
+    fn multiline_progress_draws_a_line_per_job() {
+        let progress = SharedBuf::default();
+        let mut copy_opts = quiet_opts();
+        copy_opts.progress_output(Box::new(progress.clone()));
+        copy_opts.progress_output_tty = true;
+        let renderer = std::sync::Arc::new(MultiProgress::new(3, 0, 3000, &copy_opts));
+
+        std::thread::scope(|scope| {
+            for job in 0..3 {
+                let renderer = renderer.clone();
+                scope.spawn(move || {
+                    for bytes in (0..=1000).step_by(100) {
+                        renderer.update(
+                            job,
+                            JobProgress {
+                                file: Some(format!("file{}", job)),
+                                bytes,
+                                size: 1000,
+                                transferred: bytes,
+                            },
+                        );
+                    }
+                });
+            }
+        });
+        renderer.finish();
+
+        // every redraw moves up over the four lines of the previous one
+        let text = progress.text();
+        let draws: Vec<&str> = text.split("\x1b[4A").collect();
+        assert!(draws.len() >= 2);
+        for draw in &draws {
+            assert_eq!(draw.matches("\x1b[2K").count(), 4, "{:?}", draw);
+            assert_eq!(draw.lines().count(), 4);
+        }
+        let last = draws.last().unwrap();
+        for job in 1..=3 {
+            assert!(last.contains(&format!("Job {:>2}: idle", job)));
+        }
+        let size = get_str_size_precise(3000);
+        assert!(last.contains(&format!("Total: ({:>8} /{:>8})", size, size)));
+    }
+
+    #[test]
+    fn multiline_progress_prints_plain_lines_without_a_terminal() {
+        let progress = SharedBuf::default();
+        let mut copy_opts = quiet_opts();
+        copy_opts.progress_output(Box::new(progress.clone()));
+        let renderer = MultiProgress::new(2, 0, 100, &copy_opts);
+        renderer.update(
+            0,
+            JobProgress {
+                file: Some("a".to_owned()),
+                bytes: 10,
+                size: 50,
+                transferred: 10,
+            },
+        );
+        // plain lines are throttled to one print a second
+        renderer.update(1, JobProgress::default());
+        renderer.finish();
+
+        let text = progress.text();
+        assert!(!text.contains('\x1b'));
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert!(lines[0].starts_with("Job  1: 'a'"));
+        assert_eq!(lines[1], "Job  2: idle");
+        assert_eq!(lines[3], "Job  1: idle");
+    }
+
+    #[test]
+    fn copy_aborts_when_inodes_would_run_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        for i in 0..8 {
+            std::fs::write(src.join(format!("sub/f{}", i)), "x").unwrap();
+        }
+
+        // free inodes as last read from the destination
+        let mut copy_opts = quiet_opts();
+        copy_opts.recursive(true);
+        copy_opts.stats_store.free_inodes = Some((std::time::Instant::now(), Some(6), 0));
+        let err = copy(path_str(&src), path_str(&dst), copy_opts).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(
+            err.to_string(),
+            "not enough free inodes on the destination for 10 new files with a margin of 0: 6 free, 4 short"
+        );
+        assert!(!dst.exists());
+    }
+
+    #[test]
+    fn free_inodes_margin_is_checked_per_file() {
+        let dst = Path::new("/nonexistent");
+        let mut copy_opts = quiet_opts();
+        copy_opts.min_free_inodes(3);
+        copy_opts.stats_store.free_inodes = Some((std::time::Instant::now(), Some(5), 0));
+        check_free_inodes(dst, 1, &mut copy_opts).unwrap();
+        copy_opts.stats_store.created_files = 2;
+        let err = check_free_inodes(dst, 1, &mut copy_opts).unwrap_err();
+        assert!(err.to_string().ends_with("3 free, 1 short"));
+
+        // filesystems without an inode limit are never short
+        copy_opts.stats_store.free_inodes = Some((std::time::Instant::now(), None, 0));
+        check_free_inodes(dst, u64::MAX / 2, &mut copy_opts).unwrap();
+    }
+
+    #[test]
+    fn copy_is_checked_against_the_digest_file() {
+        use super::super::digest::{sha256, to_hex};
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("a"), "first").unwrap();
+        std::fs::write(src.join("sub/b"), "second").unwrap();
+
+        // digests as written by sha256sum from inside the source directory
+        let sums = dir.path().join("SHA256SUMS");
+        std::fs::write(
+            &sums,
+            format!(
+                "{}  ./a\n{}  sub/b\n",
+                to_hex(&sha256(b"first")),
+                to_hex(&sha256(b"second"))
+            ),
+        )
+        .unwrap();
+        let mut copy_opts = quiet_opts();
+        copy_opts.recursive(true).checksum_from(Some(&sums));
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(std::fs::read(dst.join("sub/b")).unwrap(), b"second");
+
+        // a wrong digest fails the copy of that file
+        std::fs::write(
+            &sums,
+            format!(
+                "{}  ./a\n{}  sub/b\n",
+                to_hex(&sha256(b"first")),
+                to_hex(&sha256(b"other"))
+            ),
+        )
+        .unwrap();
+        let dst = dir.path().join("d2");
+        let mut copy_opts = quiet_opts();
+        copy_opts.recursive(true).checksum_from(Some(&sums));
+        let err = copy(path_str(&src), path_str(&dst), copy_opts).unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+    }
+
+    #[test]
+    fn file_capabilities_are_carried_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("ping"), dir.path().join("ping.copy"));
+        std::fs::write(&src, "binary").unwrap();
+
+        // cap_net_raw+ep as a revision 2 `vfs_cap_data`
+        let mut caps = Vec::new();
+        caps.extend_from_slice(&0x0200_0001u32.to_le_bytes());
+        caps.extend_from_slice(&(1u32 << 13).to_le_bytes());
+        caps.extend_from_slice(&[0; 12]);
+        if !set_xattr(&src, c"security.capability", &caps) {
+            // setting capabilities needs CAP_SETFCAP
+            return;
+        }
+        let expected = get_xattr(&src, c"security.capability").unwrap();
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.preserve_caps(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(get_xattr(&dst, c"security.capability"), Some(expected));
+    }
+
+    #[test]
+    fn padded_copy_ends_in_a_hole() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        let data = random_bytes(10_000);
+        std::fs::write(&src, &data).unwrap();
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.pad_to(util::parse_size("1M").unwrap());
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        let copied = std::fs::read(&dst).unwrap();
+        assert_eq!(copied.len(), 1 << 20);
+        assert_eq!(&copied[..data.len()], &data[..]);
+        assert!(copied[data.len()..].iter().all(|&b| b == 0));
+        assert!(std::fs::metadata(&dst).unwrap().blocks() * 512 < 64 * 1024);
+
+        // the source must fit in the padded size
+        let mut copy_opts = quiet_opts();
+        copy_opts.pad_to(4096);
+        let err = copy(path_str(&src), path_str(&dir.path().join("d2")), copy_opts).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("larger than the padded size"));
+    }
+
+    /// Loop device detached when dropped.
+    struct LoopDevice(std::path::PathBuf);
+
+    impl LoopDevice {
+        /// Attaches `image` to a free loop device, if permitted.
+        fn attach(image: &Path) -> Option<Self> {
+            let out = std::process::Command::new("losetup")
+                .args(["--find", "--show"])
+                .arg(image)
+                .output()
+                .ok()?;
+            if !out.status.success() {
+                return None;
+            }
+            let device = String::from_utf8(out.stdout).ok()?;
+            Some(Self(device.trim().into()))
+        }
+    }
+
+    impl Drop for LoopDevice {
+        fn drop(&mut self) {
+            let _ = std::process::Command::new("losetup")
+                .arg("--detach")
+                .arg(&self.0)
+                .status();
+        }
+    }
+
+    #[test]
+    fn block_device_is_copied_to_its_reported_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("disk");
+        let data = random_bytes(1 << 20);
+        std::fs::write(&image, &data).unwrap();
+        let Some(device) = LoopDevice::attach(&image) else {
+            // attaching loop devices needs privileges
+            return;
+        };
+        assert_eq!(std::fs::metadata(&device.0).unwrap().len(), 0);
+
+        let dst = dir.path().join("disk.img");
+        copy(path_str(&device.0), path_str(&dst), quiet_opts()).unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+
+        // an overridden size is copied instead of the reported one
+        let dst = dir.path().join("head.img");
+        let mut copy_opts = quiet_opts();
+        copy_opts.device_size(Some(64 * 1024));
+        copy(path_str(&device.0), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), &data[..64 * 1024]);
+    }
+
+    #[test]
+    fn per_file_throughput_is_recorded_for_each_file() {
+        const MB: u64 = 1024 * 1024;
+        let dir = tempfile::tempdir().unwrap();
+        let (small, large) = (dir.path().join("small"), dir.path().join("large"));
+        std::fs::write(&small, random_bytes(100 * 1024)).unwrap();
+        std::fs::write(&large, random_bytes(2 * MB as usize)).unwrap();
+
+        // the small file fits in the burst of the limiter, the large one is
+        // held back to about the limit
+        let mut copy_opts = quiet_opts();
+        copy_opts
+            .per_file_throughput(true)
+            .limit_rate(MB)
+            .block_size(64 * 1024);
+        for src in [&small, &large] {
+            copy_file(src, &src.with_extension("copy"), &mut copy_opts).unwrap();
+        }
+        let recorded = &copy_opts.stats_store.per_file_throughput;
+        assert_eq!(recorded.len(), 2);
+        assert_eq!((&recorded[0].0, &recorded[1].0), (&small, &large));
+        let (small_rate, large_rate) = (recorded[0].1, recorded[1].1);
+        assert!(large_rate > MB / 2 && large_rate < 3 * MB, "{}", large_rate);
+        assert!(small_rate > large_rate, "{} {}", small_rate, large_rate);
+    }
+
+    #[test]
+    fn transactional_copy_leaves_the_target_alone_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, out) = (dir.path().join("s"), dir.path().join("out"));
+        std::fs::create_dir(&src).unwrap();
+        for name in ["a", "b", "c"] {
+            std::fs::write(src.join(name), name).unwrap();
+        }
+        std::fs::create_dir_all(out.join("s")).unwrap();
+        std::fs::write(out.join("s/old"), "old").unwrap();
+        let listing = |path: &Path| {
+            let mut names: Vec<_> = std::fs::read_dir(path)
+                .unwrap()
+                .map(|e| e.unwrap().file_name())
+                .collect();
+            names.sort();
+            names
+        };
+
+        // "b" vanishes while "a" is copied, failing the copy half way
+        let mut copy_opts = quiet_opts();
+        copy_opts
+            .recursive(true)
+            .force(true)
+            .transactional(true)
+            .sort(SortOrder::Name)
+            .progress(true)
+            .progress_handler(remove_sibling);
+        copy(path_str(&src), path_str(&out), copy_opts).unwrap_err();
+        assert_eq!(listing(&out), ["s"]);
+        assert_eq!(listing(&out.join("s")), ["old"]);
+
+        // a complete copy replaces the target as a whole
+        let mut copy_opts = quiet_opts();
+        copy_opts.recursive(true).force(true).transactional(true);
+        copy(path_str(&src), path_str(&out), copy_opts).unwrap();
+        assert_eq!(listing(&out), ["s"]);
+        assert_eq!(listing(&out.join("s")), ["a", "c"]);
+    }
+
+    #[test]
+    fn foreign_owned_destination_warns_or_refuses() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, own, foreign) = (
+            dir.path().join("s"),
+            dir.path().join("own"),
+            dir.path().join("foreign"),
+        );
+        std::fs::write(&src, "x").unwrap();
+        std::fs::create_dir(&own).unwrap();
+        std::fs::create_dir(&foreign).unwrap();
+        // SAFETY: `geteuid` has no preconditions and can't fail
+        let euid = unsafe { libc::geteuid() };
+        if std::os::unix::fs::chown(&foreign, Some(euid + 1), None).is_err() {
+            // giving away a directory needs CAP_CHOWN
+            return;
+        }
+
+        let (mut copy_opts, _, err) = captured_opts();
+        copy_opts.foreign_owner(ForeignOwner::Warn);
+        copy(path_str(&src), path_str(&own), copy_opts).unwrap();
+        assert_eq!(err.text(), "");
+
+        let (mut copy_opts, _, err) = captured_opts();
+        copy_opts.foreign_owner(ForeignOwner::Warn);
+        copy(path_str(&src), path_str(&foreign), copy_opts).unwrap();
+        assert!(
+            err.text().contains(&format!(
+                "is owned by uid {}, not by the current user (uid {})",
+                euid + 1,
+                euid
+            )),
+            "{}",
+            err.text()
+        );
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.foreign_owner(ForeignOwner::Refuse);
+        let err = copy(path_str(&src), path_str(&foreign.join("t")), copy_opts).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(!foreign.join("t").exists());
+    }
+
+    #[test]
+    fn s3_etag_of_the_copied_file_is_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        let data: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&src, data).unwrap();
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.s3_etag(Some(8192)).block_size(4096);
+        copy_file(&src, &dst, &mut copy_opts).unwrap();
+        assert_eq!(
+            copy_opts.stats_store.last_etag.as_deref(),
+            Some("0390222aa30f620e9b40bd87dd4611f6-3")
+        );
+    }
+
+    #[test]
+    fn group_of_the_destination_directory_is_applied() {
+        const DIR_GID: u32 = 12345;
+        let dir = tempfile::tempdir().unwrap();
+        let (src, out) = (dir.path().join("s"), dir.path().join("out"));
+        std::fs::write(&src, "x").unwrap();
+        std::fs::create_dir(&out).unwrap();
+        if std::os::unix::fs::chown(&out, None, Some(DIR_GID)).is_err() {
+            // giving a directory to a group the user isn't in needs CAP_CHOWN
+            return;
+        }
+
+        let gid_of = |name: &str| std::fs::metadata(out.join(name)).unwrap().gid();
+        let mut copy_opts = quiet_opts();
+        copy_opts.group(Some(DestGroup::Dir));
+        copy(path_str(&src), path_str(&out.join("dir")), copy_opts).unwrap();
+        assert_eq!(gid_of("dir"), DIR_GID);
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.group(Some(DestGroup::Id(DIR_GID + 1)));
+        copy(path_str(&src), path_str(&out.join("id")), copy_opts).unwrap();
+        assert_eq!(gid_of("id"), DIR_GID + 1);
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.group(Some(DestGroup::Source));
+        copy(path_str(&src), path_str(&out.join("source")), copy_opts).unwrap();
+        assert_eq!(gid_of("source"), std::fs::metadata(&src).unwrap().gid());
+    }
+
+    #[test]
+    fn ignored_errors_are_printed_above_the_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir(&src).unwrap();
+        for name in ["a", "b", "c"] {
+            std::fs::write(src.join(name), name).unwrap();
+        }
+        // a directory in the way of "b" makes its copy fail
+        std::fs::create_dir_all(dst.join("s/b/x")).unwrap();
+
+        for tty in [false, true] {
+            let progress = SharedBuf::default();
+            let mut copy_opts = quiet_opts();
+            copy_opts
+                .recursive(true)
+                .force(true)
+                .dircopy_err(true)
+                .progress_inline_errors(true)
+                .sort(SortOrder::Name)
+                .progress(true)
+                .progress_output(Box::new(progress.clone()));
+            copy_opts.progress_output_tty = tty;
+            copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+            assert_eq!(std::fs::read(dst.join("s/c")).unwrap(), b"c");
+
+            // the error replaces the progress line on a terminal, and ends it
+            // otherwise
+            let text = progress.text();
+            let clear = if tty { "\r\x1b[2K" } else { "\n" };
+            let failure = text.find("Failed to copy file: ").unwrap();
+            assert!(text[..failure].ends_with(clear), "{:?}", text);
+            let error = text[failure..].lines().next().unwrap();
+            let summary = format!(
+                "\n1 file(s) failed to copy:\n  {}\n",
+                &error["Failed to copy file: ".len()..]
+            );
+            assert!(text.ends_with(&summary), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn should_copy_decides_per_destination_state_and_flags() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1 << 30);
+        let meta = |name: &str, len: usize, mtime_offset: i64| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, vec![b'x'; len]).unwrap();
+            let mtime = if mtime_offset < 0 {
+                base - std::time::Duration::from_secs(mtime_offset.unsigned_abs())
+            } else {
+                base + std::time::Duration::from_secs(mtime_offset as u64)
+            };
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+            std::fs::metadata(&path).unwrap()
+        };
+        let src = meta("src", 10, 0);
+        let equal = meta("equal", 10, 0);
+        let newer = meta("newer", 10, 60);
+        let older = meta("older", 10, -60);
+        let larger = meta("larger", 20, 0);
+        let smaller = meta("smaller", 5, 0);
+
+        let decide = |dst: Option<&Metadata>, set: fn(&mut CopyOptions)| {
+            let mut copy_opts = quiet_opts();
+            set(&mut copy_opts);
+            should_copy(&src, dst, &copy_opts).map_err(|e| e.kind())
+        };
+        let none: fn(&mut CopyOptions) = |_| {};
+        let update: fn(&mut CopyOptions) = |o| {
+            o.update(true);
+        };
+        let update_resume: fn(&mut CopyOptions) = |o| {
+            o.update(true).resume(true);
+        };
+
+        // a missing destination is always copied
+        assert_eq!(decide(None, none), Ok(CopyDecision::Copy));
+        assert_eq!(decide(None, update), Ok(CopyDecision::Copy));
+
+        // the quick check of --update
+        assert_eq!(decide(Some(&equal), update), Ok(CopyDecision::Skip));
+        assert_eq!(decide(Some(&newer), update), Ok(CopyDecision::Skip));
+        assert_eq!(decide(Some(&older), update), Ok(CopyDecision::Copy));
+        assert_eq!(decide(Some(&larger), update), Ok(CopyDecision::Copy));
+        assert_eq!(decide(Some(&smaller), update), Ok(CopyDecision::Copy));
+        assert_eq!(decide(Some(&equal), update_resume), Ok(CopyDecision::Skip));
+        assert_eq!(
+            decide(Some(&smaller), update_resume),
+            Ok(CopyDecision::Resume)
+        );
+
+        // without --update, an existing destination needs a flag
+        for dst in [&equal, &newer, &older, &larger, &smaller] {
+            assert_eq!(decide(Some(dst), none), Err(io::ErrorKind::AlreadyExists));
+            assert_eq!(
+                decide(Some(dst), |o| {
+                    o.force(true);
+                }),
+                Ok(CopyDecision::Copy)
+            );
+            assert_eq!(
+                decide(Some(dst), |o| {
+                    o.resume(true);
+                }),
+                Ok(CopyDecision::Resume)
+            );
+            assert_eq!(
+                decide(Some(dst), |o| {
+                    o.repair(true);
+                }),
+                Ok(CopyDecision::Repair)
+            );
+            assert_eq!(
+                decide(Some(dst), |o| {
+                    o.delta(true);
+                }),
+                Ok(CopyDecision::Delta)
+            );
+        }
+
+        // only the timestamps of a destination of the same size are set
+        let touch: fn(&mut CopyOptions) = |o| {
+            o.touch_only(true).force(true);
+        };
+        assert_eq!(decide(Some(&older), touch), Ok(CopyDecision::Touch));
+        assert_eq!(decide(Some(&larger), touch), Ok(CopyDecision::Copy));
+    }
+
+    #[test]
+    fn min_refresh_redraws_a_paused_progress_line() {
+        let progress = SharedBuf::default();
+        let mut copy_opts = quiet_opts();
+        copy_opts
+            .progress_output(Box::new(progress.clone()))
+            .progress_min_refresh(Some(std::time::Duration::from_millis(200)));
+        let monitor = std::sync::Arc::new(StallMonitor::new(&copy_opts));
+
+        // half of the file took 10s, and nothing moves afterwards
+        monitor.update(
+            ProgressLine {
+                head: "\r[".to_string(),
+                tail: "]".to_string(),
+                transferred: 50,
+                total: 100,
+                start_time: std::time::Instant::now()
+                    .checked_sub(std::time::Duration::from_secs(10)),
+            },
+            true,
+        );
+        std::thread::sleep(std::time::Duration::from_millis(1300));
+        drop(monitor);
+
+        let text = progress.text();
+        let etas: Vec<_> = text
+            .split("\r[")
+            .skip(1)
+            .map(|line| line.trim_end_matches(']').to_string())
+            .collect();
+        assert!(etas.len() >= 4, "{:?}", text);
+        assert_eq!(etas[0], "00:00:10");
+        // the ETA keeps growing while the transfer is paused
+        assert_eq!(etas.last().unwrap(), "00:00:11");
+    }
+
+    #[test]
+    fn regular_only_copies_just_the_regular_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir(&src).unwrap();
+        std::fs::write(src.join("data"), "data").unwrap();
+        std::os::unix::fs::symlink("data", src.join("link")).unwrap();
+        let fifo = std::ffi::CString::new(path_str(&src.join("fifo"))).unwrap();
+        // SAFETY: `fifo` is a valid NUL terminated path
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+
+        // a FIFO without a writer would block the copy if it was opened
+        let (mut copy_opts, out, _) = captured_opts();
+        copy_opts
+            .recursive(true)
+            .regular_only(true)
+            .verbose(true)
+            .stats(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        let copied: Vec<_> = std::fs::read_dir(&dst)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(copied, ["data"]);
+
+        let text = out.text();
+        for name in ["link", "fifo"] {
+            let note = format!("Skipping non-regular file '{}'", path_str(&src.join(name)));
+            assert!(text.contains(&note), "{}", text);
+        }
+        assert!(text.contains("Non-regular files skipped: 2"), "{}", text);
+    }
+
+    #[test]
+    fn completion_message_is_clean_without_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("f"), dir.path().join("g"));
+        std::fs::write(&src, "x").unwrap();
+
+        let (mut copy_opts, out, _) = captured_opts();
+        copy_opts.verbose(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        let text = out.text();
+        assert!(text.lines().any(|l| l == "Copied 'f'"), "{:?}", text);
+        assert!(!text.contains('\r'), "{:?}", text);
+        assert!(text.lines().all(|l| l == l.trim_end()), "{:?}", text);
+
+        // nothing is printed without verbose or progress
+        let (copy_opts, out, _) = captured_opts();
+        copy(path_str(&src), path_str(&dir.path().join("h")), copy_opts).unwrap();
+        assert_eq!(out.text(), "");
+
+        // with the progress, the message ends the progress line first
+        let progress = SharedBuf::default();
+        let mut copy_opts = quiet_opts();
+        copy_opts
+            .progress(true)
+            .progress_output(Box::new(progress.clone()));
+        copy(path_str(&src), path_str(&dir.path().join("i")), copy_opts).unwrap();
+        assert!(
+            progress.text().contains("\nCopied file 'f'\n"),
+            "{:?}",
+            progress.text()
+        );
+    }
+
+    #[test]
+    fn copy_into_a_non_empty_destination_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, out) = (dir.path().join("s"), dir.path().join("out"));
+        std::fs::create_dir(&src).unwrap();
+        std::fs::write(src.join("new"), "new").unwrap();
+        let dst = out.join("s");
+        std::fs::create_dir_all(&dst).unwrap();
+        for name in ["d", "c", "b", "a"] {
+            std::fs::write(dst.join(name), "stale").unwrap();
+        }
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.recursive(true).require_empty_dest(true);
+        let err = copy(path_str(&src), path_str(&out), copy_opts.clone()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert!(
+            err.to_string()
+                .ends_with("is not empty, it contains 'a', 'b', 'c' and 1 more"),
+            "{}",
+            err
+        );
+        assert!(!dst.join("new").exists());
+
+        // an empty destination is copied into
+        for name in ["a", "b", "c", "d"] {
+            std::fs::remove_file(dst.join(name)).unwrap();
+        }
+        copy(path_str(&src), path_str(&out), copy_opts).unwrap();
+        assert_eq!(std::fs::read(dst.join("new")).unwrap(), b"new");
+    }
+
+    #[test]
+    fn moved_tree_reports_the_removal_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        // a move across filesystems copies and then removes the sources
+        let Ok(other_fs) = tempfile::tempdir_in("/dev/shm") else {
+            return;
+        };
+        let dev = |path: &Path| std::fs::metadata(path).unwrap().dev();
+        if dev(dir.path()) == dev(other_fs.path()) {
+            return;
+        }
+        let (src, dst) = (dir.path().join("s"), other_fs.path().join("d"));
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        for i in 0..20 {
+            std::fs::write(src.join(format!("sub/f{}", i)), "x").unwrap();
+        }
+
+        let (mut copy_opts, out, _) = captured_opts();
+        copy_opts
+            .recursive(true)
+            .remove(true)
+            .verbose(true)
+            .stats(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert!(!src.exists());
+        assert_eq!(std::fs::read_dir(dst.join("sub")).unwrap().count(), 20);
+
+        let text = out.text();
+        assert!(text.contains("Removed 20 source files in "), "{}", text);
+        let removal = text
+            .lines()
+            .find_map(|l| l.strip_prefix("Time taken to remove sources: "))
+            .expect("no removal time");
+        assert!(removal.ends_with(" (20 files)"), "{}", removal);
+        assert!(text.contains("Time taken to copy"), "{}", text);
+    }
+
+    #[test]
+    fn swap_exchanges_the_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        std::fs::write(&a, "first").unwrap();
+        std::fs::create_dir(&b).unwrap();
+        std::fs::write(b.join("inner"), "second").unwrap();
+
+        swap(path_str(&a), path_str(&b), quiet_opts()).unwrap();
+        assert_eq!(std::fs::read(&b).unwrap(), b"first");
+        assert_eq!(std::fs::read(a.join("inner")).unwrap(), b"second");
+
+        // a missing path isn't swapped
+        let err = swap(path_str(&a), path_str(&dir.path().join("c")), quiet_opts()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn swap_by_renames_exchanges_the_paths() {
+        // the fallback for kernels and filesystems without RENAME_EXCHANGE
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        std::fs::write(&a, "first").unwrap();
+        std::fs::write(&b, "second").unwrap();
+
+        swap_by_rename(&a, &b).unwrap();
+        assert_eq!(std::fs::read(&a).unwrap(), b"second");
+        assert_eq!(std::fs::read(&b).unwrap(), b"first");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        // the first rename is undone when the second one fails
+        let err = swap_by_rename(&a, &dir.path().join("c")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(std::fs::read(&a).unwrap(), b"second");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn special_bits_survive_the_copy_and_chown() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("s");
+        std::fs::write(&src, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&src, std::fs::Permissions::from_mode(0o4755)).unwrap();
+        let mode = |name: &str| std::fs::metadata(dir.path().join(name)).unwrap().mode() & 0o7777;
+
+        copy(
+            path_str(&src),
+            path_str(&dir.path().join("plain")),
+            quiet_opts(),
+        )
+        .unwrap();
+        assert_eq!(mode("plain"), 0o4755);
+
+        // changing the group clears the setuid bit, which is set again
+        let mut copy_opts = quiet_opts();
+        copy_opts.group(Some(DestGroup::Id(12345)));
+        if copy(
+            path_str(&src),
+            path_str(&dir.path().join("chown")),
+            copy_opts,
+        )
+        .is_ok()
+        {
+            assert_eq!(mode("chown"), 0o4755);
+        }
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.preserve_special_bits(false);
+        copy(
+            path_str(&src),
+            path_str(&dir.path().join("stripped")),
+            copy_opts,
+        )
+        .unwrap();
+        assert_eq!(mode("stripped"), 0o755);
+    }
+
+    #[test]
+    fn pv_progress_lines_are_numeric_and_sane() {
+        const SIZE: u64 = 3 * 1024 * 1024;
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::write(&src, vec![7u8; SIZE as usize]).unwrap();
+
+        // held to about two seconds to get a line a second
+        let (mut copy_opts, out, err) = captured_opts();
+        copy_opts
+            .limit_rate(1024 * 1024)
+            .block_size(64 * util::KB)
+            .progress(true)
+            .progress_format(ProgressFormat::Pv);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(out.text(), "");
+
+        let text = err.text();
+        let lines: Vec<(f64, u64, u64, u64, u64)> = text
+            .lines()
+            .map(|l| {
+                let f: Vec<&str> = l.split(' ').collect();
+                assert_eq!(f.len(), 5, "{}", l);
+                (
+                    f[0].parse().unwrap(),
+                    f[1].parse().unwrap(),
+                    f[2].parse().unwrap(),
+                    f[3].parse().unwrap(),
+                    f[4].parse().unwrap(),
+                )
+            })
+            .collect();
+        assert!(lines.len() >= 2, "{}", text);
+        for w in lines.windows(2) {
+            assert!(
+                w[0].0 <= w[1].0 && w[0].1 <= w[1].1 && w[0].3 <= w[1].3,
+                "{}",
+                text
+            );
         }
-    } else {
-        // if source is a file, copy the individual file
-        copy_opts.stats_store.total = src_stat.len();
-        if let Err(e) = copy_file(source, destination.as_path(), &mut copy_opts) {
-            return Err(e);
-        } else if copy_opts.remove {
-            // if move option was specified, remove source file after
-            // successful copy
-            if let Err(e) = std::fs::remove_file(source) {
-                return Err(io::Error::new(
-                    e.kind(),
-                    format!("failed to remove source file: {}", &e),
-                ));
+        for &(elapsed, bytes, rate, percent, eta) in &lines {
+            assert!(bytes <= SIZE && percent == bytes * 100 / SIZE, "{}", text);
+            if bytes > 0 && elapsed >= 0.5 {
+                // the rate is the average over the elapsed time, which is
+                // read again for it
+                let average = bytes as f64 / elapsed;
+                assert!((rate as f64) < average * 1.5 && (rate as f64) > average / 1.5);
+                assert!(
+                    eta as f64 <= (SIZE - bytes) as f64 / average + 1.0,
+                    "{}",
+                    text
+                );
             }
         }
+        let last = lines.last().unwrap();
+        assert_eq!((last.1, last.3, last.4), (SIZE, 100, 0));
     }
 
-    // stop timer
-    let end = std::time::Instant::now();
+    #[test]
+    fn empty_file_is_copied() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("empty"), dir.path().join("copy"));
+        std::fs::write(&src, "").unwrap();
+        std::fs::set_permissions(&src, std::fs::Permissions::from_mode(0o640)).unwrap();
 
-    // verify copy stats
-    if copy_opts.stats_store.transferred != copy_opts.stats_store.total {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!(
-                "error in copy: transferred={}, total={}",
-                &copy_opts.stats_store.transferred, &copy_opts.stats_store.total
-            ),
-        ));
+        let (mut copy_opts, out, err) = captured_opts();
+        copy_opts
+            .progress(true)
+            .progress_format(ProgressFormat::Percent);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        let dst_meta = std::fs::metadata(&dst).unwrap();
+        assert_eq!(dst_meta.len(), 0);
+        assert_eq!(dst_meta.mode() & 0o777, 0o640);
+        // nothing to transfer is complete right away
+        assert_eq!(out.text(), "100\n");
+        assert_eq!(err.text(), "");
     }
 
-    // if statistics are requested, calculate and show the file transfer
-    // statisctics
-    if copy_opts.show_stats {
-        copy_opts.stats_store.time_taken = end.sub(start);
-        println!(
-            "\nTime taken to copy: {:?}",
-            copy_opts.stats_store.time_taken
-        );
-        let transfer_speed = (copy_opts.stats_store.total as f64
-            / copy_opts.stats_store.time_taken.as_micros() as f64)
-            as u64
-            * 1_000_000;
+    #[test]
+    fn empty_file_replaces_an_existing_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("empty"), dir.path().join("copy"));
+        std::fs::write(&src, "").unwrap();
+        std::fs::write(&dst, "old contents").unwrap();
 
-        println!("Transfer speed: {}/s", get_str_size_precise(transfer_speed));
+        let err = copy(path_str(&src), path_str(&dst), quiet_opts()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        let mut copy_opts = quiet_opts();
+        copy_opts.force(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(std::fs::metadata(&dst).unwrap().len(), 0);
     }
 
-    Ok(())
-}
+    #[test]
+    fn empty_files_in_a_tree_are_copied() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("a"), "data").unwrap();
+        std::fs::write(src.join("sub/empty"), "").unwrap();
+        std::fs::write(src.join("sub/z"), "").unwrap();
 
-fn copy_file(src: &Path, dst: &Path, copy_opts: &mut CopyOptions) -> io::Result<usize> {
-    // open the source file
-    let mut src_file_handle = match File::open(src) {
-        Ok(f) => f,
-        Err(e) => {
-            return Err(io::Error::new(
-                e.kind(),
-                format!("failure in opening source file: {}", e),
-            ));
+        let progress = SharedBuf::default();
+        let mut copy_opts = quiet_opts();
+        copy_opts
+            .recursive(true)
+            .progress(true)
+            .progress_output(Box::new(progress.clone()));
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(std::fs::read(dst.join("a")).unwrap(), b"data");
+        for name in ["sub/empty", "sub/z"] {
+            assert_eq!(std::fs::metadata(dst.join(name)).unwrap().len(), 0);
         }
-    };
+        let text = progress.text();
+        assert!(text.contains("Copied file 'empty'"), "{:?}", text);
+        assert!(text.contains("Copied file 'z'"), "{:?}", text);
+    }
 
-    // retrive source file metadata
-    let src_file_metadata = match src_file_handle.metadata() {
-        Ok(m) => m,
-        Err(e) => {
-            return Err(io::Error::new(
-                e.kind(),
-                format!("failure in fetching metadata for source file: {}", &e),
-            ));
-        }
-    };
+    #[test]
+    fn overlapping_sources_copy_a_file_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, out) = (dir.path().join("s"), dir.path().join("out"));
+        std::fs::create_dir(&src).unwrap();
+        std::fs::create_dir(&out).unwrap();
+        std::fs::write(src.join("shared"), "shared").unwrap();
+        std::fs::write(src.join("other"), "other").unwrap();
 
-    // check if destination file exists
-    let dst_file_metadata = match std::fs::metadata(dst) {
-        Ok(m) => {
-            // if destination file exists
-            if !copy_opts.force && !copy_opts.resume {
-                // if neither of force or resume option specified, abort copy
-                return Err(io::Error::new(
-                    io::ErrorKind::AlreadyExists,
-                    format!(
-                        "file '{}' exists, can't copy file without --force or --continue option",
-                        dst.to_str().unwrap_or("")
-                    ),
-                ));
+        // the clones of the options share the sources copied so far
+        let (mut copy_opts, out_text, _) = captured_opts();
+        copy_opts
+            .recursive(true)
+            .verbose(true)
+            .stats(true)
+            .skip_duplicate_sources(true);
+        copy(path_str(&src), path_str(&out), copy_opts.clone()).unwrap();
+        copy(path_str(&src.join("shared")), path_str(&out), copy_opts).unwrap();
+        assert_eq!(std::fs::read(out.join("s/shared")).unwrap(), b"shared");
+        assert!(!out.join("shared").exists());
+        let text = out_text.text();
+        assert!(text.contains("Skipping already copied file"), "{}", text);
+        assert!(text.contains("Duplicate sources skipped: 1"), "{}", text);
+
+        // the other way around, the file is skipped in the directory
+        let out = dir.path().join("out2");
+        std::fs::create_dir(&out).unwrap();
+        let mut copy_opts = quiet_opts();
+        copy_opts.recursive(true).skip_duplicate_sources(true);
+        copy(
+            path_str(&src.join("shared")),
+            path_str(&out),
+            copy_opts.clone(),
+        )
+        .unwrap();
+        copy(path_str(&src), path_str(&out), copy_opts).unwrap();
+        assert!(out.join("shared").exists());
+        assert!(!out.join("s/shared").exists());
+        assert!(out.join("s/other").exists());
+    }
+
+    #[test]
+    fn strict_total_accounts_for_skipped_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir(&src).unwrap();
+        std::fs::write(src.join("a"), "first").unwrap();
+        let mut copy_opts = quiet_opts();
+        copy_opts.recursive(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+
+        // "a" is up to date and skipped, only "b" is copied
+        std::fs::write(src.join("b"), "second").unwrap();
+        let mut copy_opts = quiet_opts();
+        copy_opts
+            .recursive(true)
+            .update(true)
+            .strict_total(true)
+            .no_target_directory(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(std::fs::read(dst.join("b")).unwrap(), b"second");
+
+        // a mismatch is reported with the breakdown of the bytes
+        let mut copy_opts = quiet_opts();
+        let stats = &mut copy_opts.stats_store;
+        (
+            stats.scanned,
+            stats.transferred,
+            stats.skipped_bytes,
+            stats.failed_bytes,
+        ) = (100, 60, 20, 10);
+        assert_eq!(
+            check_strict_total(&copy_opts).unwrap_err().to_string(),
+            "error in copy: 10 bytes missing, scanned=100, copied=60, skipped=20, failed=10"
+        );
+        copy_opts.stats_store.failed_bytes = 20;
+        check_strict_total(&copy_opts).unwrap();
+    }
+
+    #[test]
+    fn store_keeps_one_object_per_digest() {
+        use super::super::digest::{sha256, to_hex};
+        let dir = tempfile::tempdir().unwrap();
+        let (src, store) = (dir.path().join("s"), dir.path().join("store"));
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("a"), "same").unwrap();
+        std::fs::write(src.join("sub/b"), "same").unwrap();
+        std::fs::write(src.join("c"), "other").unwrap();
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.recursive(true);
+        copy_to_store(&[path_str(&src)], path_str(&store), copy_opts).unwrap();
+
+        let (same, other) = (to_hex(&sha256(b"same")), to_hex(&sha256(b"other")));
+        let object = |hex: &str| store.join(&hex[..2]).join(&hex[2..]);
+        assert_eq!(std::fs::read(object(&same)).unwrap(), b"same");
+        assert_eq!(std::fs::read(object(&other)).unwrap(), b"other");
+        let mut objects = 0;
+        for entry in std::fs::read_dir(&store).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                objects += std::fs::read_dir(path).unwrap().count();
             }
-            Some(m)
         }
-        Err(_e) => {
-            // if destination file doesn't exist
-            if let Some(dst_dir) = dst.parent() {
-                // create all the directories in the destination path
-                if let Err(e) = std::fs::create_dir_all(dst_dir) {
-                    // throw any error other than EEXIST
-                    if e.kind() != io::ErrorKind::AlreadyExists {
-                        return Err(io::Error::new(
-                            e.kind(),
-                            format!("failure in creating destination directory: {}", &e),
-                        ));
+        assert_eq!(objects, 2);
+
+        let index = std::fs::read_to_string(store.join("index")).unwrap();
+        let mut lines: Vec<_> = index.lines().collect();
+        lines.sort_by_key(|l| l.split_once("  ").unwrap().1);
+        assert_eq!(
+            lines,
+            [
+                format!("{}  s/a", same),
+                format!("{}  s/c", other),
+                format!("{}  s/sub/b", same)
+            ]
+        );
+    }
+
+    #[test]
+    fn fast_copies_show_no_progress_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("f");
+        std::fs::write(&src, vec![3u8; 2 * 1024 * 1024]).unwrap();
+        let copy_with = |dst: &str, limit_rate: u64| {
+            let progress = SharedBuf::default();
+            let mut copy_opts = quiet_opts();
+            copy_opts
+                .limit_rate(limit_rate)
+                .block_size(64 * util::KB)
+                .progress(true)
+                .progress_output(Box::new(progress.clone()))
+                .progress_suppress_when_fast(Some(std::time::Duration::from_millis(300)));
+            copy(path_str(&src), path_str(&dir.path().join(dst)), copy_opts).unwrap();
+            progress.text()
+        };
+
+        // only the completion message is left of a copy within the grace
+        let text = copy_with("fast", 0);
+        assert_eq!(text, "Copied file 'f'\n");
+
+        // a copy held to about a second shows the progress after the grace
+        let text = copy_with("slow", 1024 * 1024);
+        assert!(text.contains("Copying file 'f'"), "{:?}", text);
+        assert!(text.ends_with("\nCopied file 'f'\n"), "{:?}", text);
+    }
+
+    #[test]
+    fn path_mapper_reshapes_the_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("s");
+        std::fs::create_dir_all(src.join("x/y")).unwrap();
+        std::fs::write(src.join("top"), "top").unwrap();
+        std::fs::write(src.join("x/mid"), "mid").unwrap();
+        std::fs::write(src.join("x/y/deep.tmp"), "tmp").unwrap();
+        std::fs::write(src.join("x/y/deep"), "deep").unwrap();
+        let listing = |path: &Path| {
+            let mut files = Vec::new();
+            let mut dirs = vec![path.to_owned()];
+            while let Some(dir) = dirs.pop() {
+                for entry in std::fs::read_dir(dir).unwrap() {
+                    let entry_path = entry.unwrap().path();
+                    if entry_path.is_dir() {
+                        dirs.push(entry_path);
+                    } else {
+                        let rel = entry_path.strip_prefix(path).unwrap();
+                        files.push(rel.to_str().unwrap().to_owned());
                     }
                 }
             }
-            None
+            files.sort();
+            files
+        };
+
+        let flatten: PathMapper = std::sync::Arc::new(|p: &Path| p.file_name().map(Into::into));
+        let dst = dir.path().join("flat");
+        let mut copy_opts = quiet_opts();
+        copy_opts.recursive(true).path_mapper(Some(flatten));
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(listing(&dst), ["deep", "deep.tmp", "mid", "top"]);
+
+        let skip_tmp: PathMapper = std::sync::Arc::new(|p: &Path| {
+            (p.extension() != Some(std::ffi::OsStr::new("tmp"))).then(|| p.to_owned())
+        });
+        let dst = dir.path().join("kept");
+        let mut copy_opts = quiet_opts();
+        copy_opts.recursive(true).path_mapper(Some(skip_tmp));
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(listing(&dst), ["top", "x/mid", "x/y/deep"]);
+        assert_eq!(std::fs::read(dst.join("x/y/deep")).unwrap(), b"deep");
+    }
+
+    #[test]
+    fn flatten_handles_name_collisions_per_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("s");
+        std::fs::create_dir_all(src.join("a")).unwrap();
+        std::fs::create_dir_all(src.join("b/c")).unwrap();
+        std::fs::write(src.join("a/data.txt"), "a").unwrap();
+        std::fs::write(src.join("b/data.txt"), "b").unwrap();
+        std::fs::write(src.join("b/c/data.txt"), "c").unwrap();
+        std::fs::write(src.join("b/c/unique"), "unique").unwrap();
+        let flatten = |name: &str, on_collision: OnCollision| {
+            let dst = dir.path().join(name);
+            let mut copy_opts = quiet_opts();
+            copy_opts
+                .recursive(true)
+                .flatten(true)
+                .on_collision(on_collision);
+            copy(path_str(&src), path_str(&dst), copy_opts).map(|_| dst)
+        };
+        let contents = |dst: &Path| {
+            let mut files: Vec<_> = std::fs::read_dir(dst)
+                .unwrap()
+                .map(|e| {
+                    let e = e.unwrap();
+                    let text = std::fs::read_to_string(e.path()).unwrap();
+                    (e.file_name().into_string().unwrap(), text)
+                })
+                .collect();
+            files.sort();
+            files
+        };
+        let pairs = |expected: &[(&str, &str)]| -> Vec<(String, String)> {
+            expected
+                .iter()
+                .map(|(n, t)| (n.to_string(), t.to_string()))
+                .collect()
+        };
+
+        let err = flatten("error", OnCollision::Error).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert!(
+            err.to_string().contains("'a/data.txt' and 'b/c/data.txt'"),
+            "{}",
+            err
+        );
+        assert!(!dir.path().join("error").exists());
+
+        // the first path in order keeps the name
+        let dst = flatten("renamed", OnCollision::Rename).unwrap();
+        assert_eq!(
+            contents(&dst),
+            pairs(&[
+                ("data-1.txt", "c"),
+                ("data-2.txt", "b"),
+                ("data.txt", "a"),
+                ("unique", "unique")
+            ])
+        );
+
+        let dst = flatten("skipped", OnCollision::Skip).unwrap();
+        assert_eq!(
+            contents(&dst),
+            pairs(&[("data.txt", "a"), ("unique", "unique")])
+        );
+    }
+
+    #[test]
+    fn repair_rewrites_only_the_corrupted_blocks() {
+        const BLOCK: usize = 64 * 1024;
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        let data = random_bytes(16 * BLOCK);
+        std::fs::write(&src, &data).unwrap();
+        let mut corrupted = data.clone();
+        for offset in [2 * BLOCK + 10, 7 * BLOCK, 16 * BLOCK - 1] {
+            corrupted[offset] ^= 0xff;
         }
-    };
+        std::fs::write(&dst, &corrupted).unwrap();
 
-    // open the destination file
-    let mut dst_file_handle: File = {
-        let mut dst_file_open_options = std::fs::OpenOptions::new();
+        let mut copy_opts = quiet_opts();
+        copy_opts.repair(true).block_size(BLOCK as u64);
+        copy_file(&src, &dst, &mut copy_opts).unwrap();
+        assert_eq!(copy_opts.stats_store.repaired_blocks, 3);
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
 
-        dst_file_open_options.create(true).write(true);
-        dst_file_open_options.mode(src_file_metadata.mode());
+        // a partial destination gets its missing blocks, and nothing else
+        std::fs::write(&dst, &data[..5 * BLOCK + 100]).unwrap();
+        let mut copy_opts = quiet_opts();
+        copy_opts.repair(true).block_size(BLOCK as u64);
+        copy_file(&src, &dst, &mut copy_opts).unwrap();
+        assert_eq!(copy_opts.stats_store.repaired_blocks, 11);
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+    }
 
-        if let Some(dst_file_meta) = &dst_file_metadata {
-            if copy_opts.resume {
-                // open in append mode if resume option is specified
-                dst_file_open_options.append(true);
-                dst_file_open_options.mode(dst_file_meta.mode());
-            }
+    #[test]
+    fn delta_writes_only_the_changed_region() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        let mut data = random_bytes(1024 * 1024);
+        std::fs::write(&dst, &data).unwrap();
+        data[300_000..300_100].copy_from_slice(&[0u8; 100]);
+        std::fs::write(&src, &data).unwrap();
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.delta(true);
+        copy_file(&src, &dst, &mut copy_opts).unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        // the change falls within a single block
+        let stats = &copy_opts.stats_store;
+        assert_eq!(stats.delta_written, DELTA_BLOCK_SIZE);
+        assert_eq!(stats.delta_reused, data.len() as u64 - DELTA_BLOCK_SIZE);
+
+        // a missing destination is copied in full
+        let new = dir.path().join("new");
+        let mut copy_opts = quiet_opts();
+        copy_opts.delta(true);
+        copy_file(&src, &new, &mut copy_opts).unwrap();
+        assert_eq!(std::fs::read(&new).unwrap(), data);
+    }
+
+    #[test]
+    fn touch_only_sets_the_times_of_matching_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir(&src).unwrap();
+        std::fs::create_dir(&dst).unwrap();
+        std::fs::write(src.join("same"), "contents").unwrap();
+        std::fs::write(src.join("new"), "new").unwrap();
+        let mtime = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1 << 30);
+        File::options()
+            .write(true)
+            .open(src.join("same"))
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        // the same size stands for the same contents, which aren't written
+        std::fs::write(dst.join("same"), "CONTENTS").unwrap();
+
+        let (mut copy_opts, out, _) = captured_opts();
+        copy_opts
+            .recursive(true)
+            .touch_only(true)
+            .stats(true)
+            .no_target_directory(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        let touched = std::fs::metadata(dst.join("same")).unwrap();
+        assert_eq!(touched.modified().unwrap(), mtime);
+        assert_eq!(std::fs::read(dst.join("same")).unwrap(), b"CONTENTS");
+        assert_eq!(std::fs::read(dst.join("new")).unwrap(), b"new");
+        assert!(
+            out.text().contains("Touched files: 1 (1 copied)"),
+            "{}",
+            out.text()
+        );
+    }
+
+    #[test]
+    fn copy_order_follows_the_order_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("s");
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        for name in ["a", "b", "sub/c", "sub/d"] {
+            std::fs::write(src.join(name), name).unwrap();
         }
+        let order_file = dir.path().join("order");
+        std::fs::write(&order_file, "sub/d\nb\nsub/c\n").unwrap();
 
-        match dst_file_open_options.open(dst) {
-            Ok(f) => f,
-            Err(e) => {
-                return Err(io::Error::new(
-                    e.kind(),
-                    format!("failure in opening destination file: {}", &e),
-                ));
-            }
+        for (unlisted, expected) in [
+            (Unlisted::Last, &["d", "b", "c", "a"][..]),
+            (Unlisted::Skip, &["d", "b", "c"][..]),
+        ] {
+            PROGRESS_ORDER.take();
+            let mut copy_opts = quiet_opts();
+            copy_opts
+                .recursive(true)
+                .order_from(Some(&order_file))
+                .unlisted(unlisted)
+                .progress(true)
+                .progress_handler(record_order);
+            let dst = dir.path().join(format!("{:?}", unlisted));
+            copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+            assert_eq!(PROGRESS_ORDER.take(), expected, "{:?}", unlisted);
+            assert_eq!(dst.join("a").exists(), unlisted == Unlisted::Last);
         }
-    };
+    }
 
-    let mut bytes_transferred: u64 = 0;
+    #[test]
+    fn sync_perms_fixes_the_mode_of_skipped_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::write(&src, "contents").unwrap();
+        std::fs::set_permissions(&src, std::fs::Permissions::from_mode(0o640)).unwrap();
+        let mtime = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1 << 30);
+        File::options()
+            .write(true)
+            .open(&src)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        // the newer destination is up to date
+        std::fs::write(&dst, "contents").unwrap();
+        std::fs::set_permissions(&dst, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let ino = std::fs::metadata(&dst).unwrap().ino();
 
-    if let Some(dst_file_meta) = &dst_file_metadata {
-        // if destination file exists
-        let dst_file_size = dst_file_meta.len();
-        if copy_opts.resume {
-            // if resume option is specified, skip the already copied bytes
-            if let Err(e) = src_file_handle.seek(SeekFrom::Start(dst_file_size)) {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!(
-                        "failed to resume copy due to seek fail on source file: {}",
-                        e
-                    ),
-                ));
-            }
+        let sync = || {
+            let (mut copy_opts, out, _) = captured_opts();
+            copy_opts
+                .update(true)
+                .sync_perms(true)
+                .verbose(true)
+                .stats(true);
+            copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+            out.text()
+        };
+        let text = sync();
+        let dst_meta = std::fs::metadata(&dst).unwrap();
+        assert_eq!(dst_meta.mode() & 0o7777, 0o640);
+        assert_eq!(dst_meta.ino(), ino);
+        assert!(text.contains("Skipping up to date file"), "{}", text);
+        assert!(text.contains("Metadata fixed: 1 files"), "{}", text);
 
-            // update transfer statistics
-            bytes_transferred = dst_file_size;
-            copy_opts.stats_store.transferred += dst_file_size;
+        // nothing is left to fix on the next run
+        let text = sync();
+        assert!(text.contains("Skipping up to date file"), "{}", text);
+        assert!(!text.contains("Metadata fixed"), "{}", text);
+    }
+
+    #[test]
+    fn move_refuses_to_remove_the_root_of_a_filesystem() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("mnt"), dir.path().join("out"));
+        std::fs::create_dir(&src).unwrap();
+        let target = std::ffi::CString::new(path_str(&src)).unwrap();
+        // SAFETY: the strings are NUL terminated and tmpfs takes no data
+        let ret = unsafe {
+            libc::mount(
+                c"tmpfs".as_ptr(),
+                target.as_ptr(),
+                c"tmpfs".as_ptr(),
+                0,
+                std::ptr::null(),
+            )
+        };
+        if ret != 0 {
+            return; // mounting isn't permitted
         }
+        std::fs::write(src.join("f"), "contents").unwrap();
+
+        let mut copy_opts = quiet_opts();
+        copy_opts.remove(true).recursive(true);
+        let err = copy(path_str(&src), path_str(&dst), copy_opts).unwrap_err();
+        assert!(src.join("f").exists());
+        assert!(!dst.exists());
+        // SAFETY: `target` is NUL terminated
+        unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) };
+        assert!(
+            err.to_string().contains("refusing to move")
+                && err.to_string().contains("the root of a filesystem"),
+            "{}",
+            err
+        );
     }
 
-    // specify progress logger
-    let prgrs_hndlr = match copy_opts.progress_handler {
-        Some(hndlr) => hndlr,
-        None => default_progress_handler,
-    };
+    #[test]
+    fn journal_progress_is_logged_at_the_interval() {
+        if Path::new("/run/systemd/journal/socket").exists() {
+            return; // the records go to the journal instead of stderr
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::write(&src, random_bytes(2 * util::MB as usize)).unwrap();
 
-    loop {
-        match util::copy_n(
-            &mut src_file_handle,
-            &mut dst_file_handle,
-            copy_opts.block_size as usize,
-        ) {
-            Ok(bytes_copied) => {
-                // if 0 bytes were read or requested number of bytes were copied
-                // successfully, exit loop
-                if bytes_copied == 0 || bytes_transferred == src_file_metadata.len() {
-                    break;
-                }
+        let (mut copy_opts, _, err) = captured_opts();
+        copy_opts
+            .limit_rate(1024 * 1024)
+            .block_size(64 * util::KB)
+            .journal_progress(Some(std::time::Duration::from_millis(300)));
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        let text = err.text();
+        let records: Vec<&str> = text
+            .lines()
+            .filter(|l| l.starts_with("rs_filecopy: Copying '"))
+            .collect();
+        // the throttled copy takes at least a second, logged every 300ms
+        assert!((3..=10).contains(&records.len()), "{}", text);
+        assert!(records.iter().all(|r| r.contains("overall")), "{}", text);
+    }
 
-                bytes_transferred += bytes_copied as u64;
-                copy_opts.stats_store.transferred += bytes_copied as u64;
+    #[test]
+    fn split_writes_numbered_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, out) = (dir.path().join("big"), dir.path().join("out"));
+        let data = random_bytes(10 * 1024);
+        std::fs::write(&src, &data).unwrap();
 
-                // skip progress logging if not requested
-                if !copy_opts.show_progress {
-                    continue;
-                }
+        split(path_str(&src), path_str(&out), 4096, quiet_opts()).unwrap();
+        let mut names: Vec<_> = std::fs::read_dir(&out)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["big.000", "big.001", "big.002"]);
+        let sizes: Vec<_> = names
+            .iter()
+            .map(|n| std::fs::metadata(out.join(n)).unwrap().len())
+            .collect();
+        assert_eq!(sizes, [4096, 4096, 2048]);
+        let joined: Vec<u8> = names
+            .iter()
+            .flat_map(|n| std::fs::read(out.join(n)).unwrap())
+            .collect();
+        assert_eq!(joined, data);
+    }
 
-                prgrs_hndlr(
-                    src,
-                    dst,
-                    bytes_transferred,
-                    src_file_metadata.len(),
-                    copy_opts,
-                );
-            }
-            Err(e) => {
-                return Err(io::Error::new(
-                    e.kind(),
-                    format!(
-                        "error while copying file '{}': {}",
-                        &src.to_str().unwrap_or(""),
-                        e
-                    ),
-                ))
-            }
+    #[test]
+    fn join_reassembles_split_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, out) = (dir.path().join("big"), dir.path().join("out"));
+        let data = random_bytes(10 * 1024);
+        std::fs::write(&src, &data).unwrap();
+        split(path_str(&src), path_str(&out), 4096, quiet_opts()).unwrap();
+
+        let joined = dir.path().join("joined");
+        let prefix = format!("{}.", path_str(&out.join("big")));
+        join(&prefix, path_str(&joined), quiet_opts()).unwrap();
+        assert_eq!(std::fs::read(&joined).unwrap(), data);
+    }
+
+    #[test]
+    fn join_fails_on_a_missing_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, out) = (dir.path().join("big"), dir.path().join("out"));
+        std::fs::write(&src, random_bytes(10 * 1024)).unwrap();
+        split(path_str(&src), path_str(&out), 4096, quiet_opts()).unwrap();
+        std::fs::remove_file(out.join("big.001")).unwrap();
+
+        let joined = dir.path().join("joined");
+        let prefix = format!("{}.", path_str(&out.join("big")));
+        let err = join(&prefix, path_str(&joined), quiet_opts()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(
+            err.to_string().contains("gap in the chunks of")
+                && err.to_string().contains("chunk 1 is missing before"),
+            "{}",
+            err
+        );
+        assert!(!joined.exists());
+    }
+
+    #[test]
+    fn head_and_tail_copy_slices_of_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("s");
+        let data = random_bytes(10000);
+        std::fs::write(&src, &data).unwrap();
+
+        let slice = |head: Option<u64>, tail: Option<u64>, name: &str| {
+            let dst = dir.path().join(name);
+            let mut copy_opts = quiet_opts();
+            copy_opts.head(head).tail(tail);
+            copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+            std::fs::read(dst).unwrap()
+        };
+        assert_eq!(slice(Some(4096), None, "head"), data[..4096]);
+        assert_eq!(slice(None, Some(4096), "tail"), data[10000 - 4096..]);
+        // a slice longer than the file copies the whole file
+        assert_eq!(slice(Some(1 << 20), None, "long_head"), data);
+        assert_eq!(slice(None, Some(1 << 20), "long_tail"), data);
+    }
+
+    #[test]
+    fn offset_and_length_copy_a_range_of_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("s");
+        let data = random_bytes(10000);
+        std::fs::write(&src, &data).unwrap();
+
+        let range = |offset: u64, length: Option<u64>, name: &str| {
+            let dst = dir.path().join(name);
+            let mut copy_opts = quiet_opts();
+            copy_opts.offset(offset).length(length);
+            copy(path_str(&src), path_str(&dst), copy_opts).map(|_| std::fs::read(dst).unwrap())
+        };
+        assert_eq!(range(3000, Some(4000), "middle").unwrap(), data[3000..7000]);
+        // without a length the range extends to the end of the file
+        assert_eq!(range(3000, None, "rest").unwrap(), data[3000..]);
+
+        let err = range(20000, Some(1), "beyond").unwrap_err();
+        assert!(err.to_string().contains("is beyond the end of"), "{}", err);
+    }
+
+    #[test]
+    fn fill_writes_the_pattern_up_to_the_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let dst = dir.path().join("out");
+        fill(&[0xAB], 100_000, path_str(&dst), quiet_opts()).unwrap();
+        let written = std::fs::read(&dst).unwrap();
+        assert_eq!(written.len(), 100_000);
+        assert!(written.iter().all(|&b| b == 0xAB));
+
+        // a longer pattern is cut off at the size
+        let dst = dir.path().join("pattern");
+        fill(&[1, 2, 3], 10, path_str(&dst), quiet_opts()).unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), [1, 2, 3, 1, 2, 3, 1, 2, 3, 1]);
+    }
+
+    #[test]
+    fn move_truncates_the_source_before_removing_it() {
+        let dir = tempfile::tempdir().unwrap();
+        // a move on the same filesystem is a rename, which leaves no data behind
+        let Ok(other_fs) = tempfile::tempdir_in("/dev/shm") else {
+            return;
+        };
+        let dev = |path: &Path| std::fs::metadata(path).unwrap().dev();
+        if dev(dir.path()) == dev(other_fs.path()) {
+            return;
         }
+        let (src, dst) = (dir.path().join("s"), other_fs.path().join("d"));
+        let data = random_bytes(100_000);
+        std::fs::write(&src, &data).unwrap();
+        // the held handle outlives the removal of the source
+        let held = File::open(&src).unwrap();
+
+        let (mut copy_opts, out, _) = captured_opts();
+        copy_opts
+            .remove(true)
+            .truncate_source_after(true)
+            .verbose(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert!(!src.exists());
+        assert_eq!(held.metadata().unwrap().len(), 0);
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        assert!(
+            out.text().contains("Truncated source file"),
+            "{}",
+            out.text()
+        );
+
+        // a source with another hard link keeps its data
+        let link = dir.path().join("link");
+        std::fs::write(&src, &data).unwrap();
+        std::fs::hard_link(&src, &link).unwrap();
+        let mut copy_opts = quiet_opts();
+        copy_opts
+            .remove(true)
+            .force(true)
+            .truncate_source_after(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert!(!src.exists());
+        assert_eq!(std::fs::read(&link).unwrap(), data);
     }
 
-    // verify file transfer
-    if bytes_transferred != src_file_metadata.len() {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!(
-                "error while copying file '{}': missing {} bytes in destination",
-                &src.to_str().unwrap_or(""),
-                src_file_metadata.len() - bytes_transferred
-            ),
-        ));
+    #[test]
+    fn move_wipes_the_source_before_removing_it() {
+        let dir = tempfile::tempdir().unwrap();
+        // a move on the same filesystem is a rename, which leaves no data behind
+        let Ok(other_fs) = tempfile::tempdir_in("/dev/shm") else {
+            return;
+        };
+        let dev = |path: &Path| std::fs::metadata(path).unwrap().dev();
+        if dev(dir.path()) == dev(other_fs.path()) {
+            return;
+        }
+        let (src, dst) = (dir.path().join("s"), other_fs.path().join("d"));
+        let data = vec![0x5A; 100_000];
+        std::fs::write(&src, &data).unwrap();
+        // the held handle outlives the removal of the source
+        let held = File::open(&src).unwrap();
+
+        let (mut copy_opts, out, _) = captured_opts();
+        copy_opts.remove(true).wipe_source(Some(2)).verbose(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert!(!src.exists());
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        // the random passes are followed by a pass of zeros over the whole file
+        let mut wiped = Vec::new();
+        io::Read::read_to_end(&mut &held, &mut wiped).unwrap();
+        assert_eq!(wiped.len(), data.len());
+        assert!(wiped.iter().all(|&b| b == 0));
+        assert!(
+            out.text().contains("with 2 random passes"),
+            "{}",
+            out.text()
+        );
     }
 
-    // sync permissions between source and destination files
-    dst_file_handle.set_permissions(src_file_metadata.permissions())?;
+    #[test]
+    fn preserve_times_restores_directory_times_after_filling_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir_all(src.join("a/b")).unwrap();
+        std::fs::create_dir_all(src.join("c")).unwrap();
+        for file in ["a/b/f", "a/g", "c/h", "top"] {
+            std::fs::write(src.join(file), file).unwrap();
+        }
+        let dirs = ["", "a", "a/b", "c"];
+        for (i, d) in dirs.iter().enumerate() {
+            let mtime = std::time::SystemTime::UNIX_EPOCH
+                + std::time::Duration::from_secs((1 << 30) + i as u64 * 86400);
+            File::open(src.join(d))
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        }
 
-    // print the final message about the file copy
-    if copy_opts.show_progress {
-        if copy_opts.remove {
-            println!(
-                "\rMoved file '{}'  ",
-                &src.file_name()
-                    .unwrap_or_else(|| std::ffi::OsStr::new(""))
-                    .to_str()
-                    .unwrap_or("")
-            );
-        } else {
-            println!(
-                "\rCopied file '{}' ",
-                &src.file_name()
-                    .unwrap_or_else(|| std::ffi::OsStr::new(""))
-                    .to_str()
-                    .unwrap_or("")
-            );
+        let mut copy_opts = quiet_opts();
+        copy_opts.recursive(true).preserve_times(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        let mtime = |path: &Path| std::fs::metadata(path).unwrap().mtime();
+        for d in dirs {
+            assert_eq!(mtime(&dst.join(d)), mtime(&src.join(d)), "'{}'", d);
         }
     }
-    Ok(bytes_transferred as usize)
-}
 
-#[inline]
-fn default_progress_handler(
-    src: &Path,
-    _dst: &Path,
-    bytes_transferred: u64,
-    total: u64,
-    copy_opts: &CopyOptions,
-) {
-    let human_readable = true;
-    let str_stats_transferred = get_str_size_precise(copy_opts.stats_store.transferred);
-    let str_bytes_transferred = get_str_size_precise(bytes_transferred);
-    let str_stats_total = get_str_size_precise(copy_opts.stats_store.total);
-    let str_bytes_total = get_str_size_precise(total);
+    #[test]
+    fn same_fs_only_refuses_a_copy_across_filesystems() {
+        let dir = tempfile::tempdir().unwrap();
+        let Ok(other_fs) = tempfile::tempdir_in("/dev/shm") else {
+            return;
+        };
+        let dev = |path: &Path| std::fs::metadata(path).unwrap().dev();
+        if dev(dir.path()) == dev(other_fs.path()) {
+            return;
+        }
+        let src = dir.path().join("s");
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("sub/f"), "contents").unwrap();
 
-    if human_readable {
-        print!(
-            "\rCopying file {:50} ({:>8} /{:>8})\tTotal: ({:>8} /{:>8})",
-            format!(
-                "'{}'",
-                src.file_name()
-                    .unwrap_or_else(|| std::ffi::OsStr::new("/"))
-                    .to_str()
-                    .unwrap_or("")
-            ),
-            &str_bytes_transferred,
-            &str_bytes_total,
-            &str_stats_transferred,
-            &str_stats_total,
-        )
-    } else {
-        print!(
-            "\rCopying file {:50} ({:8}/{:8})\tTotal: ({:10}/{:10})",
-            format!("'{}'", src.to_str().unwrap_or("")),
-            &bytes_transferred,
-            &total,
-            &copy_opts.stats_store.transferred,
-            &copy_opts.stats_store.total,
-        )
+        // a recursive copy is checked against the destination root, which
+        // doesn't exist yet
+        let dst = other_fs.path().join("new/d");
+        let mut copy_opts = quiet_opts();
+        copy_opts.recursive(true).same_fs_only(true);
+        let err = copy(path_str(&src), path_str(&dst), copy_opts).unwrap_err();
+        assert!(err.to_string().contains("different filesystems"), "{}", err);
+        assert!(!other_fs.path().join("new").exists());
+
+        let dst = dir.path().join("d");
+        let mut copy_opts = quiet_opts();
+        copy_opts.recursive(true).same_fs_only(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap();
+        assert_eq!(std::fs::read(dst.join("sub/f")).unwrap(), b"contents");
     }
 
-    let _ = std::io::stdout().flush();
-}
+    #[test]
+    fn dst_dir_creation_retries_transient_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("a/b");
+        let failing = |errors: Vec<i32>| {
+            let mut errors = errors.into_iter();
+            move |d: &Path| match errors.next() {
+                Some(errno) => Err(io::Error::from_raw_os_error(errno)),
+                None => std::fs::create_dir_all(d),
+            }
+        };
 
-#[inline]
-fn get_str_size_precise(bytes: u64) -> String {
-    let result: String;
-    if bytes > util::GB {
-        result = format!("{:.2}G", (bytes as f64) / (util::GB as f64));
-    } else if bytes > util::MB {
-        result = format!("{:.2}M", (bytes as f64) / (util::MB as f64));
-    } else if bytes > util::KB {
-        result = format!("{:.2}K", (bytes as f64) / (util::KB as f64));
-    } else {
-        result = format!("{}B", bytes);
+        let (mut copy_opts, out, _) = captured_opts();
+        copy_opts.retry_operation(2).verbose(true);
+        let create = failing(vec![libc::EAGAIN, libc::ETIMEDOUT]);
+        create_dst_dir_with(&target, &copy_opts, create).unwrap();
+        assert!(target.is_dir());
+        assert_eq!(
+            out.text().matches("retrying in").count(),
+            2,
+            "{}",
+            out.text()
+        );
+
+        // an existing directory counts as created
+        let create = failing(vec![libc::EEXIST]);
+        create_dst_dir_with(&target, &copy_opts, create).unwrap();
+
+        // fatal errors aren't retried, and transient ones only so often
+        let create = failing(vec![libc::EACCES]);
+        let err = create_dst_dir_with(&target, &copy_opts, create).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+        let create = failing(vec![libc::EBUSY; 3]);
+        let err = create_dst_dir_with(&target, &copy_opts, create).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBUSY));
+    }
+
+    #[test]
+    fn failed_move_leaves_the_sources_intact() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir(&src).unwrap();
+        std::fs::write(src.join("a"), "first").unwrap();
+        std::fs::write(src.join("b"), "second").unwrap();
+        // the copy of `b` fails on the existing destination
+        std::fs::create_dir_all(dst.join("s")).unwrap();
+        std::fs::write(dst.join("s/b"), "existing").unwrap();
+
+        let mut copy_opts = quiet_opts();
+        copy_opts
+            .recursive(true)
+            .remove(true)
+            .sort(SortOrder::Name)
+            .wipe_source(Some(1))
+            .truncate_source_after(true);
+        copy(path_str(&src), path_str(&dst), copy_opts).unwrap_err();
+        assert_eq!(std::fs::read(dst.join("s/a")).unwrap(), b"first");
+        assert_eq!(std::fs::read(src.join("a")).unwrap(), b"first");
+        assert_eq!(std::fs::read(src.join("b")).unwrap(), b"second");
+    }
+
+    #[test]
+    fn slices_are_refused_by_the_modes_which_copy_whole_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("s");
+        std::fs::write(&src, random_bytes(10000)).unwrap();
+        let out = dir.path().join("out");
+        let sliced = || {
+            let mut copy_opts = quiet_opts();
+            copy_opts.head(Some(4096));
+            copy_opts
+        };
+
+        let src = path_str(&src);
+        let results = [
+            concat(&[src, src], path_str(&out), sliced()),
+            copy_to_writer(&[src], &mut Vec::new(), sliced()),
+            split(src, path_str(&out), 4096, sliced()),
+            fill(&[0], 10, path_str(&out), sliced()),
+        ];
+        for result in results {
+            let err = result.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            assert!(
+                err.to_string()
+                    .contains("head, tail, offset and length options can't be used when"),
+                "{}",
+                err
+            );
+        }
+        assert!(!out.exists());
     }
-    result
 }
@@ -1,4 +1,5 @@
 mod filecopy;
 pub use filecopy::*;
 
+pub(crate) mod digest;
 pub(crate) mod util;
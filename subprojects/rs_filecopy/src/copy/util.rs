@@ -1,23 +1,43 @@
+use super::{CompressAlgo, SortOrder, TimesPrecision};
+use std::os::unix::{ffi::OsStrExt, fs::MetadataExt, io::AsRawFd};
 use std::{
-    fs::{self, File},
+    fs::{self, File, OpenOptions},
     io,
-    io::{Read, Write},
-    path::Path,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 pub(crate) const KB: u64 = 1024;
 pub(crate) const MB: u64 = 1024 * KB;
 pub(crate) const GB: u64 = 1024 * MB;
 
+/// Maximum length of a single file name in bytes
+pub(crate) const NAME_MAX: usize = 255;
+
+/// Mount table of the running system
+pub(crate) const MOUNT_TABLE: &str = "/proc/mounts";
+
+/// Filesystem types whose contents are generated by the kernel and make no
+/// sense to copy
+const PSEUDO_FS_TYPES: &[&str] = &["proc", "sysfs", "devpts", "cgroup", "cgroup2"];
+
+pub(crate) const MIN_BLOCK_SIZE: u64 = 4 * KB;
+pub(crate) const MAX_BLOCK_SIZE: u64 = GB;
+
 #[derive(Debug)]
 pub(crate) struct DirFile {
     path: String,
     size: u64,
+    modified: Option<SystemTime>,
 }
 impl DirFile {
     pub(crate) fn size(&self) -> u64 {
         self.size
     }
+    pub(crate) fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
     pub(crate) fn path(&self) -> &String {
         &self.path
     }
@@ -39,18 +59,222 @@ pub(crate) fn delete_dir_recursive(basepath: &Path) -> io::Result<()> {
     Ok(())
 }
 
+/// Returns why the directory `path` must not be removed as a whole, if it
+/// resolves to the root of a filesystem, the home directory of the user or
+/// the current working directory. The path is canonicalized first, so that
+/// symlinks and `..` components can't hide any of them.
+pub(crate) fn protected_dir(path: &Path) -> io::Result<Option<&'static str>> {
+    let path = fs::canonicalize(path)?;
+    let is_fs_root = match path.parent() {
+        None => true,
+        Some(parent) => fs::metadata(parent)?.dev() != fs::metadata(&path)?.dev(),
+    };
+    let is_same = |other: Option<PathBuf>| {
+        other
+            .and_then(|p| fs::canonicalize(p).ok())
+            .is_some_and(|p| p == path)
+    };
+    if is_fs_root {
+        Ok(Some("the root of a filesystem"))
+    } else if is_same(std::env::var_os("HOME").map(PathBuf::from)) {
+        Ok(Some("the home directory"))
+    } else if is_same(std::env::current_dir().ok()) {
+        Ok(Some("the current working directory"))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Checks that `path` can be created as a directory, i.e., that the deepest
+/// existing component of `path` is a directory. Returns an error naming the
+/// offending component if it is a file, so that conflicts are reported before
+/// any data is copied instead of surfacing from [`std::fs::create_dir_all`].
+pub(crate) fn validate_dir_path(path: &Path) -> io::Result<()> {
+    for component in path.ancestors() {
+        if let Ok(metadata) = fs::metadata(component) {
+            if metadata.is_dir() {
+                return Ok(());
+            }
+            return Err(io::Error::other(format!(
+                "path component '{}' is a file, not a directory",
+                component.to_str().unwrap_or("")
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `path` and each of its file names are within the length
+/// limits of the system, i.e., `PATH_MAX` and [`NAME_MAX`]. Returns an error
+/// naming the offending path or file name along with its length and the limit
+/// so that it is reported before opening the file fails with `ENAMETOOLONG`.
+pub(crate) fn check_path_len(path: &Path) -> io::Result<()> {
+    let path_len = path.as_os_str().len();
+    if path_len >= libc::PATH_MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "destination path '{}' is too long: {} bytes, limit is {} bytes",
+                path.to_str().unwrap_or(""),
+                path_len,
+                libc::PATH_MAX - 1
+            ),
+        ));
+    }
+    for component in path.components() {
+        if let Component::Normal(name) = component {
+            if name.len() > NAME_MAX {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "file name '{}' in destination path is too long: {} bytes, limit is {} bytes",
+                        name.to_str().unwrap_or(""),
+                        name.len(),
+                        NAME_MAX
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Shortens every file name in `path` longer than [`NAME_MAX`] by keeping
+/// its beginning and appending a hash of the full name, so that distinct long
+/// names still map to distinct short ones. This is lossy, the original name
+/// can't be recovered from the shortened one.
+pub(crate) fn shorten_long_names(path: &Path) -> PathBuf {
+    const HASH_LEN: usize = 17;
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) if name.len() > NAME_MAX => {
+                let bytes = name.as_bytes();
+                let mut keep = NAME_MAX - HASH_LEN;
+                // avoid splitting a multi-byte utf-8 character
+                while keep > 0 && (bytes[keep] & 0xC0) == 0x80 {
+                    keep -= 1;
+                }
+                let mut short_name = std::ffi::OsStr::from_bytes(&bytes[..keep]).to_owned();
+                short_name.push(format!("~{:016x}", fnv1a_hash(bytes)));
+                result.push(short_name);
+            }
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Computes the 64-bit FNV-1a hash of `bytes`, which stays stable across
+/// runs and builds.
+fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Reads the mount points of pseudo filesystems, like `proc` and `sysfs`,
+/// from the mount table at `mount_table`, which has the format of
+/// `/proc/mounts`. Returns the mount points along with their filesystem type.
+pub(crate) fn read_pseudo_fs_mounts(mount_table: &Path) -> io::Result<Vec<(PathBuf, String)>> {
+    let content = fs::read(mount_table)?;
+    let mut mounts = Vec::new();
+    for line in content.split(|b| *b == b'\n') {
+        let mut fields = line.split(|b| *b == b' ');
+        let (Some(_), Some(mount_point), Some(fs_type)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let fs_type = String::from_utf8_lossy(fs_type).into_owned();
+        if PSEUDO_FS_TYPES.contains(&fs_type.as_str()) {
+            mounts.push((unescape_mount_path(mount_point), fs_type));
+        }
+    }
+    Ok(mounts)
+}
+
+/// Decodes the octal escapes, like `\040` for a space, used for the special
+/// characters of the paths in the mount table.
+fn unescape_mount_path(escaped: &[u8]) -> PathBuf {
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut i = 0;
+    while i < escaped.len() {
+        if escaped[i] == b'\\'
+            && i + 3 < escaped.len()
+            && escaped[i + 1..i + 4]
+                .iter()
+                .all(|b| (b'0'..=b'7').contains(b))
+        {
+            let value = escaped[i + 1..i + 4]
+                .iter()
+                .fold(0u32, |acc, b| acc * 8 + (b - b'0') as u32);
+            bytes.push(value as u8);
+            i += 4;
+        } else {
+            bytes.push(escaped[i]);
+            i += 1;
+        }
+    }
+    PathBuf::from(std::ffi::OsStr::from_bytes(&bytes))
+}
+
 /// Given a path, it generates a list of file paths and the file size
 /// recursively. It returns any error thrown by [`std::fs::read_dir`] or
 /// [`std::fs::DirEntry::metadata`] with some extra message to give context
 /// of what went wrong. The [`io::ErrorKind`] value remains the same.
-pub(crate) fn list_dir_recursive_rel(basepath: &Path) -> Result<Vec<DirFile>, io::Error> {
-    list_dir_recursive_rel_util(basepath, Path::new(""))
+/// The entries of each directory are visited in the order given by `sort`.
+/// The directories in `skip_dirs`, relative to `basepath`, aren't descended
+/// into. The scan fails once more than `max_entries` files and directories
+/// are found. A directory which was already visited, like a bind mount of
+/// one of its ancestors, is skipped, and its path is added to `loops` for
+/// the caller to warn about.
+pub(crate) fn list_dir_recursive_rel(
+    basepath: &Path,
+    sort: SortOrder,
+    skip_dirs: &[PathBuf],
+    max_entries: Option<u64>,
+    loops: &mut Vec<PathBuf>,
+) -> Result<Vec<DirFile>, io::Error> {
+    let mut scan = DirScan {
+        basepath,
+        sort,
+        skip_dirs,
+        max_entries,
+        found: 0,
+        visited: std::collections::HashSet::new(),
+        loops: Vec::new(),
+    };
+    if let Ok(m) = fs::metadata(basepath) {
+        scan.visited.insert((m.dev(), m.ino()));
+    }
+    let result = list_dir_recursive_rel_util(&mut scan, Path::new(""));
+    loops.append(&mut scan.loops);
+    result
+}
+
+/// Options and state of a recursive directory scan
+struct DirScan<'a> {
+    basepath: &'a Path,
+    sort: SortOrder,
+    skip_dirs: &'a [PathBuf],
+    max_entries: Option<u64>,
+    found: u64,
+    visited: std::collections::HashSet<(u64, u64)>,
+    loops: Vec<PathBuf>,
 }
 
-fn list_dir_recursive_rel_util(basepath: &Path, abspath: &Path) -> Result<Vec<DirFile>, io::Error> {
+fn list_dir_recursive_rel_util(
+    scan: &mut DirScan,
+    abspath: &Path,
+) -> Result<Vec<DirFile>, io::Error> {
+    let basepath = scan.basepath;
     let mut result = Vec::<DirFile>::new();
     let read_path = basepath.join(abspath);
-    let dir_reader = match std::fs::read_dir(&read_path.as_path()) {
+    let dir_reader = match std::fs::read_dir(read_path.as_path()) {
         Ok(r) => r,
         Err(e) => {
             return Err(io::Error::new(
@@ -63,6 +287,7 @@ fn list_dir_recursive_rel_util(basepath: &Path, abspath: &Path) -> Result<Vec<Di
             ));
         }
     };
+    let mut entries = Vec::new();
     for entry in dir_reader {
         let entry = match entry {
             Ok(e) => e,
@@ -73,6 +298,14 @@ fn list_dir_recursive_rel_util(basepath: &Path, abspath: &Path) -> Result<Vec<Di
                 ));
             }
         };
+        scan.found += 1;
+        if let Some(max) = scan.max_entries.filter(|max| scan.found > *max) {
+            return Err(io::Error::other(format!(
+                "found more than {} files and directories under '{}', stopping the scan",
+                max,
+                basepath.to_str().unwrap_or("")
+            )));
+        }
         let metadata = match entry.metadata() {
             Ok(m) => m,
             Err(e) => {
@@ -86,77 +319,1400 @@ fn list_dir_recursive_rel_util(basepath: &Path, abspath: &Path) -> Result<Vec<Di
                 ));
             }
         };
-        let path = abspath.join(&entry.file_name());
+        // symlinks to files are copied with the size of their target
+        let metadata = if metadata.file_type().is_symlink() {
+            fs::metadata(entry.path())
+                .ok()
+                .filter(|m| !m.is_dir())
+                .unwrap_or(metadata)
+        } else {
+            metadata
+        };
+        entries.push((entry.file_name(), metadata));
+    }
+
+    match scan.sort {
+        SortOrder::None => {}
+        SortOrder::Name => entries.sort_by(|a, b| a.0.cmp(&b.0)),
+        SortOrder::Size => entries.sort_by(|a, b| a.1.len().cmp(&b.1.len()).then(a.0.cmp(&b.0))),
+    }
+
+    for (file_name, metadata) in entries {
+        let path = abspath.join(file_name);
         if metadata.is_dir() {
-            if let Ok(mut filelist) = list_dir_recursive_rel_util(basepath, path.as_path()) {
-                result.append(&mut filelist);
+            if scan.skip_dirs.contains(&path) {
+                continue;
+            }
+            if !scan.visited.insert((metadata.dev(), metadata.ino())) {
+                scan.loops.push(basepath.join(&path));
+                continue;
+            }
+            match list_dir_recursive_rel_util(scan, path.as_path()) {
+                Ok(mut filelist) => result.append(&mut filelist),
+                // unreadable subdirectories are skipped, but not the limit
+                Err(e) if scan.max_entries.is_some_and(|max| scan.found > max) => return Err(e),
+                Err(_) => {}
             }
         } else {
             result.push(DirFile {
                 path: String::from(path.as_path().to_str().unwrap_or("")),
                 size: metadata.len(),
+                modified: metadata.modified().ok(),
             });
         }
     }
     Ok(result)
 }
 
-/// Parsee a human readable size to bytes. In case of an error, it returns
-/// byte value of 8M, i.e., 8 * 1024 * 1024 bytes
-pub(crate) fn parse_size_from_str(str_size: &str) -> u64 {
-    let str_size_bytes = str_size.as_bytes();
-    let mut i = 0;
-    for x in str_size_bytes {
-        if (b'0'..=b'9').contains(x) {
-            i += 1
-        } else {
-            break;
+/// Lists the `count` most recently modified regular files directly under
+/// `basepath` whose name matches the shell glob `pattern`, newest first. Files
+/// with the same modification time are ordered by name.
+pub(crate) fn list_dir_newest(
+    basepath: &Path,
+    count: usize,
+    pattern: &str,
+) -> Result<Vec<DirFile>, io::Error> {
+    let dir_reader = match std::fs::read_dir(basepath) {
+        Ok(r) => r,
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!(
+                    "failure in reading directory '{}': {}",
+                    &basepath.to_str().unwrap_or(""),
+                    &e
+                ),
+            ));
+        }
+    };
+    let mut entries = Vec::new();
+    for entry in dir_reader {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("failure in reading directory entry: {}", e),
+                ));
+            }
+        };
+        if !glob_match(pattern, &entry.file_name()) {
+            continue;
+        }
+        match fs::metadata(entry.path()) {
+            Ok(m) if m.is_file() => entries.push((entry.file_name(), m)),
+            Ok(_) => {}
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!(
+                        "failure in reading metadata entry for file '{}': {}",
+                        &entry.path().to_str().unwrap_or(""),
+                        e
+                    ),
+                ));
+            }
+        }
+    }
+
+    entries.sort_by(|a, b| {
+        let (a_modified, b_modified) = (a.1.modified().ok(), b.1.modified().ok());
+        b_modified.cmp(&a_modified).then(a.0.cmp(&b.0))
+    });
+    entries.truncate(count);
+
+    Ok(entries
+        .into_iter()
+        .map(|(file_name, metadata)| DirFile {
+            path: String::from(file_name.to_str().unwrap_or("")),
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+        .collect())
+}
+
+/// Matches the file `name` against the shell glob `pattern`, which supports
+/// `*`, `?` and bracket expressions like `[a-z]` or `[!0-9]`. As in the shell,
+/// a leading dot in `name` is only matched by a literal dot.
+fn glob_match(pattern: &str, name: &std::ffi::OsStr) -> bool {
+    let name = name.as_bytes();
+    if name.first() == Some(&b'.') && !pattern.starts_with('.') {
+        return false;
+    }
+    glob_match_bytes(pattern.as_bytes(), name)
+}
+
+fn glob_match_bytes(pattern: &[u8], name: &[u8]) -> bool {
+    // position of the last `*` in the pattern and of the name it is matched
+    // against, to backtrack to on a mismatch
+    let mut backtrack: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, n));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(1),
+            Some(b'[') => match_bracket(&pattern[p..], name[n]),
+            Some(&c) if c == name[n] => Some(1),
+            _ => None,
+        };
+        match (step, backtrack) {
+            (Some(len), _) => {
+                p += len;
+                n += 1;
+            }
+            (None, Some((star, matched))) => {
+                // let the `*` consume one more byte of the name
+                p = star + 1;
+                n = matched + 1;
+                backtrack = Some((star, matched + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches `c` against the bracket expression at the start of `pattern`.
+/// Returns the length of the expression if it matches. An unterminated
+/// bracket matches a literal `[`.
+fn match_bracket(pattern: &[u8], c: u8) -> Option<usize> {
+    let negate = matches!(pattern.get(1), Some(b'!') | Some(b'^'));
+    let mut i = if negate { 2 } else { 1 };
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        match pattern.get(i) {
+            None => return if c == b'[' { Some(1) } else { None },
+            Some(b']') if !first => break,
+            Some(&lo) => {
+                if pattern.get(i + 1) == Some(&b'-')
+                    && pattern.get(i + 2).is_some_and(|&hi| hi != b']')
+                {
+                    matched |= (lo..=pattern[i + 2]).contains(&c);
+                    i += 3;
+                } else {
+                    matched |= lo == c;
+                    i += 1;
+                }
+            }
+        }
+        first = false;
+    }
+    if matched != negate {
+        Some(i + 1)
+    } else {
+        None
+    }
+}
+
+/// Reads the start time of the last successful run from the state file at
+/// `path`. The state file holds the time as nanoseconds since the Unix epoch.
+/// Returns `None` if the state file doesn't exist.
+pub(crate) fn read_run_state(path: &Path) -> io::Result<Option<SystemTime>> {
+    let content = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!(
+                    "failure in reading state file '{}': {}",
+                    path.to_str().unwrap_or(""),
+                    e
+                ),
+            ))
+        }
+    };
+    let nanos = content.trim().parse::<u64>().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "invalid timestamp in state file '{}': {}",
+                path.to_str().unwrap_or(""),
+                e
+            ),
+        )
+    })?;
+    Ok(Some(UNIX_EPOCH + std::time::Duration::from_nanos(nanos)))
+}
+
+/// Reads the relative paths listed in the file at `path`, one per line.
+/// Empty lines are ignored and a leading `./` is removed.
+pub(crate) fn read_path_list(path: &Path) -> io::Result<Vec<String>> {
+    let content = fs::read_to_string(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "failure in reading path list '{}': {}",
+                path.to_str().unwrap_or(""),
+                e
+            ),
+        )
+    })?;
+    Ok(content
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| line.strip_prefix("./").unwrap_or(line).to_owned())
+        .collect())
+}
+
+/// Records `time` as the start time of the last successful run in the state
+/// file at `path`. The file is replaced atomically so that an interrupted
+/// write doesn't leave a corrupt state behind.
+pub(crate) fn write_run_state(path: &Path, time: SystemTime) -> io::Result<()> {
+    let nanos = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    write_atomic(path, format!("{}\n", nanos).as_bytes()).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "failure in writing state file '{}': {}",
+                path.to_str().unwrap_or(""),
+                e
+            ),
+        )
+    })
+}
+
+/// Escapes `s` for use as a JSON string value, without the quotes.
+pub(crate) fn json_escape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
         }
     }
+    result
+}
+
+/// Quotes `path` for a POSIX shell by wrapping it in single quotes, with the
+/// single quotes in it written as `'\''`.
+pub(crate) fn shell_quote(path: &Path) -> String {
+    let path = String::from_utf8_lossy(path.as_os_str().as_bytes());
+    format!("'{}'", path.replace('\'', "'\\''"))
+}
 
-    let (size_num, size_suffix) = (
-        String::from_utf8(str_size_bytes[..i].to_vec())
-            .unwrap_or_else(|e| {
-                println!("found invalid utf-8 size string: {}", e);
-                "8".to_string()
-            })
-            .parse::<u64>()
-            .unwrap_or(8),
-        String::from_utf8(str_size_bytes[i..].to_vec()).unwrap_or_else(|e| {
-            println!("found invalid utf-8 size suffix string: {}", e);
-            "M".to_string()
-        }),
-    );
-    match size_suffix.as_str() {
-        "k" | "K" => size_num * KB,
-        "m" | "M" => size_num * MB,
-        "g" | "G" => size_num * GB,
-        _ => 8 * MB,
+/// Quotes `s` for use as a CSV field if it contains a separator, a quote or
+/// a line break.
+pub(crate) fn csv_escape(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
     }
 }
 
+/// Replaces the content of the file at `path` with `content` atomically by
+/// writing it to a temporary file and renaming it over `path`, so that readers
+/// always see either the old or the new content in full.
+pub(crate) fn write_atomic(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, content)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Parses a size like `512`, `32K`, `8M` or `1G` into bytes, where a plain
+/// number is a count of bytes. Anything which isn't a valid size, or which
+/// doesn't fit into 64 bits, is `None`.
+pub(crate) fn parse_size(str_size: &str) -> Option<u64> {
+    let digits = str_size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(str_size.len());
+    let size_num = str_size[..digits].parse::<u64>().ok()?;
+    let unit = match &str_size[digits..] {
+        "" => 1,
+        "k" | "K" => KB,
+        "m" | "M" => MB,
+        "g" | "G" => GB,
+        _ => return None,
+    };
+    size_num.checked_mul(unit)
+}
+
+/// Path of the socket of the native protocol of the systemd journal
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Sends a record with the `KEY=value` `fields` to the systemd journal over
+/// its native protocol. Newlines in the values are replaced with spaces, as
+/// they would end the field.
+pub(crate) fn journal_send(fields: &[(&str, String)]) -> io::Result<()> {
+    let mut record = String::new();
+    for (key, value) in fields {
+        record.push_str(key);
+        record.push('=');
+        record.push_str(&value.replace('\n', " "));
+        record.push('\n');
+    }
+    let socket = std::os::unix::net::UnixDatagram::unbound()?;
+    socket.send_to(record.as_bytes(), JOURNAL_SOCKET)?;
+    Ok(())
+}
+
+/// Parses a human readable duration, like `500ms`, `30s`, `5m` or `1h`, where
+/// a plain number is taken as seconds. Returns `None` for an invalid value.
+pub(crate) fn parse_duration_from_str(str_duration: &str) -> Option<Duration> {
+    let digits = str_duration
+        .bytes()
+        .take_while(|b| b.is_ascii_digit())
+        .count();
+    let value = str_duration[..digits].parse::<u64>().ok()?;
+    match &str_duration[digits..] {
+        "ms" => Some(Duration::from_millis(value)),
+        "" | "s" => Some(Duration::from_secs(value)),
+        "m" => Some(Duration::from_secs(value * 60)),
+        "h" => Some(Duration::from_secs(value * 3600)),
+        _ => None,
+    }
+}
+
+/// I/O scheduling classes of `ioprio_set`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum IoClass {
+    Realtime = 1,
+    BestEffort = 2,
+    Idle = 3,
+}
+
+/// Longest repeating pattern accepted by [`parse_fill_pattern`], in bytes
+const MAX_FILL_PATTERN: usize = 64;
+
+/// Parses a fill pattern given as hex bytes with a `0x` prefix, like `0x00`
+/// or `0xDEADBEEF`. Returns `None` for an invalid or too long pattern.
+pub(crate) fn parse_fill_pattern(spec: &str) -> Option<Vec<u8>> {
+    let hex = spec
+        .strip_prefix("0x")
+        .or_else(|| spec.strip_prefix("0X"))?;
+    if hex.is_empty()
+        || hex.len() % 2 != 0
+        || hex.len() / 2 > MAX_FILL_PATTERN
+        || !hex.bytes().all(|b| b.is_ascii_hexdigit())
+    {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Parses an I/O priority of the form `<class>[:level]`, where the class is
+/// one of `realtime`, `best-effort` or `idle` and the level ranges from 0
+/// (highest) to 7 (lowest), defaulting to 4. The idle class takes no level.
+pub(crate) fn parse_io_priority(spec: &str) -> Option<(IoClass, u32)> {
+    let (class, level) = match spec.split_once(':') {
+        Some((class, level)) => (class, Some(level.parse::<u32>().ok()?)),
+        None => (spec, None),
+    };
+    let class = match class {
+        "realtime" | "rt" => IoClass::Realtime,
+        "best-effort" | "be" => IoClass::BestEffort,
+        "idle" if level.is_none() => return Some((IoClass::Idle, 0)),
+        _ => return None,
+    };
+    let level = level.unwrap_or(4);
+    if level > 7 {
+        return None;
+    }
+    Some((class, level))
+}
+
+/// Sets the I/O scheduling class and level of the current process. Only
+/// schedulers which support I/O priorities, like BFQ, act on them.
+pub(crate) fn set_io_priority(class: IoClass, level: u32) -> io::Result<()> {
+    const IOPRIO_CLASS_SHIFT: u32 = 13;
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+    let ioprio = ((class as u32) << IOPRIO_CLASS_SHIFT) | level;
+    // SAFETY: `ioprio_set` only takes integer arguments, a `who` of 0 is the
+    // calling process
+    if unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            ioprio as libc::c_int,
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Token bucket limiting the transfer rate to `rate` bytes per second, with a
+/// burst of up to one second worth of data. With a ramp up duration, the
+/// bucket starts empty and the allowed rate grows linearly from zero to
+/// `rate` over that duration, which avoids the initial burst.
+#[derive(Clone)]
+pub(crate) struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    start: Instant,
+    last: Instant,
+    ramp_up: Option<Duration>,
+}
+
+impl RateLimiter {
+    /// Longest single sleep while waiting for tokens
+    const MAX_WAIT: Duration = Duration::from_millis(100);
+
+    pub(crate) fn new(rate: u64, ramp_up: Option<Duration>) -> Self {
+        let now = Instant::now();
+        let ramp_up = ramp_up.filter(|d| !d.is_zero());
+        Self {
+            rate: rate as f64,
+            tokens: if ramp_up.is_some() { 0.0 } else { rate as f64 },
+            start: now,
+            last: now,
+            ramp_up,
+        }
+    }
+
+    /// Blocks until `bytes` more bytes may be transferred.
+    pub(crate) fn throttle(&mut self, bytes: u64) {
+        self.refill();
+        self.tokens -= bytes as f64;
+        while self.tokens < 0.0 {
+            let rate = self.rate_at(self.last.duration_since(self.start).as_secs_f64());
+            let wait = if rate > 0.0 {
+                Duration::from_secs_f64(-self.tokens / rate).min(Self::MAX_WAIT)
+            } else {
+                Self::MAX_WAIT
+            };
+            std::thread::sleep(wait.max(Duration::from_millis(1)));
+            self.refill();
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let from = self.last.duration_since(self.start).as_secs_f64();
+        let to = now.duration_since(self.start).as_secs_f64();
+        self.tokens = (self.tokens + self.earned(to) - self.earned(from)).min(self.rate);
+        self.last = now;
+    }
+
+    /// Allowed rate at `elapsed` seconds since the start
+    fn rate_at(&self, elapsed: f64) -> f64 {
+        match self.ramp_up {
+            Some(ramp) if elapsed < ramp.as_secs_f64() => self.rate * elapsed / ramp.as_secs_f64(),
+            _ => self.rate,
+        }
+    }
+
+    /// Total bytes allowed in the first `elapsed` seconds
+    fn earned(&self, elapsed: f64) -> f64 {
+        match self.ramp_up {
+            Some(ramp) => {
+                let ramp = ramp.as_secs_f64();
+                if elapsed <= ramp {
+                    self.rate * elapsed * elapsed / (2.0 * ramp)
+                } else {
+                    self.rate * ramp / 2.0 + self.rate * (elapsed - ramp)
+                }
+            }
+            None => self.rate * elapsed,
+        }
+    }
+}
+
+fn statvfs(path: &Path) -> io::Result<libc::statvfs> {
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: `c_path` is a valid NUL terminated string and `stat` is only
+    // written to by `statvfs`
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat)
+}
+
+/// Returns the space available to unprivileged users on the filesystem
+/// holding `path`, in bytes.
+pub(crate) fn free_space(path: &Path) -> io::Result<u64> {
+    let stat = statvfs(path)?;
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Returns the number of inodes available to unprivileged users on the
+/// filesystem holding `path`, or `None` if the filesystem doesn't have a fixed
+/// number of inodes.
+pub(crate) fn free_inodes(path: &Path) -> io::Result<Option<u64>> {
+    let stat = statvfs(path)?;
+    if stat.f_files == 0 {
+        return Ok(None);
+    }
+    Ok(Some(stat.f_favail as u64))
+}
+
+/// Atomically exchanges the paths `a` and `b` with `renameat2` and
+/// `RENAME_EXCHANGE`. Both paths have to exist on the same filesystem, which
+/// has to support the exchange.
+pub(crate) fn exchange_paths(a: &Path, b: &Path) -> io::Result<()> {
+    const RENAME_EXCHANGE: libc::c_uint = 2;
+
+    let c_a = std::ffi::CString::new(a.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let c_b = std::ffi::CString::new(b.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: `c_a` and `c_b` are valid NUL terminated strings which outlive
+    // the call
+    if unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            libc::AT_FDCWD,
+            c_a.as_ptr(),
+            libc::AT_FDCWD,
+            c_b.as_ptr(),
+            RENAME_EXCHANGE,
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Takes an exclusive advisory lock on `path`, creating the file if needed.
+/// Fails right away if another process holds the lock. The lock is released
+/// when the returned file is closed, which the kernel also does if the process
+/// dies.
+pub(crate) fn lock_file(path: &Path) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to open lock file: {}", e)))?;
+    // SAFETY: `file` is an open file descriptor for the duration of the call
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!(
+                    "another copy is in progress, '{}' is locked",
+                    path.display()
+                ),
+            ));
+        }
+        return Err(io::Error::new(
+            err.kind(),
+            format!("failed to lock '{}': {}", path.display(), err),
+        ));
+    }
+    Ok(file)
+}
+
+/// Returns the size in bytes of the block device at `path`.
+pub(crate) fn block_device_size(path: &Path) -> io::Result<u64> {
+    // _IOR(0x12, 114, size_t)
+    const BLKGETSIZE64: libc::c_ulong = (2 << 30)
+        | ((std::mem::size_of::<libc::size_t>() as libc::c_ulong) << 16)
+        | (0x12 << 8)
+        | 114;
+
+    let device = File::open(path)?;
+    let mut size: u64 = 0;
+    // SAFETY: `BLKGETSIZE64` writes a single u64 to `size` and the file
+    // descriptor is valid for the lifetime of `device`
+    if unsafe { libc::ioctl(device.as_raw_fd(), BLKGETSIZE64, &mut size) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(size)
+}
+
+/// Converts `time` to the local wall clock time of the day and returns it as
+/// a tuple of hours, minutes and seconds.
+pub(crate) fn local_time_of_day(time: SystemTime) -> (u32, u32, u32) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0) as libc::time_t;
+    // SAFETY: `localtime_r` only writes to the `tm` struct passed to it
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+        return (
+            (secs / 3600 % 24) as u32,
+            (secs / 60 % 60) as u32,
+            (secs % 60) as u32,
+        );
+    }
+    (tm.tm_hour as u32, tm.tm_min as u32, tm.tm_sec as u32)
+}
+
+/// Clamps `block_size` to the range [`MIN_BLOCK_SIZE`, `MAX_BLOCK_SIZE`]. A
+/// block size of 0 is kept as is, as it is an error rather than an extreme.
+pub(crate) fn clamp_block_size(block_size: u64) -> u64 {
+    if block_size == 0 {
+        return 0;
+    }
+    block_size.clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+/// Copies upto `len` bytes of data from `src` to `dst`, or until `src`
+/// reaches EOF if `len` is `None`, using `buf` as the intermediate buffer.
+/// Returns the total number of bytes actually transferred or an error if it
+/// occurs.
+pub fn copy_stream<R: Read, W: Write + ?Sized>(
+    src: &mut R,
+    dst: &mut W,
+    len: Option<u64>,
+    buf: &mut [u8],
+) -> io::Result<u64> {
+    if buf.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "copy buffer must not be empty",
+        ));
+    }
+    let mut bytes_copied: u64 = 0;
+    loop {
+        let chunk = match len {
+            Some(l) => min(l - bytes_copied, buf.len() as u64) as usize,
+            None => buf.len(),
+        };
+        if chunk == 0 {
+            break;
+        }
+        let read_cnt = match src.read(&mut buf[..chunk]) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        dst.write_all(&buf[..read_cnt])?;
+        bytes_copied += read_cnt as u64;
+    }
+    Ok(bytes_copied)
+}
+
 /// Copies upto `bytes_to_read` bytes of data from `src` to `dst`. Returns
 /// the total number of bytes actually transferred or an error if it occurs.
-pub(crate) fn copy_n(src: &mut File, dst: &mut File, bytes_to_read: usize) -> io::Result<usize> {
+pub(crate) fn copy_n<R: Read, W: Write>(
+    src: &mut R,
+    dst: &mut W,
+    bytes_to_read: usize,
+) -> io::Result<usize> {
     const DEFAULT_BUFFER_SIZE: usize = 32 * KB as usize;
-    let mut bytes_to_read_local = bytes_to_read;
     let mut buf = [0u8; DEFAULT_BUFFER_SIZE];
+    copy_stream(src, dst, Some(bytes_to_read as u64), &mut buf).map(|n| n as usize)
+}
+
+/// A reader which endlessly repeats a byte pattern.
+pub(crate) struct PatternReader<'a> {
+    pattern: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PatternReader<'a> {
+    /// Creates a reader repeating the non-empty `pattern`.
+    pub(crate) fn new(pattern: &'a [u8]) -> Self {
+        PatternReader { pattern, pos: 0 }
+    }
+}
+
+impl Read for PatternReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        for byte in buf.iter_mut() {
+            *byte = self.pattern[self.pos];
+            self.pos = (self.pos + 1) % self.pattern.len();
+        }
+        Ok(buf.len())
+    }
+}
+
+/// A writer for the destination file which needs a final step once all the
+/// data has been written.
+pub(crate) trait FinishWrite: Write {
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/// How the data is written to a destination file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum WriteMode {
+    /// Written as is through the page cache
+    Buffered,
+    /// Runs of zeros at least this many bytes long are left as holes
+    Sparse(u64),
+    /// Written with `O_DIRECT`, bypassing the page cache
+    Direct,
+}
+
+/// Strategy of a file copy. The data is moved by the generic [`copy_stream`]
+/// loop, through a buffer reused for every block, into the writer picked for
+/// the [`WriteMode`], which holds the `File` specific fast paths.
+pub(crate) struct CopyStrategy<'a> {
+    writer: Box<dyn FinishWrite + 'a>,
+    buf: Vec<u8>,
+}
+
+impl<'a> CopyStrategy<'a> {
+    /// Creates the strategy writing to `file` in `mode` through a buffer of
+    /// `buf_size` bytes. `on_fallback` is called if direct I/O has to fall
+    /// back to the page cache.
+    pub(crate) fn new(
+        file: &'a mut File,
+        mode: WriteMode,
+        buf_size: u64,
+        on_fallback: Box<dyn FnMut() + 'a>,
+    ) -> Self {
+        let writer: Box<dyn FinishWrite + 'a> = match mode {
+            WriteMode::Buffered => Box::new(SparseWriter::new(file, None)),
+            WriteMode::Sparse(min_hole) => Box::new(SparseWriter::new(file, Some(min_hole))),
+            WriteMode::Direct => Box::new(DirectWriter::new(file, on_fallback)),
+        };
+        Self {
+            writer,
+            buf: vec![0; std::cmp::max(buf_size, 1) as usize],
+        }
+    }
+
+    /// Creates the strategy writing to the stream `writer`, like stdout,
+    /// through a buffer of `buf_size` bytes. None of the `File` specific fast
+    /// paths apply to a stream, which is flushed when finished.
+    pub(crate) fn stream(writer: &'a mut dyn Write, buf_size: u64) -> Self {
+        Self {
+            writer: Box::new(StreamWriter(writer)),
+            buf: vec![0; std::cmp::max(buf_size, 1) as usize],
+        }
+    }
+
+    /// Copies up to `len` bytes from `src`. Returns the number of bytes
+    /// copied, which is less than `len` only if `src` has ended.
+    pub(crate) fn copy_block<R: Read>(&mut self, src: &mut R, len: u64) -> io::Result<u64> {
+        copy_stream(src, &mut self.writer, Some(len), &mut self.buf)
+    }
+
+    /// Completes the writes once all the data has been copied.
+    pub(crate) fn finish(&mut self) -> io::Result<()> {
+        self.writer.finish()
+    }
+}
+
+/// A writer for a destination stream of a [`CopyStrategy`].
+struct StreamWriter<'a>(&'a mut dyn Write);
+
+impl Write for StreamWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl FinishWrite for StreamWriter<'_> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Fits the copy buffers of `jobs` jobs, a block of `block_size` bytes each,
+/// into `max_memory` bytes. Returns the number of jobs and the block size to
+/// use. Blocks are shrunk first, down to 1M, and then the number of jobs is
+/// reduced. A single job still gets a block of [`MIN_BLOCK_SIZE`] if the limit
+/// is below it.
+pub(crate) fn fit_memory(jobs: usize, block_size: u64, max_memory: u64) -> (usize, u64) {
+    if (jobs as u64).saturating_mul(block_size) <= max_memory {
+        return (jobs, block_size);
+    }
+    let min_block = min(block_size, MB);
+    let jobs = (max_memory / min_block).clamp(1, jobs as u64);
+    let block_size = (max_memory / jobs).clamp(min(MIN_BLOCK_SIZE, block_size), block_size);
+    (jobs as usize, block_size)
+}
+
+/// Size of the buffer of a [`CopyStrategy`] which copies `len` bytes in
+/// blocks of `block_size` bytes. Small files don't need a buffer of a whole
+/// block, while a `len` of 0 may still hide contents, like pseudo files.
+pub(crate) fn copy_buffer_size(block_size: u64, len: u64) -> u64 {
+    min(block_size, std::cmp::max(len, MIN_BLOCK_SIZE))
+}
+
+/// A writer for the destination file which, when `min_hole` is set, turns
+/// runs of zero bytes at least `min_hole` bytes long into holes by seeking
+/// over them instead of writing them. Shorter zero runs are written densely
+/// to avoid fragmenting the destination. Without `min_hole`, all data is
+/// written as is. [`SparseWriter::finish`] must be called once all the data
+/// has been written so that a trailing hole extends the file to its full size.
+pub(crate) struct SparseWriter<'a> {
+    file: &'a mut File,
+    min_hole: Option<u64>,
+    pending_zeros: u64,
+}
+
+impl<'a> SparseWriter<'a> {
+    pub(crate) fn new(file: &'a mut File, min_hole: Option<u64>) -> Self {
+        Self {
+            file,
+            min_hole,
+            pending_zeros: 0,
+        }
+    }
+
+    /// Flushes any pending zero run, either as a hole or densely, and writes
+    /// `data` after it.
+    fn write_data(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.pending_zeros > 0 {
+            if self.pending_zeros >= self.min_hole.unwrap_or(u64::MAX) {
+                self.file
+                    .seek(SeekFrom::Current(self.pending_zeros as i64))?;
+            } else {
+                write_zeros(self.file, self.pending_zeros)?;
+            }
+            self.pending_zeros = 0;
+        }
+        self.file.write_all(data)
+    }
+}
+
+impl FinishWrite for SparseWriter<'_> {
+    /// Writes out the trailing zero run, if any, and sets the final length of
+    /// the destination file when it ends with a hole.
+    fn finish(&mut self) -> io::Result<()> {
+        if self.pending_zeros > 0 {
+            self.write_data(&[])?;
+            let len = self.file.stream_position()?;
+            self.file.set_len(len)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for SparseWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let min_hole = match self.min_hole {
+            Some(h) => h,
+            None => return self.file.write(buf),
+        };
+
+        let mut data_start = 0;
+        let mut i = 0;
+        while i < buf.len() {
+            if buf[i] != 0 {
+                i += 1;
+                continue;
+            }
+            let run_start = i;
+            while i < buf.len() && buf[i] == 0 {
+                i += 1;
+            }
+            let run_len = (i - run_start) as u64;
+            if run_start == data_start {
+                // zero run continues the one pending from the previous write
+                self.pending_zeros += run_len;
+                data_start = i;
+            } else if run_len >= min_hole || i == buf.len() {
+                // zero run is either large enough to become a hole or may
+                // continue in the next write
+                self.write_data(&buf[data_start..run_start])?;
+                self.pending_zeros = run_len;
+                data_start = i;
+            }
+        }
+        if data_start < buf.len() {
+            self.write_data(&buf[data_start..])?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Writes the data of `file` to `dst` for estimating its compressibility.
+/// Files larger than `sample_threshold` are sampled by writing only a chunk of
+/// `sample_size` bytes from their start, middle and end. Returns the number of
+/// bytes written.
+pub(crate) fn write_file_sample<W: Write + ?Sized>(
+    file: &Path,
+    dst: &mut W,
+    sample_threshold: u64,
+    sample_size: u64,
+) -> io::Result<u64> {
+    let mut src = File::open(file)?;
+    let len = src.metadata()?.len();
+    let mut buf = [0u8; 32 * KB as usize];
+    if len <= sample_threshold {
+        return copy_stream(&mut src, dst, None, &mut buf);
+    }
+    let mut written = 0;
+    for offset in [0, len / 2 - sample_size / 2, len - sample_size] {
+        src.seek(SeekFrom::Start(offset))?;
+        written += copy_stream(&mut src, dst, Some(sample_size), &mut buf)?;
+    }
+    Ok(written)
+}
+
+/// A writer which only counts the bytes written to it.
+#[derive(Default)]
+struct ByteCounter(u64);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Compresses the data written with `feed` with `algo` at its default level,
+/// returning the number of bytes fed to it along with the length of the
+/// compressed output.
+pub(crate) fn measure_compression<F>(algo: CompressAlgo, feed: F) -> io::Result<(u64, u64)>
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<u64>,
+{
+    let mut compressed = ByteCounter::default();
+    let fed = match algo {
+        CompressAlgo::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(&mut compressed, flate2::Compression::default());
+            let fed = feed(&mut encoder)?;
+            encoder.finish()?;
+            fed
+        }
+        CompressAlgo::Zstd => {
+            let mut encoder = zstd::Encoder::new(&mut compressed, 0)?;
+            let fed = feed(&mut encoder)?;
+            encoder.finish()?;
+            fed
+        }
+    };
+    Ok((fed, compressed.0))
+}
+
+/// Checks if `path` is an HTTP or HTTPS URL.
+pub(crate) fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+/// Returns the last segment of the path of `url`, without the query and
+/// fragment, to be used as a file name.
+pub(crate) fn url_file_name(url: &str) -> Option<&str> {
+    let rest = url.split_once("://").map(|(_, r)| r).unwrap_or(url);
+    let rest = rest.split(['?', '#']).next().unwrap_or("");
+    let (_, path) = rest.split_once('/')?;
+    path.rsplit('/').next().filter(|name| !name.is_empty())
+}
+
+/// Response to a request for a URL source made with [`open_url`].
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+pub(crate) struct UrlResponse {
+    /// Body of the response, from the requested offset
+    pub(crate) body: Box<dyn Read + Send>,
+    /// Size of the whole resource, if the server reports it
+    pub(crate) total: Option<u64>,
+    /// Modification time of the resource, if the server reports it
+    pub(crate) modified: Option<std::time::SystemTime>,
+}
+
+/// Requests the resource at `url`, following redirects. A non-zero `offset`
+/// requests the content from that offset with a range request, which fails if
+/// the server doesn't support ranges. The body is empty if `offset` is the
+/// size of the resource. Responses other than 2xx are errors.
+#[cfg(feature = "http")]
+pub(crate) fn open_url(url: &str, offset: u64) -> io::Result<UrlResponse> {
+    let mut request = ureq::get(url);
+    if offset > 0 {
+        request = request.set("Range", &format!("bytes={}-", offset));
+    }
+    let response = match request.call() {
+        Ok(r) => r,
+        Err(ureq::Error::Status(416, r)) if offset > 0 => {
+            // nothing is left to download if the resource is complete
+            let total = r.header("Content-Range").and_then(content_range_total);
+            if total != Some(offset) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "can't resume download of '{}' at offset {}, the server reports a size of {}",
+                        url,
+                        offset,
+                        total.map_or("unknown".to_owned(), |t| t.to_string())
+                    ),
+                ));
+            }
+            return Ok(UrlResponse {
+                body: Box::new(io::empty()),
+                total,
+                modified: None,
+            });
+        }
+        Err(ureq::Error::Status(code, r)) => {
+            return Err(io::Error::other(format!(
+                "request for '{}' failed: HTTP {} {}",
+                url,
+                code,
+                r.status_text()
+            )))
+        }
+        Err(ureq::Error::Transport(e)) => {
+            return Err(io::Error::other(format!(
+                "request for '{}' failed: {}",
+                url, e
+            )))
+        }
+    };
+    if offset > 0 && response.status() != 206 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "can't resume download of '{}', the server doesn't support range requests",
+                url
+            ),
+        ));
+    }
+
+    let length = response
+        .header("Content-Length")
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    let total = if offset > 0 {
+        response
+            .header("Content-Range")
+            .and_then(content_range_total)
+            .or(length.map(|l| offset + l))
+    } else {
+        length
+    };
+    let modified = response
+        .header("Last-Modified")
+        .and_then(|v| httpdate::parse_http_date(v).ok());
+    Ok(UrlResponse {
+        body: Box::new(response.into_reader()),
+        total,
+        modified,
+    })
+}
+
+/// Fails as URL sources need the `http` feature.
+#[cfg(not(feature = "http"))]
+pub(crate) fn open_url(url: &str, _offset: u64) -> io::Result<UrlResponse> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "can't download '{}', URL sources need rs_filecopy built with the 'http' feature",
+            url
+        ),
+    ))
+}
+
+/// Parses the size of the whole resource from a `Content-Range` header, like
+/// `bytes 100-199/200` or `bytes */200`.
+#[cfg(feature = "http")]
+fn content_range_total(value: &str) -> Option<u64> {
+    value.rsplit_once('/')?.1.trim().parse().ok()
+}
+
+/// Changes the group of `file` to `gid`, keeping its owner.
+pub(crate) fn set_file_group(file: &File, gid: u32) -> io::Result<()> {
+    // SAFETY: the file descriptor is valid for the lifetime of `file`, and an
+    // owner of -1 leaves it unchanged
+    if unsafe { libc::fchown(file.as_raw_fd(), u32::MAX, gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Looks up the id of the group `name`, which may also be a numeric id.
+pub(crate) fn group_id(name: &str) -> Option<u32> {
+    if let Ok(gid) = name.parse::<u32>() {
+        return Some(gid);
+    }
+    let c_name = std::ffi::CString::new(name).ok()?;
+    // SAFETY: `c_name` is a valid NUL terminated string, and the returned
+    // entry is only read before any other call which could overwrite it
+    let group = unsafe { libc::getgrnam(c_name.as_ptr()) };
+    if group.is_null() {
+        return None;
+    }
+    Some(unsafe { (*group).gr_gid })
+}
+
+/// Sets the access and modification times of `file` to the ones in
+/// `metadata`, keeping the nanoseconds unless `precision` is
+/// [`TimesPrecision::Second`].
+pub(crate) fn set_file_times(
+    file: &File,
+    metadata: &fs::Metadata,
+    precision: TimesPrecision,
+) -> io::Result<()> {
+    let nsec = |nsec: i64| match precision {
+        TimesPrecision::Second => 0,
+        TimesPrecision::Nanosecond => nsec as libc::c_long,
+    };
+    let times = [
+        libc::timespec {
+            tv_sec: metadata.atime() as libc::time_t,
+            tv_nsec: nsec(metadata.atime_nsec()),
+        },
+        libc::timespec {
+            tv_sec: metadata.mtime() as libc::time_t,
+            tv_nsec: nsec(metadata.mtime_nsec()),
+        },
+    ];
+    // SAFETY: `times` holds the two timespecs expected by `futimens` and the
+    // file descriptor is valid for the lifetime of `file`
+    if unsafe { libc::futimens(file.as_raw_fd(), times.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Copies the extended attribute `name` from `src` to `dst`. Returns `false`
+/// without changing `dst` if `src` doesn't have the attribute or if either
+/// filesystem doesn't support extended attributes.
+pub(crate) fn copy_xattr(src: &File, dst: &File, name: &std::ffi::CStr) -> io::Result<bool> {
+    let is_unsupported =
+        |e: &io::Error| matches!(e.raw_os_error(), Some(libc::ENODATA) | Some(libc::ENOTSUP));
+
+    let mut value = Vec::new();
     loop {
-        let remaining_bytes = min(bytes_to_read_local as u64, DEFAULT_BUFFER_SIZE as u64) as usize;
-        match src.read(&mut buf[..remaining_bytes]) {
-            Ok(read_cnt) => {
-                if read_cnt == 0 || bytes_to_read_local == 0 {
-                    break;
-                }
-                bytes_to_read_local -= read_cnt;
-                dst.write_all(&buf[..read_cnt])?;
+        // SAFETY: `value` has room for `value.len()` bytes and the file
+        // descriptor is valid for the lifetime of `src`
+        let len = unsafe {
+            libc::fgetxattr(
+                src.as_raw_fd(),
+                name.as_ptr(),
+                value.as_mut_ptr() as *mut libc::c_void,
+                value.len(),
+            )
+        };
+        if len < 0 {
+            let e = io::Error::last_os_error();
+            if is_unsupported(&e) {
+                return Ok(false);
             }
-            Err(_e) => {
-                break;
+            if e.raw_os_error() == Some(libc::ERANGE) {
+                // attribute grew since its size was queried
+                value.clear();
+                continue;
             }
+            return Err(e);
+        }
+        if value.is_empty() && len > 0 {
+            value.resize(len as usize, 0);
+            continue;
         }
+        value.truncate(len as usize);
+        break;
     }
-    Ok(bytes_to_read - bytes_to_read_local)
+
+    // SAFETY: `value` holds `value.len()` bytes and the file descriptor is
+    // valid for the lifetime of `dst`
+    let ret = unsafe {
+        libc::fsetxattr(
+            dst.as_raw_fd(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        if is_unsupported(&e) {
+            return Ok(false);
+        }
+        return Err(e);
+    }
+    Ok(true)
+}
+
+/// Alignment of the buffer, file offset and length of writes to a file
+/// opened with `O_DIRECT`
+pub(crate) const DIRECT_IO_ALIGN: usize = 4 * KB as usize;
+
+/// A heap allocated buffer aligned to [`DIRECT_IO_ALIGN`].
+struct AlignedBuf {
+    ptr: std::ptr::NonNull<u8>,
+    layout: std::alloc::Layout,
+}
+
+impl AlignedBuf {
+    fn new(size: usize) -> Self {
+        let layout = std::alloc::Layout::from_size_align(size, DIRECT_IO_ALIGN)
+            .expect("invalid aligned buffer layout");
+        // SAFETY: `layout` has a non-zero size
+        let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+        let ptr =
+            std::ptr::NonNull::new(ptr).unwrap_or_else(|| std::alloc::handle_alloc_error(layout));
+        Self { ptr, layout }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        // SAFETY: `ptr` points to `layout.size()` initialized bytes
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: `ptr` points to `layout.size()` initialized bytes
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` was allocated with `layout`
+        unsafe { std::alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+/// A writer for a destination file opened with `O_DIRECT`. Data is gathered
+/// in an aligned buffer and written in aligned chunks. If the filesystem
+/// rejects the direct write, `on_fallback` is called to warn about it and
+/// `O_DIRECT` is cleared so that the copy continues through the page cache. The trailing non-aligned
+/// tail is always written through the page cache by [`DirectWriter::finish`].
+pub(crate) struct DirectWriter<'a> {
+    file: &'a mut File,
+    buf: AlignedBuf,
+    len: usize,
+    on_fallback: Box<dyn FnMut() + 'a>,
+}
+
+impl<'a> DirectWriter<'a> {
+    const BUFFER_SIZE: usize = MB as usize;
+
+    pub(crate) fn new(file: &'a mut File, on_fallback: Box<dyn FnMut() + 'a>) -> Self {
+        Self {
+            file,
+            buf: AlignedBuf::new(Self::BUFFER_SIZE),
+            len: 0,
+            on_fallback,
+        }
+    }
+
+    /// Writes out the aligned part of the buffered data.
+    fn write_aligned(&mut self) -> io::Result<()> {
+        let aligned_len = self.len - self.len % DIRECT_IO_ALIGN;
+        if aligned_len == 0 {
+            return Ok(());
+        }
+        if let Err(e) = self.file.write_all(&self.buf.as_slice()[..aligned_len]) {
+            if e.raw_os_error() != Some(libc::EINVAL) {
+                return Err(e);
+            }
+            (self.on_fallback)();
+            set_direct_io(self.file, false)?;
+            self.file.write_all(&self.buf.as_slice()[..aligned_len])?;
+        }
+        self.buf
+            .as_mut_slice()
+            .copy_within(aligned_len..self.len, 0);
+        self.len -= aligned_len;
+        Ok(())
+    }
+}
+
+impl Write for DirectWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.len == Self::BUFFER_SIZE {
+            self.write_aligned()?;
+        }
+        let count = min((Self::BUFFER_SIZE - self.len) as u64, buf.len() as u64) as usize;
+        self.buf.as_mut_slice()[self.len..self.len + count].copy_from_slice(&buf[..count]);
+        self.len += count;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_aligned()?;
+        self.file.flush()
+    }
+}
+
+impl FinishWrite for DirectWriter<'_> {
+    /// Writes out the buffered data, with the non-aligned tail written
+    /// through the page cache.
+    fn finish(&mut self) -> io::Result<()> {
+        self.write_aligned()?;
+        if self.len > 0 {
+            set_direct_io(self.file, false)?;
+            self.file.write_all(&self.buf.as_slice()[..self.len])?;
+            self.len = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Sets or clears `O_DIRECT` on an open file.
+fn set_direct_io(file: &File, enable: bool) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    // SAFETY: `fd` is a valid open file descriptor owned by `file`
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = if enable {
+        flags | libc::O_DIRECT
+    } else {
+        flags & !libc::O_DIRECT
+    };
+    // SAFETY: `fd` is a valid open file descriptor owned by `file`
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Access pattern hints for the page cache.
+#[derive(Clone, Copy)]
+pub(crate) enum FileAdvice {
+    /// The file will be read sequentially
+    Sequential,
+    /// The cached data of the file won't be needed again
+    DontNeed,
+}
+
+/// Gives an access pattern hint for the whole of `file` to the kernel using
+/// `posix_fadvise`. Since it is only a hint, failures are ignored. This is a
+/// no-op on platforms without `posix_fadvise`.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub(crate) fn fadvise(file: &File, advice: FileAdvice) {
+    let advice = match advice {
+        FileAdvice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+        FileAdvice::DontNeed => libc::POSIX_FADV_DONTNEED,
+    };
+    // SAFETY: the file descriptor is valid for the lifetime of `file`
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, advice);
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+pub(crate) fn fadvise(_file: &File, _advice: FileAdvice) {}
+
+fn write_zeros(dst: &mut File, count: u64) -> io::Result<()> {
+    const ZERO_BUFFER_SIZE: u64 = 32 * KB;
+    let buf = [0u8; ZERO_BUFFER_SIZE as usize];
+    let mut remaining = count;
+    while remaining > 0 {
+        let chunk = min(remaining, ZERO_BUFFER_SIZE);
+        dst.write_all(&buf[..chunk as usize])?;
+        remaining -= chunk;
+    }
+    Ok(())
 }
 
 fn min(a: u64, b: u64) -> u64 {
@@ -165,3 +1721,371 @@ fn min(a: u64, b: u64) -> u64 {
     }
     b
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_accepts_bytes_and_units() {
+        assert_eq!(parse_size("0"), Some(0));
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("32k"), Some(32 * KB));
+        assert_eq!(parse_size("8M"), Some(8 * MB));
+        assert_eq!(parse_size("2G"), Some(2 * GB));
+    }
+
+    #[test]
+    fn parse_size_rejects_invalid_sizes() {
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("8MB"), None);
+        assert_eq!(parse_size("-1"), None);
+        assert_eq!(parse_size("99999999999999999999G"), None);
+        assert_eq!(parse_size("18446744073709551615G"), None);
+    }
+
+    #[test]
+    fn clamp_block_size_keeps_sane_bounds() {
+        assert_eq!(clamp_block_size(0), 0);
+        assert_eq!(clamp_block_size(1), MIN_BLOCK_SIZE);
+        assert_eq!(clamp_block_size(8 * MB), 8 * MB);
+        assert_eq!(clamp_block_size(500 * GB), MAX_BLOCK_SIZE);
+    }
+
+    /// Reader which returns at most `step` bytes per read.
+    struct ShortReader<R: Read> {
+        inner: R,
+        step: usize,
+    }
+
+    impl<R: Read> Read for ShortReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = std::cmp::min(buf.len(), self.step);
+            self.inner.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn copy_stream_copies_between_cursors() {
+        let data: Vec<u8> = (0..=255).cycle().take(10_000).collect();
+        let mut src = io::Cursor::new(data.clone());
+        let mut dst = io::Cursor::new(Vec::new());
+        let mut buf = [0u8; 64];
+        assert_eq!(
+            copy_stream(&mut src, &mut dst, None, &mut buf).unwrap(),
+            10_000
+        );
+        assert_eq!(dst.into_inner(), data);
+    }
+
+    #[test]
+    fn copy_stream_handles_short_reads_and_limits() {
+        let data: Vec<u8> = (0..100).collect();
+        let mut src = ShortReader {
+            inner: io::Cursor::new(data.clone()),
+            step: 7,
+        };
+        let mut dst = io::Cursor::new(Vec::new());
+        let mut buf = [0u8; 16];
+        assert_eq!(
+            copy_stream(&mut src, &mut dst, Some(50), &mut buf).unwrap(),
+            50
+        );
+        assert_eq!(dst.get_ref()[..], data[..50]);
+
+        // the rest is copied up to the end of the source
+        assert_eq!(
+            copy_stream(&mut src, &mut dst, Some(80), &mut buf).unwrap(),
+            50
+        );
+        assert_eq!(dst.into_inner(), data);
+    }
+
+    #[test]
+    fn copy_stream_rejects_empty_buffer() {
+        let mut src = io::Cursor::new(vec![1u8]);
+        let mut dst = io::Cursor::new(Vec::new());
+        assert!(copy_stream(&mut src, &mut dst, None, &mut []).is_err());
+    }
+
+    #[test]
+    fn copy_strategy_writes_sparse_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sparse");
+        let mut file = File::create(&path).unwrap();
+        let mut data = vec![0u8; 4 * MB as usize];
+        data[0] = 1;
+        let mut strategy = CopyStrategy::new(
+            &mut file,
+            WriteMode::Sparse(64 * KB),
+            copy_buffer_size(MB, data.len() as u64),
+            Box::new(|| {}),
+        );
+        let mut src = io::Cursor::new(&data);
+        while strategy.copy_block(&mut src, MB).unwrap() > 0 {}
+        strategy.finish().unwrap();
+        drop(strategy);
+
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.len(), data.len() as u64);
+        assert!(metadata.blocks() * 512 < data.len() as u64);
+        assert_eq!(fs::read(&path).unwrap(), data);
+    }
+
+    #[test]
+    fn copy_buffer_fits_small_files() {
+        assert_eq!(copy_buffer_size(8 * MB, 100), MIN_BLOCK_SIZE);
+        assert_eq!(copy_buffer_size(8 * MB, 0), MIN_BLOCK_SIZE);
+        assert_eq!(copy_buffer_size(8 * MB, GB), 8 * MB);
+    }
+
+    #[test]
+    fn fit_memory_keeps_fitting_configurations() {
+        assert_eq!(fit_memory(4, 8 * MB, 32 * MB), (4, 8 * MB));
+        assert_eq!(fit_memory(1, 8 * MB, GB), (1, 8 * MB));
+    }
+
+    #[test]
+    fn fit_memory_shrinks_blocks_then_jobs() {
+        // smaller blocks are enough
+        assert_eq!(fit_memory(8, 8 * MB, 16 * MB), (8, 2 * MB));
+        // blocks of 1M still don't fit all the jobs
+        assert_eq!(fit_memory(16, 8 * MB, 4 * MB), (4, MB));
+        // a single job with the smallest block
+        assert_eq!(fit_memory(16, 8 * MB, KB), (1, MIN_BLOCK_SIZE));
+        // blocks below 1M are kept as they are
+        assert_eq!(fit_memory(8, 64 * KB, 128 * KB), (2, 64 * KB));
+    }
+
+    #[test]
+    fn sorted_listing_is_independent_of_creation_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let names = ["b", "a", "c/z", "c/y", "d"];
+        let sizes = [3, 2, 1, 1, 0];
+        let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+        for (base, reverse) in [(&first, false), (&second, true)] {
+            fs::create_dir_all(base.join("c")).unwrap();
+            let mut files: Vec<_> = names.iter().zip(sizes).collect();
+            if reverse {
+                files.reverse();
+            }
+            for (name, size) in files {
+                fs::write(base.join(name), vec![0u8; size]).unwrap();
+            }
+        }
+
+        let paths = |base: &Path, sort| -> Vec<String> {
+            list_dir_recursive_rel(base, sort, &[], None, &mut Vec::new())
+                .unwrap()
+                .iter()
+                .map(|f| f.path().to_owned())
+                .collect()
+        };
+        assert_eq!(
+            paths(&first, SortOrder::Name),
+            ["a", "b", "c/y", "c/z", "d"]
+        );
+        assert_eq!(
+            paths(&second, SortOrder::Name),
+            paths(&first, SortOrder::Name)
+        );
+        // directories are ordered by their own size, which is filesystem specific
+        let by_size = paths(&first, SortOrder::Size);
+        let files: Vec<_> = by_size.iter().filter(|p| !p.starts_with('c')).collect();
+        assert_eq!(files, ["d", "a", "b"]);
+        assert_eq!(paths(&second, SortOrder::Size), by_size);
+    }
+
+    #[test]
+    fn ramp_up_grows_the_rate_linearly() {
+        let limiter = RateLimiter::new(1000, Some(Duration::from_secs(2)));
+        assert_eq!(limiter.rate_at(0.0), 0.0);
+        assert_eq!(limiter.rate_at(1.0), 500.0);
+        assert_eq!(limiter.rate_at(2.0), 1000.0);
+        assert_eq!(limiter.rate_at(5.0), 1000.0);
+        assert_eq!(limiter.earned(1.0), 250.0);
+        assert_eq!(limiter.earned(2.0), 1000.0);
+        assert_eq!(limiter.earned(3.0), 2000.0);
+
+        let limiter = RateLimiter::new(1000, None);
+        assert_eq!(limiter.rate_at(0.0), 1000.0);
+        assert_eq!(limiter.earned(1.5), 1500.0);
+    }
+
+    #[test]
+    fn ramp_up_stays_below_the_cap_until_the_window_ends() {
+        const RATE: u64 = 10 * MB;
+        const CHUNK: u64 = 16 * KB;
+        let mut limiter = RateLimiter::new(RATE, Some(Duration::from_millis(400)));
+        let start = Instant::now();
+        let transfer_until = |limiter: &mut RateLimiter, secs: f64| {
+            let mut bytes = 0;
+            while start.elapsed().as_secs_f64() < secs {
+                limiter.throttle(CHUNK);
+                bytes += CHUNK;
+            }
+            bytes as f64
+        };
+
+        // an eighth of the cap is allowed in the first half of the ramp
+        let ramping = transfer_until(&mut limiter, 0.2);
+        assert!(ramping < RATE as f64 * 0.1, "{}", ramping);
+        transfer_until(&mut limiter, 0.5);
+        let full = transfer_until(&mut limiter, 0.8);
+        assert!(
+            full > RATE as f64 * 0.2 && full < RATE as f64 * 0.4,
+            "{}",
+            full
+        );
+    }
+
+    #[test]
+    fn lock_file_excludes_a_second_copy_until_released() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("copy.lock");
+        let held = lock_file(&path).unwrap();
+
+        // the lock is per open file, so another thread contends like another
+        // process would
+        let contender = path.clone();
+        let err = std::thread::spawn(move || lock_file(&contender).unwrap_err())
+            .join()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(
+            err.to_string().starts_with("another copy is in progress"),
+            "{}",
+            err
+        );
+
+        drop(held);
+        lock_file(&path).unwrap();
+    }
+
+    #[test]
+    fn io_priority_accepts_classes_and_levels() {
+        assert_eq!(parse_io_priority("idle"), Some((IoClass::Idle, 0)));
+        assert_eq!(
+            parse_io_priority("best-effort"),
+            Some((IoClass::BestEffort, 4))
+        );
+        assert_eq!(parse_io_priority("be:7"), Some((IoClass::BestEffort, 7)));
+        assert_eq!(
+            parse_io_priority("realtime:0"),
+            Some((IoClass::Realtime, 0))
+        );
+        for spec in ["", "idle:3", "be:8", "rt:x", "fast", "be:"] {
+            assert_eq!(parse_io_priority(spec), None, "{}", spec);
+        }
+    }
+
+    #[test]
+    fn scan_stops_at_the_entry_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("a")).unwrap();
+        for i in 0..5 {
+            fs::write(dir.path().join(format!("a/f{}", i)), "x").unwrap();
+        }
+        // a symlink back to an ancestor is listed, not descended into
+        std::os::unix::fs::symlink("..", dir.path().join("a/up")).unwrap();
+
+        let mut loops = Vec::new();
+        let listed =
+            list_dir_recursive_rel(dir.path(), SortOrder::Name, &[], Some(7), &mut loops).unwrap();
+        let names: Vec<_> = listed.iter().map(|f| f.path()).collect();
+        assert_eq!(names, ["a/f0", "a/f1", "a/f2", "a/f3", "a/f4", "a/up"]);
+
+        let err = list_dir_recursive_rel(dir.path(), SortOrder::Name, &[], Some(6), &mut loops)
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("found more than 6 files and directories"),
+            "{}",
+            err
+        );
+        assert!(loops.is_empty());
+    }
+
+    /// Bind mount unmounted when dropped.
+    struct BindMount(PathBuf);
+
+    impl BindMount {
+        /// Mounts `src` on `dst`, if permitted.
+        fn new(src: &Path, dst: &Path) -> Option<Self> {
+            let src = std::ffi::CString::new(src.as_os_str().as_bytes()).unwrap();
+            let target = std::ffi::CString::new(dst.as_os_str().as_bytes()).unwrap();
+            // SAFETY: the paths are NUL terminated and a bind mount takes no
+            // filesystem type or data
+            let ret = unsafe {
+                libc::mount(
+                    src.as_ptr(),
+                    target.as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_BIND,
+                    std::ptr::null(),
+                )
+            };
+            (ret == 0).then(|| Self(dst.to_owned()))
+        }
+    }
+
+    impl Drop for BindMount {
+        fn drop(&mut self) {
+            let target = std::ffi::CString::new(self.0.as_os_str().as_bytes()).unwrap();
+            // SAFETY: `target` is NUL terminated
+            unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) };
+        }
+    }
+
+    #[test]
+    fn scan_skips_directories_forming_a_loop() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("sub/loop")).unwrap();
+        fs::write(dir.path().join("sub/f"), "x").unwrap();
+        // the mounted directory is the top of the scan again
+        let Some(_mount) = BindMount::new(dir.path(), &dir.path().join("sub/loop")) else {
+            // bind mounts need CAP_SYS_ADMIN
+            return;
+        };
+
+        let mut loops = Vec::new();
+        let listed =
+            list_dir_recursive_rel(dir.path(), SortOrder::Name, &[], None, &mut loops).unwrap();
+        let names: Vec<_> = listed.iter().map(|f| f.path()).collect();
+        assert_eq!(names, ["sub/f"]);
+        assert_eq!(loops, [dir.path().join("sub/loop")]);
+    }
+
+    #[test]
+    fn protected_dir_finds_roots_and_the_working_directory() {
+        assert_eq!(
+            protected_dir(Path::new("/")).unwrap(),
+            Some("the root of a filesystem")
+        );
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("a")).unwrap();
+        assert_eq!(protected_dir(&dir.path().join("a/..")).unwrap(), None);
+        // the tests run from the package directory
+        assert_eq!(
+            protected_dir(Path::new("src/..")).unwrap(),
+            Some("the current working directory")
+        );
+    }
+
+    #[test]
+    fn fill_patterns_are_parsed_from_hex() {
+        assert_eq!(parse_fill_pattern("0x00"), Some(vec![0x00]));
+        assert_eq!(parse_fill_pattern("0XFF"), Some(vec![0xFF]));
+        assert_eq!(
+            parse_fill_pattern("0xDEADbeef"),
+            Some(vec![0xDE, 0xAD, 0xBE, 0xEF])
+        );
+        for invalid in ["", "00", "0x", "0x0", "0xZZ", "0x+1"] {
+            assert_eq!(parse_fill_pattern(invalid), None, "{}", invalid);
+        }
+        let longest = format!("0x{}", "ab".repeat(MAX_FILL_PATTERN));
+        assert_eq!(parse_fill_pattern(&longest).map(|p| p.len()), Some(64));
+        assert_eq!(parse_fill_pattern(&format!("{}ab", longest)), None);
+    }
+}
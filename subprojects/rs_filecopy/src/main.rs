@@ -6,17 +6,113 @@ use copy::util as copyutils;
 
 #[derive(Default, Debug)]
 struct CmdlineCfg {
-    src_path: String,
+    src_paths: Vec<String>,
     dst_path: String,
+    target_directory: bool,
+    no_target_directory: bool,
     block_size: u64,
     progress: bool,
+    progress_format: String,
     statistics: bool,
+    per_file_throughput: bool,
+    s3_etag: Option<u64>,
     recursive: bool,
     force: bool,
     no_dir_err: bool,
+    progress_inline_errors: bool,
     verbose: bool,
     remove: bool,
+    force_remove_root: bool,
+    truncate_source_after: bool,
+    wipe_passes: Option<u32>,
     resume: bool,
+    update: bool,
+    repair: bool,
+    delta: bool,
+    touch_only: bool,
+    sync_perms: bool,
+    sparse: bool,
+    sparse_min_hole: u64,
+    eta_format: String,
+    state_file: Option<String>,
+    keep_newest: Option<usize>,
+    pattern: Option<String>,
+    verify_perms: Option<String>,
+    foreign_owner: String,
+    require_empty_dest: bool,
+    same_fs_only: bool,
+    group: Option<String>,
+    list_only: bool,
+    dry_run: bool,
+    on_complete: Option<String>,
+    format: String,
+    null_separated: bool,
+    prefix: String,
+    suffix: String,
+    show_current_speed: bool,
+    sort: String,
+    order: String,
+    order_from: Option<String>,
+    unlisted: String,
+    truncate_long_names: bool,
+    direct_io: bool,
+    fadvise: bool,
+    tolerate_vanished: bool,
+    append: bool,
+    swap: bool,
+    split: Option<u64>,
+    join: Option<String>,
+    fill: Option<Vec<u8>>,
+    fill_size: u64,
+    compress_preview: Option<String>,
+    selinux: bool,
+    progress_file: Option<String>,
+    report: Option<String>,
+    retry_operation: u32,
+    jobs: usize,
+    max_memory: Option<u64>,
+    progress_multiline: bool,
+    to_tar: Option<String>,
+    cas_store: Option<String>,
+    from_tar: Option<String>,
+    verify_only: bool,
+    checksum: bool,
+    checksum_block: bool,
+    checksum_from: Option<String>,
+    lock: Option<String>,
+    ionice: Option<(copyutils::IoClass, u32)>,
+    no_verify_size: bool,
+    strict_total: bool,
+    copy_contents: bool,
+    limit_rate: u64,
+    ramp_up: Option<std::time::Duration>,
+    acl: bool,
+    caps: bool,
+    min_free_space: u64,
+    min_free_inodes: u64,
+    max_files: u64,
+    regular_only: bool,
+    skip_duplicate_sources: bool,
+    pad_to: u64,
+    device_size: Option<u64>,
+    head: Option<u64>,
+    tail: Option<u64>,
+    offset: u64,
+    length: Option<u64>,
+    dedupe: bool,
+    transactional: bool,
+    broken_symlinks: String,
+    on_shrink: String,
+    flatten: bool,
+    on_collision: String,
+    on_perm_error: String,
+    no_special_bits: bool,
+    progress_update_on_stall: Option<std::time::Duration>,
+    progress_min_refresh: Option<std::time::Duration>,
+    progress_suppress_when_fast: Option<std::time::Duration>,
+    journal_progress: Option<std::time::Duration>,
+    preserve_times: bool,
+    times_precision: String,
 }
 
 impl CmdlineCfg {
@@ -28,26 +124,223 @@ impl CmdlineCfg {
 fn main() {
     let cmdline_params = parse_cmdline_args();
     let copy_opts = cmdline_cfg_to_copy_opts(&cmdline_params);
-    if let Err(e) = copy::copy(
-        cmdline_params.src_path.as_str(),
-        cmdline_params.dst_path.as_str(),
-        copy_opts,
-    ) {
-        if cmdline_params.remove {
-            println!("Move failed: {}", e);
+    if let Err((failed, e)) = run(&cmdline_params, copy_opts) {
+        eprintln!("{}: {}", failed, e);
+        std::process::exit(1);
+    }
+}
+
+/// Runs the operation, followed by the completion hook once it has succeeded.
+/// A failure is returned along with what failed, for its message.
+fn run(
+    cmdline_params: &CmdlineCfg,
+    copy_opts: copy::CopyOptions,
+) -> Result<(), (&'static str, std::io::Error)> {
+    let start = std::time::Instant::now();
+    if let Err(e) = run_copy(cmdline_params, copy_opts.clone()) {
+        let failed = if cmdline_params.verify_only {
+            "Verify failed"
+        } else if cmdline_params.swap {
+            "Swap failed"
+        } else if cmdline_params.remove {
+            "Move failed"
         } else {
-            println!("Copy failed: {}", e);
+            "Copy failed"
+        };
+        return Err((failed, e));
+    }
+
+    // the completion hook runs once the whole operation has succeeded
+    if let Some(cmd) = &cmdline_params.on_complete {
+        let dst_path = cmdline_params
+            .to_tar
+            .as_ref()
+            .or(cmdline_params.cas_store.as_ref())
+            .unwrap_or(&cmdline_params.dst_path);
+        copy::run_completion_hook(cmd, dst_path, start.elapsed(), &copy_opts)
+            .map_err(|e| ("Completion hook failed", e))?;
+    }
+    Ok(())
+}
+
+fn run_copy(cmdline_params: &CmdlineCfg, copy_opts: copy::CopyOptions) -> std::io::Result<()> {
+    // held until the copy returns, the kernel drops it if we get killed
+    let _lock = match &cmdline_params.lock {
+        Some(lock_path) => Some(copyutils::lock_file(Path::new(lock_path))?),
+        None => None,
+    };
+
+    if let Some((class, level)) = cmdline_params.ionice {
+        if let Err(e) = copyutils::set_io_priority(class, level) {
+            if e.raw_os_error() != Some(libc::EPERM) {
+                return Err(std::io::Error::new(
+                    e.kind(),
+                    format!("failure in setting the I/O priority: {}", e),
+                ));
+            }
+            eprintln!("Warning: not permitted to set the realtime I/O priority, which needs CAP_SYS_ADMIN, keeping the current one");
         }
-        std::process::exit(1);
     }
+
+    // the state is loaded and recorded once for all the sources, so that
+    // every source is filtered against the last run instead of the previous
+    // source
+    let state_file = cmdline_params.state_file.as_deref().map(Path::new);
+    let run_start_time = std::time::SystemTime::now();
+    let mut copy_opts = copy_opts;
+    if let Some(state_file) = state_file {
+        copy_opts.modified_since(copyutils::read_run_state(state_file)?);
+    }
+
+    copy_sources(cmdline_params, copy_opts)?;
+
+    // record the start time of this run for the next one
+    if let Some(state_file) = state_file {
+        copyutils::write_run_state(state_file, run_start_time)?;
+    }
+    Ok(())
+}
+
+fn copy_sources(cmdline_params: &CmdlineCfg, copy_opts: copy::CopyOptions) -> std::io::Result<()> {
+    let dst_path = cmdline_params.dst_path.as_str();
+    let src_paths: Vec<&str> = cmdline_params
+        .src_paths
+        .iter()
+        .map(|s| s.as_str())
+        .collect();
+
+    if let Some(archive) = &cmdline_params.to_tar {
+        return copy::copy_to_tar(&src_paths, archive, copy_opts);
+    }
+
+    if let Some(store) = &cmdline_params.cas_store {
+        return copy::copy_to_store(&src_paths, store, copy_opts);
+    }
+
+    if let Some(archive) = &cmdline_params.from_tar {
+        return copy::copy_from_tar(archive, dst_path, copy_opts);
+    }
+
+    if cmdline_params.append {
+        return copy::concat(&src_paths, dst_path, copy_opts);
+    }
+
+    if let Some(pattern) = &cmdline_params.fill {
+        return copy::fill(pattern, cmdline_params.fill_size, dst_path, copy_opts);
+    }
+
+    if let Some(prefix) = &cmdline_params.join {
+        return copy::join(prefix, dst_path, copy_opts);
+    }
+
+    if let Some(chunk_size) = cmdline_params.split {
+        if src_paths.len() != 1 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "exactly one source file can be split with --split",
+            ));
+        }
+        return copy::split(src_paths[0], dst_path, chunk_size, copy_opts);
+    }
+
+    if cmdline_params.swap {
+        if src_paths.len() != 1 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "exactly two paths can be swapped with --swap",
+            ));
+        }
+        return copy::swap(src_paths[0], dst_path, copy_opts);
+    }
+
+    if dst_path == "-" {
+        return copy::copy_to_writer(&src_paths, &mut std::io::stdout().lock(), copy_opts);
+    }
+
+    // the target directory is always copied into
+    if cmdline_params.target_directory {
+        if let Err(e) = std::fs::create_dir_all(dst_path) {
+            return Err(std::io::Error::new(
+                e.kind(),
+                format!("failure in creating target directory: {}", e),
+            ));
+        }
+    } else if src_paths.len() == 1 {
+        return copy::copy(src_paths[0], dst_path, copy_opts);
+    }
+
+    // an exact destination name only fits a single source
+    if cmdline_params.no_target_directory {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "only a single source can be copied with --no-target-directory",
+        ));
+    }
+
+    // multiple sources are copied into the destination directory
+    if !Path::new(dst_path).is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "destination must be a directory for multiple sources, use --append to concatenate them",
+        ));
+    }
+    for src_path in src_paths {
+        copy::copy(src_path, dst_path, copy_opts.clone())?;
+    }
+    Ok(())
+}
+
+/// Parses the number given to an option, exiting with a usage error naming
+/// `what` the number is if it isn't valid.
+fn parse_number<T>(cargs: &mut App, value: &str, what: &str) -> T
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value.parse::<T>().unwrap_or_else(|e| {
+        cargs
+            .error(
+                clap::ErrorKind::InvalidValue,
+                format!("invalid {} '{}': {}", what, value, e),
+            )
+            .exit()
+    })
+}
+
+/// Parses the duration given to an option, exiting with a usage error naming
+/// `what` the duration is if it isn't valid.
+fn parse_duration(cargs: &mut App, value: &str, what: &str) -> std::time::Duration {
+    copyutils::parse_duration_from_str(value).unwrap_or_else(|| {
+        cargs
+            .error(
+                clap::ErrorKind::InvalidValue,
+                format!("invalid {} '{}'", what, value),
+            )
+            .exit()
+    })
+}
+
+/// Parses the size given to an option, like `512`, `4K` or `1G`, exiting with
+/// a usage error naming `what` the size is if it isn't valid.
+fn parse_size(cargs: &mut App, value: &str, what: &str) -> u64 {
+    copyutils::parse_size(value).unwrap_or_else(|| {
+        cargs
+            .error(
+                clap::ErrorKind::InvalidValue,
+                format!("invalid {} '{}'", what, value),
+            )
+            .exit()
+    })
 }
 
 fn parse_cmdline_args() -> CmdlineCfg {
-    let mut cmdline_config_val = CmdlineCfg::new();
+    parse_cmdline_args_from(std::env::args().collect())
+}
 
-    let args_vec: Vec<String> = std::env::args().collect();
+fn parse_cmdline_args_from(args_vec: Vec<String>) -> CmdlineCfg {
+    let mut cmdline_config_val = CmdlineCfg::new();
 
-    let  cargs = App::new(Path::new(&args_vec[0].as_str()).file_name().unwrap().to_str().unwrap())
+    let mut cargs = App::new(Path::new(&args_vec[0].as_str()).file_name().unwrap().to_str().unwrap())
         .about("A file copy utility written in rust with progress and statistics tracking")
         .arg(
             Arg::new("block-size")
@@ -55,13 +348,19 @@ fn parse_cmdline_args() -> CmdlineCfg {
                 .long("block-size")
                 .takes_value(true)
                 .default_value("8M")
-                .help("Block size for transfer (in units of K, M and G. Ex: 32M)"),
+                .help("Block size for transfer, in bytes or in units of K, M and G (Ex: 32M), kept between 4K and 1G"),
         )
         .arg(
             Arg::new("progress")
                 .short('p')
                 .long("progress")
-                .help("Show progress of the transfer"),
+                .takes_value(true)
+                .value_name("FORMAT")
+                .min_values(0)
+                .require_equals(true)
+                .possible_values(["full", "percent", "pv", "bar"])
+                .default_missing_value("full")
+                .help("Show progress of the transfer, in full, as only the overall percentage, as pv numeric lines of elapsed seconds, bytes, rate, percentage and ETA seconds on stderr, or as a bar, drawn on the controlling terminal when copying to stdout with '-' as the destination"),
         )
         .arg(
             Arg::new("recursive")
@@ -75,6 +374,18 @@ fn parse_cmdline_args() -> CmdlineCfg {
                 .long("stats")
                 .help("Show statistics of the transfer"),
         )
+        .arg(
+            Arg::new("s3-etag")
+                .long("s3-etag")
+                .takes_value(true)
+                .value_name("PART_SIZE")
+                .help("Compute the S3 ETag of every file for an upload in parts of PART_SIZE while copying it (in units of K, M and G. Ex: 8M)"),
+        )
+        .arg(
+            Arg::new("per-file-throughput")
+                .long("per-file-throughput")
+                .help("Show the transfer speed of every file in verbose mode and with the statistics"),
+        )
         .arg(
             Arg::new("force")
                 .short('f')
@@ -87,12 +398,44 @@ fn parse_cmdline_args() -> CmdlineCfg {
                 .long("move")
                 .help("Remove the source file after transfer"),
         )
+        .arg(
+            Arg::new("truncate-source-after")
+            .long("truncate-source-after")
+            .requires("move")
+            .help("Truncate every moved source file to zero right before removing it once the move is copied, freeing its space even if it's held open (not a secure wipe)")
+        )
+        .arg(
+            Arg::new("wipe-source")
+            .long("wipe-source")
+            .requires("move")
+            .help("Overwrite every moved source file with random data and then zeros before removing it once the move is copied, best effort on journaling and copy-on-write filesystems")
+        )
+        .arg(
+            Arg::new("wipe-passes")
+            .long("wipe-passes")
+            .takes_value(true)
+            .value_name("N")
+            .requires("wipe-source")
+            .help("Number of passes of random data written by --wipe-source before the pass of zeros [default: 1]")
+        )
+        .arg(
+            Arg::new("force-remove-root")
+            .long("force-remove-root")
+            .requires("move")
+            .help("Allow moving a directory which is the root of a filesystem, the home directory or the current working directory")
+        )
         .arg(
             Arg::new("nodirerr")
                 .short('n')
                 .long("no-dir-error")
                 .help("Ignore errors while copying directories"),
         )
+        .arg(
+            Arg::new("progress-inline-errors")
+                .long("progress-inline-errors")
+                .requires("nodirerr")
+                .help("Print the ignored errors above the progress line and list them again at the end"),
+        )
         .arg(
             Arg::new("verbose")
             .short('v')
@@ -105,34 +448,1055 @@ fn parse_cmdline_args() -> CmdlineCfg {
             .long("continue")
             .help("Resume a partially completed copy")
         )
-        .arg(Arg::new("SRC").help("Path to source file").required(true))
-        .arg(Arg::new("DST").help("Path to destination").required(true))
+        .arg(
+            Arg::new("update")
+            .short('u')
+            .long("update")
+            .help("Replace existing files only if the source is newer or has the same modification time but a different size")
+        )
+        .arg(
+            Arg::new("repair")
+            .long("repair")
+            .conflicts_with_all(&["resume", "append"])
+            .help("Repair existing destination files in place, copying only the blocks which differ from the source")
+        )
+        .arg(
+            Arg::new("delta")
+            .long("delta")
+            .conflicts_with_all(&["resume", "append", "repair"])
+            .help("Update existing destination files in place with an rsync style delta transfer, writing only the changed parts")
+        )
+        .arg(
+            Arg::new("touch-only")
+            .long("touch-only")
+            .conflicts_with_all(&["append", "move"])
+            .help("Only set the source timestamps on existing destination files of the same size, comparing their contents first with --checksum")
+        )
+        .arg(
+            Arg::new("sync-perms")
+            .long("sync-perms")
+            .requires("update")
+            .help("Re-apply the source permissions, group and preserved timestamps to destination files skipped as up to date")
+        )
+        .arg(
+            Arg::new("sparse")
+            .long("sparse")
+            .help("Create holes in the destination for runs of zero bytes")
+        )
+        .arg(
+            Arg::new("sparse-min-hole")
+            .long("sparse-min-hole")
+            .takes_value(true)
+            .default_value("4K")
+            .help("Minimum length of a zero run to be turned into a hole with --sparse (in units of K, M and G. Ex: 64K)")
+        )
+        .arg(
+            Arg::new("eta-format")
+            .long("eta-format")
+            .takes_value(true)
+            .possible_values(["relative", "clock"])
+            .default_value("relative")
+            .help("Show the ETA in progress as time remaining (relative) or as the completion time (clock)")
+        )
+        .arg(
+            Arg::new("since-last-run")
+            .long("since-last-run")
+            .takes_value(true)
+            .value_name("STATE_FILE")
+            .help("Copy only files modified since the last successful run recorded in the state file")
+        )
+        .arg(
+            Arg::new("keep-newest")
+            .long("keep-newest")
+            .takes_value(true)
+            .value_name("N")
+            .conflicts_with("move")
+            .help("Copy only the N most recently modified files directly under the source directory")
+        )
+        .arg(
+            Arg::new("pattern")
+            .long("pattern")
+            .takes_value(true)
+            .value_name("GLOB")
+            .requires("keep-newest")
+            .help("Select only the files matching the shell glob for --keep-newest (Ex: '*.log')")
+        )
+        .arg(
+            Arg::new("group")
+            .long("group")
+            .takes_value(true)
+            .require_equals(true)
+            .value_name("source|dir|NAME")
+            .help("Set the group of the copied files to the one of the source, of the destination directory, or to the group NAME or id")
+        )
+        .arg(
+            Arg::new("warn-foreign")
+            .long("warn-foreign")
+            .help("Warn when the destination directory is owned by another user")
+        )
+        .arg(
+            Arg::new("refuse-foreign")
+            .long("refuse-foreign")
+            .help("Fail when the destination directory is owned by another user")
+        )
+        .arg(
+            Arg::new("require-empty-dest")
+            .long("require-empty-dest")
+            .conflicts_with("resume")
+            .help("Refuse to copy a directory into a destination directory which isn't empty")
+        )
+        .arg(
+            Arg::new("same-fs-only")
+            .long("same-fs-only")
+            .help("Refuse to copy when the source and the destination are on different filesystems")
+        )
+        .arg(
+            Arg::new("verify-perms")
+            .long("verify-perms")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .possible_values(["warn", "strict"])
+            .default_missing_value("warn")
+            .help("Verify the destination permissions after copy and warn, or fail with 'strict', on mismatch")
+        )
+        .arg(
+            Arg::new("list-only")
+            .long("list-only")
+            .help("List the files which would be copied without copying them")
+        )
+        .arg(
+            Arg::new("dry-run")
+            .long("dry-run")
+            .conflicts_with_all(&["list-only", "verify-only", "append", "to-tar", "from-tar"])
+            .help("Print the directories which would be created and the files which would be copied, without copying them")
+        )
+        .arg(
+            Arg::new("on-complete")
+            .long("on-complete")
+            .takes_value(true)
+            .value_name("CMD")
+            .conflicts_with_all(&["list-only", "verify-only"])
+            .help("Run the shell command CMD once the whole operation succeeds, with the destination as its argument and in FILECOPY_DEST, and the bytes and seconds taken in FILECOPY_BYTES and FILECOPY_ELAPSED")
+        )
+        .arg(
+            Arg::new("format")
+            .long("format")
+            .takes_value(true)
+            .require_equals(true)
+            .possible_values(["text", "shell"])
+            .requires("dry-run")
+            .help("Print the dry run plan as text (default) or as quoted shell commands")
+        )
+        .arg(
+            Arg::new("verify-only")
+            .long("verify-only")
+            .conflicts_with_all(&["list-only", "append", "to-tar", "from-tar"])
+            .help("Compare the destination with the source without copying anything")
+        )
+        .arg(
+            Arg::new("checksum")
+            .long("checksum")
+            .help("Also compare the file contents by their SHA-256 digest when verifying")
+        )
+        .arg(
+            Arg::new("checksum-block")
+            .long("checksum-block")
+            .conflicts_with("o-direct")
+            .help("Read back and compare the SHA-256 digest of every block right after writing it")
+        )
+        .arg(
+            Arg::new("checksum-from")
+            .long("checksum-from")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Verify every copied file against its SHA-256 digest listed in FILE, in the format of sha256sum")
+        )
+        .arg(
+            Arg::new("lock")
+            .long("lock")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Take an exclusive lock on PATH for the duration of the copy, fail if another copy holds it")
+        )
+        .arg(
+            Arg::new("ionice")
+            .long("ionice")
+            .takes_value(true)
+            .require_equals(true)
+            .value_name("CLASS[:LEVEL]")
+            .help("Run the copy with the I/O scheduling class realtime, best-effort or idle and level 0 (highest) to 7, which only schedulers like BFQ act on")
+        )
+        .arg(
+            Arg::new("no-verify-size")
+            .long("no-verify-size")
+            .help("Copy until EOF without checking the copied size, for streaming sources like pipes")
+        )
+        .arg(
+            Arg::new("strict-total")
+            .long("strict-total")
+            .conflicts_with("no-verify-size")
+            .help("Fail unless the copied, skipped and failed bytes add up to the scanned total")
+        )
+        .arg(
+            Arg::new("copy-contents")
+            .long("copy-contents")
+            .help("Descend into pseudo filesystem mounts like /proc and /sys in recursive copies")
+        )
+        .arg(
+            Arg::new("dedupe")
+            .long("dedupe")
+            .help("Hard link identical files in a recursive copy instead of copying them again")
+        )
+        .arg(
+            Arg::new("transactional")
+            .long("transactional")
+            .conflicts_with_all(&["move", "resume"])
+            .help("Copy directories aside and move them into place only once complete, an existing destination is replaced with --force")
+        )
+        .arg(
+            Arg::new("null")
+            .short('0')
+            .long("null")
+            .help("Separate listed file paths with NUL instead of newline")
+        )
+        .arg(
+            Arg::new("prefix")
+            .long("prefix")
+            .takes_value(true)
+            .help("Prepend a prefix to the name of every copied file")
+        )
+        .arg(
+            Arg::new("suffix")
+            .long("suffix")
+            .takes_value(true)
+            .help("Append a suffix to the name of every copied file (Ex: .bak)")
+        )
+        .arg(
+            Arg::new("show-current-speed")
+            .long("show-current-speed")
+            .help("Show the current transfer speed alongside the average in progress")
+        )
+        .arg(
+            Arg::new("progress-update-on-stall")
+            .long("progress-update-on-stall")
+            .takes_value(true)
+            .value_name("DURATION")
+            .require_equals(true)
+            .default_missing_value("2s")
+            .help("Show a stalled indicator in progress when no data moves for DURATION (default 2s)")
+        )
+        .arg(
+            Arg::new("progress-min-refresh")
+            .long("progress-min-refresh")
+            .takes_value(true)
+            .value_name("MS")
+            .help("Redraw the progress line at least every MS milliseconds, even when no data moves")
+        )
+        .arg(
+            Arg::new("progress-suppress-when-fast")
+            .long("progress-suppress-when-fast")
+            .takes_value(true)
+            .value_name("MS")
+            .help("Show the progress only once the copy runs for MS milliseconds, so fast copies print no progress line")
+        )
+        .arg(
+            Arg::new("sort")
+            .long("sort")
+            .takes_value(true)
+            .possible_values(["none", "name", "size"])
+            .default_value("none")
+            .help("Order in which directory entries are copied")
+        )
+        .arg(
+            Arg::new("order")
+            .long("order")
+            .takes_value(true)
+            .require_equals(true)
+            .possible_values(["largest", "smallest", "name", "none"])
+            .default_value("none")
+            .help("Order in which all the files of a recursive copy are copied, across directories")
+        )
+        .arg(
+            Arg::new("order-from")
+            .long("order-from")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Copy the files of a recursive copy in the order of the relative paths listed in FILE, one per line")
+        )
+        .arg(
+            Arg::new("unlisted")
+            .long("unlisted")
+            .takes_value(true)
+            .require_equals(true)
+            .possible_values(["last", "skip"])
+            .default_value("last")
+            .help("Handling of the files which aren't listed in the --order-from file")
+        )
+        .arg(
+            Arg::new("broken-symlinks")
+            .long("broken-symlinks")
+            .takes_value(true)
+            .require_equals(true)
+            .possible_values(["copy", "skip", "error"])
+            .default_value("error")
+            .help("Recreate, skip with a warning, or fail on source symlinks whose target doesn't exist")
+        )
+        .arg(
+            Arg::new("on-shrink")
+            .long("on-shrink")
+            .takes_value(true)
+            .require_equals(true)
+            .possible_values(["error", "truncate-dest"])
+            .default_value("error")
+            .help("Fail, or keep the data copied so far, when a source file shrinks during the copy")
+        )
+        .arg(
+            Arg::new("flatten")
+            .long("flatten")
+            .help("Copy the files of source directories straight into the destination directory, without their subdirectories")
+        )
+        .arg(
+            Arg::new("on-collision")
+            .long("on-collision")
+            .takes_value(true)
+            .require_equals(true)
+            .possible_values(["error", "rename", "skip"])
+            .default_value("error")
+            .help("Fail, add a numeric suffix, or copy only the first file, when flattened files have the same name")
+        )
+        .arg(
+            Arg::new("on-perm-error")
+            .long("on-perm-error")
+            .takes_value(true)
+            .require_equals(true)
+            .possible_values(["warn", "error"])
+            .default_value("warn")
+            .help("Keep the copied file with a warning, or fail, when its permissions, group or capabilities can't be set")
+        )
+        .arg(
+            Arg::new("no-preserve-special-bits")
+            .long("no-preserve-special-bits")
+            .help("Don't carry over the setuid, setgid and sticky bits of the sources")
+        )
+        .arg(
+            Arg::new("truncate-long-names")
+            .long("truncate-long-names")
+            .help("Shorten destination file names which are too long using a hash of the name (lossy)")
+        )
+        .arg(
+            Arg::new("o-direct")
+            .long("o-direct")
+            .help("Write the destination with O_DIRECT to bypass the page cache")
+        )
+        .arg(
+            Arg::new("fadvise")
+            .long("fadvise")
+            .help("Hint the kernel to not keep the copied data in the page cache")
+        )
+        .arg(
+            Arg::new("tolerate-vanished")
+            .long("tolerate-vanished")
+            .help("Skip source files which vanish during the copy instead of failing")
+        )
+        .arg(
+            Arg::new("append")
+            .long("append")
+            .conflicts_with_all(&["resume", "recursive"])
+            .help("Concatenate all the source files into the destination file")
+        )
+        .arg(
+            Arg::new("split")
+            .long("split")
+            .takes_value(true)
+            .value_name("SIZE")
+            .conflicts_with_all(&["append", "resume", "recursive", "to-tar", "from-tar", "cas-store"])
+            .help("Split the source file into chunks of up to SIZE (Ex: 1G), named with a .000, .001, ... suffix, in the destination directory")
+        )
+        .arg(
+            Arg::new("join")
+            .long("join")
+            .takes_value(true)
+            .value_name("PREFIX")
+            .conflicts_with_all(&["split", "append", "resume", "recursive", "to-tar", "from-tar", "cas-store"])
+            .help("Join the chunk files named PREFIX followed by a number, like PREFIX000, in numeric order into the destination file")
+        )
+        .arg(
+            Arg::new("fill")
+            .long("fill")
+            .takes_value(true)
+            .value_name("PATTERN")
+            .requires("size")
+            .conflicts_with_all(&["join", "split", "append", "resume", "recursive", "to-tar", "from-tar", "cas-store", "move"])
+            .help("Write the repeating hex PATTERN (Ex: 0x00, 0xDEADBEEF) into the destination file up to --size instead of copying a source")
+        )
+        .arg(
+            Arg::new("size")
+            .long("size")
+            .takes_value(true)
+            .value_name("SIZE")
+            .requires("fill")
+            .help("Size of the file written by --fill (in units of K, M and G. Ex: 10M)")
+        )
+        .arg(
+            Arg::new("swap")
+            .long("swap")
+            .conflicts_with_all(&["append", "move", "recursive", "to-tar", "from-tar"])
+            .help("Atomically exchange the two given paths instead of copying")
+        )
+        .arg(
+            Arg::new("compress-preview")
+            .long("compress-preview")
+            .takes_value(true)
+            .possible_values(["gzip", "zstd"])
+            .help("Estimate the compression ratio of the source without copying it")
+        )
+        .arg(
+            Arg::new("selinux")
+            .long("selinux")
+            .help("Preserve the SELinux security context of the source files")
+        )
+        .arg(
+            Arg::new("acl")
+            .long("acl")
+            .help("Preserve the ACLs of the source files and the default ACLs of the source directories")
+        )
+        .arg(
+            Arg::new("preserve-caps")
+            .long("preserve-caps")
+            .help("Preserve the file capabilities of the source files, which needs CAP_SETFCAP")
+        )
+        .arg(
+            Arg::new("preserve")
+            .long("preserve")
+            .takes_value(true)
+            .require_equals(true)
+            .use_delimiter(true)
+            .possible_values(["context", "acl", "caps", "timestamps"])
+            .help("Preserve the specified attributes ('context' is the same as --selinux, 'acl' is the same as --acl, 'caps' is the same as --preserve-caps)")
+        )
+        .arg(
+            Arg::new("times-precision")
+            .long("times-precision")
+            .takes_value(true)
+            .require_equals(true)
+            .possible_values(["second", "nanosecond"])
+            .default_value("nanosecond")
+            .help("Precision of the timestamps preserved with --preserve=timestamps")
+        )
+        .arg(
+            Arg::new("progress-file")
+            .long("progress-file")
+            .takes_value(true)
+            .help("Keep the current progress as JSON in the specified file, replaced atomically on each update")
+        )
+        .arg(
+            Arg::new("journal-progress")
+            .long("journal-progress")
+            .takes_value(true)
+            .value_name("INTERVAL")
+            .help("Log the progress to the systemd journal once every INTERVAL (Ex: 30s, 5m), or to stderr without a journal")
+        )
+        .arg(
+            Arg::new("report")
+            .long("report")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Write the outcome of every file to PATH at the end, as CSV for a .csv extension and JSON otherwise")
+        )
+        .arg(
+            Arg::new("limit-rate")
+            .long("limit-rate")
+            .takes_value(true)
+            .value_name("SIZE")
+            .help("Limit the transfer rate to SIZE bytes per second (in units of K, M and G. Ex: 10M)")
+        )
+        .arg(
+            Arg::new("ramp-up")
+            .long("ramp-up")
+            .takes_value(true)
+            .value_name("DURATION")
+            .requires("limit-rate")
+            .help("Increase the transfer rate linearly up to the --limit-rate value over DURATION (Ex: 500ms, 10s, 1m)")
+        )
+        .arg(
+            Arg::new("pad-to")
+            .long("pad-to")
+            .takes_value(true)
+            .value_name("SIZE")
+            .conflicts_with("resume")
+            .help("Pad the destination files with zeros up to SIZE, as a hole (in units of K, M and G. Ex: 1M)")
+        )
+        .arg(
+            Arg::new("device-size")
+            .long("device-size")
+            .takes_value(true)
+            .value_name("SIZE")
+            .help("Copy SIZE bytes from block device sources instead of their reported size (in units of K, M and G. Ex: 8G)")
+        )
+        .arg(
+            Arg::new("head")
+            .long("head")
+            .takes_value(true)
+            .value_name("SIZE")
+            .conflicts_with_all(&["tail", "recursive"])
+            .help("Copy only the first SIZE bytes of the source file (in units of K, M and G. Ex: 4K)")
+        )
+        .arg(
+            Arg::new("tail")
+            .long("tail")
+            .takes_value(true)
+            .value_name("SIZE")
+            .conflicts_with_all(&["recursive"])
+            .help("Copy only the last SIZE bytes of the source file (in units of K, M and G. Ex: 4K)")
+        )
+        .arg(
+            Arg::new("offset")
+            .long("offset")
+            .takes_value(true)
+            .value_name("SIZE")
+            .conflicts_with_all(&["head", "tail", "recursive"])
+            .help("Start copying the source file at SIZE bytes (in units of K, M and G. Ex: 1M)")
+        )
+        .arg(
+            Arg::new("length")
+            .long("length")
+            .takes_value(true)
+            .value_name("SIZE")
+            .conflicts_with_all(&["head", "tail", "recursive"])
+            .help("Copy only SIZE bytes of the source file from the --offset (in units of K, M and G. Ex: 512K)")
+        )
+        .arg(
+            Arg::new("min-free-space")
+            .long("min-free-space")
+            .takes_value(true)
+            .value_name("SIZE")
+            .help("Stop the copy before the free space on the destination drops below SIZE (in units of K, M and G. Ex: 1G)")
+        )
+        .arg(
+            Arg::new("min-free-inodes")
+            .long("min-free-inodes")
+            .takes_value(true)
+            .value_name("N")
+            .help("Stop the copy before the free inodes on the destination drop below N")
+        )
+        .arg(
+            Arg::new("max-files")
+            .long("max-files")
+            .takes_value(true)
+            .value_name("N")
+            .help("Fail the scan of the source directory once more than N files and directories are found")
+        )
+        .arg(
+            Arg::new("regular-only")
+            .long("regular-only")
+            .help("Copy only regular files from source directories, skipping symlinks, FIFOs, devices and sockets")
+        )
+        .arg(
+            Arg::new("skip-duplicate-sources")
+            .long("skip-duplicate-sources")
+            .help("Copy a file only once when it is reached through more than one source, like a directory and one of its children")
+        )
+        .arg(
+            Arg::new("retry-operation")
+            .long("retry-operation")
+            .takes_value(true)
+            .value_name("N")
+            .help("Retry the whole copy up to N times on transient failures, resuming partially copied files and retrying transient directory creation failures")
+        )
+        .arg(
+            Arg::new("jobs")
+            .short('j')
+            .long("jobs")
+            .takes_value(true)
+            .value_name("N")
+            .default_value("1")
+            .help("Copy up to N files in parallel in recursive copies, largest files first")
+        )
+        .arg(
+            Arg::new("max-memory")
+            .long("max-memory")
+            .takes_value(true)
+            .value_name("SIZE")
+            .help("Keep the combined copy buffers of the jobs, a block each, within SIZE, using smaller blocks or fewer jobs")
+        )
+        .arg(
+            Arg::new("progress-multiline")
+            .long("progress-multiline")
+            .help("Show the progress of parallel copies as a line per job and an overall line")
+        )
+        .arg(
+            Arg::new("target-directory")
+            .short('t')
+            .long("target-directory")
+            .takes_value(true)
+            .value_name("DIR")
+            .conflicts_with_all(&["append", "to-tar", "from-tar", "cas-store"])
+            .help("Copy all the sources into DIR, creating it if needed, with every path argument being a source")
+        )
+        .arg(
+            Arg::new("no-target-directory")
+            .short('T')
+            .long("no-target-directory")
+            .conflicts_with_all(&["target-directory", "append", "to-tar", "from-tar", "cas-store"])
+            .help("Treat the destination as the name of the copy, even if it's an existing directory")
+        )
+        .arg(
+            Arg::new("to-tar")
+            .long("to-tar")
+            .takes_value(true)
+            .value_name("ARCHIVE")
+            .conflicts_with_all(&["append", "resume", "list-only"])
+            .help("Write the sources into a tar archive instead of a destination path")
+        )
+        .arg(
+            Arg::new("from-tar")
+            .long("from-tar")
+            .takes_value(true)
+            .value_name("ARCHIVE")
+            .conflicts_with_all(&["to-tar", "append", "resume", "list-only"])
+            .help("Extract the entries of a tar archive into the destination directory")
+        )
+        .arg(
+            Arg::new("cas-store")
+            .long("cas-store")
+            .takes_value(true)
+            .value_name("DIR")
+            .conflicts_with_all(&["to-tar", "from-tar", "append", "resume", "list-only", "move"])
+            .help("Store the sources in DIR by the SHA-256 digest of their contents, indexing their paths in DIR/index")
+        )
+        .arg(
+            Arg::new("PATHS")
+                .help("Paths to source files, or HTTP(S) URLs when built with the http feature, followed by the path to destination")
+                .required(true)
+                .min_values(1)
+                .multiple_values(true),
+        )
         .after_help(
             "Supply source and destination respectively as positional arguments after specifying the options"
         );
 
-    let matches = cargs.get_matches_from(args_vec);
+    let matches = cargs
+        .try_get_matches_from_mut(args_vec)
+        .unwrap_or_else(|e| e.exit());
 
     if let Some(blksize) = matches.value_of("block-size") {
-        let block_size = copyutils::parse_size_from_str(blksize);
-        cmdline_config_val.block_size = block_size;
+        let block_size = match copyutils::parse_size(blksize) {
+            Some(0) => cargs
+                .error(
+                    clap::ErrorKind::InvalidValue,
+                    "block size must be greater than 0",
+                )
+                .exit(),
+            Some(size) => size,
+            None => cargs
+                .error(
+                    clap::ErrorKind::InvalidValue,
+                    format!("invalid block size '{}'", blksize),
+                )
+                .exit(),
+        };
+        cmdline_config_val.block_size = copyutils::clamp_block_size(block_size);
+        if cmdline_config_val.block_size != block_size {
+            eprintln!(
+                "Warning: block size of {} bytes is out of range, using {} bytes instead",
+                block_size, cmdline_config_val.block_size
+            );
+        }
+    }
+
+    if let Some(min_hole) = matches.value_of("sparse-min-hole") {
+        cmdline_config_val.sparse_min_hole = parse_size(&mut cargs, min_hole, "minimum hole size");
+    }
+
+    if let Some(eta_format) = matches.value_of("eta-format") {
+        cmdline_config_val.eta_format = eta_format.to_owned();
+    }
+
+    if let Some(state_file) = matches.value_of("since-last-run") {
+        cmdline_config_val.state_file = Some(state_file.to_owned());
+    }
+
+    if let Some(keep_newest) = matches.value_of("keep-newest") {
+        cmdline_config_val.keep_newest = Some(parse_number(&mut cargs, keep_newest, "file count"));
+    }
+
+    if let Some(pattern) = matches.value_of("pattern") {
+        cmdline_config_val.pattern = Some(pattern.to_owned());
+    }
+
+    if let Some(group) = matches.value_of("group") {
+        if !matches!(group, "source" | "dir") && copyutils::group_id(group).is_none() {
+            cargs
+                .error(
+                    clap::ErrorKind::InvalidValue,
+                    format!("unknown group '{}'", group),
+                )
+                .exit();
+        }
+        cmdline_config_val.group = Some(group.to_owned());
+    }
+
+    if matches.occurrences_of("refuse-foreign") > 0 {
+        cmdline_config_val.foreign_owner = "refuse".to_owned();
+    } else if matches.occurrences_of("warn-foreign") > 0 {
+        cmdline_config_val.foreign_owner = "warn".to_owned();
+    }
+    cmdline_config_val.require_empty_dest = matches.occurrences_of("require-empty-dest") > 0;
+    cmdline_config_val.same_fs_only = matches.occurrences_of("same-fs-only") > 0;
+
+    if let Some(verify_perms) = matches.value_of("verify-perms") {
+        cmdline_config_val.verify_perms = Some(verify_perms.to_owned());
+    }
+
+    if let Some(prefix) = matches.value_of("prefix") {
+        cmdline_config_val.prefix = prefix.to_owned();
+    }
+
+    if let Some(suffix) = matches.value_of("suffix") {
+        cmdline_config_val.suffix = suffix.to_owned();
+    }
+
+    if let Some(broken_symlinks) = matches.value_of("broken-symlinks") {
+        cmdline_config_val.broken_symlinks = broken_symlinks.to_owned();
+    }
+
+    if let Some(on_shrink) = matches.value_of("on-shrink") {
+        cmdline_config_val.on_shrink = on_shrink.to_owned();
+    }
+
+    cmdline_config_val.flatten = matches.occurrences_of("flatten") > 0;
+    if let Some(on_collision) = matches.value_of("on-collision") {
+        cmdline_config_val.on_collision = on_collision.to_owned();
+    }
+
+    if let Some(on_perm_error) = matches.value_of("on-perm-error") {
+        cmdline_config_val.on_perm_error = on_perm_error.to_owned();
+    }
+    cmdline_config_val.no_special_bits = matches.occurrences_of("no-preserve-special-bits") > 0;
+
+    if let Some(sort) = matches.value_of("sort") {
+        cmdline_config_val.sort = sort.to_owned();
     }
 
-    cmdline_config_val.progress = matches.occurrences_of("progress") > 0;
+    if let Some(order) = matches.value_of("order") {
+        cmdline_config_val.order = order.to_owned();
+    }
+
+    if let Some(order_from) = matches.value_of("order-from") {
+        cmdline_config_val.order_from = Some(order_from.to_owned());
+    }
+
+    if let Some(unlisted) = matches.value_of("unlisted") {
+        cmdline_config_val.unlisted = unlisted.to_owned();
+    }
+
+    if let Some(algo) = matches.value_of("compress-preview") {
+        cmdline_config_val.compress_preview = Some(algo.to_owned());
+    }
+
+    if let Some(progress_file) = matches.value_of("progress-file") {
+        cmdline_config_val.progress_file = Some(progress_file.to_owned());
+    }
+
+    if let Some(interval) = matches.value_of("journal-progress") {
+        cmdline_config_val.journal_progress = Some(parse_duration(
+            &mut cargs,
+            interval,
+            "journal progress interval",
+        ));
+    }
+
+    if let Some(report) = matches.value_of("report") {
+        cmdline_config_val.report = Some(report.to_owned());
+    }
+
+    if let Some(rate) = matches.value_of("limit-rate") {
+        cmdline_config_val.limit_rate = parse_size(&mut cargs, rate, "rate limit");
+    }
+
+    if let Some(ramp_up) = matches.value_of("ramp-up") {
+        cmdline_config_val.ramp_up = Some(parse_duration(&mut cargs, ramp_up, "ramp up duration"));
+    }
+
+    if let Some(pad_to) = matches.value_of("pad-to") {
+        cmdline_config_val.pad_to = parse_size(&mut cargs, pad_to, "padded size");
+    }
+    if let Some(device_size) = matches.value_of("device-size") {
+        cmdline_config_val.device_size = Some(parse_size(&mut cargs, device_size, "device size"));
+    }
+
+    if let Some(head) = matches.value_of("head") {
+        cmdline_config_val.head = Some(parse_size(&mut cargs, head, "head size"));
+    }
+
+    if let Some(tail) = matches.value_of("tail") {
+        cmdline_config_val.tail = Some(parse_size(&mut cargs, tail, "tail size"));
+    }
+
+    if let Some(offset) = matches.value_of("offset") {
+        cmdline_config_val.offset = parse_size(&mut cargs, offset, "offset");
+    }
+
+    if let Some(length) = matches.value_of("length") {
+        cmdline_config_val.length = Some(parse_size(&mut cargs, length, "length"));
+    }
+    if let Some(min_free) = matches.value_of("min-free-space") {
+        cmdline_config_val.min_free_space = parse_size(&mut cargs, min_free, "free space");
+    }
+
+    if let Some(min_free) = matches.value_of("min-free-inodes") {
+        cmdline_config_val.min_free_inodes = parse_number(&mut cargs, min_free, "inode count");
+    }
+
+    cmdline_config_val.regular_only = matches.occurrences_of("regular-only") > 0;
+    cmdline_config_val.skip_duplicate_sources =
+        matches.occurrences_of("skip-duplicate-sources") > 0;
+
+    if let Some(max_files) = matches.value_of("max-files") {
+        cmdline_config_val.max_files = parse_number(&mut cargs, max_files, "file count");
+    }
+
+    if let Some(retries) = matches.value_of("retry-operation") {
+        cmdline_config_val.retry_operation = parse_number(&mut cargs, retries, "retry count");
+    }
+
+    if let Some(jobs) = matches.value_of("jobs") {
+        cmdline_config_val.jobs = parse_number(&mut cargs, jobs, "job count");
+    }
+
+    if let Some(max_memory) = matches.value_of("max-memory") {
+        match copyutils::parse_size(max_memory) {
+            Some(size) if size > 0 => cmdline_config_val.max_memory = Some(size),
+            _ => cargs
+                .error(
+                    clap::ErrorKind::InvalidValue,
+                    format!("invalid memory limit '{}'", max_memory),
+                )
+                .exit(),
+        }
+    }
+
+    cmdline_config_val.progress_multiline = matches.occurrences_of("progress-multiline") > 0;
+    cmdline_config_val.progress =
+        matches.occurrences_of("progress") > 0 || cmdline_config_val.progress_multiline;
+    if let Some(progress_format) = matches.value_of("progress") {
+        cmdline_config_val.progress_format = progress_format.to_owned();
+    }
     cmdline_config_val.recursive = matches.occurrences_of("recursive") > 0;
     cmdline_config_val.statistics = matches.occurrences_of("stats") > 0;
+    cmdline_config_val.per_file_throughput = matches.occurrences_of("per-file-throughput") > 0;
+    if let Some(part_size) = matches.value_of("s3-etag") {
+        cmdline_config_val.s3_etag = Some(parse_size(&mut cargs, part_size, "part size"));
+    }
     cmdline_config_val.force = matches.occurrences_of("force") > 0;
     cmdline_config_val.remove = matches.occurrences_of("move") > 0;
+    cmdline_config_val.force_remove_root = matches.occurrences_of("force-remove-root") > 0;
+    cmdline_config_val.truncate_source_after = matches.occurrences_of("truncate-source-after") > 0;
+    if matches.occurrences_of("wipe-source") > 0 {
+        match matches
+            .value_of("wipe-passes")
+            .unwrap_or("1")
+            .parse::<u32>()
+        {
+            Ok(passes) if passes > 0 => cmdline_config_val.wipe_passes = Some(passes),
+            _ => cargs
+                .error(
+                    clap::ErrorKind::InvalidValue,
+                    "number of wipe passes must be a positive integer",
+                )
+                .exit(),
+        }
+    }
     cmdline_config_val.no_dir_err = matches.occurrences_of("nodirerr") > 0;
+    cmdline_config_val.progress_inline_errors =
+        matches.occurrences_of("progress-inline-errors") > 0;
     cmdline_config_val.verbose = matches.occurrences_of("verbose") > 0;
     cmdline_config_val.resume = matches.occurrences_of("resume") > 0;
+    cmdline_config_val.update = matches.occurrences_of("update") > 0;
+    cmdline_config_val.repair = matches.occurrences_of("repair") > 0;
+    cmdline_config_val.delta = matches.occurrences_of("delta") > 0;
+    cmdline_config_val.touch_only = matches.occurrences_of("touch-only") > 0;
+    cmdline_config_val.sync_perms = matches.occurrences_of("sync-perms") > 0;
+    cmdline_config_val.sparse = matches.occurrences_of("sparse") > 0;
+    cmdline_config_val.list_only = matches.occurrences_of("list-only") > 0;
+    cmdline_config_val.dry_run = matches.occurrences_of("dry-run") > 0;
+    if let Some(cmd) = matches.value_of("on-complete") {
+        cmdline_config_val.on_complete = Some(cmd.to_owned());
+    }
+    if let Some(format) = matches.value_of("format") {
+        cmdline_config_val.format = format.to_owned();
+    }
+    cmdline_config_val.verify_only = matches.occurrences_of("verify-only") > 0;
+    cmdline_config_val.checksum = matches.occurrences_of("checksum") > 0;
+    cmdline_config_val.checksum_block = matches.occurrences_of("checksum-block") > 0;
+    if let Some(checksum_from) = matches.value_of("checksum-from") {
+        cmdline_config_val.checksum_from = Some(checksum_from.to_owned());
+    }
+    if let Some(lock) = matches.value_of("lock") {
+        cmdline_config_val.lock = Some(lock.to_owned());
+    }
+    if let Some(ionice) = matches.value_of("ionice") {
+        match copyutils::parse_io_priority(ionice) {
+            Some(priority) => cmdline_config_val.ionice = Some(priority),
+            None => cargs
+                .error(
+                    clap::ErrorKind::InvalidValue,
+                    format!("invalid I/O priority '{}', expected realtime[:0-7], best-effort[:0-7] or idle", ionice),
+                )
+                .exit(),
+        }
+    }
+    cmdline_config_val.no_verify_size = matches.occurrences_of("no-verify-size") > 0;
+    cmdline_config_val.strict_total = matches.occurrences_of("strict-total") > 0;
+    cmdline_config_val.copy_contents = matches.occurrences_of("copy-contents") > 0;
+    cmdline_config_val.dedupe = matches.occurrences_of("dedupe") > 0;
+    cmdline_config_val.transactional = matches.occurrences_of("transactional") > 0;
+    cmdline_config_val.null_separated = matches.occurrences_of("null") > 0;
+    cmdline_config_val.no_target_directory = matches.occurrences_of("no-target-directory") > 0;
+    cmdline_config_val.selinux = matches.occurrences_of("selinux") > 0
+        || matches
+            .values_of("preserve")
+            .is_some_and(|mut attrs| attrs.any(|attr| attr == "context"));
+    cmdline_config_val.preserve_times = matches
+        .values_of("preserve")
+        .is_some_and(|mut attrs| attrs.any(|attr| attr == "timestamps"));
+    if let Some(precision) = matches.value_of("times-precision") {
+        cmdline_config_val.times_precision = precision.to_owned();
+    }
+    cmdline_config_val.acl = matches.occurrences_of("acl") > 0
+        || matches
+            .values_of("preserve")
+            .is_some_and(|mut attrs| attrs.any(|attr| attr == "acl"));
+    cmdline_config_val.caps = matches.occurrences_of("preserve-caps") > 0
+        || matches
+            .values_of("preserve")
+            .is_some_and(|mut attrs| attrs.any(|attr| attr == "caps"));
+    cmdline_config_val.append = matches.occurrences_of("append") > 0;
+    cmdline_config_val.swap = matches.occurrences_of("swap") > 0;
+    cmdline_config_val.tolerate_vanished = matches.occurrences_of("tolerate-vanished") > 0;
+    cmdline_config_val.fadvise = matches.occurrences_of("fadvise") > 0;
+    cmdline_config_val.direct_io = matches.occurrences_of("o-direct") > 0;
+    cmdline_config_val.truncate_long_names = matches.occurrences_of("truncate-long-names") > 0;
+    cmdline_config_val.show_current_speed = matches.occurrences_of("show-current-speed") > 0;
+
+    if let Some(stall) = matches.value_of("progress-update-on-stall") {
+        cmdline_config_val.progress_update_on_stall =
+            Some(parse_duration(&mut cargs, stall, "stall duration"));
+    }
+
+    if let Some(refresh) = matches.value_of("progress-min-refresh") {
+        match parse_number(&mut cargs, refresh, "refresh interval") {
+            0 => cargs
+                .error(
+                    clap::ErrorKind::InvalidValue,
+                    "refresh interval must be greater than 0",
+                )
+                .exit(),
+            ms => {
+                cmdline_config_val.progress_min_refresh = Some(std::time::Duration::from_millis(ms))
+            }
+        }
+    }
+
+    if let Some(grace) = matches.value_of("progress-suppress-when-fast") {
+        match parse_number(&mut cargs, grace, "grace period") {
+            0 => cargs
+                .error(
+                    clap::ErrorKind::InvalidValue,
+                    "grace period must be greater than 0",
+                )
+                .exit(),
+            ms => {
+                cmdline_config_val.progress_suppress_when_fast =
+                    Some(std::time::Duration::from_millis(ms))
+            }
+        }
+    }
+
+    if let Some(archive) = matches.value_of("to-tar") {
+        cmdline_config_val.to_tar = Some(archive.to_owned());
+    }
+
+    if let Some(archive) = matches.value_of("from-tar") {
+        cmdline_config_val.from_tar = Some(archive.to_owned());
+    }
 
-    if let Some(src_path) = matches.value_of("SRC") {
-        cmdline_config_val.src_path = src_path.to_owned();
+    if let Some(store) = matches.value_of("cas-store") {
+        cmdline_config_val.cas_store = Some(store.to_owned());
     }
 
-    if let Some(dst_path) = matches.value_of("DST") {
-        cmdline_config_val.dst_path = dst_path.to_owned();
+    if let Some(chunk_size) = matches.value_of("split") {
+        cmdline_config_val.split = Some(parse_size(&mut cargs, chunk_size, "chunk size"));
+    }
+
+    if let Some(prefix) = matches.value_of("join") {
+        cmdline_config_val.join = Some(prefix.to_owned());
+    }
+
+    if let Some(pattern) = matches.value_of("fill") {
+        match copyutils::parse_fill_pattern(pattern) {
+            Some(bytes) => cmdline_config_val.fill = Some(bytes),
+            None => cargs
+                .error(
+                    clap::ErrorKind::InvalidValue,
+                    format!(
+                        "invalid fill pattern '{}', expected hex bytes like 0x00 or 0xDEADBEEF",
+                        pattern
+                    ),
+                )
+                .exit(),
+        }
+    }
+
+    if let Some(size) = matches.value_of("size") {
+        cmdline_config_val.fill_size = parse_size(&mut cargs, size, "fill size");
+    }
+
+    if let Some(paths) = matches.values_of("PATHS") {
+        let mut paths: Vec<String> = paths.map(|s| s.to_owned()).collect();
+        // the last path is the destination unless writing to an archive, a
+        // store or into a target directory, and the only path when extracting
+        // from an archive, joining chunks or filling a file
+        if cmdline_config_val.from_tar.is_some()
+            || cmdline_config_val.join.is_some()
+            || cmdline_config_val.fill.is_some()
+        {
+            if paths.len() != 1 {
+                let option = if cmdline_config_val.join.is_some() {
+                    "--join"
+                } else if cmdline_config_val.fill.is_some() {
+                    "--fill"
+                } else {
+                    "--from-tar"
+                };
+                cargs
+                    .error(
+                        clap::ErrorKind::WrongNumberOfValues,
+                        format!("only the destination path is expected with {}", option),
+                    )
+                    .exit();
+            }
+            if let Some(dst_path) = paths.pop() {
+                cmdline_config_val.dst_path = dst_path;
+            }
+        } else if let Some(target_dir) = matches.value_of("target-directory") {
+            cmdline_config_val.dst_path = target_dir.to_owned();
+            cmdline_config_val.target_directory = true;
+        } else if cmdline_config_val.to_tar.is_none() && cmdline_config_val.cas_store.is_none() {
+            if paths.len() < 2 {
+                cargs
+                    .error(
+                        clap::ErrorKind::TooFewValues,
+                        "both source and destination paths are required",
+                    )
+                    .exit();
+            }
+            if let Some(dst_path) = paths.pop() {
+                cmdline_config_val.dst_path = dst_path;
+            }
+        }
+        cmdline_config_val.src_paths = paths;
     }
     // println!("{:?}", &cmdline_config_val);
     cmdline_config_val
@@ -147,10 +1511,447 @@ fn cmdline_cfg_to_copy_opts(cmdline_cfg: &CmdlineCfg) -> copy::CopyOptions {
         .recursive(cmdline_cfg.recursive)
         .progress(cmdline_cfg.progress)
         .remove(cmdline_cfg.remove)
+        .force_remove_root(cmdline_cfg.force_remove_root)
+        .truncate_source_after(cmdline_cfg.truncate_source_after)
+        .wipe_source(cmdline_cfg.wipe_passes)
         .stats(cmdline_cfg.statistics)
+        .per_file_throughput(cmdline_cfg.per_file_throughput)
+        .s3_etag(cmdline_cfg.s3_etag)
         .dircopy_err(cmdline_cfg.no_dir_err)
+        .progress_inline_errors(cmdline_cfg.progress_inline_errors)
         .verbose(cmdline_cfg.verbose)
-        .resume(cmdline_cfg.resume);
+        .resume(cmdline_cfg.resume)
+        .update(cmdline_cfg.update)
+        .repair(cmdline_cfg.repair)
+        .delta(cmdline_cfg.delta)
+        .touch_only(cmdline_cfg.touch_only)
+        .sync_perms(cmdline_cfg.sync_perms)
+        .sparse(cmdline_cfg.sparse)
+        .sparse_min_hole(cmdline_cfg.sparse_min_hole)
+        .name_prefix(&cmdline_cfg.prefix)
+        .name_suffix(&cmdline_cfg.suffix)
+        .show_current_speed(cmdline_cfg.show_current_speed)
+        .truncate_long_names(cmdline_cfg.truncate_long_names)
+        .direct_io(cmdline_cfg.direct_io)
+        .fadvise(cmdline_cfg.fadvise)
+        .tolerate_vanished(cmdline_cfg.tolerate_vanished)
+        .selinux(cmdline_cfg.selinux)
+        .acl(cmdline_cfg.acl)
+        .preserve_caps(cmdline_cfg.caps)
+        .preserve_times(cmdline_cfg.preserve_times)
+        .times_precision(match cmdline_cfg.times_precision.as_str() {
+            "second" => copy::TimesPrecision::Second,
+            _ => copy::TimesPrecision::Nanosecond,
+        })
+        .pad_to(cmdline_cfg.pad_to)
+        .device_size(cmdline_cfg.device_size)
+        .head(cmdline_cfg.head)
+        .tail(cmdline_cfg.tail)
+        .offset(cmdline_cfg.offset)
+        .length(cmdline_cfg.length)
+        .min_free_space(cmdline_cfg.min_free_space)
+        .min_free_inodes(cmdline_cfg.min_free_inodes)
+        .max_files(cmdline_cfg.max_files)
+        .regular_only(cmdline_cfg.regular_only)
+        .skip_duplicate_sources(cmdline_cfg.skip_duplicate_sources)
+        .require_empty_dest(cmdline_cfg.require_empty_dest)
+        .same_fs_only(cmdline_cfg.same_fs_only)
+        .dedupe(cmdline_cfg.dedupe)
+        .transactional(cmdline_cfg.transactional)
+        .progress_file(cmdline_cfg.progress_file.as_deref().map(Path::new))
+        .report(cmdline_cfg.report.as_deref().map(Path::new))
+        .retry_operation(cmdline_cfg.retry_operation)
+        .jobs(cmdline_cfg.jobs)
+        .max_memory(cmdline_cfg.max_memory)
+        .progress_multiline(cmdline_cfg.progress_multiline)
+        // keep the bar visible while the copied data is piped
+        .progress_to_terminal(cmdline_cfg.dst_path == "-" && cmdline_cfg.progress_format == "bar")
+        .compress_preview(match cmdline_cfg.compress_preview.as_deref() {
+            Some("gzip") => Some(copy::CompressAlgo::Gzip),
+            Some("zstd") => Some(copy::CompressAlgo::Zstd),
+            _ => None,
+        })
+        .progress_stall_indicator(cmdline_cfg.progress_update_on_stall)
+        .progress_min_refresh(cmdline_cfg.progress_min_refresh)
+        .progress_suppress_when_fast(cmdline_cfg.progress_suppress_when_fast)
+        .journal_progress(cmdline_cfg.journal_progress)
+        .broken_symlinks(match cmdline_cfg.broken_symlinks.as_str() {
+            "copy" => copy::BrokenSymlinks::Copy,
+            "skip" => copy::BrokenSymlinks::Skip,
+            _ => copy::BrokenSymlinks::Error,
+        })
+        .on_shrink(match cmdline_cfg.on_shrink.as_str() {
+            "truncate-dest" => copy::OnShrink::TruncateDest,
+            _ => copy::OnShrink::Error,
+        })
+        .flatten(cmdline_cfg.flatten)
+        .on_collision(match cmdline_cfg.on_collision.as_str() {
+            "rename" => copy::OnCollision::Rename,
+            "skip" => copy::OnCollision::Skip,
+            _ => copy::OnCollision::Error,
+        })
+        .preserve_special_bits(!cmdline_cfg.no_special_bits)
+        .on_perm_error(match cmdline_cfg.on_perm_error.as_str() {
+            "error" => copy::OnPermError::Error,
+            _ => copy::OnPermError::Warn,
+        })
+        .sort(match cmdline_cfg.sort.as_str() {
+            "name" => copy::SortOrder::Name,
+            "size" => copy::SortOrder::Size,
+            _ => copy::SortOrder::None,
+        })
+        .order(match cmdline_cfg.order.as_str() {
+            "largest" => copy::CopyOrder::Largest,
+            "smallest" => copy::CopyOrder::Smallest,
+            "name" => copy::CopyOrder::Name,
+            _ => copy::CopyOrder::None,
+        })
+        .order_from(cmdline_cfg.order_from.as_deref().map(Path::new))
+        .unlisted(match cmdline_cfg.unlisted.as_str() {
+            "skip" => copy::Unlisted::Skip,
+            _ => copy::Unlisted::Last,
+        })
+        .list_only(cmdline_cfg.list_only)
+        .dry_run(cmdline_cfg.dry_run)
+        .dry_run_format(match cmdline_cfg.format.as_str() {
+            "shell" => copy::DryRunFormat::Shell,
+            _ => copy::DryRunFormat::Text,
+        })
+        .verify_only(cmdline_cfg.verify_only)
+        .checksum(cmdline_cfg.checksum)
+        .checksum_block(cmdline_cfg.checksum_block)
+        .checksum_from(cmdline_cfg.checksum_from.as_deref().map(Path::new))
+        .verify_size(!cmdline_cfg.no_verify_size)
+        .strict_total(cmdline_cfg.strict_total)
+        .copy_contents(cmdline_cfg.copy_contents)
+        .limit_rate(cmdline_cfg.limit_rate)
+        .ramp_up(cmdline_cfg.ramp_up)
+        .null_separated(cmdline_cfg.null_separated)
+        .no_target_directory(cmdline_cfg.no_target_directory)
+        .keep_newest(cmdline_cfg.keep_newest)
+        .pattern(cmdline_cfg.pattern.as_deref().unwrap_or("*"))
+        .verify_perms(match cmdline_cfg.verify_perms.as_deref() {
+            Some("strict") => copy::PermVerify::Strict,
+            Some(_) => copy::PermVerify::Warn,
+            None => copy::PermVerify::Off,
+        })
+        .group(match cmdline_cfg.group.as_deref() {
+            Some("source") => Some(copy::DestGroup::Source),
+            Some("dir") => Some(copy::DestGroup::Dir),
+            Some(name) => copyutils::group_id(name).map(copy::DestGroup::Id),
+            None => None,
+        })
+        .foreign_owner(match cmdline_cfg.foreign_owner.as_str() {
+            "refuse" => copy::ForeignOwner::Refuse,
+            "warn" => copy::ForeignOwner::Warn,
+            _ => copy::ForeignOwner::Allow,
+        })
+        .eta_format(match cmdline_cfg.eta_format.as_str() {
+            "clock" => copy::EtaFormat::Clock,
+            _ => copy::EtaFormat::Relative,
+        });
+
+    // the percentage is meant to be piped, so keep everything else out of it
+    if cmdline_cfg.progress_format == "percent" {
+        copy_opts
+            .progress_format(copy::ProgressFormat::Percent)
+            .verbose(false)
+            .stats(false)
+            .show_current_speed(false);
+    } else if cmdline_cfg.progress_format == "pv" {
+        copy_opts
+            .progress_format(copy::ProgressFormat::Pv)
+            .show_current_speed(false);
+    } else if cmdline_cfg.progress_format == "bar" {
+        copy_opts
+            .progress_format(copy::ProgressFormat::Bar)
+            .show_current_speed(false);
+    }
+
+    // stdout carries the copied data, so all the messages go to stderr
+    if cmdline_cfg.dst_path == "-" {
+        copy_opts.output(copy::Output::new(
+            Box::new(std::io::stderr()),
+            Box::new(std::io::stderr()),
+        ));
+    }
 
     copy_opts
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmdline_cfg(args: &[&str]) -> CmdlineCfg {
+        let args = std::iter::once("rs_filecopy").chain(args.iter().copied());
+        parse_cmdline_args_from(args.map(str::to_owned).collect())
+    }
+
+    fn path_str(path: &Path) -> &str {
+        path.to_str().unwrap()
+    }
+
+    #[test]
+    fn since_last_run_filters_all_sources_against_last_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b, d) = (
+            dir.path().join("a"),
+            dir.path().join("b"),
+            dir.path().join("d"),
+        );
+        for path in [&a, &b, &d] {
+            std::fs::create_dir(path).unwrap();
+        }
+        std::fs::write(a.join("x"), "x").unwrap();
+        std::fs::write(b.join("y"), "y").unwrap();
+
+        let state = dir.path().join("state");
+        let cfg = cmdline_cfg(&[
+            "-r",
+            "--since-last-run",
+            path_str(&state),
+            path_str(&a),
+            path_str(&b),
+            path_str(&d),
+        ]);
+
+        // the first run copies every source
+        run_copy(&cfg, cmdline_cfg_to_copy_opts(&cfg)).unwrap();
+        assert!(d.join("a/x").exists());
+        assert!(d.join("b/y").exists());
+
+        // the second run only copies what changed since the first one
+        std::fs::remove_file(d.join("a/x")).unwrap();
+        std::fs::write(b.join("z"), "z").unwrap();
+        run_copy(&cfg, cmdline_cfg_to_copy_opts(&cfg)).unwrap();
+        assert!(!d.join("a/x").exists());
+        assert!(d.join("b/z").exists());
+    }
+
+    /// Copies to stdout with the bar progress for
+    /// `progress_bar_is_drawn_on_the_terminal`, which runs it on a pseudo
+    /// terminal. Stdout is redirected to a file around the copy to keep the
+    /// output of the test harness out of it.
+    #[test]
+    fn progress_bar_terminal_child() {
+        let (Ok(src), Ok(out)) = (
+            std::env::var("RS_FILECOPY_TEST_BAR_SRC"),
+            std::env::var("RS_FILECOPY_TEST_BAR_OUT"),
+        ) else {
+            return;
+        };
+        use std::io::Write;
+        use std::os::unix::io::AsRawFd;
+
+        let cfg = cmdline_cfg(&["--progress=bar", "--block-size", "64K", &src, "-"]);
+        let out = std::fs::File::create(out).unwrap();
+        std::io::stdout().flush().unwrap();
+        // SAFETY: only plain descriptors are duplicated
+        let saved = unsafe { libc::dup(libc::STDOUT_FILENO) };
+        unsafe { libc::dup2(out.as_raw_fd(), libc::STDOUT_FILENO) };
+        let result = copy_sources(&cfg, cmdline_cfg_to_copy_opts(&cfg));
+        std::io::stdout().flush().unwrap();
+        unsafe {
+            libc::dup2(saved, libc::STDOUT_FILENO);
+            libc::close(saved);
+        }
+        result.unwrap();
+    }
+
+    #[test]
+    fn progress_bar_is_drawn_on_the_terminal() {
+        use std::io::Read;
+        use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+        use std::os::unix::process::CommandExt;
+
+        let (mut master, mut slave) = (-1, -1);
+        // SAFETY: the descriptors are written by `openpty` only
+        let opened = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                std::ptr::null(),
+            )
+        };
+        if opened != 0 {
+            // nothing to test without pseudo terminals
+            return;
+        }
+        // SAFETY: the descriptors were just opened and aren't owned elsewhere
+        let (master, slave) =
+            unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+        for fd in [master.as_raw_fd(), slave.as_raw_fd()] {
+            // SAFETY: `fd` is open
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let (src, out) = (dir.path().join("src"), dir.path().join("out"));
+        let data: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&src, &data).unwrap();
+
+        // run the child on its own session with the terminal as the
+        // controlling one, and with nowhere else to draw the bar
+        let mut cmd = std::process::Command::new(std::env::current_exe().unwrap());
+        cmd.args(["--exact", "tests::progress_bar_terminal_child"])
+            .env("RS_FILECOPY_TEST_BAR_SRC", &src)
+            .env("RS_FILECOPY_TEST_BAR_OUT", &out)
+            .stdin(std::process::Stdio::from(slave))
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null());
+        // SAFETY: only async signal safe calls are made in the child
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setsid() == -1 || libc::ioctl(libc::STDIN_FILENO, libc::TIOCSCTTY, 0) == -1
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut child = cmd.spawn().unwrap();
+        drop(cmd);
+
+        // the terminal reports an error once the child closes it
+        let mut terminal = std::fs::File::from(master);
+        let reader = std::thread::spawn(move || {
+            let mut text = Vec::new();
+            let _ = terminal.read_to_end(&mut text);
+            text
+        });
+        assert!(child.wait().unwrap().success());
+        let text = String::from_utf8_lossy(&reader.join().unwrap()).into_owned();
+
+        assert!(
+            text.contains(&format!("[{}] 100%", "#".repeat(30))),
+            "{:?}",
+            text
+        );
+        assert_eq!(std::fs::read(&out).unwrap(), data);
+    }
+
+    /// Returns the I/O priority of the calling thread.
+    fn io_priority() -> libc::c_long {
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        // SAFETY: `ioprio_get` only takes integer arguments
+        unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0) }
+    }
+
+    #[test]
+    fn ionice_sets_the_io_priority_before_copying() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::write(&src, "x").unwrap();
+
+        for (spec, class, level) in [
+            ("--ionice=idle", copyutils::IoClass::Idle, 0),
+            ("--ionice=best-effort:6", copyutils::IoClass::BestEffort, 6),
+        ] {
+            let cfg = cmdline_cfg(&[spec, "-f", path_str(&src), path_str(&dst)]);
+            assert_eq!(cfg.ionice, Some((class, level)));
+
+            // the priority is set on the calling thread, so the copy runs on
+            // its own one to leave the others of the test harness alone
+            let ioprio = std::thread::scope(|s| {
+                s.spawn(|| {
+                    run_copy(&cfg, cmdline_cfg_to_copy_opts(&cfg)).unwrap();
+                    io_priority()
+                })
+                .join()
+                .unwrap()
+            });
+            assert_eq!(
+                ioprio,
+                ((class as libc::c_long) << 13) | level as libc::c_long
+            );
+        }
+    }
+
+    #[test]
+    fn target_directory_takes_every_positional_as_a_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        std::fs::write(&a, "a").unwrap();
+        std::fs::write(&b, "b").unwrap();
+
+        // the target directory doesn't exist yet and is created
+        let target = format!("{}/", path_str(&dir.path().join("dir")));
+        let cfg = cmdline_cfg(&["-t", &target, path_str(&a), path_str(&b)]);
+        assert_eq!(cfg.src_paths, [path_str(&a), path_str(&b)]);
+        copy_sources(&cfg, cmdline_cfg_to_copy_opts(&cfg)).unwrap();
+        assert_eq!(std::fs::read(dir.path().join("dir/a")).unwrap(), b"a");
+        assert_eq!(std::fs::read(dir.path().join("dir/b")).unwrap(), b"b");
+    }
+
+    #[test]
+    fn no_target_directory_copies_onto_the_exact_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::create_dir(&src).unwrap();
+        std::fs::create_dir(&dst).unwrap();
+        std::fs::write(src.join("x"), "x").unwrap();
+
+        let cfg = cmdline_cfg(&["-T", "-r", path_str(&src), path_str(&dst)]);
+        copy_sources(&cfg, cmdline_cfg_to_copy_opts(&cfg)).unwrap();
+        assert_eq!(std::fs::read(dst.join("x")).unwrap(), b"x");
+        assert!(!dst.join("s").exists());
+
+        // an exact name fits a single source only
+        let cfg = cmdline_cfg(&["-T", "-r", path_str(&src), path_str(&src), path_str(&dst)]);
+        let err = copy_sources(&cfg, cmdline_cfg_to_copy_opts(&cfg)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn completion_hook_runs_once_after_success_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("s"), dir.path().join("d"));
+        std::fs::write(&src, "data").unwrap();
+        let marker = dir.path().join("marker");
+        let hook = format!("echo \"$1 $FILECOPY_BYTES\" >> '{}'", path_str(&marker));
+
+        let cfg = cmdline_cfg(&["--on-complete", &hook, path_str(&src), path_str(&dst)]);
+        run(&cfg, cmdline_cfg_to_copy_opts(&cfg)).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&marker).unwrap(),
+            format!("{} 4\n", path_str(&dst))
+        );
+
+        // neither a failed copy nor a dry run run the hook
+        let missing = dir.path().join("missing");
+        let cfg = cmdline_cfg(&["--on-complete", &hook, path_str(&missing), path_str(&dst)]);
+        let (failed, _) = run(&cfg, cmdline_cfg_to_copy_opts(&cfg)).unwrap_err();
+        assert_eq!(failed, "Copy failed");
+        let other = dir.path().join("other");
+        let cfg = cmdline_cfg(&[
+            "--dry-run",
+            "--on-complete",
+            &hook,
+            path_str(&src),
+            path_str(&other),
+        ]);
+        run(&cfg, cmdline_cfg_to_copy_opts(&cfg)).unwrap();
+        assert_eq!(std::fs::read_to_string(&marker).unwrap().lines().count(), 1);
+
+        // a failing hook fails the run but keeps the copy
+        let cfg = cmdline_cfg(&["--on-complete", "exit 3", path_str(&src), path_str(&other)]);
+        let (failed, _) = run(&cfg, cmdline_cfg_to_copy_opts(&cfg)).unwrap_err();
+        assert_eq!(failed, "Completion hook failed");
+        assert_eq!(std::fs::read(&other).unwrap(), b"data");
+    }
+
+    #[test]
+    fn sizes_without_a_unit_are_bytes() {
+        let cfg = cmdline_cfg(&["--head", "100", "src", "dst"]);
+        assert_eq!(cfg.head, Some(100));
+        let cfg = cmdline_cfg(&["--offset", "1000", "--length", "10", "src", "dst"]);
+        assert_eq!((cfg.offset, cfg.length), (1000, Some(10)));
+        let cfg = cmdline_cfg(&["--fill", "0xAB", "--size", "10", "dst"]);
+        assert_eq!(cfg.fill_size, 10);
+        let cfg = cmdline_cfg(&["--limit-rate", "2M", "--pad-to", "4K", "src", "dst"]);
+        assert_eq!((cfg.limit_rate, cfg.pad_to), (2 << 20, 4 << 10));
+    }
+}